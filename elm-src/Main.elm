@@ -102,7 +102,12 @@ update msg model =
     PlayerTick ->
       case model.playbackState of
          Playing currentMedia -> 
-          ({model | playbackState = Playing  { currentMedia | progressMillis = min (currentMedia.progressMillis + 1000) currentMedia.lengthMillis} }, Cmd.none)
+          let
+            progressMillis = case currentMedia.lengthMillis of
+              Just lengthMillis -> min (currentMedia.progressMillis + 1000) lengthMillis
+              Nothing -> currentMedia.progressMillis + 1000
+          in
+          ({model | playbackState = Playing  { currentMedia | progressMillis = progressMillis} }, Cmd.none)
          _ ->
           (model, Cmd.none)
 
@@ -190,24 +195,31 @@ view model =
 trackLength : PlaybackState -> Int
 trackLength playbackState =
   case playbackState of
-    Playing media -> media.lengthMillis
-    Paused media -> media.lengthMillis
+    Playing media -> Maybe.withDefault 0 media.lengthMillis
+    Paused media -> Maybe.withDefault 0 media.lengthMillis
     Stopped -> 0
 
 progressBar : Model -> Html Msg
 progressBar model =
   case model.playbackState of
-    Playing media -> 
+    Playing media -> mediaProgress media
+    Paused media -> mediaProgress media
+    Stopped ->  progress [ Attr.value "0", Attr.max "100", class "progress-bar"] []
+
+mediaProgress : CurrentMedia -> Html Msg
+mediaProgress media =
+  case media.lengthMillis of
+    Just lengthMillis ->
       div []
-        [ progress [ Attr.value (String.fromInt (media.progressMillis // 1000)), Attr.max (String.fromInt (media.lengthMillis // 1000)), class "progress-bar"] []
-        , text <| "progress: " ++ String.fromInt media.progressMillis ++ " max length: " ++ String.fromInt media.lengthMillis
+        [ progress [ Attr.value (String.fromInt (media.progressMillis // 1000)), Attr.max (String.fromInt (lengthMillis // 1000)), class "progress-bar"] []
+        , text <| "progress: " ++ String.fromInt media.progressMillis ++ " max length: " ++ String.fromInt lengthMillis
         ]
-    Paused media ->
+    -- streams without a length show an indeterminate progress bar
+    Nothing ->
       div []
-        [ progress [ Attr.value (String.fromInt (media.progressMillis // 1000)), Attr.max (String.fromInt (media.lengthMillis // 1000)), class "progress-bar"] []
-        , text <| "progress: " ++ String.fromInt media.progressMillis ++ " max length: " ++ String.fromInt media.lengthMillis
+        [ progress [ class "progress-bar"] []
+        , text <| "progress: " ++ String.fromInt media.progressMillis
         ]
-    Stopped ->  progress [ Attr.value "0", Attr.max "100", class "progress-bar"] []
 
 
 
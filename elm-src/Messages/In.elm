@@ -14,6 +14,8 @@ type IncomingMessage = RegisterSuccess
     | PlayerState PlaybackState (Dict String String)
     | Error
     | VolumeChange Int
+    -- messages this client doesn't handle (yet), e.g. StationsChange
+    | Unknown
 
 -- type MessageKind = RegisterSuccessKind
 --     -- | PlayKind
@@ -30,7 +32,8 @@ type PlaybackState = Playing CurrentMedia | Paused CurrentMedia | Stopped
 
 type alias CurrentMedia =
   { id: Int
-  , lengthMillis: Int
+  -- Nothing for streams of unknown length, such as internet radio
+  , lengthMillis: Maybe Int
   , progressMillis: Int
   }
 
@@ -38,7 +41,7 @@ decodeCurrentMedia : Decoder CurrentMedia
 decodeCurrentMedia =
   Json.Decode.map3 CurrentMedia
     (field "id" Json.Decode.int)
-    (field "length" Json.Decode.int)
+    (field "length" (Json.Decode.nullable Json.Decode.int))
     (field "progress" Json.Decode.int)
 
 decodePlaybackState : Decoder PlaybackState
@@ -72,7 +75,8 @@ messageDecoder =
       "FsChange" -> succeed FsChange
       "RegisterSuccess" -> succeed RegisterSuccess
       "Error" -> succeed Error
-      _ -> fail "Can't decode message kind"     
+      -- newer servers may send message kinds this client doesn't know about
+      _ -> succeed Unknown
     )
 
 playbackChangeDecoder : Decoder IncomingMessage
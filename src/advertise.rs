@@ -0,0 +1,61 @@
+use std::sync::{Arc, RwLock};
+
+use crate::network_interfaces;
+
+/// The urls users can open the application with, the preferred one first.
+/// Kept up to date by the network monitor when they depend on the network interfaces.
+pub type AdvertisedUrls = Arc<RwLock<Vec<String>>>;
+
+/// How the advertised urls are determined
+#[derive(Debug, Clone)]
+pub enum AdvertiseConfig {
+    /// A public url, the loopback address or specific bind addresses, which don't change with the network
+    Fixed(Vec<String>),
+    /// The addresses of the network interfaces, as the server listens on all of them
+    Interfaces {
+        /// The interface passed with --interface, by name or address
+        interface: Option<String>,
+        port: u16,
+        /// http or https
+        scheme: &'static str,
+        /// Whether IpV6 connections are accepted as well
+        ipv6: bool,
+        /// Urls of bind addresses beside the unspecified one
+        bind_urls: Vec<String>,
+    },
+}
+
+impl AdvertiseConfig {
+    pub fn urls(&self) -> Result<Vec<String>, String> {
+        match self {
+            AdvertiseConfig::Fixed(urls) => Ok(urls.clone()),
+            AdvertiseConfig::Interfaces{interface, port, scheme, ipv6, bind_urls} => {
+                let mut urls: Vec<String> = network_interfaces::host_addresses(interface.as_ref().map(|interface| interface.as_str()))?
+                    .iter()
+                    .filter(|ip| *ipv6 || ip.is_ipv4())
+                    .map(|ip| format!("{}://{}:{}", scheme, network_interfaces::url_host(ip), port))
+                    .collect();
+                urls.extend(bind_urls.iter().cloned());
+                Ok(urls)
+            },
+        }
+    }
+
+    /// Whether the urls change along with the network interfaces
+    pub fn follows_network(&self) -> bool {
+        match self {
+            AdvertiseConfig::Fixed(_) => false,
+            AdvertiseConfig::Interfaces{..} => true,
+        }
+    }
+}
+
+pub fn print_urls(urls: &[String]) {
+    if let Some(url) = urls.first() {
+        println!("Open {}/player in your browser", url);
+    }
+    if urls.len() > 1 {
+        println!("Also reachable at:");
+        urls.iter().skip(1).for_each(|url| println!("    {}/player", url));
+    }
+}
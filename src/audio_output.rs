@@ -0,0 +1,116 @@
+//! Selection of the libvlc audio output module and device, e.g. HDMI or a USB DAC.
+//! Every zone has its own selection, which is persisted and applied to both media players of the zone.
+
+use serde::{Serialize, Deserialize};
+
+use crate::store::Store;
+use crate::vlc_helpers;
+use crate::zones::DEFAULT_ZONE_NAME;
+
+/// Name of the file the selection is persisted in
+const STORE_NAME: &str = "audio_output";
+
+/// Accepted as name of libvlc's dummy output, which discards all audio. Useful without sound hardware.
+pub const NULL_OUTPUT: &str = "null";
+
+/// libvlc module name of the dummy output
+const DUMMY_MODULE: &str = "adummy";
+
+/// The selected output module and device. None leaves the choice to libvlc.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioOutput {
+    pub output: Option<String>,
+    pub device: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OutputInfo {
+    pub name: String,
+    pub description: String,
+    pub devices: Vec<DeviceInfo>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub description: String,
+}
+
+/// The output modules available to libvlc along with their devices
+pub fn list(instance: &vlc::Instance) -> Vec<OutputInfo> {
+    vlc_helpers::audio_outputs(instance)
+        .into_iter()
+        .map(|(name, description)| OutputInfo {
+            devices: vlc_helpers::audio_output_devices(instance, &name)
+                .into_iter()
+                .map(|(id, description)| DeviceInfo {
+                    id,
+                    description,
+                })
+                .collect(),
+            name,
+            description,
+        })
+        .collect()
+}
+
+/// The default zone keeps the file name used before there were zones
+fn store_name(zone: &str) -> String {
+    if zone == DEFAULT_ZONE_NAME { STORE_NAME.to_string() } else { format!("{}_{}", STORE_NAME, zone) }
+}
+
+/// Maps the name used by clients to the libvlc module name
+fn module_name(output: &str) -> &str {
+    if output == NULL_OUTPUT { DUMMY_MODULE } else { output }
+}
+
+impl AudioOutput {
+    /// Loads the selection of the named zone
+    pub fn load(store: &Store, zone: &str) -> Self {
+        store.load(&store_name(zone))
+    }
+
+    pub fn save(&self, store: &Store, zone: &str) {
+        store.save_or_log(&store_name(zone), self)
+    }
+
+    /// Checks that the output and device exist.
+    /// Devices are only checked if libvlc can enumerate the devices of the output.
+    pub fn validate(&self, instance: &vlc::Instance) -> Result<(), String> {
+        let output = match &self.output {
+            Some(output) => module_name(output),
+            None if self.device.is_some() => return Err(String::from("A device can only be selected along with an output")),
+            None => return Ok(()),
+        };
+        let outputs = list(instance);
+        let info = outputs
+            .iter()
+            .find(|info| info.name == output)
+            .ok_or_else(|| format!("Unknown audio output '{}'", output))?;
+        match &self.device {
+            Some(device) if !info.devices.is_empty() && !info.devices.iter().any(|d| &d.id == device) => {
+                Err(format!("Unknown device '{}' of audio output '{}'", device, output))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the selection to the media player.
+    /// A changed output takes effect with the next media played, a changed device of the current output right away.
+    pub fn apply(&self, mediaplayer: &vlc::MediaPlayer) {
+        let output = match &self.output {
+            Some(output) => module_name(output),
+            // libvlc keeps a previously selected output, its own choice is back after a restart
+            None => return,
+        };
+        if unsafe { vlc_helpers::set_audio_output(mediaplayer, output) }.is_err() {
+            println!("Failed to select audio output {}", output);
+        }
+        if let Some(device) = &self.device {
+            if unsafe { vlc_helpers::set_audio_output_device(mediaplayer, output, device) }.is_err() {
+                println!("Failed to select device {} of audio output {}", device, output);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Optional authentication with passwords and tokens, sessions kept in a cookie and the roles that limit what a connection may do.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpMessage, HttpRequest};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+
+pub const SESSION_COOKIE: &str = "fidelitas_session";
+/// Sessions end this long after the login
+const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// What a connection is allowed to do, every role may do everything the roles below it may do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Receives the player state, but can't change anything
+    Listener,
+    /// Controls playback, the queue and the volume
+    Controller,
+    /// Changes the library, settings, outputs, alarms and synchronized playback as well
+    Admin,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Result<Role, String> {
+        match role {
+            "listener" => Ok(Role::Listener),
+            "controller" => Ok(Role::Controller),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("'{}' is not a role, expected listener, controller or admin", role)),
+        }
+    }
+}
+
+/// A secret which grants a role, either a password entered on the login page or the guest token passed in urls
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub secret: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    role: Role,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+pub struct Auth {
+    /// Passwords, authentication is disabled if there are none
+    passwords: Vec<Credential>,
+    /// The token of urls shared with guests
+    guest: Option<Credential>,
+    sessions: RwLock<HashMap<String, Session>>,
+}
+
+impl Auth {
+    pub fn new(mut passwords: Vec<Credential>, guest: Option<Credential>) -> Self {
+        // a password shared by several roles logs into the highest one
+        passwords.sort_by_key(|credential| std::cmp::Reverse(credential.role));
+        Auth {
+            passwords,
+            guest,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Without passwords, everyone is an admin
+    pub fn enabled(&self) -> bool {
+        !self.passwords.is_empty()
+    }
+
+    /// The role granted by a password
+    pub fn login(&self, password: &str) -> Option<Role> {
+        self.passwords.iter()
+            .find(|credential| constant_time_eq(credential.secret.as_bytes(), password.as_bytes()))
+            .map(|credential| credential.role)
+    }
+
+    /// The role granted by the token of a url, which may only be the guest token.
+    /// Passwords are not accepted in urls, as they would end up in logs, the browser history and Referer headers.
+    fn url_token_role(&self, token: &str) -> Option<Role> {
+        self.guest.as_ref()
+            .filter(|guest| constant_time_eq(guest.secret.as_bytes(), token.as_bytes()))
+            .map(|guest| guest.role)
+    }
+
+    /// The role granted by an `Authorization: Bearer` header, the guest token or a password for clients that can't log in
+    fn bearer_role(&self, token: &str) -> Option<Role> {
+        self.url_token_role(token).or_else(|| self.login(token))
+    }
+
+    /// Starts a session with the role and returns its id
+    pub fn create_session(&self, role: Role) -> String {
+        let id = random_token();
+
+        let now = Instant::now();
+        let mut sessions = self.sessions.write().expect("Sessions lock is poisoned. This is a bug.");
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(id.clone(), Session {
+            role,
+            expires: now + SESSION_LIFETIME,
+        });
+        id
+    }
+
+    pub fn end_session(&self, req: &HttpRequest) {
+        if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+            self.sessions.write().expect("Sessions lock is poisoned. This is a bug.").remove(cookie.value());
+        }
+    }
+
+    fn session_role(&self, id: &str) -> Option<Role> {
+        self.sessions.read().expect("Sessions lock is poisoned. This is a bug.")
+            .get(id)
+            .filter(|session| session.expires > Instant::now())
+            .map(|session| session.role)
+    }
+
+    /// The role of a request, from its session cookie, an `Authorization: Bearer` header or the guest token in its url.
+    /// None if authentication is enabled and the request has none of them or they are invalid.
+    pub fn role(&self, req: &HttpRequest, token: Option<&str>) -> Option<Role> {
+        if !self.enabled() {
+            return Some(Role::Admin);
+        }
+        let session_role = req.cookie(SESSION_COOKIE).and_then(|cookie| self.session_role(cookie.value()));
+        let bearer = req.headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .filter(|header| header.starts_with("Bearer "))
+            .map(|header| header["Bearer ".len()..].trim());
+        // the highest role any of them grants
+        vec![session_role, bearer.and_then(|token| self.bearer_role(token)), token.and_then(|token| self.url_token_role(token))]
+            .into_iter()
+            .flatten()
+            .max()
+    }
+}
+
+/// The Set-Cookie header value of a session.
+/// The cookie is not sent along with requests from other sites, so their pages can't use the session.
+pub fn session_cookie(id: &str, secure: bool) -> String {
+    let secure = if secure { "; Secure" } else { "" };
+    format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}", SESSION_COOKIE, id, SESSION_LIFETIME.as_secs(), secure)
+}
+
+/// The Set-Cookie header value which removes the session cookie
+pub fn expired_session_cookie() -> String {
+    format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict", SESSION_COOKIE)
+}
+
+/// 32 random bytes in hex, e.g. for session ids
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compares secrets without revealing through the time taken how much of them matches
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::Cookie;
+    use actix_web::test::TestRequest;
+
+    fn auth() -> Auth {
+        Auth::new(vec![
+            Credential { secret: String::from("listen"), role: Role::Listener },
+            Credential { secret: String::from("control"), role: Role::Controller },
+            Credential { secret: String::from("administer"), role: Role::Admin },
+        ], Some(Credential { secret: String::from("guest"), role: Role::Controller }))
+    }
+
+    fn request(session: Option<&str>, bearer: Option<&str>) -> HttpRequest {
+        let mut request = TestRequest::default();
+        if let Some(session) = session {
+            request = request.cookie(Cookie::new(SESSION_COOKIE, session.to_string()));
+        }
+        if let Some(bearer) = bearer {
+            request = request.header("Authorization", format!("Bearer {}", bearer));
+        }
+        request.to_http_request()
+    }
+
+    #[test]
+    fn everyone_is_admin_without_passwords() {
+        let auth = Auth::new(Vec::new(), Some(Credential { secret: String::from("guest"), role: Role::Listener }));
+        assert!(!auth.enabled());
+        assert_eq!(auth.role(&request(None, None), None), Some(Role::Admin));
+        assert_eq!(auth.role(&request(None, Some("wrong")), Some("guest")), Some(Role::Admin));
+    }
+
+    #[test]
+    fn grants_the_highest_role() {
+        let auth = auth();
+        assert_eq!(auth.role(&request(None, None), None), None);
+        assert_eq!(auth.role(&request(None, Some("listen")), None), Some(Role::Listener));
+        assert_eq!(auth.role(&request(None, Some("listen")), Some("guest")), Some(Role::Controller));
+        let admin_session = auth.create_session(Role::Admin);
+        assert_eq!(auth.role(&request(Some(&admin_session), Some("listen")), Some("guest")), Some(Role::Admin));
+        let listener_session = auth.create_session(Role::Listener);
+        assert_eq!(auth.role(&request(Some(&listener_session), Some("administer")), None), Some(Role::Admin));
+        // invalid credentials don't take away the role of valid ones
+        assert_eq!(auth.role(&request(Some("unknown"), Some("wrong")), Some("guest")), Some(Role::Controller));
+    }
+
+    #[test]
+    fn rejects_expired_sessions() {
+        let auth = auth();
+        let session = auth.create_session(Role::Admin);
+        assert_eq!(auth.role(&request(Some(&session), None), None), Some(Role::Admin));
+        auth.sessions.write().expect("Sessions lock is poisoned").insert(String::from("expired"), Session {
+            role: Role::Admin,
+            expires: Instant::now(),
+        });
+        assert_eq!(auth.role(&request(Some("expired"), None), None), None);
+        auth.end_session(&request(Some(&session), None));
+        assert_eq!(auth.role(&request(Some(&session), None), None), None);
+    }
+
+    #[test]
+    fn guest_token_grants_only_its_role() {
+        let auth = auth();
+        assert_eq!(auth.role(&request(None, None), Some("guest")), Some(Role::Controller));
+        assert_eq!(auth.role(&request(None, Some("guest")), None), Some(Role::Controller));
+        // the guest token is no password
+        assert_eq!(auth.login("guest"), None);
+        assert_eq!(auth.role(&request(None, None), Some("gues")), None);
+    }
+
+    #[test]
+    fn passwords_are_not_accepted_in_urls() {
+        let auth = auth();
+        assert_eq!(auth.role(&request(None, None), Some("administer")), None);
+        assert_eq!(auth.role(&request(None, None), Some("listen")), None);
+        assert_eq!(auth.role(&request(None, Some("administer")), None), Some(Role::Admin));
+        assert_eq!(auth.login("administer"), Some(Role::Admin));
+    }
+
+    #[test]
+    fn shared_passwords_log_into_the_highest_role() {
+        let auth = Auth::new(vec![
+            Credential { secret: String::from("shared"), role: Role::Listener },
+            Credential { secret: String::from("shared"), role: Role::Admin },
+        ], None);
+        assert_eq!(auth.login("shared"), Some(Role::Admin));
+        assert_eq!(auth.login("Shared"), None);
+    }
+}
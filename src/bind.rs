@@ -0,0 +1,267 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// An address the http server listens on
+#[derive(Debug, Clone)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A bound address, ready to be handed to the http server
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Listener {
+    /// The bound tcp address, None for unix sockets
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+/// Parses a bind address: `IP`, `IP:PORT`, `[IPV6]:PORT` or `unix:PATH`.
+/// Addresses without a port use `default_port`.
+pub fn parse_bind(spec: &str, default_port: u16) -> Result<BindAddress, String> {
+    if let Some(path) = spec.strip_prefix("unix:") {
+        return parse_unix(path);
+    }
+    if let Ok(address) = spec.parse::<SocketAddr>() {
+        return Ok(BindAddress::Tcp(address));
+    }
+    // IPv6 addresses without a port may be given with or without brackets
+    let ip = match spec.strip_prefix('[') {
+        Some(bracketed) => bracketed.strip_suffix(']').and_then(|ip| ip.parse::<Ipv6Addr>().ok()).map(IpAddr::V6),
+        None => spec.parse::<IpAddr>().ok(),
+    };
+    match ip {
+        Some(ip) => Ok(BindAddress::Tcp(SocketAddr::new(ip, default_port))),
+        None => Err(format!("'{}' is not an IP address, IP:PORT or unix:PATH", spec)),
+    }
+}
+
+#[cfg(unix)]
+fn parse_unix(path: &str) -> Result<BindAddress, String> {
+    if path.is_empty() {
+        return Err(String::from("The unix socket path is empty"));
+    }
+    Ok(BindAddress::Unix(PathBuf::from(path)))
+}
+
+#[cfg(not(unix))]
+fn parse_unix(_path: &str) -> Result<BindAddress, String> {
+    Err(String::from("Unix sockets are not supported on this platform"))
+}
+
+/// Binds all given addresses, failing if any of them can't be bound
+pub fn listen(addresses: &[BindAddress]) -> Result<Vec<Listener>, String> {
+    addresses.iter().map(|address| match address {
+        BindAddress::Tcp(address) => TcpListener::bind(address)
+            .map(Listener::Tcp)
+            .map_err(|e| format!("Failed to bind {}: {}", address, e)),
+        #[cfg(unix)]
+        BindAddress::Unix(path) => {
+            remove_stale_socket(path)?;
+            Ok(Listener::Unix(path.clone()))
+        },
+    }).collect()
+}
+
+/// Binds all interfaces on both IPv6 and IPv4.
+/// Where the IPv6 socket accepts IPv4 connections as well, it is the only one.
+pub fn listen_dual_stack(port: u16) -> Result<Vec<Listener>, String> {
+    listen_pair(Ipv6Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED, port)
+}
+
+/// Binds only the loopback addresses, for running behind a reverse proxy on the same host
+pub fn listen_loopback(port: u16) -> Result<Vec<Listener>, String> {
+    listen_pair(Ipv6Addr::LOCALHOST, Ipv4Addr::LOCALHOST, port)
+}
+
+/// Binds both addresses, tolerating a missing IPv6 stack and an IPv6 socket that already accepts IPv4
+fn listen_pair(v6: Ipv6Addr, v4: Ipv4Addr, port: u16) -> Result<Vec<Listener>, String> {
+    let mut listeners = Vec::with_capacity(2);
+    let v6_error = match TcpListener::bind(SocketAddr::new(IpAddr::V6(v6), port)) {
+        Ok(listener) => {
+            listeners.push(Listener::Tcp(listener));
+            None
+        },
+        Err(e) => Some(e),
+    };
+    match TcpListener::bind(SocketAddr::new(IpAddr::V4(v4), port)) {
+        Ok(listener) => listeners.push(Listener::Tcp(listener)),
+        Err(ref e) if v6_error.is_none() && e.kind() == io::ErrorKind::AddrInUse => (),
+        Err(e) => return Err(format!("Failed to bind {}:{}: {}", v4, port, e)),
+    }
+    if let Some(e) = v6_error {
+        println!("Listening on IPv4 only, failed to bind [{}]:{}: {}", v6, port, e);
+    }
+    Ok(listeners)
+}
+
+/// A socket file left behind by a previous run would make binding fail
+#[cfg(unix)]
+fn remove_stale_socket(path: &PathBuf) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .map_err(|e| format!("Failed to remove stale unix socket {}: {}", path.display(), e)),
+        Ok(_) => Err(format!("Refusing to bind unix socket {}: the path exists and is not a socket", path.display())),
+        Err(_) => Ok(()),
+    }
+}
+
+/// The url the server can be reached with through the given bind address, with `scheme` being http or https.
+/// None for unix sockets and unspecified addresses, whose reachable address depends on the network interface.
+pub fn advertised_url(address: &BindAddress, scheme: &str) -> Option<String> {
+    match address {
+        BindAddress::Tcp(address) if address.ip().is_unspecified() => None,
+        BindAddress::Tcp(address) => Some(format!("{}://{}", scheme, address)),
+        #[cfg(unix)]
+        BindAddress::Unix(_) => None,
+    }
+}
+
+/// The tcp addresses with their port replaced, e.g. to listen for plain http requests to redirect to https
+pub fn with_port(addresses: &[BindAddress], port: u16) -> Vec<BindAddress> {
+    addresses.iter()
+        .filter_map(|address| match address {
+            BindAddress::Tcp(address) => Some(BindAddress::Tcp(SocketAddr::new(address.ip(), port))),
+            #[cfg(unix)]
+            BindAddress::Unix(_) => None,
+        })
+        .collect()
+}
+
+/// Splits a host header or the authority of a url into the host and the port.
+/// The host of IPv6 addresses keeps its brackets, as they contain colons themselves.
+/// None if the host contains characters not allowed in host names and IP addresses or the port is invalid.
+pub fn split_host_port(authority: &str) -> Option<(&str, Option<u16>)> {
+    if authority.is_empty() || !authority.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c)) {
+        return None;
+    }
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority.find(']')? + 1;
+        let address = &authority[1..end - 1];
+        if address.is_empty() || address.contains('[') {
+            return None;
+        }
+        authority.split_at(end)
+    } else {
+        match authority.find(':') {
+            Some(index) => authority.split_at(index),
+            None => (authority, ""),
+        }
+    };
+    if host.is_empty() || (!host.starts_with('[') && host.contains(['[', ']'])) {
+        return None;
+    }
+    let port = match port {
+        "" => None,
+        port => {
+            let digits = port.strip_prefix(':')?;
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            Some(digits.parse::<u16>().ok().filter(|port| *port != 0)?)
+        },
+    };
+    Some((host, port))
+}
+
+/// Checks a url passed to be advertised instead of the bind address, e.g. the address of a reverse proxy.
+/// The url may have a path, for proxies serving the player below one.
+pub fn parse_public_url(url: &str) -> Result<String, String> {
+    let rest = match url.strip_prefix("http://").or_else(|| url.strip_prefix("https://")) {
+        Some(rest) => rest,
+        None => return Err(format!("'{}' is not an http:// or https:// url", url)),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+    if split_host_port(authority).is_none() {
+        return Err(format!("'{}' has no valid host and port", url));
+    }
+    if path.contains(|c: char| c.is_whitespace() || c.is_control() || c == '?' || c == '#') {
+        return Err(format!("'{}' has an invalid path", url));
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(spec: &str, default_port: u16) -> Option<SocketAddr> {
+        match parse_bind(spec, default_port) {
+            Ok(BindAddress::Tcp(address)) => Some(address),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_ip_addresses_with_and_without_port() {
+        assert_eq!(tcp("192.168.1.20:9000", 8088), Some(SocketAddr::from(([192, 168, 1, 20], 9000))));
+        assert_eq!(tcp("192.168.1.20", 8088), Some(SocketAddr::from(([192, 168, 1, 20], 8088))));
+        assert_eq!(tcp("[::1]:9000", 8088), Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9000)));
+        assert_eq!(tcp("[::1]", 8088), Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8088)));
+        assert_eq!(tcp("::1", 8088), Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8088)));
+    }
+
+    #[test]
+    fn rejects_invalid_bind_addresses() {
+        for spec in &["", "localhost", "localhost:80", "1.2.3.4:99999", "1.2.3.4:", "[::1", "::1]", "[[::1]]", "[1.2.3.4]", "1.2.3"] {
+            assert!(parse_bind(spec, 8088).is_err(), "accepted {}", spec);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parses_unix_sockets() {
+        match parse_bind("unix:/run/fidelitas.sock", 8088) {
+            Ok(BindAddress::Unix(path)) => assert_eq!(path, PathBuf::from("/run/fidelitas.sock")),
+            _ => panic!("Not parsed as unix socket"),
+        }
+        assert!(parse_bind("unix:", 8088).is_err());
+    }
+
+    #[test]
+    fn splits_host_and_port() {
+        assert_eq!(split_host_port("player.local:8080"), Some(("player.local", Some(8080))));
+        assert_eq!(split_host_port("player.local"), Some(("player.local", None)));
+        assert_eq!(split_host_port("[2001:db8::2]:443"), Some(("[2001:db8::2]", Some(443))));
+        assert_eq!(split_host_port("[::1]"), Some(("[::1]", None)));
+        for authority in &["host:0", "host:65536", "host:+80", "host:", "[::1]:", "user@host"] {
+            assert_eq!(split_host_port(authority), None, "accepted {}", authority);
+        }
+    }
+
+    #[test]
+    fn accepts_public_urls() {
+        assert_eq!(parse_public_url("https://music.example.com/"), Ok(String::from("https://music.example.com")));
+        assert_eq!(parse_public_url("http://192.168.1.20:8088"), Ok(String::from("http://192.168.1.20:8088")));
+        assert_eq!(parse_public_url("https://[2001:db8::2]:8443"), Ok(String::from("https://[2001:db8::2]:8443")));
+        assert_eq!(parse_public_url("https://example.com/fidelitas/"), Ok(String::from("https://example.com/fidelitas")));
+    }
+
+    #[test]
+    fn rejects_invalid_public_urls() {
+        for url in &[
+            "", "example.com", "ftp://example.com", "http://", "https:///player", "http://host/ path", "http://host:port",
+            "http://host:99999", "http://user@host", "http://host?x=1", "http://host/player?x=1", "http://host/#top", "http://2001:db8::2",
+        ] {
+            assert!(parse_public_url(url).is_err(), "accepted {}", url);
+        }
+    }
+}
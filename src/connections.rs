@@ -0,0 +1,218 @@
+//! The websocket connections registered with the player, each with a bounded queue of outgoing messages.
+//! Messages wait in the queue while the connection's mailbox is full, which happens when its client reads slower than the player sends,
+//! e.g. a phone on bad Wi-Fi. Queued state is replaced by newer state, and clients that fall too far behind are resynchronized or dropped.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use actix::Addr;
+use actix::prelude::SendError;
+
+use crate::websocket::{OutgoingMsg, PlayerWs, Disconnect};
+use crate::zones::DEFAULT_ZONE;
+
+/// A connection with more messages waiting is sent the complete state instead, once it catches up
+const MAX_QUEUED_MESSAGES: usize = 32;
+/// Connections that didn't catch up for this long are closed
+const MAX_LAG: Duration = Duration::from_secs(60);
+
+struct Client {
+    /// The zone the connection subscribed to, None for all zones
+    zone: Option<u64>,
+    /// Messages waiting for room in the connection's mailbox, oldest first
+    queue: VecDeque<OutgoingMsg>,
+    /// Set when the queue overflowed and was discarded. The connection needs the complete state.
+    needs_resync: bool,
+    /// Since when messages have been waiting, None while the connection keeps up
+    behind_since: Option<Instant>,
+}
+
+enum Flushed {
+    /// Everything was sent or is waiting for room
+    Ok,
+    /// The actor stopped without unregistering, e.g. after its connection failed
+    Closed,
+}
+
+impl Client {
+    fn new(zone: Option<u64>) -> Self {
+        Client {
+            zone,
+            queue: VecDeque::new(),
+            needs_resync: false,
+            behind_since: None,
+        }
+    }
+
+    /// Queues the message, replacing the queued messages it supersedes
+    fn push(&mut self, msg: OutgoingMsg) {
+        match msg {
+            OutgoingMsg::PlayerState{..} => self.needs_resync = false,
+            // the complete state the connection is going to be sent covers it
+            _ if self.needs_resync => return,
+            _ => {},
+        }
+        self.queue.retain(|queued| !msg.supersedes(queued));
+        self.queue.push_back(msg);
+        if self.queue.len() > MAX_QUEUED_MESSAGES {
+            println!("A connection fell {} messages behind, sending it the complete state once it catches up", self.queue.len());
+            self.queue.clear();
+            self.needs_resync = true;
+        }
+    }
+
+    /// Moves queued messages into the connection's mailbox until it is full
+    fn flush(&mut self, addr: &Addr<PlayerWs>) -> Flushed {
+        while let Some(msg) = self.queue.pop_front() {
+            match addr.try_send(msg) {
+                Ok(()) => {},
+                Err(SendError::Full(msg)) => {
+                    self.queue.push_front(msg);
+                    break;
+                },
+                Err(SendError::Closed(_)) => return Flushed::Closed,
+            }
+        }
+        if self.queue.is_empty() && !self.needs_resync {
+            self.behind_since = None;
+        } else if self.behind_since.is_none() {
+            self.behind_since = Some(Instant::now());
+        }
+        Flushed::Ok
+    }
+}
+
+/// Changed through shared references, so the player can broadcast from methods that don't change its state
+#[derive(Default)]
+pub struct Connections {
+    clients: RefCell<HashMap<Addr<PlayerWs>, Client>>,
+}
+
+impl Connections {
+    /// Connections receive updates of the default zone until they subscribe to another zone, or to all zones
+    pub fn register(&self, addr: Addr<PlayerWs>) {
+        self.clients.borrow_mut().insert(addr, Client::new(Some(DEFAULT_ZONE)));
+    }
+
+    pub fn unregister(&self, addr: &Addr<PlayerWs>) {
+        self.clients.borrow_mut().remove(addr);
+    }
+
+    /// Limits the zone updates the connection receives to a single zone, or all zones for None
+    pub fn subscribe(&self, addr: &Addr<PlayerWs>, zone: Option<u64>) {
+        self.clients.borrow_mut()
+            .entry(addr.clone())
+            .or_insert_with(|| Client::new(zone))
+            .zone = zone;
+    }
+
+    /// Sends the message to every connection subscribed to its zone
+    pub fn broadcast(&self, msg: OutgoingMsg) {
+        let zone_id = msg.zone_id();
+        self.send_where(|_, client| match (zone_id, client.zone) {
+            // connections subscribed to a single zone only hear about the other zones' shared state
+            (Some(zone_id), Some(subscription)) => zone_id == subscription,
+            _ => true,
+        }, msg);
+    }
+
+    /// Sends the message to a single connection
+    pub fn send(&self, addr: &Addr<PlayerWs>, msg: OutgoingMsg) {
+        if !self.clients.borrow().contains_key(addr) {
+            // replies to connections which unregistered in the meantime
+            if let Err(e) = addr.try_send(msg) {
+                println!("Failed to send message to an unregistered connection: {}", e);
+            }
+            return;
+        }
+        self.send_where(|client_addr, _| client_addr == addr, msg);
+    }
+
+    fn send_where<F: Fn(&Addr<PlayerWs>, &Client) -> bool>(&self, matches: F, msg: OutgoingMsg) {
+        let mut clients = self.clients.borrow_mut();
+        let mut closed = Vec::new();
+        for (addr, client) in clients.iter_mut().filter(|(addr, client)| matches(addr, client)) {
+            client.push(msg.clone());
+            if let Flushed::Closed = client.flush(addr) {
+                closed.push(addr.clone());
+            }
+        }
+        if !closed.is_empty() {
+            println!("Unregistered {} closed connection(s)", closed.len());
+            for addr in closed {
+                clients.remove(&addr);
+            }
+        }
+    }
+
+    /// Retries sending the queued messages and closes connections that didn't catch up in time.
+    /// Returns the connections which need the complete state along with the zone they subscribed to.
+    pub fn flush(&self) -> Vec<(Addr<PlayerWs>, Option<u64>)> {
+        let mut clients = self.clients.borrow_mut();
+        let mut removed = Vec::new();
+        let mut resync = Vec::new();
+        for (addr, client) in clients.iter_mut() {
+            if let Flushed::Closed = client.flush(addr) {
+                removed.push(addr.clone());
+            } else if client.behind_since.map(|since| since.elapsed() > MAX_LAG).unwrap_or(false) {
+                println!("Closing a connection which has been falling behind for {} seconds", MAX_LAG.as_secs());
+                // delivered regardless of the full mailbox
+                addr.do_send(Disconnect);
+                removed.push(addr.clone());
+            } else if client.needs_resync {
+                resync.push((addr.clone(), client.zone));
+            }
+        }
+        for addr in removed {
+            clients.remove(&addr);
+        }
+        resync
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_state_is_replaced() {
+        let mut client = Client::new(Some(DEFAULT_ZONE));
+        for volume in 0..100 {
+            client.push(OutgoingMsg::VolumeChange{zone_id: DEFAULT_ZONE, volume});
+        }
+        client.push(OutgoingMsg::Error);
+        client.push(OutgoingMsg::MuteChange{zone_id: DEFAULT_ZONE, muted: true});
+        client.push(OutgoingMsg::VolumeChange{zone_id: DEFAULT_ZONE, volume: 100});
+        assert!(!client.needs_resync);
+        assert_eq!(client.queue.len(), 3);
+        match (&client.queue[0], &client.queue[1], &client.queue[2]) {
+            (OutgoingMsg::Error, OutgoingMsg::MuteChange{..}, OutgoingMsg::VolumeChange{volume: 100, ..}) => {},
+            _ => panic!("Superseded messages were not replaced"),
+        }
+    }
+
+    #[test]
+    fn overflowing_queue_is_resynchronized() {
+        let mut client = Client::new(Some(DEFAULT_ZONE));
+        for _ in 0..MAX_QUEUED_MESSAGES {
+            client.push(OutgoingMsg::Error);
+        }
+        assert_eq!(client.queue.len(), MAX_QUEUED_MESSAGES);
+        assert!(!client.needs_resync);
+
+        client.push(OutgoingMsg::Error);
+        assert!(client.queue.is_empty());
+        assert!(client.needs_resync);
+
+        // the complete state covers everything until it is queued, replies and notifications included
+        client.push(OutgoingMsg::VolumeChange{zone_id: DEFAULT_ZONE, volume: 40});
+        client.push(OutgoingMsg::RateLimited);
+        assert!(client.queue.is_empty());
+
+        client.push(OutgoingMsg::empty_player_state(DEFAULT_ZONE));
+        assert!(!client.needs_resync);
+        client.push(OutgoingMsg::MuteChange{zone_id: DEFAULT_ZONE, muted: true});
+        assert_eq!(client.queue.len(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+//! Parser for cue sheets, as commonly shipped alongside single-file album rips.
+//! Only the commands needed to split a file into tracks are interpreted, everything else is ignored.
+
+/// Cue sheet timestamps are given in minutes, seconds and frames, with 75 frames per second
+const FRAMES_PER_SECOND: i64 = 75;
+
+#[derive(Clone, Debug)]
+pub struct CueSheet {
+    pub performer: Option<String>,
+    pub title: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+/// A media file referenced by a cue sheet and the tracks it contains
+#[derive(Clone, Debug)]
+pub struct CueFile {
+    /// The file name as written in the cue sheet, usually relative to the cue sheet's directory
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start of the track (INDEX 01) in milliseconds
+    pub start: i64,
+}
+
+/// Parses the contents of a cue sheet.
+/// Tracks without an INDEX 01 entry are dropped, as their start offset is unknown.
+pub fn parse(contents: &str) -> CueSheet {
+    let mut sheet = CueSheet {
+        performer: None,
+        title: None,
+        files: Vec::new(),
+    };
+
+    // the track currently being parsed, only committed once the next track or file starts
+    let mut pending_track: Option<PendingTrack> = None;
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let tokens = tokenize(line);
+        let (command, args) = match tokens.split_first() {
+            Some((command, args)) => (command.to_uppercase(), args),
+            None => continue,
+        };
+
+        match command.as_str() {
+            "FILE" => {
+                commit_track(&mut sheet, pending_track.take());
+                if let Some(name) = args.first() {
+                    sheet.files.push(CueFile {
+                        name: name.clone(),
+                        tracks: Vec::new(),
+                    });
+                }
+            },
+            "TRACK" => {
+                commit_track(&mut sheet, pending_track.take());
+                if let Some(number) = args.first().and_then(|n| n.parse::<u32>().ok()) {
+                    pending_track = Some(PendingTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: None,
+                    });
+                }
+            },
+            "TITLE" => {
+                let title = args.first().cloned();
+                match pending_track {
+                    Some(ref mut track) => track.title = title,
+                    None => sheet.title = title,
+                }
+            },
+            "PERFORMER" => {
+                let performer = args.first().cloned();
+                match pending_track {
+                    Some(ref mut track) => track.performer = performer,
+                    None => sheet.performer = performer,
+                }
+            },
+            "INDEX" => {
+                if let (Some(index), Some(timestamp)) = (args.first(), args.get(1)) {
+                    if index.parse::<u32>() == Ok(1) {
+                        if let Some(ref mut track) = pending_track {
+                            track.start = parse_timestamp(timestamp);
+                        }
+                    }
+                }
+            },
+            _ => {
+                // REM, FLAGS, ISRC, PREGAP, ... are not relevant for playback
+            }
+        }
+    }
+    commit_track(&mut sheet, pending_track.take());
+    sheet
+}
+
+struct PendingTrack {
+    number: u32,
+    title: Option<String>,
+    performer: Option<String>,
+    start: Option<i64>,
+}
+
+fn commit_track(sheet: &mut CueSheet, track: Option<PendingTrack>) {
+    if let Some(PendingTrack {number, title, performer, start: Some(start)}) = track {
+        if let Some(file) = sheet.files.last_mut() {
+            file.tracks.push(CueTrack {
+                number,
+                title,
+                performer,
+                start,
+            });
+        }
+    }
+}
+
+/// Parses a `mm:ss:ff` timestamp into milliseconds
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let parts: Vec<i64> = timestamp
+        .split(':')
+        .map(|part| part.parse::<i64>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match parts.as_slice() {
+        [minutes, seconds, frames] => Some((minutes * 60 + seconds) * 1000 + frames * 1000 / FRAMES_PER_SECOND),
+        _ => None,
+    }
+}
+
+/// Splits a cue sheet line into whitespace separated tokens, keeping double quoted strings together
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in line.trim().chars() {
+        match c {
+            '"' => {
+                if in_quotes {
+                    tokens.push(current.clone());
+                    current.clear();
+                }
+                in_quotes = !in_quotes;
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(current.clone());
+                    current.clear();
+                }
+            },
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\u{feff}REM GENRE Jazz
+PERFORMER \"The Band\"
+TITLE \"Live at Home\"
+FILE \"live.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"Intro\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Second Song\"
+    PERFORMER \"Guest\"
+    INDEX 00 03:58:60
+    INDEX 01 04:02:37
+  TRACK 03 AUDIO
+    TITLE \"No Index\"
+FILE \"encore.flac\" WAVE
+  track 1 audio
+    index 1 01:00:74
+";
+
+    #[test]
+    fn parses_header_files_and_tracks() {
+        let sheet = parse(SHEET);
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.title.as_deref(), Some("Live at Home"));
+        assert_eq!(sheet.files.len(), 2);
+        assert_eq!(sheet.files[0].name, "live.flac");
+        assert_eq!(sheet.files[1].name, "encore.flac");
+
+        let tracks = &sheet.files[0].tracks;
+        // the track without INDEX 01 is dropped
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].number, 1);
+        assert_eq!(tracks[0].title.as_deref(), Some("Intro"));
+        assert_eq!(tracks[0].performer, None);
+        assert_eq!(tracks[1].title.as_deref(), Some("Second Song"));
+        assert_eq!(tracks[1].performer.as_deref(), Some("Guest"));
+    }
+
+    #[test]
+    fn starts_at_index_01_not_index_00() {
+        let sheet = parse(SHEET);
+        // 4 minutes, 2 seconds and 37 of 75 frames
+        assert_eq!(sheet.files[0].tracks[1].start, 242_000 + 493);
+    }
+
+    #[test]
+    fn commands_are_case_insensitive() {
+        let sheet = parse(SHEET);
+        let tracks = &sheet.files[1].tracks;
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].start, 60_000 + 986);
+    }
+
+    #[test]
+    fn converts_frames_to_milliseconds() {
+        assert_eq!(parse_timestamp("00:00:00"), Some(0));
+        assert_eq!(parse_timestamp("00:00:01"), Some(13));
+        assert_eq!(parse_timestamp("00:00:75"), Some(1000));
+        assert_eq!(parse_timestamp("90:00:00"), Some(5_400_000));
+        assert_eq!(parse_timestamp("00:00"), None);
+        assert_eq!(parse_timestamp("aa:00:00"), None);
+    }
+
+    #[test]
+    fn tokenizes_quoted_strings() {
+        assert_eq!(tokenize("  TITLE \"A  quoted title\"  "), vec!["TITLE", "A  quoted title"]);
+        assert_eq!(tokenize("FILE \"\" WAVE"), vec!["FILE", "", "WAVE"]);
+        assert_eq!(tokenize(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn tracks_before_the_first_file_are_dropped() {
+        let sheet = parse("TRACK 01 AUDIO\nINDEX 01 00:00:00\n");
+        assert!(sheet.files.is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+//! Encoding and decoding of the DNS messages mDNS is made of, limited to the record types DNS-SD needs.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Set on the class of a record to replace cached records of the same name and type, instead of adding to them
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set on the class of a question to ask for a unicast response
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// Header flags of a response with authoritative answers
+pub const RESPONSE_FLAGS: u16 = 0x8400;
+const FLAG_RESPONSE: u16 = 0x8000;
+
+const MAX_POINTER_JUMPS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub unicast_response: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordData {
+    Ptr(String),
+    Srv{port: u16, target: String},
+    Txt(Vec<String>),
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub cache_flush: bool,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl RecordData {
+    fn rtype(&self) -> Option<u16> {
+        match self {
+            RecordData::Ptr(_) => Some(TYPE_PTR),
+            RecordData::Srv{..} => Some(TYPE_SRV),
+            RecordData::Txt(_) => Some(TYPE_TXT),
+            RecordData::A(_) => Some(TYPE_A),
+            RecordData::Aaaa(_) => Some(TYPE_AAAA),
+            RecordData::Other => None,
+        }
+    }
+}
+
+impl Record {
+    pub fn rtype(&self) -> Option<u16> {
+        self.data.rtype()
+    }
+}
+
+/// DNS names are compared case insensitively
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+impl Message {
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_RESPONSE != 0
+    }
+
+    /// All records of the message, answers first
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.answers.iter().chain(self.additionals.iter())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(512);
+        push_u16(&mut buffer, self.id);
+        push_u16(&mut buffer, self.flags);
+        push_u16(&mut buffer, self.questions.len() as u16);
+        push_u16(&mut buffer, self.answers.len() as u16);
+        push_u16(&mut buffer, 0);
+        push_u16(&mut buffer, self.additionals.len() as u16);
+        for question in &self.questions {
+            push_name(&mut buffer, &question.name);
+            push_u16(&mut buffer, question.qtype);
+            push_u16(&mut buffer, CLASS_IN | if question.unicast_response { UNICAST_RESPONSE } else { 0 });
+        }
+        for record in self.answers.iter().chain(self.additionals.iter()) {
+            push_record(&mut buffer, record);
+        }
+        buffer
+    }
+
+    /// Parses a message, None if it is malformed
+    pub fn decode(packet: &[u8]) -> Option<Message> {
+        let mut reader = Reader { packet, position: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let question_count = reader.u16()?;
+        let answer_count = reader.u16()?;
+        let authority_count = reader.u16()?;
+        let additional_count = reader.u16()?;
+
+        let mut message = Message { id, flags, ..Message::default() };
+        for _ in 0..question_count {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let class = reader.u16()?;
+            message.questions.push(Question {
+                name,
+                qtype,
+                unicast_response: class & UNICAST_RESPONSE != 0,
+            });
+        }
+        for _ in 0..answer_count {
+            message.answers.push(reader.record()?);
+        }
+        // authority records only matter for probing, which is not done
+        for _ in 0..authority_count {
+            reader.record()?;
+        }
+        for _ in 0..additional_count {
+            message.additionals.push(reader.record()?);
+        }
+        Some(message)
+    }
+}
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Writes the name uncompressed. Labels are split at dots, so instance names must not contain any.
+fn push_name(buffer: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label);
+    }
+    buffer.push(0);
+}
+
+fn push_record(buffer: &mut Vec<u8>, record: &Record) {
+    let rtype = match record.rtype() {
+        Some(rtype) => rtype,
+        None => return,
+    };
+    push_name(buffer, &record.name);
+    push_u16(buffer, rtype);
+    push_u16(buffer, CLASS_IN | if record.cache_flush { CACHE_FLUSH } else { 0 });
+    buffer.extend_from_slice(&record.ttl.to_be_bytes());
+
+    let mut data = Vec::new();
+    match &record.data {
+        RecordData::Ptr(target) => push_name(&mut data, target),
+        RecordData::Srv{port, target} => {
+            // priority and weight
+            push_u16(&mut data, 0);
+            push_u16(&mut data, 0);
+            push_u16(&mut data, *port);
+            push_name(&mut data, target);
+        },
+        RecordData::Txt(entries) => {
+            for entry in entries {
+                let entry = &entry.as_bytes()[..entry.len().min(255)];
+                data.push(entry.len() as u8);
+                data.extend_from_slice(entry);
+            }
+            // a TXT record needs at least one string, even if it is empty
+            if entries.is_empty() {
+                data.push(0);
+            }
+        },
+        RecordData::A(ip) => data.extend_from_slice(&ip.octets()),
+        RecordData::Aaaa(ip) => data.extend_from_slice(&ip.octets()),
+        RecordData::Other => (),
+    }
+    push_u16(buffer, data.len() as u16);
+    buffer.extend_from_slice(&data);
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.position..self.position + length)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a possibly compressed name
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut position = self.position;
+        // where reading continues after the name, once a pointer was followed
+        let mut end = None;
+        let mut jumps = 0;
+        loop {
+            let length = *self.packet.get(position)? as usize;
+            if length == 0 {
+                position += 1;
+                break;
+            }
+            if length & 0xc0 == 0xc0 {
+                let low = *self.packet.get(position + 1)? as usize;
+                if end.is_none() {
+                    end = Some(position + 2);
+                }
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return None;
+                }
+                position = ((length & 0x3f) << 8) | low;
+                continue;
+            }
+            let label = self.packet.get(position + 1..position + 1 + length)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            position += 1 + length;
+        }
+        self.position = end.unwrap_or(position);
+        Some(labels.join("."))
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let data_start = self.position;
+        let data_end = data_start + length;
+        if data_end > self.packet.len() {
+            return None;
+        }
+        let data = match rtype {
+            TYPE_PTR => RecordData::Ptr(self.name()?),
+            TYPE_SRV => {
+                let _priority = self.u16()?;
+                let _weight = self.u16()?;
+                let port = self.u16()?;
+                RecordData::Srv{port, target: self.name()?}
+            },
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                while self.position < data_end {
+                    let entry_length = self.bytes(1)?[0] as usize;
+                    let entry = self.bytes(entry_length)?;
+                    if !entry.is_empty() {
+                        entries.push(String::from_utf8_lossy(entry).into_owned());
+                    }
+                }
+                RecordData::Txt(entries)
+            },
+            TYPE_A if length == 4 => {
+                let octets = self.bytes(4)?;
+                RecordData::A(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+            },
+            TYPE_AAAA if length == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                RecordData::Aaaa(Ipv6Addr::from(octets))
+            },
+            _ => RecordData::Other,
+        };
+        self.position = data_end;
+        Some(Record {
+            name,
+            ttl,
+            cache_flush: class & CACHE_FLUSH != 0,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str, data: RecordData) -> Record {
+        Record {
+            name: name.to_string(),
+            ttl: 120,
+            cache_flush: true,
+            data,
+        }
+    }
+
+    #[test]
+    fn round_trips_a_response() {
+        let message = Message {
+            id: 0,
+            flags: RESPONSE_FLAGS,
+            questions: vec![Question {
+                name: String::from("_fidelitas._tcp.local"),
+                qtype: TYPE_PTR,
+                unicast_response: true,
+            }],
+            answers: vec![record("_fidelitas._tcp.local", RecordData::Ptr(String::from("living room._fidelitas._tcp.local")))],
+            additionals: vec![
+                record("host.local", RecordData::Srv{port: 8088, target: String::from("host.local")}),
+                record("host.local", RecordData::Txt(vec![String::from("path=/player"), String::from("tls=0")])),
+                record("host.local", RecordData::A(Ipv4Addr::new(192, 168, 1, 20))),
+                record("host.local", RecordData::Aaaa("2001:db8::2".parse().expect("Invalid test address"))),
+            ],
+        };
+        let decoded = Message::decode(&message.encode()).expect("Failed to decode encoded message");
+        assert!(decoded.is_response());
+        assert_eq!(decoded.questions, message.questions);
+        assert_eq!(decoded.answers, message.answers);
+        assert_eq!(decoded.additionals, message.additionals);
+        assert_eq!(decoded.records().count(), 5);
+    }
+
+    #[test]
+    fn encodes_empty_txt_records_with_an_empty_string() {
+        let message = Message {
+            answers: vec![record("a.local", RecordData::Txt(Vec::new()))],
+            ..Message::default()
+        };
+        let packet = message.encode();
+        // rdlength 1, then the empty string
+        assert_eq!(&packet[packet.len() - 3..], &[0, 1, 0]);
+        let decoded = Message::decode(&packet).expect("Failed to decode encoded message");
+        assert_eq!(decoded.answers[0].data, RecordData::Txt(Vec::new()));
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        let mut packet = vec![0, 1, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+        // question at offset 12: _http._tcp.local PTR
+        packet.extend_from_slice(b"\x05_http\x04_tcp\x05local\x00");
+        push_u16(&mut packet, TYPE_PTR);
+        push_u16(&mut packet, CLASS_IN);
+        // answer named by a pointer to the question, pointing to "web" followed by a pointer as well
+        packet.extend_from_slice(&[0xc0, 12]);
+        push_u16(&mut packet, TYPE_PTR);
+        push_u16(&mut packet, CLASS_IN);
+        packet.extend_from_slice(&120u32.to_be_bytes());
+        push_u16(&mut packet, 6);
+        packet.extend_from_slice(b"\x03web\xc0\x0c");
+
+        let message = Message::decode(&packet).expect("Failed to decode compressed message");
+        assert_eq!(message.id, 1);
+        assert!(!message.is_response());
+        assert_eq!(message.questions[0].name, "_http._tcp.local");
+        assert_eq!(message.answers[0].name, "_http._tcp.local");
+        assert_eq!(message.answers[0].data, RecordData::Ptr(String::from("web._http._tcp.local")));
+        assert!(!message.answers[0].cache_flush);
+    }
+
+    #[test]
+    fn skips_unknown_records() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        packet.extend_from_slice(b"\x01a\x00");
+        // HINFO, whose data isn't decoded
+        push_u16(&mut packet, 13);
+        push_u16(&mut packet, CLASS_IN);
+        packet.extend_from_slice(&120u32.to_be_bytes());
+        push_u16(&mut packet, 3);
+        packet.extend_from_slice(&[1, 2, 3]);
+        push_record(&mut packet, &record("b", RecordData::A(Ipv4Addr::new(10, 0, 0, 1))));
+
+        let message = Message::decode(&packet).expect("Failed to decode message with unknown record");
+        assert_eq!(message.answers[0].data, RecordData::Other);
+        assert_eq!(message.answers[0].rtype(), None);
+        assert_eq!(message.answers[1].data, RecordData::A(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let message = Message {
+            answers: vec![record("host.local", RecordData::A(Ipv4Addr::new(192, 168, 1, 20)))],
+            ..Message::default()
+        };
+        let packet = message.encode();
+        for length in 0..packet.len() {
+            assert!(Message::decode(&packet[..length]).is_none(), "Decoded a packet truncated to {} bytes", length);
+        }
+
+        // a name pointing to itself
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+        assert!(Message::decode(&packet).is_none());
+    }
+
+    #[test]
+    fn compares_names_case_insensitively() {
+        assert!(same_name("Living-Room.local.", "living-room.LOCAL"));
+        assert!(!same_name("kitchen.local", "living-room.local"));
+    }
+}
@@ -0,0 +1,107 @@
+//! The 10-band equalizer of libvlc and its presets.
+//! The equalizer settings are persisted and applied to every media player of the player thread.
+
+use serde::{Serialize, Deserialize};
+
+use crate::store::Store;
+use crate::vlc_helpers;
+
+/// Name of the file the equalizer settings are persisted in
+const STORE_NAME: &str = "equalizer";
+
+/// libvlc limits the preamp and the band amplification to this range, in dB
+pub const MAX_AMP: f32 = 20.0;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Equalizer {
+    pub enabled: bool,
+    /// Name of the preset the values were taken from, None once they have been changed individually
+    pub preset: Option<String>,
+    /// In dB
+    pub preamp: f32,
+    /// Amplification of each band in dB, in the order of `band_frequencies`
+    pub bands: Vec<f32>,
+}
+
+impl Default for Equalizer {
+    fn default() -> Self {
+        Equalizer {
+            enabled: false,
+            preset: None,
+            preamp: 0.0,
+            bands: vec![0.0; band_frequencies().len()],
+        }
+    }
+}
+
+/// Names of the presets clients may choose from
+pub fn presets() -> Vec<String> {
+    vlc_helpers::equalizer_preset_names()
+}
+
+/// Center frequencies of the bands in Hz
+pub fn band_frequencies() -> Vec<f32> {
+    vlc_helpers::equalizer_band_frequencies()
+}
+
+fn clamp_amp(amp: f32) -> f32 {
+    amp.clamp(-MAX_AMP, MAX_AMP)
+}
+
+impl Equalizer {
+    pub fn load(store: &Store) -> Self {
+        let mut equalizer: Equalizer = store.load(STORE_NAME);
+        // the band count depends on the libvlc version
+        equalizer.bands.resize(band_frequencies().len(), 0.0);
+        equalizer
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+
+    /// Takes over the values of the named preset and enables the equalizer
+    pub fn apply_preset(&mut self, name: &str) -> Result<(), String> {
+        let index = presets()
+            .iter()
+            .position(|preset| preset.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("Unknown equalizer preset '{}'", name))?;
+        let (preamp, bands) = vlc_helpers::equalizer_preset(index as u32)
+            .ok_or_else(|| format!("Failed to load equalizer preset '{}'", name))?;
+        self.enabled = true;
+        self.preset = Some(presets()[index].clone());
+        self.preamp = preamp;
+        self.bands = bands;
+        Ok(())
+    }
+
+    pub fn set_band(&mut self, band: usize, amp: f32) -> Result<(), String> {
+        if !amp.is_finite() {
+            return Err(format!("Invalid amplification {}", amp));
+        }
+        match self.bands.get_mut(band) {
+            Some(value) => *value = clamp_amp(amp),
+            None => return Err(format!("Invalid equalizer band {}", band)),
+        }
+        self.preset = None;
+        Ok(())
+    }
+
+    pub fn set_preamp(&mut self, preamp: f32) -> Result<(), String> {
+        if !preamp.is_finite() {
+            return Err(format!("Invalid preamp {}", preamp));
+        }
+        self.preamp = clamp_amp(preamp);
+        self.preset = None;
+        Ok(())
+    }
+
+    /// Applies the settings to the media player, disabling its equalizer if the equalizer is not enabled
+    pub fn apply(&self, mediaplayer: &vlc::MediaPlayer) {
+        let settings = if self.enabled { Some((self.preamp, &self.bands[..])) } else { None };
+        if unsafe { vlc_helpers::set_equalizer(mediaplayer, settings) }.is_err() {
+            println!("Failed to set the equalizer of the media player");
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Parser for podcast feeds in RSS 2.0 and Atom format.
+//! Only entries with an audio enclosure are of interest, everything else is skipped.
+
+use chrono::DateTime;
+
+#[derive(Clone, Debug)]
+pub struct Feed {
+    pub title: Option<String>,
+    pub entries: Vec<FeedEntry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeedEntry {
+    /// Identifies the entry across refreshes. Falls back to the enclosure url if the feed does not provide one.
+    pub guid: String,
+    pub title: String,
+    /// Publication date as unix timestamp in seconds
+    pub published: Option<i64>,
+    /// Url of the media file
+    pub url: String,
+}
+
+/// Parses an RSS 2.0 or Atom feed
+pub fn parse(xml: &str) -> Result<Feed, String> {
+    let document = roxmltree::Document::parse(xml).map_err(|e| format!("Invalid feed xml: {}", e))?;
+    let root = document.root_element();
+
+    match root.tag_name().name() {
+        "rss" => {
+            let channel = child(&root, "channel").ok_or("RSS feed without channel")?;
+            Ok(Feed {
+                title: child_text(&channel, "title"),
+                entries: channel
+                    .children()
+                    .filter(|n| n.has_tag_name("item"))
+                    .filter_map(|item| parse_rss_item(&item))
+                    .collect(),
+            })
+        },
+        "feed" => {
+            Ok(Feed {
+                title: child_text(&root, "title"),
+                entries: root
+                    .children()
+                    .filter(|n| n.has_tag_name("entry"))
+                    .filter_map(|entry| parse_atom_entry(&entry))
+                    .collect(),
+            })
+        },
+        other => Err(format!("Unsupported feed format with root element '{}'", other)),
+    }
+}
+
+fn parse_rss_item(item: &roxmltree::Node) -> Option<FeedEntry> {
+    let url = child(item, "enclosure")?.attribute("url")?.to_string();
+    Some(FeedEntry {
+        guid: child_text(item, "guid").unwrap_or_else(|| url.clone()),
+        title: child_text(item, "title").unwrap_or_else(|| url.clone()),
+        published: child_text(item, "pubDate")
+            .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+            .map(|date| date.timestamp()),
+        url,
+    })
+}
+
+fn parse_atom_entry(entry: &roxmltree::Node) -> Option<FeedEntry> {
+    let url = entry
+        .children()
+        .filter(|n| n.has_tag_name("link") && n.attribute("rel") == Some("enclosure"))
+        .filter_map(|n| n.attribute("href"))
+        .next()?
+        .to_string();
+    Some(FeedEntry {
+        guid: child_text(entry, "id").unwrap_or_else(|| url.clone()),
+        title: child_text(entry, "title").unwrap_or_else(|| url.clone()),
+        published: child_text(entry, "published")
+            .or_else(|| child_text(entry, "updated"))
+            .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+            .map(|date| date.timestamp()),
+        url,
+    })
+}
+
+/// Finds the first child element with the given local name, ignoring namespaces
+fn child<'a, 'input>(node: &roxmltree::Node<'a, 'input>, name: &str) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn child_text(node: &roxmltree::Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
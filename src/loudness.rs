@@ -0,0 +1,529 @@
+//! ReplayGain and EBU R128 loudness.
+//! Gains are read from ReplayGain and R128 tags in the background once the media directory is scanned, once per file.
+//! Files without tags can be measured as well, following ITU-R BS.1770 / EBU R128.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use actix::Addr;
+use serde::{Serialize, Deserialize};
+use symphonia::core::audio::{SampleBuffer, SignalSpec};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+use crate::player::{Player, PlayerMsg};
+use crate::store::Store;
+
+/// Name of the file read and measured gains are persisted in
+const STORE_NAME: &str = "loudness";
+
+/// Loudness ReplayGain 2.0 normalizes to, in LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// R128 tags (Opus) are relative to -23 LUFS instead of the ReplayGain reference
+const R128_OFFSET: f32 = 5.0;
+
+/// Number of files whose tags are read before reporting them to the player
+const TAG_BATCH: usize = 100;
+
+/// Blocks quieter than this are ignored entirely, in LUFS
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this much quieter than the ungated loudness are ignored, in LU
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Gain and peak of a file, gains in dB and peaks as linear sample amplitude
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGain {
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// The gain to apply in dB, including the preamp.
+    /// Album mode falls back to the track gain and vice versa.
+    /// With clipping prevention, the gain is reduced so the peak stays below full scale.
+    pub fn applied_gain(&self, mode: ReplayGainMode, preamp: f32, prevent_clipping: bool) -> Option<f32> {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return None,
+            ReplayGainMode::Track => (self.track_gain.or(self.album_gain), self.track_peak.or(self.album_peak)),
+            ReplayGainMode::Album => (self.album_gain.or(self.track_gain), self.album_peak.or(self.track_peak)),
+        };
+        let gain = gain? + preamp;
+        match peak {
+            Some(peak) if prevent_clipping && peak > 0.0 => {
+                let max_gain = -20.0 * peak.log10();
+                Some(gain.min(max_gain))
+            },
+            _ => Some(gain),
+        }
+    }
+}
+
+/// Converts a gain in dB to a linear amplitude factor
+pub fn gain_factor(gain: f32) -> f32 {
+    10f32.powf(gain / 20.0)
+}
+
+/// Parses values like "-6.54 dB" or "0.988547"
+fn parse_tag_number(value: &str) -> Option<f32> {
+    value.split_whitespace().next()?.parse::<f32>().ok()
+}
+
+fn apply_tag(gain: &mut ReplayGain, tag: &Tag) {
+    let value = tag.value.to_string();
+    match tag.std_key {
+        Some(StandardTagKey::ReplayGainTrackGain) => gain.track_gain = parse_tag_number(&value),
+        Some(StandardTagKey::ReplayGainTrackPeak) => gain.track_peak = parse_tag_number(&value),
+        Some(StandardTagKey::ReplayGainAlbumGain) => gain.album_gain = parse_tag_number(&value),
+        Some(StandardTagKey::ReplayGainAlbumPeak) => gain.album_peak = parse_tag_number(&value),
+        _ => {
+            // not every container maps the tags to standard keys, e.g. mp4 freeform atoms
+            let key = tag.key.to_uppercase();
+            if key.ends_with("REPLAYGAIN_TRACK_GAIN") {
+                gain.track_gain = parse_tag_number(&value);
+            } else if key.ends_with("REPLAYGAIN_TRACK_PEAK") {
+                gain.track_peak = parse_tag_number(&value);
+            } else if key.ends_with("REPLAYGAIN_ALBUM_GAIN") {
+                gain.album_gain = parse_tag_number(&value);
+            } else if key.ends_with("REPLAYGAIN_ALBUM_PEAK") {
+                gain.album_peak = parse_tag_number(&value);
+            } else if key == "R128_TRACK_GAIN" {
+                // Q7.8 fixed point
+                gain.track_gain = value.trim().parse::<i32>().ok().map(|q| q as f32 / 256.0 + R128_OFFSET);
+            } else if key == "R128_ALBUM_GAIN" {
+                gain.album_gain = value.trim().parse::<i32>().ok().map(|q| q as f32 / 256.0 + R128_OFFSET);
+            }
+        }
+    }
+}
+
+fn probe(path: &Path) -> Result<symphonia::core::probe::ProbeResult, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    symphonia::default::get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|e| e.to_string())
+}
+
+/// Reads ReplayGain and R128 tags. Returns None if the file has no gain tags or can't be read.
+pub fn read_tags(path: &Path) -> Option<ReplayGain> {
+    let mut probed = probe(path).ok()?;
+    let mut gain = ReplayGain::default();
+
+    // tags in front of the container (e.g. ID3v2) and tags of the container itself
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            revision.tags().iter().for_each(|tag| apply_tag(&mut gain, tag));
+        }
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        revision.tags().iter().for_each(|tag| apply_tag(&mut gain, tag));
+    }
+
+    if gain.is_empty() { None } else { Some(gain) }
+}
+
+/// Second order IIR filter in direct form 1
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// The two stages of the K-weighting filter, a high shelf followed by a high pass, for the given sample rate
+fn k_weighting(rate: f64) -> (Biquad, Biquad) {
+    use std::f64::consts::PI;
+
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+    (shelf, high_pass)
+}
+
+/// Channel weights, assuming the usual L R C LFE Ls Rs order for 5.1
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+fn energy_to_loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Decodes the whole file and measures its integrated loudness and sample peak.
+/// The result contains the track gain and peak.
+pub fn measure(path: &Path) -> Result<ReplayGain, String> {
+    let mut probed = probe(path)?;
+    let track = probed.format.default_track().ok_or("No audio track")?.clone();
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+
+    // the filters and buffers are set up for the signal of the first packet
+    let mut stream_spec: Option<SignalSpec> = None;
+    let mut filters: Vec<(Biquad, Biquad)> = Vec::new();
+    let mut channels = 0;
+    let mut sub_block_len = 0;
+    // weighted energy and frame count of consecutive 100ms sub blocks
+    let mut sub_blocks: Vec<(f64, usize)> = Vec::new();
+    let mut current = (0.0, 0);
+    let mut peak: f32 = 0.0;
+    let mut sample_buffer: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            // end of stream
+            Err(symphonia::core::errors::Error::IoError(_)) => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        if packet.track_id() != track.id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // skip corrupted packets, like players do
+            Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let spec = *decoded.spec();
+        match stream_spec {
+            None => {
+                channels = spec.channels.count();
+                filters = vec![k_weighting(f64::from(spec.rate)); channels];
+                sub_block_len = (spec.rate / 10) as usize;
+                stream_spec = Some(spec);
+            },
+            Some(stream_spec) if stream_spec != spec => {
+                return Err(String::from("Channels or sample rate change within the file"));
+            },
+            Some(_) => {},
+        }
+        let buffer = sample_buffer.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * channels {
+            *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+
+        for frame in buffer.samples().chunks(channels) {
+            let mut energy = 0.0;
+            for (channel, sample) in frame.iter().enumerate() {
+                peak = peak.max(sample.abs());
+                let (shelf, high_pass) = &mut filters[channel];
+                let filtered = high_pass.process(shelf.process(f64::from(*sample)));
+                energy += channel_weight(channel, channels) * filtered * filtered;
+            }
+            current.0 += energy;
+            current.1 += 1;
+            if current.1 == sub_block_len {
+                sub_blocks.push(current);
+                current = (0.0, 0);
+            }
+        }
+    }
+
+    // 400ms gating blocks overlapping by 75%
+    let blocks: Vec<f64> = sub_blocks
+        .windows(4)
+        .map(|window| {
+            let (energy, frames) = window.iter().fold((0.0, 0), |acc, b| (acc.0 + b.0, acc.1 + b.1));
+            energy / frames as f64
+        })
+        .filter(|energy| energy_to_loudness(*energy) > ABSOLUTE_GATE)
+        .collect();
+    if blocks.is_empty() {
+        return Err(String::from("File is too short or silent"));
+    }
+
+    let ungated = blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let relative_gate = energy_to_loudness(ungated) + RELATIVE_GATE;
+    let gated: Vec<f64> = blocks.into_iter().filter(|energy| energy_to_loudness(*energy) > relative_gate).collect();
+    let integrated = energy_to_loudness(gated.iter().sum::<f64>() / gated.len() as f64);
+
+    Ok(ReplayGain {
+        track_gain: Some((REFERENCE_LOUDNESS - integrated) as f32),
+        track_peak: Some(peak),
+        album_gain: None,
+        album_peak: None,
+    })
+}
+
+/// Modification time of the file as unix timestamp in seconds
+fn modified(path: &Path) -> Option<u64> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    modified.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Measurement {
+    /// Modification time of the file when it was read or measured, changed files are read again
+    modified: u64,
+    /// None if the file has no gain tags and wasn't measured
+    gain: Option<ReplayGain>,
+}
+
+/// Gains read from tags and results of previous measurements, keyed by file path
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LoudnessCache {
+    measurements: HashMap<String, Measurement>,
+}
+
+impl LoudnessCache {
+    pub fn load(store: &Store) -> Self {
+        store.load(STORE_NAME)
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+
+    /// The gain of the file, if the file hasn't changed since it was read.
+    /// Some(None) if the file has no gain tags and wasn't measured.
+    pub fn get(&self, path: &str) -> Option<Option<ReplayGain>> {
+        let measurement = self.measurements.get(path)?;
+        if modified(Path::new(path)) == Some(measurement.modified) {
+            Some(measurement.gain)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, path: String, gain: Option<ReplayGain>) {
+        if let Some(modified) = modified(Path::new(&path)) {
+            self.measurements.insert(path, Measurement {
+                modified,
+                gain,
+            });
+        }
+    }
+}
+
+/// Spawns a thread reading the gain tags of the `unread` files, reported to the player in batches.
+/// With `analyze`, files without tags, including the `untagged` ones, are measured one after another afterwards.
+/// Each result is sent back to the player as PlayerMsg.
+pub fn spawn_analyzer(player: Addr<Player>, unread: Vec<String>, mut untagged: Vec<String>, analyze: bool) {
+    if unread.is_empty() && (untagged.is_empty() || !analyze) {
+        return;
+    }
+    thread::spawn(move || {
+        for paths in unread.chunks(TAG_BATCH) {
+            let gains: Vec<(String, Option<ReplayGain>)> = paths
+                .iter()
+                .map(|path| (path.clone(), read_tags(Path::new(path))))
+                .collect();
+            if !player.connected() {
+                // the player is gone, nobody is interested in results anymore
+                return;
+            }
+            untagged.extend(gains.iter().filter(|(_, gain)| gain.is_none()).map(|(path, _)| path.clone()));
+            player.do_send(PlayerMsg::TagsRead(gains));
+        }
+
+        if !analyze || untagged.is_empty() {
+            return;
+        }
+        println!("Measuring loudness of {} files without gain tags in the background", untagged.len());
+        for path in untagged {
+            let result = measure(Path::new(&path));
+            if !player.connected() {
+                break;
+            }
+            player.do_send(PlayerMsg::LoudnessMeasured{path, result});
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+
+    /// Writes a 16 bit PCM wave file with a 1 kHz sine of the given peak amplitude in every channel,
+    /// followed by the given number of seconds of silence
+    fn write_sine(name: &str, rate: u32, channels: u16, amplitude: f64, seconds: u32, silence: u32) -> std::path::PathBuf {
+        let frames = rate * (seconds + silence);
+        let data_len = frames * u32::from(channels) * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&channels.to_le_bytes());
+        wav.extend_from_slice(&rate.to_le_bytes());
+        wav.extend_from_slice(&(rate * u32::from(channels) * 2).to_le_bytes());
+        wav.extend_from_slice(&(channels * 2).to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for frame in 0..frames {
+            let sample = if frame < rate * seconds {
+                let phase = 2.0 * std::f64::consts::PI * 1000.0 * f64::from(frame) / f64::from(rate);
+                (amplitude * phase.sin() * f64::from(i16::MAX)).round() as i16
+            } else {
+                0
+            };
+            for _ in 0..channels {
+                wav.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        let path = std::env::temp_dir().join(format!("fidelitas-test-loudness-{}-{}.wav", name, std::process::id()));
+        std::fs::write(&path, wav).expect("Failed to write test file");
+        path
+    }
+
+    fn measure_sine(name: &str, rate: u32, channels: u16, amplitude: f64, seconds: u32, silence: u32) -> Result<ReplayGain, String> {
+        let path = write_sine(name, rate, channels, amplitude, seconds, silence);
+        let result = measure(&path);
+        std::fs::remove_file(&path).ok();
+        result
+    }
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("Missing value");
+        assert!((actual - expected).abs() < 0.05, "{} is not close to {}", actual, expected);
+    }
+
+    #[test]
+    fn measures_sine_loudness() {
+        // a 1 kHz sine with a peak of -20 dBFS measures -23 LUFS in a single channel
+        let mono = measure_sine("mono", 48000, 1, 0.1, 5, 0).expect("Failed to measure");
+        assert_close(mono.track_gain, 5.0);
+        assert_close(mono.track_peak, 0.1);
+        // the channels add up, a second one makes it 3 LU louder
+        let stereo = measure_sine("stereo", 44100, 2, 0.1, 5, 0).expect("Failed to measure");
+        assert_close(stereo.track_gain, 2.0);
+        assert_eq!((mono.album_gain, stereo.album_gain), (None, None));
+    }
+
+    #[test]
+    fn silence_is_gated() {
+        // without gating the silence would halve the energy, making it 3 LU quieter.
+        // only the three blocks overlapping the end of the sine (75%, 50% and 25% of its energy) pass the gates
+        let gated = measure_sine("gated", 48000, 1, 0.1, 5, 5).expect("Failed to measure");
+        assert_close(gated.track_gain, 5.0 - 10.0 * (48.5f32 / 50.0).log10());
+        assert!(measure_sine("silent", 48000, 1, 0.0, 0, 2).is_err());
+        // shorter than a single gating block
+        assert!(measure_sine("short", 48000, 1, 0.1, 0, 0).is_err());
+    }
+
+    #[test]
+    fn applied_gain_falls_back_between_track_and_album() {
+        let track_only = ReplayGain { track_gain: Some(-6.0), track_peak: Some(0.5), ..Default::default() };
+        let album_only = ReplayGain { album_gain: Some(-8.0), album_peak: Some(0.5), ..Default::default() };
+        let both = ReplayGain { album_gain: Some(-8.0), ..track_only };
+        assert_eq!(track_only.applied_gain(ReplayGainMode::Album, 0.0, false), Some(-6.0));
+        assert_eq!(album_only.applied_gain(ReplayGainMode::Track, 0.0, false), Some(-8.0));
+        assert_eq!(both.applied_gain(ReplayGainMode::Track, 0.0, false), Some(-6.0));
+        assert_eq!(both.applied_gain(ReplayGainMode::Album, 0.0, false), Some(-8.0));
+        assert_eq!(both.applied_gain(ReplayGainMode::Off, 0.0, false), None);
+        assert_eq!(ReplayGain::default().applied_gain(ReplayGainMode::Track, 0.0, false), None);
+    }
+
+    #[test]
+    fn applied_gain_adds_preamp_and_prevents_clipping() {
+        let gain = ReplayGain { track_gain: Some(4.0), track_peak: Some(0.5), ..Default::default() };
+        assert_eq!(gain.applied_gain(ReplayGainMode::Track, 3.0, false), Some(7.0));
+        // a peak of 0.5 leaves about 6 dB of headroom
+        assert_close(gain.applied_gain(ReplayGainMode::Track, 3.0, true), 6.02);
+        assert_eq!(gain.applied_gain(ReplayGainMode::Track, -3.0, true), Some(1.0));
+        let unknown_peak = ReplayGain { track_peak: None, ..gain };
+        assert_eq!(unknown_peak.applied_gain(ReplayGainMode::Track, 3.0, true), Some(7.0));
+    }
+
+    #[test]
+    fn parses_tag_numbers() {
+        assert_eq!(parse_tag_number("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_tag_number("  0.988547"), Some(0.988547));
+        assert_eq!(parse_tag_number("+2.5 dB"), Some(2.5));
+        assert_eq!(parse_tag_number("dB"), None);
+        assert_eq!(parse_tag_number(""), None);
+    }
+
+    #[test]
+    fn applies_replay_gain_and_r128_tags() {
+        let mut gain = ReplayGain::default();
+        let tags = [
+            Tag::new(Some(StandardTagKey::ReplayGainTrackGain), "REPLAYGAIN_TRACK_GAIN", Value::from("-6.5 dB")),
+            Tag::new(None, "----:com.apple.iTunes:replaygain_track_peak", Value::from("0.75")),
+            Tag::new(None, "replaygain_album_gain", Value::from("-7 dB")),
+            Tag::new(None, "TITLE", Value::from("-1 dB")),
+        ];
+        tags.iter().for_each(|tag| apply_tag(&mut gain, tag));
+        assert_eq!(gain, ReplayGain {
+            track_gain: Some(-6.5),
+            track_peak: Some(0.75),
+            album_gain: Some(-7.0),
+            album_peak: None,
+        });
+
+        // Q7.8 relative to -23 LUFS, -512 is -2 dB
+        let mut gain = ReplayGain::default();
+        apply_tag(&mut gain, &Tag::new(None, "R128_TRACK_GAIN", Value::from("-512")));
+        apply_tag(&mut gain, &Tag::new(None, "R128_ALBUM_GAIN", Value::from("384")));
+        assert_eq!((gain.track_gain, gain.album_gain), (Some(3.0), Some(6.5)));
+        apply_tag(&mut gain, &Tag::new(None, "R128_TRACK_GAIN", Value::from("-2 dB")));
+        assert_eq!(gain.track_gain, None);
+    }
+}
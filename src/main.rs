@@ -1,35 +1,87 @@
 use std::path::{Path, PathBuf};
-use std::thread;
-use std::collections::{HashSet, VecDeque};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
 
 use actix_web::{web, App, HttpResponse, HttpRequest, HttpServer};
 use actix_files::NamedFile;
 use actix_web_actors::ws;
-use actix::{Addr};
 
-use vlc;
-use crossbeam_channel;
+use futures::{future, Future};
+use serde::Deserialize;
 
 mod network_interfaces;
 mod websocket;
 mod vlc_helpers;
 mod media_fs;
+mod cue_sheet;
+mod mp4_chapters;
+mod player;
+mod stations;
+mod store;
+mod feed;
+mod podcasts;
+mod resume;
+mod queue;
+mod settings;
+mod loudness;
+mod equalizer;
+mod audio_output;
+mod timers;
+mod zones;
+mod sync;
+mod bind;
+mod advertise;
+mod network_monitor;
+mod dns;
+mod mdns;
+mod qr;
+mod share;
+mod tls;
+mod auth;
+mod origin;
+mod rate_limit;
+mod connections;
 
-use websocket::{OutgoingMsg, PlaybackState, PlayerWs, CurrentMedia};
-use media_fs::{ParseMediaConfig, parse_media_dir};
+use websocket::{PlayerWs};
+use media_fs::{ParseMediaConfig};
+use player::{Player, PlayerConfig, GetPlayerState};
+use store::Store;
+use resume::ResumePolicy;
+use audio_output::AudioOutput;
+use zones::{ZoneConfig, DEFAULT_ZONE};
+use sync::{SyncConfig, MediaPaths, MediaTokens};
+use bind::{BindAddress, Listener};
+use advertise::{AdvertiseConfig, AdvertisedUrls};
+use share::{Share, ShareQuery};
+use tls::TlsConfig;
+use auth::{Auth, Credential, Role};
+use origin::OriginPolicy;
+use rate_limit::RateLimits;
 
-pub struct AppState {
-    sender: crossbeam_channel::Sender<PlayerMsg>,
-}
+/// Longest duration accepted for options given in minutes, about a week
+const MAX_MINUTES: u64 = 10_000;
 
-pub enum PlayerMsg {
-    Play(u64),
-    Pause,
-    Resume,
-    Stop,
-    Register(Addr<PlayerWs>),
-    Unregister(Addr<PlayerWs>),
-    VolumeChange(u64),
+/// How long --discover waits for responses
+const DISCOVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+pub struct AppState {
+    player: actix::Addr<Player>,
+    /// The page template, rendered for every request
+    index_template: String,
+    advertised_urls: AdvertisedUrls,
+    /// Shared with guests through `/api/share?guest=true`
+    guest_token: Option<String>,
+    auth: Auth,
+    /// The login page template, rendered with a message
+    login_template: String,
+    /// Pages which may open the websocket
+    origins: OriginPolicy,
+    rate_limits: RateLimits,
+    /// Empty unless this instance is a leader
+    media_paths: MediaPaths,
+    /// Tokens of the connected followers, empty unless this instance is a leader
+    media_tokens: MediaTokens,
 }
 
 fn valid_directory(s: String) -> Result<(), String>{
@@ -57,35 +109,185 @@ fn valid_port(port: String) -> Result<(), String>{
 
 }
 
-fn populate_html_template(ip: &str, port: &str) -> std::io::Result<()> {
-    use std::fs;
-    use std::io::prelude::*;
-    
-    let template = fs::read_to_string("./templates/index.html")?;
-    let populated =
-        template
-            .replace("{{IP}}", ip)
-            .replace("{{PORT}}", port);
-    let mut file = fs::File::create("./static/index.html")?;
-    file.write_all(populated.as_bytes())?;
-    Ok(())
+fn valid_minutes(minutes: String) -> Result<(), String>{
+    match minutes.parse::<u64>() {
+        Ok(0) => Err(String::from("Needs to be at least one minute")),
+        Ok(value) if value <= MAX_MINUTES => Ok(()),
+        Ok(_) => Err(format!("'{}' is more than {} minutes", minutes, MAX_MINUTES)),
+        Err(_) => Err(format!("'{}' is not a valid number of minutes", minutes)),
+    }
 }
 
-fn broadcast(connections: &HashSet<Addr<PlayerWs>>, msgkind: OutgoingMsg) {
-    for conn in connections {
-        match conn.try_send(
-            msgkind.clone()
-        ){
-            Ok(_) => {},
-            Err(e) => {println!("Failed to broadcast: {}", e)}
-        }
+fn valid_minutes_or_zero(minutes: String) -> Result<(), String>{
+    match minutes.parse::<u64>() {
+        Ok(value) if value <= MAX_MINUTES => Ok(()),
+        Ok(_) => Err(format!("'{}' is more than {} minutes", minutes, MAX_MINUTES)),
+        Err(_) => Err(format!("'{}' is not a valid number of minutes", minutes)),
     }
 }
 
+fn valid_bind(address: String) -> Result<(), String>{
+    bind::parse_bind(&address, 0).map(|_| ())
+}
 
-fn index(_req: HttpRequest) -> actix_web::Result<NamedFile> {
-    let path: PathBuf = PathBuf::from("./static/index.html");
-    Ok(NamedFile::open(path)?)
+fn valid_public_url(url: String) -> Result<(), String>{
+    bind::parse_public_url(&url).map(|_| ())
+}
+
+fn valid_token(token: String) -> Result<(), String>{
+    share::valid_token(&token)
+}
+
+fn valid_password(password: String) -> Result<(), String>{
+    if password.is_empty() {
+        Err(String::from("Passwords can't be empty"))
+    } else {
+        Ok(())
+    }
+}
+
+fn valid_role(role: String) -> Result<(), String>{
+    Role::parse(&role).map(|_| ())
+}
+
+fn valid_origin(origin: String) -> Result<(), String>{
+    match origin::normalize(&origin) {
+        Some(_) => Ok(()),
+        None => Err(format!("'{}' is not a valid origin, expected e.g. https://example.com:8443", origin)),
+    }
+}
+
+fn valid_rate(rate: String) -> Result<(), String>{
+    match rate.parse::<u32>() {
+        Ok(0) => Err(String::from("Needs to be at least one message per second")),
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a valid number of messages per second", rate)),
+    }
+}
+
+fn valid_zone(zone: String) -> Result<(), String>{
+    zones::parse_zone(&zone).map(|_| ())
+}
+
+/// The websocket url for the page, derived from the Host header or the Forwarded and X-Forwarded-* headers of a reverse proxy.
+/// None if the host contains characters that have no place in a host, so it can't break out of the page's script.
+/// A token the page was opened with, e.g. from a shared guest url, is passed on to the websocket.
+fn websocket_url(req: &HttpRequest, token: Option<&str>) -> Option<String> {
+    let info = req.connection_info();
+    let host = info.host();
+    if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c)) {
+        return None;
+    }
+    let scheme = if info.scheme() == "https" { "wss" } else { "ws" };
+    match token {
+        Some(token) => Some(format!("{}://{}/api/ws?token={}", scheme, host, token)),
+        None => Some(format!("{}://{}/api/ws", scheme, host)),
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+fn index((req, state, query): (HttpRequest, web::Data<AppState>, web::Query<TokenQuery>)) -> HttpResponse {
+    let token = query.token.as_deref().filter(|token| share::valid_token(token).is_ok());
+    if state.auth.role(&req, token).is_none() {
+        return HttpResponse::SeeOther()
+            .header("Location", "/player/login")
+            .finish();
+    }
+    match websocket_url(&req, token) {
+        Some(url) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(state.index_template.replace("{{WS_URL}}", &url)),
+        None => HttpResponse::BadRequest().body("Invalid host"),
+    }
+}
+
+fn login_page(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(state.login_template.replace("{{MESSAGE}}", ""))
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    password: String,
+}
+
+fn login((req, state, form): (HttpRequest, web::Data<AppState>, web::Form<LoginForm>)) -> HttpResponse {
+    match state.auth.login(&form.password) {
+        Some(role) => {
+            println!("Login with role {:?}", role);
+            let session = state.auth.create_session(role);
+            HttpResponse::SeeOther()
+                .header("Set-Cookie", auth::session_cookie(&session, req.connection_info().scheme() == "https"))
+                .header("Location", "/player")
+                .finish()
+        },
+        None => {
+            println!("Failed login attempt from {}", req.connection_info().remote().unwrap_or("unknown address"));
+            HttpResponse::Unauthorized()
+                .content_type("text/html; charset=utf-8")
+                .body(state.login_template.replace("{{MESSAGE}}", "Wrong password"))
+        },
+    }
+}
+
+fn logout((req, state): (HttpRequest, web::Data<AppState>)) -> HttpResponse {
+    state.auth.end_session(&req);
+    HttpResponse::SeeOther()
+        .header("Set-Cookie", auth::expired_session_cookie())
+        .header("Location", "/player/login")
+        .finish()
+}
+
+fn share((req, state, query): (HttpRequest, web::Data<AppState>, web::Query<ShareQuery>)) -> HttpResponse {
+    // the guest token grants a role, so only admins may hand it out
+    let required_role = if query.guest { Role::Admin } else { Role::Listener };
+    match state.auth.role(&req, None) {
+        Some(role) if role >= required_role => (),
+        Some(_) => return HttpResponse::Forbidden().body("Sharing the guest url requires the admin role"),
+        None => return HttpResponse::Unauthorized().body("Not logged in"),
+    }
+    let guest_token = match (query.guest, &state.guest_token) {
+        (false, _) => None,
+        (true, Some(token)) => Some(token.as_str()),
+        (true, None) => return HttpResponse::NotFound().body("No guest token configured"),
+    };
+    let urls: Vec<String> = state.advertised_urls.read()
+        .expect("Advertised urls lock is poisoned. This is a bug.")
+        .iter()
+        .map(|url| share::player_url(url, guest_token))
+        .collect();
+    let qr_code = match urls.first() {
+        Some(url) => share::qr_code(url),
+        None => return HttpResponse::NotFound().body("No url to share"),
+    };
+    match query.format.as_deref() {
+        None | Some("json") => HttpResponse::Ok().json(Share {
+            urls,
+            qr_svg: qr_code.to_svg(),
+        }),
+        Some("svg") => HttpResponse::Ok()
+            .content_type("image/svg+xml")
+            .body(qr_code.to_svg()),
+        Some("png") => HttpResponse::Ok()
+            .content_type("image/png")
+            .body(qr_code.to_png()),
+        Some(format) => HttpResponse::BadRequest().body(format!("Unknown format '{}', expected json, svg or png", format)),
+    }
+}
+
+fn redirect_to_https((req, https_port): (HttpRequest, web::Data<u16>)) -> HttpResponse {
+    let path_and_query = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    match tls::https_redirect_url(req.connection_info().host(), *https_port.get_ref(), path_and_query) {
+        Some(url) => HttpResponse::PermanentRedirect()
+            .header("Location", url)
+            .finish(),
+        None => HttpResponse::BadRequest().body("Invalid host"),
+    }
 }
 
 fn controls(_req: HttpRequest) -> actix_web::Result<NamedFile> {
@@ -108,13 +310,137 @@ fn roboto_woff2(_req: HttpRequest) -> actix_web::Result<NamedFile> {
     Ok(NamedFile::open(path)?)
 }
 
-fn api_websocket((req, state): (HttpRequest, web::Data<AppState>), stream: web::Payload) -> actix_web::Result<HttpResponse, actix_web::Error> {
-    let resp = ws::start(PlayerWs{ sender: state.sender.clone() }, &req, stream);
+#[derive(Deserialize)]
+struct MediaQuery {
+    media_token: Option<String>,
+}
+
+/// Streams a registered media file to followers, which pass the media token they received when connecting, and to logged in clients
+fn media_file((req, state, media_id, query): (HttpRequest, web::Data<AppState>, web::Path<u64>, web::Query<MediaQuery>)) -> actix_web::Result<NamedFile> {
+    // without authentication everyone would be logged in as admin
+    let logged_in = state.auth.enabled() && state.auth.role(&req, None).is_some();
+    let authorized = logged_in || match &query.media_token {
+        Some(media_token) => state.media_tokens
+            .read()
+            .expect("Media tokens lock poisoned. This is a bug.")
+            .iter()
+            .any(|token| auth::constant_time_eq(token.as_bytes(), media_token.as_bytes())),
+        None => false,
+    };
+    if !authorized {
+        return Err(actix_web::error::ErrorForbidden("Missing or invalid media token"));
+    }
+    let path = state.media_paths
+        .read()
+        .expect("Media paths lock poisoned. This is a bug.")
+        .get(&media_id)
+        .cloned();
+    match path {
+        Some(path) => Ok(NamedFile::open(path)?),
+        None => Err(actix_web::error::ErrorNotFound("Unknown media")),
+    }
+}
+
+#[derive(Deserialize)]
+struct StateQuery {
+    zone_id: Option<u64>,
+}
+
+/// The complete state as sent to websocket connections, with the playback state of the zone or the default zone
+fn player_state((req, state, query): (HttpRequest, web::Data<AppState>, web::Query<StateQuery>)) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if state.auth.role(&req, None).is_none() {
+        return Box::new(future::ok(HttpResponse::Unauthorized().body("Not logged in")));
+    }
+    let zone_id = query.zone_id.unwrap_or(DEFAULT_ZONE);
+    Box::new(state.player
+        .send(GetPlayerState{zone_id})
+        .map_err(actix_web::error::ErrorInternalServerError)
+        .map(|result| match result {
+            Ok(player_state) => HttpResponse::Ok().json(player_state),
+            Err(e) => HttpResponse::NotFound().body(e),
+        }))
+}
+
+fn api_websocket((req, state, query): (HttpRequest, web::Data<AppState>, web::Query<TokenQuery>), stream: web::Payload) -> actix_web::Result<HttpResponse, actix_web::Error> {
+    let origin = req.headers().get("Origin").map(|origin| origin.to_str().unwrap_or(""));
+    let own_origin = {
+        let info = req.connection_info();
+        format!("{}://{}", info.scheme(), info.host())
+    };
+    let allowed = state.origins.allows(origin, &own_origin, &state.advertised_urls.read().expect("Advertised urls lock is poisoned. This is a bug."));
+    if !allowed {
+        println!("Rejected websocket from origin {}", origin.unwrap_or(""));
+        return Ok(HttpResponse::Forbidden().body("Origin not allowed"));
+    }
+    let role = match state.auth.role(&req, query.token.as_deref()) {
+        Some(role) => role,
+        None => return Ok(HttpResponse::Unauthorized().body("Not logged in")),
+    };
+    let player_ws = PlayerWs::new(state.player.clone(), role, &state.rate_limits);
+    let codec = actix_http::ws::Codec::new().max_size(websocket::MAX_MESSAGE_SIZE);
+    let resp = ws::handshake(&req)
+        .map(|mut resp| resp.streaming(ws::WebsocketContext::with_codec(player_ws, stream, codec)))
+        .map_err(actix_web::Error::from);
     println!("{:?}", resp);
     resp
 }
 
 
+/// Prints the instances of the application announced in the network
+fn discover() {
+    let instances = match mdns::discover(DISCOVERY_TIMEOUT) {
+        Ok(instances) => instances,
+        Err(e) => {
+            eprintln!("Failed to browse for instances via mDNS: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if instances.is_empty() {
+        println!("No instances found.");
+    }
+    for instance in &instances {
+        println!("{} ({}, protocol version {})", instance.name, instance.host, instance.txt_value("version").unwrap_or("unknown"));
+        let path = instance.txt_value("path").unwrap_or("/player");
+        // instances from before TLS support have no scheme entry
+        let scheme = instance.txt_value("scheme").unwrap_or("http");
+        for address in &instance.addresses {
+            println!("    {}://{}:{}{}", scheme, network_interfaces::url_host(address), instance.port, path);
+        }
+    }
+}
+
+fn detect_interfaces() -> Vec<network_interfaces::NetworkInterface> {
+    match network_interfaces::interfaces() {
+        Some(interfaces) => interfaces,
+        None => {
+            eprintln!("Unable to detect network interfaces on your system.");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints the network interfaces with their addresses and whether they are picked automatically
+fn list_interfaces(override_interface: Option<&str>) {
+    let interfaces = detect_interfaces();
+    let selected = network_interfaces::select_network_interface(&interfaces, override_interface).map(|interface| interface.name);
+    for interface in &interfaces {
+        let mut info = interface.name.clone();
+        if interface.default_route {
+            info.push_str(" [default route]");
+        }
+        if interface.is_virtual() || interface.is_loopback() {
+            info.push_str(" [excluded]");
+        }
+        if selected.as_ref() == Some(&interface.name) {
+            info.push_str(" [selected]");
+        }
+        println!("{}", info);
+        for ip in &interface.ip_addresses {
+            println!("    {}", ip);
+        }
+    }
+}
+
 fn main() {
 
     let matches = clap::App::new("Fidelitas")
@@ -130,6 +456,27 @@ fn main() {
             .help("The port the server will listen on")
             .validator(valid_port)
             )
+        .arg(clap::Arg::with_name("bind")
+            .takes_value(true)
+            .long("bind")
+            .value_name("ADDRESS")
+            .help("An address to listen on instead of all interfaces: IP, IP:PORT, [IPV6]:PORT or unix:PATH. Addresses without a port use --port. May be passed multiple times.")
+            .validator(valid_bind)
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("local-only")
+            )
+        .arg(clap::Arg::with_name("local-only")
+            .long("local-only")
+            .help("Only listen on the loopback addresses, e.g. behind a reverse proxy on the same host.")
+            )
+        .arg(clap::Arg::with_name("public-url")
+            .takes_value(true)
+            .long("public-url")
+            .value_name("URL")
+            .help("The url users open the application with, e.g. https://music.example.com behind a reverse proxy. Defaults to the address of the network interface.")
+            .validator(valid_public_url)
+            )
         .arg(clap::Arg::with_name("dir")
             .takes_value(true)
             .default_value("./music")
@@ -147,67 +494,519 @@ fn main() {
             .help("Explicitly allow file extensions to be read by the program. May cause crashes if files cannot be decoded.")
             .multiple(true)
         )
+        .arg(clap::Arg::with_name("data-dir")
+            .takes_value(true)
+            .default_value("./data")
+            .long("data-dir")
+            .value_name("PATH")
+            .help("The directory server side state such as radio stations is stored in. Will be created if it does not exist.")
+            )
+        .arg(clap::Arg::with_name("podcast-refresh")
+            .takes_value(true)
+            .default_value("60")
+            .long("podcast-refresh")
+            .value_name("MINUTES")
+            .help("How often subscribed podcast feeds are checked for new episodes.")
+            .validator(valid_minutes)
+            )
+        .arg(clap::Arg::with_name("resume-min-length")
+            .takes_value(true)
+            .default_value("20")
+            .long("resume-min-length")
+            .value_name("MINUTES")
+            .help("Files at least this long remember their playback position and resume there when played again. 0 disables resuming based on length.")
+            .validator(valid_minutes_or_zero)
+            )
+        .arg(clap::Arg::with_name("resume-dir")
+            .takes_value(true)
+            .long("resume-dir")
+            .value_name("PATH")
+            .help("Files inside this directory, such as an audiobook folder, always remember their playback position. May be passed multiple times.")
+            .validator(valid_directory)
+            .multiple(true)
+            .number_of_values(1)
+            )
+        .arg(clap::Arg::with_name("analyze-loudness")
+            .long("analyze-loudness")
+            .help("Measure the loudness of files without ReplayGain tags in the background, so ReplayGain can be applied to them as well.")
+            )
+        .arg(clap::Arg::with_name("audio-output")
+            .takes_value(true)
+            .long("audio-output")
+            .value_name("OUTPUT")
+            .help("The libvlc audio output to play to, e.g. alsa or pulse. 'null' discards all audio, for machines without sound hardware.")
+            )
+        .arg(clap::Arg::with_name("audio-device")
+            .takes_value(true)
+            .long("audio-device")
+            .value_name("DEVICE_ID")
+            .requires("audio-output")
+            .help("The device of the audio output to play to.")
+            )
+        .arg(clap::Arg::with_name("list-audio-outputs")
+            .long("list-audio-outputs")
+            .help("Lists the available audio outputs and their devices, then exits.")
+            )
+        .arg(clap::Arg::with_name("zone")
+            .takes_value(true)
+            .long("zone")
+            .value_name("NAME[=OUTPUT[:DEVICE]]")
+            .help("Defines a zone with its own playback, queue and volume, e.g. kitchen=alsa:hw:1,0. May be passed multiple times, the first zone is the default zone. --audio-output applies to the default zone.")
+            .validator(valid_zone)
+            .multiple(true)
+            .number_of_values(1)
+            )
+        .arg(clap::Arg::with_name("sync-port")
+            .takes_value(true)
+            .long("sync-port")
+            .value_name("PORT")
+            .help("Lead synchronized playback: followers connect to this port and play what the zones they are grouped with play. The port is bound on the same addresses as the web server.")
+            .validator(valid_port)
+            .conflicts_with("follow")
+            .requires("sync-secret")
+            )
+        .arg(clap::Arg::with_name("follow")
+            .takes_value(true)
+            .long("follow")
+            .value_name("HOST:PORT")
+            .help("Follow the leader listening for followers at this address, e.g. 192.168.1.20:8090.")
+            .requires("sync-secret")
+            )
+        .arg(clap::Arg::with_name("sync-secret")
+            .takes_value(true)
+            .long("sync-secret")
+            .value_name("SECRET")
+            .env("FIDELITAS_SYNC_SECRET")
+            .hide_env_values(true)
+            .help("The secret the leader and its followers share. Followers without it can't connect or stream media from the leader.")
+            .validator(valid_password)
+            )
+        .arg(clap::Arg::with_name("follower-name")
+            .takes_value(true)
+            .long("follower-name")
+            .value_name("NAME")
+            .requires("follow")
+            .help("The name this follower is shown with on the leader. Defaults to the host name and port.")
+            )
         .arg(clap::Arg::with_name("interface")
             .long("interface")
             .takes_value(true)
             .value_name("NETWORK_INTERFACE_NAME")
-            .help("Manually select the network interface users should access the application with.")
+            .help("Manually select the network interface users should access the application with, by name or by one of its addresses.")
         )
+        .arg(clap::Arg::with_name("tls-cert")
+            .takes_value(true)
+            .long("tls-cert")
+            .value_name("PATH")
+            .help("Serve HTTPS with this PEM certificate, optionally followed by intermediate certificates.")
+            .requires("tls-key")
+            )
+        .arg(clap::Arg::with_name("tls-key")
+            .takes_value(true)
+            .long("tls-key")
+            .value_name("PATH")
+            .help("The PEM private key of the certificate passed with --tls-cert.")
+            .requires("tls-cert")
+            )
+        .arg(clap::Arg::with_name("tls-self-signed")
+            .long("tls-self-signed")
+            .help("Serve HTTPS with a self-signed certificate for the addresses of this machine, generated in the data directory. Browsers warn about it once.")
+            .conflicts_with("tls-cert")
+            )
+        .arg(clap::Arg::with_name("redirect-port")
+            .takes_value(true)
+            .long("redirect-port")
+            .value_name("PORT")
+            .help("Redirect plain http requests on this port to https, e.g. 80 when serving https on 443.")
+            .validator(valid_port)
+            )
+        .arg(clap::Arg::with_name("guest-token")
+            .takes_value(true)
+            .long("guest-token")
+            .value_name("TOKEN")
+            .help("A token embedded in the player url shared with guests, e.g. through the QR code printed on startup.")
+            .validator(valid_token)
+            )
+        .arg(clap::Arg::with_name("guest-role")
+            .takes_value(true)
+            .long("guest-role")
+            .value_name("ROLE")
+            .help("The role the guest token grants when authentication is enabled: listener, controller or admin.")
+            .default_value("controller")
+            .validator(valid_role)
+            )
+        .arg(clap::Arg::with_name("admin-password")
+            .takes_value(true)
+            .long("admin-password")
+            .value_name("PASSWORD")
+            .env("FIDELITAS_ADMIN_PASSWORD")
+            .hide_env_values(true)
+            .help("Require a login and grant the admin role with this password. Prefer the environment variable, arguments are visible to other users of the machine.")
+            .validator(valid_password)
+            )
+        .arg(clap::Arg::with_name("controller-password")
+            .takes_value(true)
+            .long("controller-password")
+            .value_name("PASSWORD")
+            .env("FIDELITAS_CONTROLLER_PASSWORD")
+            .hide_env_values(true)
+            .help("Require a login and grant the controller role, which controls playback, with this password.")
+            .validator(valid_password)
+            )
+        .arg(clap::Arg::with_name("listener-password")
+            .takes_value(true)
+            .long("listener-password")
+            .value_name("PASSWORD")
+            .env("FIDELITAS_LISTENER_PASSWORD")
+            .hide_env_values(true)
+            .help("Require a login and grant the read-only listener role with this password.")
+            .validator(valid_password)
+            )
+        .arg(clap::Arg::with_name("allowed-origin")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("allowed-origin")
+            .value_name("ORIGIN")
+            .help("Allow pages of this origin to connect to the websocket, e.g. a custom client. The player's own urls are always allowed.")
+            .validator(valid_origin)
+            )
+        .arg(clap::Arg::with_name("ws-rate-limit")
+            .takes_value(true)
+            .long("ws-rate-limit")
+            .value_name("MESSAGES_PER_SECOND")
+            .help("Messages per second a single websocket connection may send, bursts may be twice as large.")
+            .default_value("30")
+            .validator(valid_rate)
+            )
+        .arg(clap::Arg::with_name("ws-global-rate-limit")
+            .takes_value(true)
+            .long("ws-global-rate-limit")
+            .value_name("MESSAGES_PER_SECOND")
+            .help("Messages per second all websocket connections together may send.")
+            .default_value("300")
+            .validator(valid_rate)
+            )
+        .arg(clap::Arg::with_name("no-qr")
+            .long("no-qr")
+            .help("Don't print a QR code of the player url on startup.")
+            )
+        .arg(clap::Arg::with_name("no-mdns")
+            .long("no-mdns")
+            .help("Don't advertise the application to the network via mDNS/DNS-SD.")
+            )
+        .arg(clap::Arg::with_name("mdns-name")
+            .takes_value(true)
+            .long("mdns-name")
+            .value_name("NAME")
+            .help("The name the application is advertised with via mDNS. Defaults to the host name.")
+            .conflicts_with("no-mdns")
+            )
+        .arg(clap::Arg::with_name("discover")
+            .long("discover")
+            .help("Lists the instances of the application found in the network via mDNS, then exits.")
+            )
+        .arg(clap::Arg::with_name("list-interfaces")
+            .long("list-interfaces")
+            .help("Lists the network interfaces and their addresses, then exits.")
+            )
         .get_matches();
 
+    if matches.is_present("list-interfaces") {
+        list_interfaces(matches.value_of("interface"));
+        std::process::exit(0);
+    }
+    if matches.is_present("discover") {
+        discover();
+        std::process::exit(0);
+    }
 
     let path = PathBuf::from(matches.value_of("dir").expect("Can't retrieve cli matches of flag 'dir'. This is a bug."));
     println!("Hosting files in folder: {}", path.to_str().expect("Can't convert music folder path to string. This is a bug."));
 
 
-    let port = matches.value_of("port").expect("Can't retrieve cli matches of flag 'port'. This is a bug.");
-
-    // select network interface and address
-    let interface_candidates = match network_interfaces::interfaces() {
-        Some(candidates) => candidates,
-        None => {
-            eprintln!("Unable to detect network interfaces on your system.");
+    let data_dir = PathBuf::from(matches.value_of("data-dir").expect("Can't retrieve cli matches of flag 'data-dir'. This is a bug."));
+    let store = match Store::open(&data_dir) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Failed to open data directory {}: {}", data_dir.display(), e);
             std::process::exit(1);
         }
     };
 
-    let selected_interface = match network_interfaces::select_network_interface(&interface_candidates, matches.value_of("interface")) {
-        Some (i) => i,
-        None => {
-            eprintln!("Unable to autoselect a network interface. Please manually pass the --interface flag.\n Found the following interfaces:");
-            interface_candidates.iter().for_each(|a| println!("{:?}", a));
+    let podcast_refresh_minutes: u64 = matches.value_of("podcast-refresh")
+        .expect("Can't retrieve cli matches of flag 'podcast-refresh'. This is a bug.")
+        .parse()
+        .expect("Podcast refresh interval passed validation but can't be parsed. This is a bug.");
+
+    let resume_policy = {
+        let min_length_minutes: i64 = matches.value_of("resume-min-length")
+            .expect("Can't retrieve cli matches of flag 'resume-min-length'. This is a bug.")
+            .parse()
+            .expect("Resume minimum length passed validation but can't be parsed. This is a bug.");
+        let min_length = if min_length_minutes > 0 {
+            Some(min_length_minutes.checked_mul(60 * 1000).expect("Resume minimum length passed validation but overflows. This is a bug."))
+        } else {
+            None
+        };
+        let directories: Vec<PathBuf> = matches.values_of("resume-dir")
+            .map(|dirs| dirs.map(PathBuf::from).collect())
+            .unwrap_or_default();
+        ResumePolicy::new(min_length, &directories)
+    };
+
+    let audio_output = matches.value_of("audio-output").map(|output| AudioOutput {
+        output: Some(output.to_string()),
+        device: matches.value_of("audio-device").map(|device| device.to_string()),
+    });
+    let mut zones: Vec<ZoneConfig> = matches.values_of("zone")
+        .map(|zones| zones.map(|zone| zones::parse_zone(zone).expect("Zone passed validation but can't be parsed. This is a bug.")).collect())
+        .unwrap_or_default();
+    if zones.is_empty() {
+        zones.push(ZoneConfig {
+            name: String::from(zones::DEFAULT_ZONE_NAME),
+            audio_output: None,
+        });
+    }
+    {
+        let mut names = HashSet::new();
+        if let Some(zone) = zones.iter().find(|zone| !names.insert(zone.name.clone())) {
+            eprintln!("Zone '{}' is defined more than once.", zone.name);
             std::process::exit(1);
         }
-    };
+    }
+    if audio_output.is_some() {
+        zones[0].audio_output = audio_output;
+    }
+    if matches.is_present("list-audio-outputs") || zones.iter().any(|zone| zone.audio_output.is_some()) {
+        let vlc_instance = vlc::Instance::new().expect("Failed to initialize vlc instance. This is a bug.");
+        if matches.is_present("list-audio-outputs") {
+            for output in audio_output::list(&vlc_instance) {
+                println!("{} ({})", output.name, output.description);
+                for device in output.devices {
+                    println!("    {} ({})", device.id, device.description);
+                }
+            }
+            println!("{} (discards all audio)", audio_output::NULL_OUTPUT);
+            std::process::exit(0);
+        }
+        for zone in &zones {
+            if let Some(Err(e)) = zone.audio_output.as_ref().map(|audio_output| audio_output.validate(&vlc_instance)) {
+                eprintln!("{} in zone '{}'. Pass --list-audio-outputs to list the available outputs.", e, zone.name);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let host_address = {
-        let sorted_ips = selected_interface.ip_addresses
-            .iter()
-            .fold(VecDeque::new(), network_interfaces::v4_first);
+    let port: u16 = matches.value_of("port")
+        .expect("Can't retrieve cli matches of flag 'port'. This is a bug.")
+        .parse()
+        .expect("Port passed validation but can't be parsed. This is a bug.");
+    let bind_addresses: Vec<BindAddress> = matches.values_of("bind")
+        .map(|addresses| addresses.map(|address| bind::parse_bind(address, port).expect("Bind address passed validation but can't be parsed. This is a bug.")).collect())
+        .unwrap_or_default();
+    let listeners = if matches.is_present("local-only") {
+        bind::listen_loopback(port)
+    } else if bind_addresses.is_empty() {
+        bind::listen_dual_stack(port)
+    } else {
+        bind::listen(&bind_addresses)
+    };
+    let listeners = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("{}. The port might be in use. Try and specify a free port manually with the -p flag.", e);
+            std::process::exit(1);
+        }
+    };
+    let tls_config = if let (Some(cert), Some(key)) = (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        Some(TlsConfig::new(PathBuf::from(cert), PathBuf::from(key)))
+    } else if matches.is_present("tls-self-signed") {
+        let addresses: Vec<IpAddr> = network_interfaces::interfaces()
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|interface| interface.ip_addresses)
+            .collect();
+        Some(tls::self_signed(&data_dir.join("tls"), &network_interfaces::host_name(), &addresses))
+    } else {
+        None
+    };
+    let tls_config = match tls_config.transpose() {
+        Ok(tls_config) => tls_config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    // plain http requests on this port are redirected to https
+    let redirect_listeners = match matches.value_of("redirect-port") {
+        Some(redirect_port) => {
+            if tls_config.is_none() {
+                eprintln!("--redirect-port redirects to https and requires --tls-cert or --tls-self-signed.");
+                std::process::exit(1);
+            }
+            let redirect_port: u16 = redirect_port.parse().expect("Port passed validation but can't be parsed. This is a bug.");
+            let redirect_listeners = if matches.is_present("local-only") {
+                bind::listen_loopback(redirect_port)
+            } else if bind_addresses.is_empty() {
+                bind::listen_dual_stack(redirect_port)
+            } else {
+                bind::listen(&bind::with_port(&bind_addresses, redirect_port))
+            };
+            match redirect_listeners {
+                Ok(redirect_listeners) => redirect_listeners,
+                Err(e) => {
+                    eprintln!("{}. The port might be in use. Try and specify a free port manually with the --redirect-port flag.", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        None => Vec::new(),
+    };
 
-        match sorted_ips.iter().next() {
-            Some(ip) => ip.to_string(),
+    let media_paths = MediaPaths::default();
+    let media_tokens = MediaTokens::default();
+    let sync_secret = matches.value_of("sync-secret").map(|secret| secret.to_string());
+    let sync_config = if let Some(sync_port) = matches.value_of("sync-port") {
+        let sync_port: u16 = sync_port.parse().expect("Port passed validation but can't be parsed. This is a bug.");
+        // followers fetch media over the first tcp address of the web server
+        let http_port = match listeners.iter().filter_map(Listener::local_addr).next() {
+            Some(address) => address.port(),
             None => {
-                eprintln!("Unable to get ipv4 adress from selected network interfaces. Interface: {:?}", selected_interface);
+                eprintln!("Followers stream media over tcp, bind a tcp address with --bind to lead synchronized playback.");
+                std::process::exit(1);
+            }
+        };
+        let sync_listeners = if matches.is_present("local-only") {
+            bind::listen_loopback(sync_port)
+        } else if bind_addresses.is_empty() {
+            bind::listen_dual_stack(sync_port)
+        } else {
+            bind::listen(&bind::with_port(&bind_addresses, sync_port))
+        };
+        let sync_listeners: Vec<std::net::TcpListener> = match sync_listeners {
+            Ok(sync_listeners) => sync_listeners
+                .into_iter()
+                .filter_map(|listener| match listener {
+                    Listener::Tcp(listener) => Some(listener),
+                    #[cfg(unix)]
+                    Listener::Unix(_) => None,
+                })
+                .collect(),
+            Err(e) => {
+                eprintln!("{}. The port might be in use. Try and specify a free port manually with the --sync-port flag.", e);
                 std::process::exit(1);
             }
+        };
+        if matches.is_present("tls-self-signed") {
+            println!("Followers don't trust the self-signed certificate and fail to stream media, use --tls-cert with a certificate they trust instead.");
         }
+        println!("Accepting followers on port: {}", sync_port);
+        SyncConfig::Leader {
+            listeners: sync_listeners,
+            http_port,
+            tls: tls_config.is_some(),
+            secret: sync_secret.expect("--sync-port requires --sync-secret. This is a bug."),
+            media_paths: media_paths.clone(),
+            media_tokens: media_tokens.clone(),
+        }
+    } else if let Some(leader) = matches.value_of("follow") {
+        let name = match matches.value_of("follower-name") {
+            Some(name) => name.to_string(),
+            None => format!("{}:{}", network_interfaces::host_name(), port),
+        };
+        SyncConfig::Follower {
+            leader: leader.to_string(),
+            name,
+            secret: sync_secret.expect("--follow requires --sync-secret. This is a bug."),
+        }
+    } else {
+        SyncConfig::Standalone
     };
 
-    // populate html template
-    match populate_html_template(&host_address, port) {
-        Ok (()) => {
-            println!("Populated html template");
-        },
-        Err (e) => {
-            eprintln!("failed to populate html: {}", e);
+    let advertise_config = if let Some(url) = matches.value_of("public-url") {
+        AdvertiseConfig::Fixed(vec![bind::parse_public_url(url).expect("Public url passed validation but can't be parsed. This is a bug.")])
+    } else if matches.is_present("local-only") {
+        AdvertiseConfig::Fixed(vec![format!("{}://localhost:{}", scheme, port)])
+    } else {
+        let bind_urls: Vec<String> = bind_addresses.iter().filter_map(|address| bind::advertised_url(address, scheme)).collect();
+        // listening on all interfaces, so every reachable address of every interface works
+        let unspecified: Vec<SocketAddr> = listeners.iter()
+            .filter_map(Listener::local_addr)
+            .filter(|address| address.ip().is_unspecified())
+            .collect();
+        match unspecified.first() {
+            Some(address) => AdvertiseConfig::Interfaces {
+                interface: matches.value_of("interface").map(|interface| interface.to_string()),
+                port: address.port(),
+                scheme,
+                ipv6: unspecified.iter().any(|address| address.is_ipv6()),
+                bind_urls,
+            },
+            None => AdvertiseConfig::Fixed(bind_urls),
+        }
+    };
+    let advertised_urls: AdvertisedUrls = match advertise_config.urls() {
+        Ok(urls) => Arc::new(RwLock::new(urls)),
+        Err(e) => {
+            eprintln!("{}", e);
+            list_interfaces(matches.value_of("interface"));
             std::process::exit(1);
         }
+    };
+
+    let guest_token = matches.value_of("guest-token").map(|token| token.to_string());
+    let auth = {
+        let passwords: Vec<Credential> = vec![("admin-password", Role::Admin), ("controller-password", Role::Controller), ("listener-password", Role::Listener)]
+            .into_iter()
+            .filter_map(|(arg, role)| matches.value_of(arg).map(|password| Credential { secret: password.to_string(), role }))
+            .collect();
+        let guest_role = Role::parse(matches.value_of("guest-role").expect("Can't retrieve cli matches of flag 'guest-role'. This is a bug."))
+            .expect("Role passed validation but can't be parsed. This is a bug.");
+        let guest = guest_token.as_ref().map(|token| Credential { secret: token.clone(), role: guest_role });
+        Auth::new(passwords, guest)
+    };
+    if auth.enabled() {
+        println!("Authentication enabled, log in at /player/login");
+    } else {
+        println!("Authentication disabled, everyone in the network can control the player. Set --admin-password to require a login.");
     }
 
+    let index_template = match std::fs::read_to_string("./templates/index.html") {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!("Failed to read html template ./templates/index.html: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let origins = {
+        let allowed: Vec<String> = matches.values_of("allowed-origin")
+            .map(|origins| origins.map(|origin| origin.to_string()).collect())
+            .unwrap_or_default();
+        OriginPolicy::new(&allowed, &network_interfaces::host_name())
+    };
+    let rate_limits = {
+        let rate = |arg: &str| -> u32 {
+            matches.value_of(arg)
+                .expect("Can't retrieve cli matches of a rate limit flag. This is a bug.")
+                .parse()
+                .expect("Rate passed validation but can't be parsed. This is a bug.")
+        };
+        RateLimits::new(rate("ws-rate-limit"), rate("ws-global-rate-limit"))
+    };
+
+    let login_template = match std::fs::read_to_string("./templates/login.html") {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!("Failed to read html template ./templates/login.html: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let parse_media_config = {
-        let mut extension_set : HashSet<&str> = HashSet::with_capacity(5);
+        let mut extension_set : HashSet<&str> = HashSet::with_capacity(6);
         // add default extensions
         // TODO: instead read from a default config file
         extension_set.insert("mp3");
@@ -215,6 +1014,7 @@ fn main() {
         extension_set.insert("opus");
         extension_set.insert("wav");
         extension_set.insert("m4a");
+        extension_set.insert("m4b");
 
         match matches.values_of("extension") {
             Some(a) => {
@@ -239,170 +1039,50 @@ fn main() {
         ParseMediaConfig::new(&extension_set)
     };
 
-    // initialize the channel for communication with the libvlc handler thread
-    // sender will be passed to actix web as appstate and can be safely shared across websocket handlers
-    // receiver will be passed to the global player thread, 
-    let (sender, receiver) = crossbeam_channel::unbounded();
-
-    let _handle = thread::spawn(move || {
-        // player thread setup
-
-        let mut playback_state = PlaybackState::Stopped;
-        let vlc_instance = vlc::Instance::new().expect("Failed to initialize vlc instance. This is a bug.");
-        let mediaplayer = vlc::MediaPlayer::new(&vlc_instance).expect("Failed to create vlc media player from vlc instance. This is a bug.");
-
-        let mut ws_connections: HashSet<Addr<PlayerWs>> = HashSet::new();
-        let (_media_max_id, registered_media) = parse_media_dir(0, &path, &parse_media_config).expect("Unable to read media dir.");
-
-        // channel handling loop
-        loop {
-            match receiver.recv() {
-                Ok(msg) => {
-                    match msg {
-                        PlayerMsg::Play(media_id) => {
-                            if let Some(track_path) = registered_media.get(&media_id) {
-                                println!("Received track on worker thread: k:'{}' V:'{}'", media_id, track_path);
-                                // TODO: handle resiliently instead of expect
-                                let md = vlc::Media::new_path(&vlc_instance, track_path).expect("Failed to create vlc media from file path. This is a bug.");
-                                mediaplayer.set_media(&md);
-
-                                // TODO: handle resiliently instead of expect
-                                mediaplayer.play().expect("Failed to play selected vlc media. This is a bug.");
-                                
-                                playback_state = PlaybackState::Playing{current_media: CurrentMedia::new(media_id, &mediaplayer)};
-
-                                broadcast(&ws_connections, OutgoingMsg::PlaybackChange {playback_state: playback_state});
-                            } else {
-                                println!("Received track request with invalid track_id: {}", media_id)
-                            }                            
-                        },
-                        PlayerMsg::Pause => {
-                            mediaplayer.pause();
-                            match playback_state {
-                                PlaybackState::Playing{current_media} => {
-                                    playback_state = PlaybackState::Paused{current_media: CurrentMedia::new(current_media.id, &mediaplayer)};
-                                    broadcast(&ws_connections, OutgoingMsg::PlaybackChange{playback_state: playback_state});
-                                }
-                                PlaybackState::Paused {current_media: _} => {
-                                    println!("received pause message but is already paused");
-                                    // frontend state might be corrupted, send correct state to frontend?
-                                }
-                                PlaybackState::Stopped => {
-                                    println!("received pause message but is stopped");
-                                    // frontend state might be corrupted, send correct state to frontend?
-                                }
-                            }
-                        },
-                        // TODO: send more specific error message to client
-                        PlayerMsg::Resume => {
-                            match playback_state {
-                                PlaybackState::Playing {current_media: _} => {
-                                    println!("Resume message received but already is already playing.");
-                                    // frontend state might be corrupted, send correct state to frontend?
-                                }
-                                PlaybackState::Paused {current_media} => {
-                                    if mediaplayer.will_play() {
-                                        match mediaplayer.play() {
-                                            Ok(()) => {
-                                                playback_state = PlaybackState::Playing{current_media:current_media};
-                                                broadcast(&ws_connections, OutgoingMsg::PlaybackChange {playback_state});
-                                            },
-                                            Err(()) => {
-                                                println!("failed to play media");
-                                                broadcast(&ws_connections, OutgoingMsg::Error)
-                                            }
-                                        }
-                                    } else {
-                                        println!("player won't play media");
-                                        broadcast(&ws_connections, OutgoingMsg::Error)
-                                    }
-                                },
-                                PlaybackState::Stopped => {
-                                    println!("Pause message received but is stopped.")
-                                    // frontend state might be corrupted, send correct state to frontend?
-                                }
-                            }
-                        },
-                        PlayerMsg::Stop => {
-                            match playback_state {
-                                PlaybackState::Playing{current_media: _} => {
-                                    playback_state = PlaybackState::Stopped;
-                                    mediaplayer.stop();
-                                    broadcast(&ws_connections, OutgoingMsg::PlaybackChange{playback_state: playback_state});
-                                },
-                                PlaybackState::Paused{current_media: _} => {
-                                    playback_state = PlaybackState::Stopped;
-                                    mediaplayer.stop();
-                                },
-                                PlaybackState::Stopped => {
-                                    println!("Stop message received but is already stopped.")
-                                    // frontend state might be corrupted, send correct state to frontend?
-                                }
-                            }
-                        },
-                        PlayerMsg::VolumeChange(volume) => {
-                            use vlc::MediaPlayerAudioEx;
-                            use std::convert::TryInto;
-
-                            // TODO:handle resiliently instead of expect
-                            // check value for i32 bounds (and limits given by vlc?)
-                            match mediaplayer.set_volume(volume.try_into().expect("Failed to convert volume change message. This is a bug.")) {
-                                Ok(()) => {
-                                   broadcast(&ws_connections, OutgoingMsg::VolumeChange{volume: volume});
-                                },
-                                Err(()) => {
-                                    // TODO: log? retry?
-                                }
-                            }
-                        },
-                        PlayerMsg::Register(ws) => {
-                            ws_connections.insert(ws.clone());
-
-                            // update playback state if needed
-                            let playback_state = match playback_state {
-                                PlaybackState::Paused{current_media: _} => playback_state,
-                                PlaybackState::Stopped => playback_state,
-                                PlaybackState::Playing{current_media} => {
-                                    PlaybackState::new(current_media.id, &mediaplayer)
-                                }
-                            };
-                        
-                            match ws.try_send(
-                                OutgoingMsg::PlayerState{
-                                    playback_state: playback_state,
-                                    media: registered_media.clone()
-                                }
-                            )
-                            {
-                                Ok(_) => {},
-                                Err(e) => {println!("Failed to send PlayerState message: {}", e)}
-                            }
-                        },
-                        PlayerMsg::Unregister(ws) => {
-                            ws_connections.remove(&ws);
-                        },
-                    }
-                },
-                Err(e) => println!("Recieved error on worker thread: {}", e),
-            }
-        }
-    });
+    let player_config = PlayerConfig {
+        media_dir: path,
+        parse_media_config,
+        store,
+        podcast_refresh_interval: std::time::Duration::from_secs(podcast_refresh_minutes * 60),
+        resume_policy,
+        analyze_loudness: matches.is_present("analyze-loudness"),
+        zones,
+        sync: sync_config,
+    };
+    // the player runs on an arbiter of the system, so the system has to exist first
+    let system = actix::System::new("fidelitas");
+    // the address will be passed to actix web as appstate and can be safely shared across websocket handlers
+    let player = player::start(player_config);
 
     let app_state = web::Data::new(AppState {
-        sender: sender,
+        player,
+        index_template,
+        advertised_urls: advertised_urls.clone(),
+        guest_token: guest_token.clone(),
+        auth,
+        login_template,
+        origins,
+        rate_limits,
+        media_paths,
+        media_tokens,
     });
 
-    println!("Listening on port: {}...", port);
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             .register_data(app_state.clone())
             .service(
                 web::scope("player")
                     .route("", web::get().to(index))
+                    .route("login", web::get().to(login_page))
             )
             .service(
                 web::scope("api")
                     .route("ws", web::get().to(api_websocket))
+                    .route("media/{media_id}", web::get().to(media_file))
+                    .route("share", web::get().to(share))
+                    .route("state", web::get().to_async(player_state))
+                    .route("login", web::post().to(login))
+                    .route("logout", web::post().to(logout))
             )
             .service(
                 web::scope("static")
@@ -412,10 +1092,68 @@ fn main() {
                     .route("roboto-v20-latin-ext_latin-regular.woff", web::get().to(roboto_woff))
 
             )
-    })
-    .bind(format!("0.0.0.0:{}", port))
-    // TODO: match instead and print specific error.
-    .expect("Failed to bind port. The port might be in use. Try and specify a free port manually with the -p flag.")
-    .run()
-    .expect("Failed to start actix system. This is a bug.");
+    });
+    let https_port = listeners.iter().filter_map(Listener::local_addr).map(|address| address.port()).next().unwrap_or(port);
+    for listener in listeners {
+        server = match listener {
+            Listener::Tcp(listener) => {
+                match listener.local_addr() {
+                    Ok(address) => println!("Listening on {}://{}...", scheme, address),
+                    Err(_) => println!("Listening..."),
+                }
+                match &tls_config {
+                    #[cfg(feature = "tls")]
+                    Some(tls_config) => server.listen_ssl(listener, tls_config.acceptor().unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    })),
+                    _ => server.listen(listener),
+                }
+            },
+            #[cfg(unix)]
+            Listener::Unix(path) => {
+                println!("Listening on unix socket {}...", path.display());
+                server.bind_uds(&path)
+            },
+        }.expect("Failed to listen on a bound address. This is a bug.");
+    }
+    advertise::print_urls(&advertised_urls.read().expect("Advertised urls lock is poisoned. This is a bug."));
+    if !matches.is_present("no-qr") {
+        share::print_qr_code(&advertised_urls.read().expect("Advertised urls lock is poisoned. This is a bug."), guest_token.as_deref());
+    }
+    if matches.is_present("no-mdns") {
+        println!("Not advertising via mDNS");
+    } else if matches.is_present("local-only") {
+        println!("Not advertising via mDNS, only listening on the loopback addresses");
+    } else {
+        let host = network_interfaces::host_name();
+        let instance = mdns::label(matches.value_of("mdns-name").unwrap_or(&host));
+        if let Err(e) = mdns::spawn(instance, host, advertised_urls.clone()) {
+            println!("Failed to advertise via mDNS: {}", e);
+        }
+    }
+    if advertise_config.follows_network() {
+        network_monitor::spawn(advertise_config, advertised_urls);
+    }
+    server.start();
+    if !redirect_listeners.is_empty() {
+        let mut redirect_server = HttpServer::new(move || {
+            App::new()
+                .data(https_port)
+                .default_service(web::route().to(redirect_to_https))
+        });
+        for listener in redirect_listeners {
+            if let Listener::Tcp(listener) = listener {
+                match listener.local_addr() {
+                    Ok(address) => println!("Redirecting http://{} to https...", address),
+                    Err(_) => println!("Redirecting to https..."),
+                }
+                redirect_server = redirect_server.listen(listener).expect("Failed to listen on a bound address. This is a bug.");
+            }
+        }
+        redirect_server.start();
+    }
+    system
+        .run()
+        .expect("Failed to start actix system. This is a bug.");
 }
\ No newline at end of file
@@ -0,0 +1,456 @@
+//! Advertises the application as a DNS-SD service over mDNS, so clients in the network find it without being told an address,
+//! and browses for other instances.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::advertise::AdvertisedUrls;
+use crate::dns::{self, Message, Question, Record, RecordData};
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+pub const SERVICE_TYPE: &str = "_fidelitas._tcp.local";
+const HTTP_SERVICE_TYPE: &str = "_http._tcp.local";
+const HTTPS_SERVICE_TYPE: &str = "_https._tcp.local";
+/// Lists the service types on offer, for service browsers that don't know what to look for
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// Advertised in the TXT record, increased when the websocket protocol changes incompatibly
+pub const PROTOCOL_VERSION: &str = "1";
+const PLAYER_PATH: &str = "/player";
+
+const TTL: u32 = 120;
+/// Responses to queries not sent from the mDNS port must not be cached for long
+const LEGACY_UNICAST_TTL: u32 = 10;
+/// Announcements are repeated, in case the first one is lost
+const ANNOUNCEMENTS: u32 = 2;
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The advertised instance of the application
+#[derive(Debug, Clone, PartialEq)]
+struct Service {
+    instance: String,
+    host: String,
+    port: u16,
+    /// http or https
+    scheme: String,
+    addresses: Vec<IpAddr>,
+}
+
+/// An instance found while browsing
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+    pub txt: Vec<String>,
+}
+
+impl Instance {
+    /// The value of a `key=value` entry of the TXT record
+    pub fn txt_value(&self, key: &str) -> Option<&str> {
+        self.txt.iter()
+            .filter_map(|entry| {
+                let mut parts = entry.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(entry_key), Some(value)) if entry_key.eq_ignore_ascii_case(key) => Some(value),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+}
+
+/// Instance and host names become DNS labels, which can't contain dots
+pub fn label(name: &str) -> String {
+    let label: String = name.chars().map(|c| if c == '.' { '-' } else { c }).collect();
+    label.chars().take(63).collect()
+}
+
+/// The ip addresses and port of the advertised urls. Urls with a host name instead of an address are skipped.
+fn url_addresses(urls: &[String]) -> (Vec<IpAddr>, Option<u16>) {
+    let mut addresses = Vec::new();
+    let mut port = None;
+    for url in urls {
+        let authority = url.split_once("://").map(|x| x.1).unwrap_or("").split('/').next().unwrap_or("");
+        if let Ok(address) = authority.parse::<SocketAddr>() {
+            if !addresses.contains(&address.ip()) {
+                addresses.push(address.ip());
+            }
+            port = port.or(Some(address.port()));
+        }
+    }
+    (addresses, port)
+}
+
+impl Service {
+    fn from_urls(instance: &str, host: &str, urls: &[String]) -> Option<Service> {
+        let (addresses, port) = url_addresses(urls);
+        Some(Service {
+            instance: instance.to_string(),
+            host: format!("{}.local", label(host)),
+            port: port?,
+            scheme: urls.first()?.split("://").next()?.to_string(),
+            addresses,
+        })
+    }
+
+    /// The fidelitas service and the generic web service, which depends on the scheme
+    fn service_types(&self) -> [&'static str; 2] {
+        if self.scheme == "https" {
+            [SERVICE_TYPE, HTTPS_SERVICE_TYPE]
+        } else {
+            [SERVICE_TYPE, HTTP_SERVICE_TYPE]
+        }
+    }
+
+    fn instance_name(&self, service_type: &str) -> String {
+        format!("{}.{}", self.instance, service_type)
+    }
+
+    fn txt(&self, service_type: &str) -> Vec<String> {
+        if service_type == SERVICE_TYPE {
+            vec![
+                format!("version={}", PROTOCOL_VERSION),
+                format!("path={}", PLAYER_PATH),
+                format!("name={}", self.instance),
+                format!("scheme={}", self.scheme),
+            ]
+        } else {
+            vec![format!("path={}", PLAYER_PATH)]
+        }
+    }
+
+    fn ptr(&self, service_type: &str, ttl: u32) -> Record {
+        Record {
+            name: service_type.to_string(),
+            ttl,
+            cache_flush: false,
+            data: RecordData::Ptr(self.instance_name(service_type)),
+        }
+    }
+
+    /// The SRV and TXT records of the instance of a service type
+    fn instance_records(&self, service_type: &str, ttl: u32) -> Vec<Record> {
+        vec![
+            Record {
+                name: self.instance_name(service_type),
+                ttl,
+                cache_flush: true,
+                data: RecordData::Srv{port: self.port, target: self.host.clone()},
+            },
+            Record {
+                name: self.instance_name(service_type),
+                ttl,
+                cache_flush: true,
+                data: RecordData::Txt(self.txt(service_type)),
+            },
+        ]
+    }
+
+    fn address_records(&self, ttl: u32) -> Vec<Record> {
+        self.addresses.iter().map(|address| Record {
+            name: self.host.clone(),
+            ttl,
+            cache_flush: true,
+            data: match address {
+                IpAddr::V4(ip) => RecordData::A(*ip),
+                IpAddr::V6(ip) => RecordData::Aaaa(*ip),
+            },
+        }).collect()
+    }
+
+    /// Everything about the service, sent unasked when it starts or its addresses change
+    fn announcement(&self) -> Message {
+        let mut answers = Vec::new();
+        for service_type in &self.service_types() {
+            answers.push(self.ptr(service_type, TTL));
+            answers.extend(self.instance_records(service_type, TTL));
+        }
+        answers.extend(self.address_records(TTL));
+        Message {
+            flags: dns::RESPONSE_FLAGS,
+            answers,
+            ..Message::default()
+        }
+    }
+
+    /// The answers and additional records for a question, both empty if it is not about this service
+    fn answer(&self, question: &Question, ttl: u32) -> (Vec<Record>, Vec<Record>) {
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        let asks_for = |qtype: u16| question.qtype == qtype || question.qtype == dns::TYPE_ANY;
+
+        if dns::same_name(&question.name, SERVICE_TYPES) && asks_for(dns::TYPE_PTR) {
+            for service_type in &self.service_types() {
+                answers.push(Record {
+                    name: SERVICE_TYPES.to_string(),
+                    ttl,
+                    cache_flush: false,
+                    data: RecordData::Ptr(service_type.to_string()),
+                });
+            }
+        }
+        for service_type in &self.service_types() {
+            if dns::same_name(&question.name, service_type) && asks_for(dns::TYPE_PTR) {
+                answers.push(self.ptr(service_type, ttl));
+                additionals.extend(self.instance_records(service_type, ttl));
+                additionals.extend(self.address_records(ttl));
+            }
+            if dns::same_name(&question.name, &self.instance_name(service_type)) {
+                let records: Vec<Record> = self.instance_records(service_type, ttl)
+                    .into_iter()
+                    .filter(|record| record.rtype().is_some_and(&asks_for))
+                    .collect();
+                if !records.is_empty() {
+                    answers.extend(records);
+                    additionals.extend(self.address_records(ttl));
+                }
+            }
+        }
+        if dns::same_name(&question.name, &self.host) {
+            answers.extend(self.address_records(ttl)
+                .into_iter()
+                .filter(|record| record.rtype().is_some_and(&asks_for)));
+        }
+        additionals.retain(|record| !answers.contains(record));
+        (answers, additionals)
+    }
+}
+
+fn multicast_address() -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT))
+}
+
+/// Binds the mDNS port, shared with other responders on the host such as avahi
+#[cfg(unix)]
+fn bind_shared() -> io::Result<UdpSocket> {
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // owned from here on, so it is closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enable: libc::c_int = 1;
+    for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *option,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let mut address: libc::sockaddr_in = unsafe { mem::zeroed() };
+    address.sin_family = libc::AF_INET as libc::sa_family_t;
+    address.sin_port = MDNS_PORT.to_be();
+    address.sin_addr = libc::in_addr { s_addr: u32::from(Ipv4Addr::UNSPECIFIED).to_be() };
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Binds the mDNS port, which fails if another responder already has it
+#[cfg(not(unix))]
+fn bind_shared() -> io::Result<UdpSocket> {
+    UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT))
+}
+
+/// Starts answering queries for the service and announces it, again whenever the advertised addresses change.
+/// The addresses and port are taken from the advertised urls.
+pub fn spawn(instance: String, host: String, urls: AdvertisedUrls) -> io::Result<()> {
+    let socket = bind_shared()?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+    thread::spawn(move || {
+        let mut service: Option<Service> = None;
+        let mut announcements_left = 0;
+        let mut buffer = [0u8; 9000];
+        loop {
+            let current = {
+                let urls = urls.read().expect("Advertised urls lock is poisoned. This is a bug.");
+                Service::from_urls(&instance, &host, &urls)
+            };
+            if current != service {
+                match &current {
+                    Some(current) => println!("Advertising '{}' via mDNS on port {} at {:?}", current.instance, current.port, current.addresses),
+                    None => println!("Not advertising via mDNS, none of the advertised urls contains an ip address"),
+                }
+                service = current;
+                announcements_left = ANNOUNCEMENTS;
+            }
+            if let (Some(service), true) = (&service, announcements_left > 0) {
+                announcements_left -= 1;
+                if let Err(e) = socket.send_to(&service.announcement().encode(), multicast_address()) {
+                    println!("Failed to send mDNS announcement: {}", e);
+                }
+            }
+
+            let (length, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                // timed out, which gives the loop a chance to check the addresses
+                Err(_) => continue,
+            };
+            let service = match &service {
+                Some(service) => service,
+                None => continue,
+            };
+            let query = match Message::decode(&buffer[..length]) {
+                Some(query) if !query.is_response() => query,
+                _ => continue,
+            };
+            if let Some((response, destination)) = respond(service, &query, source) {
+                if let Err(e) = socket.send_to(&response.encode(), destination) {
+                    println!("Failed to send mDNS response to {}: {}", destination, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The response to a query and where to send it, None if the query is not about this service
+fn respond(service: &Service, query: &Message, source: SocketAddr) -> Option<(Message, SocketAddr)> {
+    // queries from other ports come from simple resolvers, which expect a plain unicast DNS response
+    let legacy_unicast = source.port() != MDNS_PORT;
+    let ttl = if legacy_unicast { LEGACY_UNICAST_TTL } else { TTL };
+
+    let mut response = Message {
+        flags: dns::RESPONSE_FLAGS,
+        ..Message::default()
+    };
+    for question in &query.questions {
+        let (answers, additionals) = service.answer(question, ttl);
+        response.answers.extend(answers);
+        response.additionals.extend(additionals);
+    }
+    if response.answers.is_empty() {
+        return None;
+    }
+    // known answer suppression: the querier already has these
+    response.answers.retain(|answer| !query.answers.iter().any(|known| {
+        known.name == answer.name && known.data == answer.data && known.ttl > ttl / 2
+    }));
+    if response.answers.is_empty() {
+        return None;
+    }
+
+    if legacy_unicast {
+        response.id = query.id;
+        response.questions = query.questions.clone();
+        for record in response.answers.iter_mut().chain(response.additionals.iter_mut()) {
+            record.cache_flush = false;
+        }
+        Some((response, source))
+    } else if query.questions.iter().all(|question| question.unicast_response) {
+        Some((response, source))
+    } else {
+        Some((response, multicast_address()))
+    }
+}
+
+/// Browses the network for instances of the application, waiting `timeout` for responses
+pub fn discover(timeout: Duration) -> io::Result<Vec<Instance>> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_loop_v4(true)?;
+    let query = Message {
+        questions: vec![Question {
+            name: SERVICE_TYPE.to_string(),
+            qtype: dns::TYPE_PTR,
+            unicast_response: true,
+        }],
+        ..Message::default()
+    };
+    socket.send_to(&query.encode(), multicast_address())?;
+
+    let mut instance_names: Vec<String> = Vec::new();
+    let mut records: Vec<Record> = Vec::new();
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let length = match socket.recv_from(&mut buffer) {
+            Ok((length, _)) => length,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        };
+        let response = match Message::decode(&buffer[..length]) {
+            Some(response) if response.is_response() => response,
+            _ => continue,
+        };
+        for record in response.records() {
+            if let RecordData::Ptr(target) = &record.data {
+                if dns::same_name(&record.name, SERVICE_TYPE) && record.ttl > 0 && !instance_names.contains(target) {
+                    instance_names.push(target.clone());
+                }
+            }
+            records.push(record.clone());
+        }
+    }
+
+    let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for record in &records {
+        let address = match record.data {
+            RecordData::A(ip) => IpAddr::V4(ip),
+            RecordData::Aaaa(ip) => IpAddr::V6(ip),
+            _ => continue,
+        };
+        let host_addresses = addresses.entry(record.name.to_lowercase()).or_default();
+        if !host_addresses.contains(&address) {
+            host_addresses.push(address);
+        }
+    }
+    let instances = instance_names.iter().filter_map(|instance_name| {
+        let (port, host) = records.iter()
+            .filter(|record| dns::same_name(&record.name, instance_name))
+            .filter_map(|record| match &record.data {
+                RecordData::Srv{port, target} => Some((*port, target.clone())),
+                _ => None,
+            })
+            .next()?;
+        let txt = records.iter()
+            .filter(|record| dns::same_name(&record.name, instance_name))
+            .filter_map(|record| match &record.data {
+                RecordData::Txt(entries) => Some(entries.clone()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_else(Vec::new);
+        let name = instance_name.strip_suffix(&format!(".{}", SERVICE_TYPE)).unwrap_or(instance_name).to_string();
+        Some(Instance {
+            name,
+            addresses: addresses.get(&host.to_lowercase()).cloned().unwrap_or_else(Vec::new),
+            host,
+            port,
+            txt,
+        })
+    }).collect();
+    Ok(instances)
+}
@@ -1,13 +1,18 @@
 /// Player filesystem utilities and events
-/// 
+///
 
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use regex::{Regex};
 
+use crate::cue_sheet;
+use crate::loudness::ReplayGain;
+use crate::mp4_chapters;
+
 /// Describes the files that are recognized as media files
 pub struct ParseMediaConfig {
     extension_re : Regex,
+    chapter_re : Regex,
 }
 impl ParseMediaConfig {
     pub fn new(file_extensions: &HashSet<&str>) -> Self {
@@ -16,43 +21,254 @@ impl ParseMediaConfig {
         let extension_str = file_extensions.iter()
             .fold(String::with_capacity(len*4),|mut a, b| { a.push_str("|\\."); a.push_str(b); a});
         let re_audio_extension = Regex::new(&format!(".+({})", &extension_str[2..])).expect("Failed to parse audio extension regex. This is a bug.");
+        let re_chapter_extension = Regex::new(r"(?i).+\.(m4b|m4a|mp4)$").expect("Failed to parse chapter extension regex. This is a bug.");
         Self {
             extension_re: re_audio_extension,
-        }        
+            chapter_re: re_chapter_extension,
+        }
+    }
+}
+
+/// A playable unit registered with the player.
+/// Either a whole file, or a section of a file described by a cue sheet or embedded chapters (virtual media).
+#[derive(Clone, Debug)]
+pub struct MediaEntry {
+    pub path: String,
+    /// Title of the cue sheet track or chapter, None for whole files
+    pub title: Option<String>,
+    /// Offset into the file where playback starts, in milliseconds
+    pub start: i64,
+    /// Offset into the file where playback stops, in milliseconds. None if the entry plays until the end of the file.
+    pub end: Option<i64>,
+    /// Gain of the file, from its tags or measured. None until the tags are read in the background.
+    pub replay_gain: Option<ReplayGain>,
+}
+
+impl MediaEntry {
+    pub fn file(path: String) -> Self {
+        MediaEntry {
+            path,
+            title: None,
+            start: 0,
+            end: None,
+            replay_gain: None,
+        }
+    }
+
+    pub fn is_virtual(&self) -> bool {
+        self.title.is_some()
+    }
+
+    /// Identifies the entry across scans of the media directory, unlike the media id which is reassigned on every scan
+    pub fn key(&self) -> String {
+        if self.is_virtual() {
+            format!("{}#{}", self.path, self.start)
+        } else {
+            self.path.clone()
+        }
+    }
+
+    /// The name shown to clients
+    pub fn display_name(&self) -> String {
+        match &self.title {
+            Some(title) => format!("{} - {}", self.path, title),
+            None => self.path.clone(),
+        }
     }
 }
 
-/// Parses the files recognized as media files according to the ParseMediaConfig in the specified directory
-pub fn parse_media_dir(mut id: u64, path: &Path, config: &ParseMediaConfig) -> Result<(u64, HashMap<u64, String>), std::io::Error>{
-    let mut registered_media: HashMap<u64, String> = HashMap::new();    
+/// Maps the registered media to the names shown to clients
+pub fn media_names(registered_media: &HashMap<u64, MediaEntry>) -> HashMap<u64, String> {
+    registered_media
+        .iter()
+        .map(|(id, entry)| (*id, entry.display_name()))
+        .collect()
+}
+
+/// Parses the files recognized as media files according to the ParseMediaConfig in the specified directory.
+/// Files covered by a cue sheet or containing chapters are registered as one virtual media per track or chapter.
+pub fn parse_media_dir(mut id: u64, path: &Path, config: &ParseMediaConfig) -> Result<(u64, HashMap<u64, MediaEntry>), std::io::Error>{
+    let mut registered_media: HashMap<u64, MediaEntry> = HashMap::new();
+
+    let mut entries = Vec::new();
     for entry in std::fs::read_dir(path)? {
         match entry {
-            Ok(good_entry) => {
-                if good_entry.path().is_dir() {
-                    // TODO: handle result instead of escalating with ?
-                    let (new_id, subdir_media) = parse_media_dir(id, &good_entry.path(), config)?;
-                    id = new_id;
-                    registered_media.extend(subdir_media);
+            Ok(good_entry) => entries.push(good_entry.path()),
+            Err(e) => {
+                println!("Failed to read a file in the media directory: {}",e )
+            }
+        }
+    }
+
+    // cue sheets need to be handled first, so the files they describe are not registered a second time
+    let mut covered_by_cue: HashSet<PathBuf> = HashSet::new();
+    for cue_path in entries.iter().filter(|p| is_cue_sheet(p)) {
+        match parse_cue_sheet(&mut id, cue_path) {
+            Ok((covered, cue_media)) => {
+                covered_by_cue.extend(covered);
+                registered_media.extend(cue_media);
+            },
+            Err(e) => {
+                println!("Failed to read cue sheet {}: {}", cue_path.display(), e)
+            }
+        }
+    }
+
+    for entry_path in entries {
+        if entry_path.is_dir() {
+            // TODO: handle result instead of escalating with ?
+            let (new_id, subdir_media) = parse_media_dir(id, &entry_path, config)?;
+            id = new_id;
+            registered_media.extend(subdir_media);
+        } else if is_cue_sheet(&entry_path) || covered_by_cue.contains(&entry_path) {
+            // already registered
+        } else {
+            // TODO: handle properly instead of expect
+            let path_str = entry_path
+                .to_str()
+                .expect("Failed to convert music folder subpath to string. This is a bug.")
+                .to_string();
+            let file_name = entry_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .expect("Failed to convert filename in music folder to string. This is a bug.");
+
+            if config.extension_re.is_match(file_name) {
+                let chapters = if config.chapter_re.is_match(file_name) {
+                    match mp4_chapters::read_chapters(&entry_path) {
+                        Ok(chapters) => chapters,
+                        Err(e) => {
+                            println!("Failed to read chapters of {}: {}", path_str, e);
+                            Vec::new()
+                        }
+                    }
                 } else {
-                    // TODO: handle properly instead of expect
-                    let path_str = good_entry
-                        .path()
-                        .to_str()
-                        .expect("Failed to convert music folder subpath to string. This is a bug.")
-                        .to_string();
-
-                    if config.extension_re.is_match(good_entry.file_name().to_str().expect("Failed to convert filename in music folder to string. This is a bug.")) {
-                        registered_media.insert(id, path_str);
+                    Vec::new()
+                };
+
+                // a single chapter spanning the whole file is no different from the file itself
+                if chapters.len() > 1 {
+                    let starts: Vec<i64> = chapters.iter().map(|c| c.start).collect();
+                    for (i, chapter) in chapters.into_iter().enumerate() {
+                        registered_media.insert(id, MediaEntry {
+                            path: path_str.clone(),
+                            title: Some(chapter.title),
+                            start: chapter.start,
+                            end: starts.get(i + 1).cloned(),
+                            replay_gain: None,
+                        });
                         id += 1;
-                    } else {
-                        println!("Ignoring file with unsupported file type in media directory: {}.", path_str)
                     }
+                } else {
+                    registered_media.insert(id, MediaEntry::file(path_str));
+                    id += 1;
                 }
-            },
-            Err(e) => {
-                println!("Failed to read a file in the media directory: {}",e )
+            } else {
+                println!("Ignoring file with unsupported file type in media directory: {}.", path_str)
             }
         }
     }
     Ok((id, registered_media))
-}
\ No newline at end of file
+}
+
+fn is_cue_sheet(path: &Path) -> bool {
+    path.is_file()
+        && path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.eq_ignore_ascii_case("cue"))
+            .unwrap_or(false)
+}
+
+/// Registers the tracks described by a cue sheet.
+/// Returns the paths of the files covered by the cue sheet along with the registered tracks.
+fn parse_cue_sheet(id: &mut u64, cue_path: &Path) -> Result<(Vec<PathBuf>, HashMap<u64, MediaEntry>), std::io::Error> {
+    // cue sheets are frequently not utf-8 encoded
+    let contents = String::from_utf8_lossy(&std::fs::read(cue_path)?).into_owned();
+    let sheet = cue_sheet::parse(&contents);
+    let directory = cue_path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut covered = Vec::new();
+    let mut registered_media = HashMap::new();
+    for file in sheet.files {
+        let file_path = directory.join(&file.name);
+        if !file_path.is_file() {
+            println!("Cue sheet {} references missing file: {}", cue_path.display(), file.name);
+            continue;
+        }
+        let path_str = match file_path.to_str() {
+            Some(path_str) => path_str.to_string(),
+            None => continue,
+        };
+
+        let starts: Vec<i64> = file.tracks.iter().map(|t| t.start).collect();
+        for (i, track) in file.tracks.into_iter().enumerate() {
+            let title = match (track.performer.as_ref().or(sheet.performer.as_ref()), track.title) {
+                (Some(performer), Some(title)) => format!("{:02}. {} - {}", track.number, performer, title),
+                (None, Some(title)) => format!("{:02}. {}", track.number, title),
+                (_, None) => format!("Track {:02}", track.number),
+            };
+            registered_media.insert(*id, MediaEntry {
+                path: path_str.clone(),
+                title: Some(title),
+                start: track.start,
+                end: starts.get(i + 1).cloned(),
+                replay_gain: None,
+            });
+            *id += 1;
+        }
+        // files without tracks are left to be registered as a whole
+        if !starts.is_empty() {
+            covered.push(file_path);
+        }
+    }
+    Ok((covered, registered_media))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cue_tracks_end_where_the_next_one_starts() {
+        let directory = std::env::temp_dir().join(format!("fidelitas-test-cue-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("Failed to create test directory");
+        std::fs::write(directory.join("album.flac"), b"").expect("Failed to write test file");
+        let cue_path = directory.join("album.cue");
+        std::fs::write(&cue_path, "FILE \"album.flac\" WAVE\n\
+            TRACK 01 AUDIO\nTITLE \"One\"\nINDEX 01 00:00:00\n\
+            TRACK 02 AUDIO\nTITLE \"Two\"\nINDEX 01 02:30:00\n\
+            FILE \"missing.flac\" WAVE\nTRACK 03 AUDIO\nINDEX 01 00:00:00\n").expect("Failed to write test file");
+
+        let mut id = 10;
+        let result = parse_cue_sheet(&mut id, &cue_path);
+        std::fs::remove_dir_all(&directory).ok();
+        let (covered, media) = result.expect("Failed to parse cue sheet");
+
+        assert_eq!(covered, vec![directory.join("album.flac")]);
+        assert_eq!(id, 12);
+        assert_eq!(media[&10].title.as_deref(), Some("01. One"));
+        assert_eq!((media[&10].start, media[&10].end), (0, Some(150_000)));
+        // the last track plays until the end of the file
+        assert_eq!((media[&11].start, media[&11].end), (150_000, None));
+        assert_eq!(media[&11].key(), format!("{}#150000", media[&11].path));
+    }
+
+    #[test]
+    fn files_without_cue_tracks_are_not_covered() {
+        let directory = std::env::temp_dir().join(format!("fidelitas-test-cue-empty-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).expect("Failed to create test directory");
+        std::fs::write(directory.join("album.flac"), b"").expect("Failed to write test file");
+        std::fs::write(directory.join("bonus.flac"), b"").expect("Failed to write test file");
+        let cue_path = directory.join("album.cue");
+        std::fs::write(&cue_path, "FILE \"bonus.flac\" WAVE\n\
+            FILE \"album.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n").expect("Failed to write test file");
+
+        let mut id = 0;
+        let result = parse_cue_sheet(&mut id, &cue_path);
+        std::fs::remove_dir_all(&directory).ok();
+        let (covered, media) = result.expect("Failed to parse cue sheet");
+
+        assert_eq!(covered, vec![directory.join("album.flac")]);
+        assert_eq!(media.len(), 1);
+    }
+}
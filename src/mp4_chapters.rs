@@ -0,0 +1,242 @@
+//! Reads embedded chapter markers from MP4 containers (.m4b audiobooks, .m4a, .mp4).
+//! Only Nero style chapter lists (the `moov/udta/chpl` box) are supported,
+//! which is what most audiobook tools write in addition to QuickTime chapter tracks.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// chpl timestamps are given in units of 100 nanoseconds
+const CHPL_UNITS_PER_MILLISECOND: u64 = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct Chapter {
+    pub title: String,
+    /// Start of the chapter in milliseconds
+    pub start: i64,
+}
+
+/// Returns the chapters embedded in the mp4 file at `path`.
+/// Returns an empty Vec if the file contains no chapter list, or an Error if the file could not be read.
+pub fn read_chapters(path: &Path) -> Result<Vec<Chapter>, std::io::Error> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let moov = match find_box(&mut file, 0, file_len, b"moov")? {
+        Some(b) => b,
+        None => return Ok(Vec::new()),
+    };
+    let udta = match find_box(&mut file, moov.0, moov.1, b"udta")? {
+        Some(b) => b,
+        None => return Ok(Vec::new()),
+    };
+    let chpl = match find_box(&mut file, udta.0, udta.1, b"chpl")? {
+        Some(b) => b,
+        None => return Ok(Vec::new()),
+    };
+
+    file.seek(SeekFrom::Start(chpl.0))?;
+    let mut content = vec![0; (chpl.1 - chpl.0) as usize];
+    file.read_exact(&mut content)?;
+    Ok(parse_chpl(&content))
+}
+
+/// Searches the boxes between `start` and `end` for a box of the given type.
+/// Returns the start and end offset of the box's content.
+fn find_box(file: &mut File, start: u64, end: u64, box_type: &[u8; 4]) -> Result<Option<(u64, u64)>, std::io::Error> {
+    let mut offset = start;
+    while offset + 8 <= end {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+
+        let mut header_len = 8;
+        let box_len = match u32_be(&header[0..4]) {
+            // box extends to the end of the enclosing box
+            0 => end - offset,
+            // 64 bit box size follows the type
+            1 => {
+                let mut large_size = [0u8; 8];
+                file.read_exact(&mut large_size)?;
+                header_len = 16;
+                u64_be(&large_size)
+            },
+            size => u64::from(size),
+        };
+        let box_end = match offset.checked_add(box_len) {
+            Some(box_end) if box_len >= header_len && box_end <= end => box_end,
+            // malformed box, don't trust anything that follows
+            _ => return Ok(None),
+        };
+        if &header[4..8] == box_type {
+            return Ok(Some((offset + header_len, box_end)));
+        }
+        offset = box_end;
+    }
+    Ok(None)
+}
+
+/// Parses the content of a chpl box, skipping chapters that are truncated
+fn parse_chpl(content: &[u8]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    if content.len() < 5 {
+        return chapters;
+    }
+    let version = content[0];
+    // version and flags, followed by 4 reserved bytes in version 1
+    let mut pos = if version == 1 { 8 } else { 4 };
+    let count = match content.get(pos) {
+        Some(count) => *count,
+        None => return chapters,
+    };
+    pos += 1;
+
+    for _ in 0..count {
+        if pos + 9 > content.len() {
+            break;
+        }
+        let start = u64_be(&content[pos..pos + 8]) / CHPL_UNITS_PER_MILLISECOND;
+        let title_len = content[pos + 8] as usize;
+        pos += 9;
+        if pos + title_len > content.len() {
+            break;
+        }
+        let title = String::from_utf8_lossy(&content[pos..pos + title_len]).into_owned();
+        pos += title_len;
+
+        chapters.push(Chapter {
+            title,
+            start: start as i64,
+        });
+    }
+    chapters
+}
+
+fn u32_be(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b))
+}
+
+fn u64_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A box with a 32 bit size
+    fn mp4_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut b = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(box_type);
+        b.extend_from_slice(content);
+        b
+    }
+
+    fn chpl(version: u8, chapters: &[(u64, &str)]) -> Vec<u8> {
+        let mut content = vec![version, 0, 0, 0];
+        if version == 1 {
+            content.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        content.push(chapters.len() as u8);
+        for (start, title) in chapters {
+            content.extend_from_slice(&(start * CHPL_UNITS_PER_MILLISECOND).to_be_bytes());
+            content.push(title.len() as u8);
+            content.extend_from_slice(title.as_bytes());
+        }
+        content
+    }
+
+    fn write_temp(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("fidelitas-test-{}-{}.m4b", std::process::id(), name));
+        std::fs::write(&path, bytes).expect("Failed to write test file");
+        path
+    }
+
+    #[test]
+    fn parses_version_0_and_1() {
+        for version in &[0, 1] {
+            let chapters = parse_chpl(&chpl(*version, &[(0, "Intro"), (61_500, "Chapter 1")]));
+            assert_eq!(chapters.len(), 2);
+            assert_eq!(chapters[0].title, "Intro");
+            assert_eq!(chapters[0].start, 0);
+            assert_eq!(chapters[1].title, "Chapter 1");
+            assert_eq!(chapters[1].start, 61_500);
+        }
+    }
+
+    #[test]
+    fn skips_truncated_chapters() {
+        let content = chpl(1, &[(0, "Intro"), (1000, "Truncated")]);
+        let chapters = parse_chpl(&content[..content.len() - 3]);
+        assert_eq!(chapters.len(), 1);
+        assert_eq!(chapters[0].title, "Intro");
+        // the count claims more chapters than there are
+        let mut content = chpl(0, &[(0, "Only")]);
+        content[4] = 5;
+        assert_eq!(parse_chpl(&content).len(), 1);
+        assert!(parse_chpl(&[1, 0, 0, 0]).is_empty());
+        assert!(parse_chpl(&[1, 0, 0, 0, 0, 0, 0]).is_empty());
+    }
+
+    #[test]
+    fn reads_chapters_from_nested_boxes() {
+        let udta = mp4_box(b"udta", &mp4_box(b"chpl", &chpl(1, &[(0, "A"), (5000, "B")])));
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvhd", &[0; 12]), udta].concat());
+        let file = [mp4_box(b"ftyp", b"M4B "), moov].concat();
+        let path = write_temp("nested", &file);
+        let chapters = read_chapters(&path).expect("Failed to read chapters");
+        std::fs::remove_file(&path).ok();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].title, "B");
+        assert_eq!(chapters[1].start, 5000);
+    }
+
+    #[test]
+    fn supports_64_bit_and_open_ended_box_sizes() {
+        let udta = mp4_box(b"udta", &mp4_box(b"chpl", &chpl(0, &[(0, "A")])));
+        let mut moov = 1u32.to_be_bytes().to_vec();
+        moov.extend_from_slice(b"moov");
+        moov.extend_from_slice(&((udta.len() + 16) as u64).to_be_bytes());
+        moov.extend_from_slice(&udta);
+        let path = write_temp("large", &moov);
+        assert_eq!(read_chapters(&path).expect("Failed to read chapters").len(), 1);
+
+        // size 0 extends to the end of the file
+        let mut moov = 0u32.to_be_bytes().to_vec();
+        moov.extend_from_slice(b"moov");
+        moov.extend_from_slice(&udta);
+        std::fs::write(&path, &moov).expect("Failed to write test file");
+        assert_eq!(read_chapters(&path).expect("Failed to read chapters").len(), 1);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn rejects_boxes_exceeding_their_parent() {
+        let mut moov = mp4_box(b"moov", &mp4_box(b"udta", &mp4_box(b"chpl", &chpl(0, &[(0, "A")]))));
+        // the udta box claims to be larger than moov
+        moov[11] += 1;
+        let path = write_temp("oversized", &moov);
+        let chapters = read_chapters(&path).expect("Failed to read chapters");
+        // a box smaller than its header
+        std::fs::write(&path, [0, 0, 0, 4, b'm', b'o', b'o', b'v']).expect("Failed to write test file");
+        let tiny = read_chapters(&path).expect("Failed to read chapters");
+        // a 64 bit size that overflows the offset of the box
+        let mut huge = mp4_box(b"ftyp", b"M4A ");
+        huge.extend_from_slice(&[0, 0, 0, 1, b'f', b'r', b'e', b'e']);
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        std::fs::write(&path, &huge).expect("Failed to write test file");
+        let overflowing = read_chapters(&path).expect("Failed to read chapters");
+        std::fs::remove_file(&path).ok();
+        assert!(chapters.is_empty());
+        assert!(tiny.is_empty());
+        assert!(overflowing.is_empty());
+    }
+
+    #[test]
+    fn files_without_chapters_have_none() {
+        let path = write_temp("plain", &mp4_box(b"ftyp", b"M4A "));
+        let chapters = read_chapters(&path).expect("Failed to read chapters");
+        std::fs::remove_file(&path).ok();
+        assert!(chapters.is_empty());
+    }
+}
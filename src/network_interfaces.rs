@@ -1,12 +1,19 @@
 use std::net::IpAddr;
-use std::collections::{VecDeque};
 
 #[derive(Debug, Clone)]
 pub struct NetworkInterface {
     pub name: String,
     pub ip_addresses: Vec<IpAddr>,
+    /// Whether the default route goes through this interface
+    pub default_route: bool,
 }
 
+/// Name prefixes of container, bridge and VPN interfaces, which are not reachable from other devices in the network
+const VIRTUAL_PREFIXES: [&str; 14] = [
+    "docker", "veth", "br-", "virbr", "vboxnet", "vmnet", "cni", "flannel",
+    "tun", "tap", "wg", "tailscale", "zt", "utun",
+];
+
 #[cfg(unix)]
 pub fn interfaces() -> Option<Vec<NetworkInterface>> {
     let unix_adapters = ifaces::Interface::get_all();
@@ -15,27 +22,60 @@ pub fn interfaces() -> Option<Vec<NetworkInterface>> {
         Err(_)=> None,
 
         Ok(adapters) => {
-            Some (
-                adapters
-                .iter()
-                .filter_map(|adapter| {
-                    match adapter.addr {
-                        None => None,
-                        Some(socket_addr) => {
-                            let mut addresses = Vec::with_capacity(1);
-                            addresses.push(socket_addr.ip());
-
-                            Some(NetworkInterface {
-                                name : adapter.name.clone(),
-                                ip_addresses : addresses
-                            })
+            let default_routes = default_route_interfaces();
+            // ifaces returns one entry per address, so the addresses of an interface are collected from all entries with its name
+            let mut interfaces: Vec<NetworkInterface> = Vec::new();
+            for adapter in &adapters {
+                let ip = match adapter.addr {
+                    None => continue,
+                    Some(socket_addr) => socket_addr.ip(),
+                };
+                match interfaces.iter_mut().find(|interface| interface.name == adapter.name) {
+                    Some(interface) => {
+                        if !interface.ip_addresses.contains(&ip) {
+                            interface.ip_addresses.push(ip);
                         }
-                    }
-                })
-                .collect()
-            )
+                    },
+                    None => interfaces.push(NetworkInterface {
+                        name : adapter.name.clone(),
+                        ip_addresses : vec![ip],
+                        default_route : default_routes.contains(&adapter.name),
+                    }),
+                }
+            }
+            Some(interfaces)
+        }
+    }
+}
+
+/// The interfaces the IpV4 and IpV6 default routes go through
+#[cfg(target_os = "linux")]
+fn default_route_interfaces() -> Vec<String> {
+    let mut names = Vec::new();
+    // columns: Iface Destination Gateway ...
+    if let Ok(routes) = std::fs::read_to_string("/proc/net/route") {
+        for line in routes.lines().skip(1) {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() > 1 && columns[1] == "00000000" {
+                names.push(columns[0].to_string());
+            }
+        }
+    }
+    // columns: destination prefix_length source source_prefix_length next_hop metric refcount use flags device
+    if let Ok(routes) = std::fs::read_to_string("/proc/net/ipv6_route") {
+        for line in routes.lines() {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() == 10 && columns[1] == "00" && columns[9] != "lo" && columns[0].chars().all(|c| c == '0') {
+                names.push(columns[9].to_string());
+            }
         }
     }
+    names
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn default_route_interfaces() -> Vec<String> {
+    Vec::new()
 }
 
 #[cfg(windows)]
@@ -51,8 +91,9 @@ pub fn interfaces() -> Option<Vec<NetworkInterface>> {
                 .iter()
                 .map(|adapter|
                     NetworkInterface {
-                        name : adapter.adapter_name().to_owned(),
+                        name : adapter.friendly_name().to_owned(),
                         ip_addresses : adapter.ip_addresses().to_vec(),
+                        default_route : !adapter.gateways().is_empty(),
                     }
                 )
                 .collect()
@@ -61,32 +102,186 @@ pub fn interfaces() -> Option<Vec<NetworkInterface>> {
     }
 }
 
+impl NetworkInterface {
+    /// Container, bridge and VPN interfaces are excluded from automatic selection
+    pub fn is_virtual(&self) -> bool {
+        let name = self.name.to_lowercase();
+        VIRTUAL_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+            || name.contains("vpn")
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.ip_addresses.iter().all(|ip| ip.is_loopback())
+    }
+
+    /// Whether other devices in the network can reach the application on this interface
+    pub fn is_reachable(&self) -> bool {
+        !self.is_virtual() && !self.is_loopback() && !self.reachable_addresses().is_empty()
+    }
+
+    /// The addresses usable in a url, IpV4 first. Link local IpV6 addresses would need a zone id and are left out.
+    pub fn reachable_addresses(&self) -> Vec<IpAddr> {
+        let mut addresses: Vec<IpAddr> = self.ip_addresses
+            .iter()
+            .filter(|ip| !ip.is_loopback() && !is_link_local_v6(ip))
+            .cloned()
+            .collect();
+        addresses.sort_by_key(|ip| ip.is_ipv6());
+        addresses
+    }
+}
+
+fn is_link_local_v6(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
 /// Selects one of the available network interfaces to determine the IP the application will use.
-/// Currently only has a basic strategy for unix, will return None for windows and require a passed override_interface parameter.
+/// Without an override, the interface carrying the default route is preferred over other wired and wireless interfaces,
+/// container, bridge and VPN interfaces are never picked.
+/// An override selects the interface by name or by one of its addresses, which is then put first.
 pub fn select_network_interface (select_from: &Vec<NetworkInterface>, override_interface: Option<&str>) -> Option<NetworkInterface> {
     match override_interface {
         None => {
-            select_from
-            .iter()
-            .filter(|a| a.name.starts_with("en") || a.name.starts_with("wl") )
-            .next()
-            .map(|a| a.clone())
+            let candidates: Vec<&NetworkInterface> = select_from
+                .iter()
+                .filter(|a| a.is_reachable())
+                .collect();
+            candidates.iter().find(|a| a.default_route)
+                .or_else(|| candidates.iter().find(|a| a.name.starts_with("en") || a.name.starts_with("wl") || a.name.starts_with("eth")))
+                .or_else(|| candidates.first())
+                .map(|a| (*a).clone())
         }
         Some(interface) => {
+            if let Ok(ip) = interface.parse::<IpAddr>() {
+                return select_from
+                    .iter()
+                    .find(|a| a.ip_addresses.contains(&ip))
+                    .map(|a| {
+                        let mut selected = a.clone();
+                        selected.ip_addresses.retain(|other| *other != ip);
+                        selected.ip_addresses.insert(0, ip);
+                        selected
+                    });
+            }
             select_from
-            .iter()
-            .filter(|a| a.name.starts_with(interface))
-            .next()
-            .map(|a| a.clone())
+                .iter()
+                .find(|a| a.name == interface)
+                .or_else(|| select_from.iter().find(|a| a.name.starts_with(interface))).cloned()
         }
     }
 }
 
-/// Sorts the available ip adresses so IpV4 adresses will be in the front and IpV6 in the back
-pub fn v4_first (mut deque: VecDeque<IpAddr>, ip : &IpAddr) -> VecDeque<IpAddr> {
+/// The addresses users can access the application with: those of the selected network interface first, IpV4 before IpV6,
+/// then those of the other reachable interfaces
+pub fn host_addresses(override_interface: Option<&str>) -> Result<Vec<IpAddr>, String> {
+    let interface_candidates = interfaces().ok_or_else(|| String::from("Unable to detect network interfaces on your system."))?;
+
+    let selected_interface = select_network_interface(&interface_candidates, override_interface)
+        .ok_or_else(|| String::from("Unable to autoselect a network interface. Please manually pass the --interface flag with an interface name or address. Pass --list-interfaces to list the available interfaces."))?;
+
+    let mut addresses = selected_interface.reachable_addresses();
+    // an address passed with --interface is the one to advertise, even if it is an IpV6 address
+    if let Some(ip) = override_interface.and_then(|interface| interface.parse::<IpAddr>().ok()) {
+        addresses.retain(|other| *other != ip);
+        addresses.insert(0, ip);
+    }
+    if addresses.is_empty() {
+        return Err(format!("Unable to get a reachable adress from selected network interface {}", selected_interface.name));
+    }
+    for interface in interface_candidates.iter().filter(|a| a.is_reachable() && a.name != selected_interface.name) {
+        addresses.extend(interface.reachable_addresses());
+    }
+    Ok(addresses)
+}
+
+/// Formats the ip for use as the host of a url, wrapping IpV6 adresses in brackets
+pub fn url_host (ip: &IpAddr) -> String {
     match ip {
-        IpAddr::V4(_) => deque.push_front(*ip),
-        IpAddr::V6(_) => deque.push_back(*ip),
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+/// The name of this machine, or "fidelitas" if it can't be determined
+pub fn host_name() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| String::from("fidelitas"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, ip_addresses: &[&str], default_route: bool) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            ip_addresses: ip_addresses.iter().map(|ip| ip.parse().expect("Invalid test address")).collect(),
+            default_route,
+        }
+    }
+
+    fn candidates() -> Vec<NetworkInterface> {
+        vec![
+            interface("lo", &["127.0.0.1", "::1"], false),
+            interface("docker0", &["172.17.0.1"], true),
+            interface("wg0", &["10.8.0.2"], false),
+            interface("wlp2s0", &["fe80::1", "2001:db8::2", "192.168.1.20"], false),
+            interface("enp3s0", &["192.168.1.30"], true),
+        ]
+    }
+
+    fn selected(override_interface: Option<&str>) -> Option<String> {
+        select_network_interface(&candidates(), override_interface).map(|interface| interface.name)
+    }
+
+    #[test]
+    fn prefers_the_default_route() {
+        assert_eq!(selected(None), Some(String::from("enp3s0")));
+    }
+
+    #[test]
+    fn skips_virtual_and_loopback_interfaces() {
+        let mut candidates = candidates();
+        candidates.retain(|interface| interface.name != "enp3s0");
+        // docker0 carries a default route, but isn't reachable from other devices
+        assert_eq!(select_network_interface(&candidates, None).map(|interface| interface.name), Some(String::from("wlp2s0")));
+
+        candidates.retain(|interface| interface.name != "wlp2s0");
+        assert!(select_network_interface(&candidates, None).is_none());
+    }
+
+    #[test]
+    fn falls_back_to_the_first_reachable_interface() {
+        let candidates = vec![interface("lo", &["127.0.0.1"], false), interface("bond0", &["192.168.1.40"], false)];
+        assert_eq!(select_network_interface(&candidates, None).map(|interface| interface.name), Some(String::from("bond0")));
+    }
+
+    #[test]
+    fn overrides_by_name_prefix_or_address() {
+        assert_eq!(selected(Some("wlp2s0")), Some(String::from("wlp2s0")));
+        assert_eq!(selected(Some("wl")), Some(String::from("wlp2s0")));
+        // overrides may pick interfaces automatic selection skips
+        assert_eq!(selected(Some("docker0")), Some(String::from("docker0")));
+        assert_eq!(selected(Some("eth1")), None);
+
+        let selected = select_network_interface(&candidates(), Some("2001:db8::2")).expect("Failed to select interface by address");
+        assert_eq!(selected.name, "wlp2s0");
+        assert_eq!(selected.ip_addresses[0], "2001:db8::2".parse::<IpAddr>().expect("Invalid test address"));
+        assert!(select_network_interface(&candidates(), Some("10.0.0.1")).is_none());
+    }
+
+    #[test]
+    fn reachable_addresses_put_ipv4_first() {
+        let addresses = candidates()[3].reachable_addresses();
+        let expected: Vec<IpAddr> = vec!["192.168.1.20".parse().expect("Invalid test address"), "2001:db8::2".parse().expect("Invalid test address")];
+        assert_eq!(addresses, expected);
+        assert!(candidates()[0].reachable_addresses().is_empty());
     }
-    deque
 }
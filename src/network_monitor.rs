@@ -0,0 +1,154 @@
+//! Watches for address and link changes, e.g. a new DHCP lease or a reconnected Wi-Fi,
+//! and selects the network interface again so the advertised urls don't go stale.
+
+use std::thread;
+use std::time::Duration;
+
+use crate::advertise::{AdvertiseConfig, AdvertisedUrls};
+
+/// How often the interfaces are checked where change notifications are not available
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Changes arrive in bursts, e.g. IpV4 and IpV6 addresses after reconnecting, so they are handled once settled
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+pub fn spawn(config: AdvertiseConfig, urls: AdvertisedUrls) {
+    thread::spawn(move || {
+        let mut changes = Changes::open();
+        loop {
+            changes.wait();
+            thread::sleep(SETTLE_DELAY);
+            changes.drain();
+            update_urls(&config, &urls);
+        }
+    });
+}
+
+fn update_urls(config: &AdvertiseConfig, urls: &AdvertisedUrls) {
+    let new_urls = match config.urls() {
+        Ok(new_urls) => new_urls,
+        Err(e) => {
+            // keep advertising the last known urls, the network may come back with the same address
+            println!("Network changed, but no address to advertise was found: {}", e);
+            return;
+        }
+    };
+    let mut urls = urls.write().expect("Advertised urls lock is poisoned. This is a bug.");
+    if *urls != new_urls {
+        println!("Network changed, advertised urls are now:");
+        new_urls.iter().for_each(|url| println!("    {}/player", url));
+        *urls = new_urls;
+    }
+}
+
+/// Notifications about changed links, addresses and routes
+enum Changes {
+    #[cfg(target_os = "linux")]
+    Netlink(netlink::Socket),
+    Poll,
+}
+
+impl Changes {
+    fn open() -> Self {
+        #[cfg(target_os = "linux")]
+        {
+            match netlink::Socket::open() {
+                Ok(socket) => return Changes::Netlink(socket),
+                Err(e) => println!("Failed to watch for network changes, checking every {} seconds instead: {}", POLL_INTERVAL.as_secs(), e),
+            }
+        }
+        Changes::Poll
+    }
+
+    /// Blocks until the network may have changed
+    fn wait(&mut self) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Changes::Netlink(socket) => {
+                if let Err(e) = socket.receive() {
+                    println!("Failed to receive network changes, checking every {} seconds instead: {}", POLL_INTERVAL.as_secs(), e);
+                    *self = Changes::Poll;
+                }
+            },
+            Changes::Poll => thread::sleep(POLL_INTERVAL),
+        }
+    }
+
+    /// Discards the notifications that arrived while settling
+    fn drain(&mut self) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Changes::Netlink(socket) => socket.drain(),
+            Changes::Poll => (),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::io;
+    use std::mem;
+
+    /// A rtnetlink socket subscribed to link, address and route changes
+    pub struct Socket {
+        fd: libc::c_int,
+    }
+
+    impl Socket {
+        pub fn open() -> io::Result<Self> {
+            let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = Socket { fd };
+            let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            address.nl_groups = (libc::RTMGRP_LINK
+                | libc::RTMGRP_IPV4_IFADDR
+                | libc::RTMGRP_IPV6_IFADDR
+                | libc::RTMGRP_IPV4_ROUTE
+                | libc::RTMGRP_IPV6_ROUTE) as u32;
+            let result = unsafe {
+                libc::bind(
+                    socket.fd,
+                    &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(socket)
+        }
+
+        /// Blocks until a notification arrives. Its content doesn't matter, the interfaces are read again anyway.
+        pub fn receive(&self) -> io::Result<()> {
+            self.recv(0).map(|_| ())
+        }
+
+        pub fn drain(&self) {
+            while let Ok(true) = self.recv(libc::MSG_DONTWAIT) {}
+        }
+
+        /// Whether a message was received
+        fn recv(&self, flags: libc::c_int) -> io::Result<bool> {
+            let mut buffer = [0u8; 8192];
+            let received = unsafe { libc::recv(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), flags) };
+            if received < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock => Ok(false),
+                    // the kernel dropped notifications because they came in faster than they were read, which only means something changed
+                    _ if e.raw_os_error() == Some(libc::ENOBUFS) => Ok(true),
+                    _ => Err(e),
+                };
+            }
+            Ok(true)
+        }
+    }
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
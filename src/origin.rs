@@ -0,0 +1,130 @@
+//! Checking the Origin of websocket upgrades, so pages of other sites opened by someone in the LAN can't control the player.
+
+use std::net::IpAddr;
+
+/// Which origins may open the websocket
+#[derive(Debug, Clone)]
+pub struct OriginPolicy {
+    /// Origins passed with --allowed-origin, normalized
+    allowed: Vec<String>,
+    /// Names of this machine, under which the page is served on its own origin
+    host_names: Vec<String>,
+}
+
+impl OriginPolicy {
+    pub fn new(allowed: &[String], host_name: &str) -> Self {
+        let host_name = host_name.to_lowercase();
+        OriginPolicy {
+            allowed: allowed.iter().filter_map(|origin| normalize(origin)).collect(),
+            host_names: vec![String::from("localhost"), format!("{}.local", host_name), host_name],
+        }
+    }
+
+    /// Whether a page of `origin` may open the websocket of the server reached as `own_origin`.
+    /// Browsers always send an Origin with websocket upgrades, so requests without one come from other clients and are allowed.
+    pub fn allows(&self, origin: Option<&str>, own_origin: &str, advertised_urls: &[String]) -> bool {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return true,
+        };
+        // sandboxed pages and local files send "null", which normalizes to nothing
+        let origin = match normalize(origin) {
+            Some(origin) => origin,
+            None => return false,
+        };
+        if self.allowed.contains(&origin) || advertised_urls.iter().filter_map(|url| normalize(url)).any(|url| url == origin) {
+            return true;
+        }
+        // The page is served from the same origin, but the Host header alone can't be trusted:
+        // a domain rebound to an address in the LAN would match its own Host. Ip addresses and the names of this machine can't be rebound.
+        if Some(&origin) != normalize(own_origin).as_ref() {
+            return false;
+        }
+        let host = host(&origin);
+        host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok()
+            || self.host_names.iter().any(|name| name == host)
+    }
+}
+
+/// The origin `scheme://host[:port]` of a url in lower case and without the default port, None if it isn't an http(s) url
+pub fn normalize(url: &str) -> Option<String> {
+    let url = url.trim().to_lowercase();
+    let (scheme, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        ("http", rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        ("https", rest)
+    } else {
+        return None;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    if authority.is_empty() || !authority.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c)) {
+        return None;
+    }
+    let default_port = if scheme == "https" { ":443" } else { ":80" };
+    let authority = if authority.ends_with(default_port) && !authority.ends_with(']') {
+        &authority[..authority.len() - default_port.len()]
+    } else {
+        authority
+    };
+    Some(format!("{}://{}", scheme, authority))
+}
+
+/// The host of a normalized origin, IPv6 addresses keep their brackets
+fn host(origin: &str) -> &str {
+    let authority = origin.split_once("://").map(|x| x.1).unwrap_or("");
+    match authority.rfind(':') {
+        Some(index) if !authority[index..].contains(']') => &authority[..index],
+        _ => authority,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_origins() {
+        assert_eq!(normalize("HTTP://Player.Local:80/player?x=1"), Some(String::from("http://player.local")));
+        assert_eq!(normalize("https://player.local:443"), Some(String::from("https://player.local")));
+        assert_eq!(normalize(" https://player.local:8443/ "), Some(String::from("https://player.local:8443")));
+        // the default port of the other scheme is kept
+        assert_eq!(normalize("https://player.local:80"), Some(String::from("https://player.local:80")));
+        assert_eq!(normalize("http://192.168.1.20:8088#top"), Some(String::from("http://192.168.1.20:8088")));
+    }
+
+    #[test]
+    fn normalizes_ipv6_origins() {
+        assert_eq!(normalize("http://[::1]:80/"), Some(String::from("http://[::1]")));
+        assert_eq!(normalize("http://[2001:db8::80]"), Some(String::from("http://[2001:db8::80]")));
+        assert_eq!(normalize("https://[2001:db8::2]:8443"), Some(String::from("https://[2001:db8::2]:8443")));
+    }
+
+    #[test]
+    fn rejects_other_origins() {
+        for origin in &["null", "", "file:///home/user/page.html", "ws://player.local", "http://", "http://user@player.local", "http://a b"] {
+            assert_eq!(normalize(origin), None, "Normalized {}", origin);
+        }
+    }
+
+    #[test]
+    fn extracts_hosts() {
+        assert_eq!(host("http://player.local:8088"), "player.local");
+        assert_eq!(host("http://[::1]:8088"), "[::1]");
+        assert_eq!(host("http://[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn allows_own_and_configured_origins() {
+        let policy = OriginPolicy::new(&[String::from("https://remote.example.com/")], "Livingroom");
+        let advertised = [String::from("http://192.168.1.20:8088")];
+        assert!(policy.allows(None, "http://rebound.example.com", &advertised));
+        assert!(policy.allows(Some("https://remote.example.com"), "http://192.168.1.20:8088", &advertised));
+        assert!(policy.allows(Some("http://192.168.1.20:8088"), "http://player.example.com", &advertised));
+        assert!(policy.allows(Some("http://livingroom.local:8088"), "http://livingroom.local:8088", &advertised));
+        assert!(policy.allows(Some("http://[::1]:8088"), "http://[::1]:8088", &advertised));
+        // a rebound domain matches its own host, but isn't a name of this machine
+        assert!(!policy.allows(Some("http://rebound.example.com"), "http://rebound.example.com", &advertised));
+        assert!(!policy.allows(Some("http://evil.example.com"), "http://192.168.1.20:8088", &advertised));
+        assert!(!policy.allows(Some("null"), "http://192.168.1.20:8088", &advertised));
+    }
+}
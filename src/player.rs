@@ -0,0 +1,1380 @@
+/// The player actor owns the libvlc instance and the media players of all zones.
+/// It runs in an arbiter of its own, so blocking libvlc calls never stall the web server,
+/// handles PlayerMsg from the websocket handlers and worker threads, answers queries like GetPlayerState,
+/// and broadcasts the resulting state changes to all registered websocket connections.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix::{Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message, MessageResult};
+use crossbeam_channel::Sender;
+
+use crate::websocket::{OutgoingMsg, PlaybackState, PlayerWs, CurrentMedia, MediaSource};
+use crate::media_fs::{ParseMediaConfig, MediaEntry, parse_media_dir, media_names};
+use crate::stations::Stations;
+use crate::podcasts::{self, Podcasts, PodcastJob, Episode, Location};
+use crate::feed::Feed;
+use crate::resume::{ResumePolicy, ResumePositions};
+use crate::queue::{Queue, QueueEntry};
+use crate::settings::{PlayerSettings, VolumeCurve, MAX_CROSSFADE, MAX_PREAMP, MAX_VOLUME_LIMIT, MAX_FADE};
+use crate::loudness::{self, LoudnessCache, ReplayGain, ReplayGainMode};
+use crate::equalizer::{self, Equalizer};
+use crate::audio_output::{self, AudioOutput, OutputInfo};
+use crate::timers::{Timers, SleepRequest, AlarmRequest, AlarmTarget};
+use crate::zones::{ZoneConfig, DEFAULT_ZONE};
+use crate::sync::{SyncConfig, SyncState, FollowerConnection};
+use crate::store::Store;
+use crate::vlc_helpers;
+use crate::connections::Connections;
+
+mod transition;
+mod fade;
+mod timers;
+mod zones;
+mod sync;
+
+use transition::Transition;
+use fade::{Fade, FadeEnd};
+use timers::{VolumeRamp, ScheduledSleepTimer};
+use self::sync::SyncRole;
+
+pub enum PlayerMsg {
+    /// A message addressed to a zone. Messages that don't concern a single zone are handled the same in every zone.
+    Zone(u64, Box<PlayerMsg>),
+    /// Moves the queue to another zone, playback continues there
+    MoveQueue(u64),
+    Play(u64),
+    Pause,
+    Resume,
+    Stop,
+    Register(Addr<PlayerWs>),
+    Unregister(Addr<PlayerWs>),
+    VolumeChange(u64),
+    ToggleMute,
+    SetMaxVolume(u64),
+    SetVolumeCurve(VolumeCurve),
+    SetVolumeFade(u64),
+    SetRate(f32),
+    PlayStation(u64),
+    AddStation{name: String, url: String},
+    RemoveStation(u64),
+    Subscribe(String),
+    Unsubscribe(u64),
+    RefreshPodcasts,
+    PlayEpisode(u64),
+    DownloadEpisode(u64),
+    DeleteDownload(u64),
+    MarkEpisodePlayed{episode_id: u64, played: bool},
+    ClearResumePosition(u64),
+    Enqueue(Vec<u64>),
+    EnqueueEpisode(u64),
+    RemoveFromQueue(usize),
+    ClearQueue,
+    PlayQueue(usize),
+    Next,
+    Previous,
+    SetGapless(bool),
+    SetCrossfade(u64),
+    SetReplayGain{mode: ReplayGainMode, preamp: f32, prevent_clipping: bool},
+    SetEqualizerEnabled(bool),
+    ApplyEqualizerPreset(String),
+    SetEqualizerBand{band: usize, amp: f32},
+    SetEqualizerPreamp(f32),
+    SetAudioOutput(AudioOutput),
+    SetSleepTimer{end: SleepRequest, fade_out: u64},
+    CancelSleepTimer,
+    AddAlarm(AlarmRequest),
+    RemoveAlarm(u64),
+    /// Sent by the podcast worker once a feed has been fetched
+    PodcastFetched{podcast_id: u64, result: Result<Feed, String>},
+    /// Sent by the podcast worker once an episode has been downloaded, with the path of the downloaded file
+    EpisodeDownloaded{episode_id: u64, result: Result<String, String>},
+    /// Sent by the loudness worker once a file has been measured
+    LoudnessMeasured{path: String, result: Result<ReplayGain, String>},
+    /// Sent by the loudness worker with the gain tags of a batch of files, None for files without tags
+    TagsRead(Vec<(String, Option<ReplayGain>)>),
+    /// Groups the follower with the zone the message is addressed to
+    GroupFollower(u64),
+    UngroupFollower(u64),
+    SetFollowerLatency{follower_id: u64, latency: i64},
+    /// Sent by the thread of a follower once it has introduced itself
+    FollowerConnected(FollowerConnection),
+    FollowerDisconnected(u64),
+    /// Sent by the connection to the leader. `url_base` is the leader's web server, which streams media to requests with `media_token`.
+    LeaderConnected{follower_id: u64, url_base: String, media_token: String},
+    LeaderDisconnected,
+    /// A round trip to the leader, `offset` being the leader's clock minus this instance's in milliseconds
+    ClockSample{offset: i64, rtt: i64},
+    LeaderState(SyncState),
+}
+
+impl Message for PlayerMsg {
+    type Result = ();
+}
+
+/// Answered with the names of the equalizer presets and the frequencies of the bands in Hz
+pub struct ListEqualizerPresets;
+
+impl Message for ListEqualizerPresets {
+    type Result = (Vec<String>, Vec<f32>);
+}
+
+/// Answered with the available audio outputs and their devices
+pub struct ListAudioOutputs;
+
+impl Message for ListAudioOutputs {
+    type Result = Vec<OutputInfo>;
+}
+
+/// Limits the zones the connection receives updates of, None subscribes to all zones.
+/// Answered with an error if the zone doesn't exist.
+pub struct SubscribeZone {
+    pub ws: Addr<PlayerWs>,
+    pub zone_id: Option<u64>,
+}
+
+impl Message for SubscribeZone {
+    type Result = Result<(), String>;
+}
+
+/// Answered with the complete state as sent to newly registered connections, with the playback state of the zone
+pub struct GetPlayerState {
+    pub zone_id: u64,
+}
+
+impl Message for GetPlayerState {
+    type Result = Result<OutgoingMsg, String>;
+}
+
+/// Everything the player needs to know at startup
+pub struct PlayerConfig {
+    pub media_dir: PathBuf,
+    pub parse_media_config: ParseMediaConfig,
+    pub store: Store,
+    pub podcast_refresh_interval: Duration,
+    pub resume_policy: ResumePolicy,
+    /// Measure the loudness of files without gain tags in the background
+    pub analyze_loudness: bool,
+    /// The first zone is the default zone
+    pub zones: Vec<ZoneConfig>,
+    pub sync: SyncConfig,
+}
+
+/// Volume of the media player until a client changes it
+const DEFAULT_VOLUME: i32 = 100;
+
+/// Highest volume the media player is set to when ReplayGain amplifies a file, libvlc allows amplification up to 200%
+const MAX_OUTPUT_VOLUME: i32 = 200;
+
+/// Slowest and fastest playback rate accepted from clients
+const MIN_RATE: f32 = 0.5;
+const MAX_RATE: f32 = 3.0;
+
+/// How often the player checks on the media player
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often the position of a playing podcast episode or resumable file is persisted
+const POSITION_SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn unix_now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Creates a vlc media for the entry, limited to the entry's bounds if it is virtual media.
+/// Playback starts `resume_at` milliseconds into the entry.
+fn create_media(vlc_instance: &vlc::Instance, entry: &MediaEntry, resume_at: i64) -> Option<vlc::Media> {
+    let md = vlc::Media::new_path(vlc_instance, &entry.path)?;
+    // libvlc takes care of seeking to the start and stopping at the end of the entry
+    let start = entry.start + resume_at;
+    if start > 0 {
+        unsafe { vlc_helpers::add_media_option(&md, &format!(":start-time={:.3}", start as f64 / 1000.0)) };
+    }
+    if let Some(end) = entry.end {
+        unsafe { vlc_helpers::add_media_option(&md, &format!(":stop-time={:.3}", end as f64 / 1000.0)) };
+    }
+    Some(md)
+}
+
+/// Creates a vlc media for the episode, from its download if available.
+/// Playback starts `resume_at` milliseconds into the episode.
+fn create_episode_media(vlc_instance: &vlc::Instance, episode: &Episode, resume_at: i64) -> Option<vlc::Media> {
+    let md = match episode.location() {
+        Location::Path(path) => vlc::Media::new_path(vlc_instance, path),
+        Location::Url(url) => vlc::Media::new_location(vlc_instance, url),
+    }?;
+    if resume_at > 0 {
+        unsafe { vlc_helpers::add_media_option(&md, &format!(":start-time={:.3}", resume_at as f64 / 1000.0)) };
+    }
+    Some(md)
+}
+
+/// Playback state of a zone
+struct Zone {
+    id: u64,
+    name: String,
+    mediaplayer: vlc::MediaPlayer,
+    /// Second media player the next queue entry is prepared on for gapless transitions and crossfades
+    standby: vlc::MediaPlayer,
+    playback_state: PlaybackState,
+    queue: Queue,
+    audio_output: AudioOutput,
+    /// The volume chosen by clients, before the volume curve, ReplayGain and fades are applied
+    volume: i32,
+    muted: bool,
+    /// Playback rate of files and podcast episodes. Stations always play at normal speed.
+    rate: f32,
+    transition: Option<Transition>,
+    /// Fade of the active media player when pausing, resuming or stopping
+    fade: Option<Fade>,
+    /// Set while the volume rises after an alarm went off
+    volume_ramp: Option<VolumeRamp>,
+    last_position_save: Instant,
+}
+
+pub struct Player {
+    vlc_instance: vlc::Instance,
+    /// The zone messages are handled in, see `select_zone`
+    zone: Zone,
+    /// All other zones by id
+    other_zones: BTreeMap<u64, Zone>,
+    settings: PlayerSettings,
+    equalizer: Equalizer,
+    timers: Timers,
+    /// Set while a sleep timer ending at a fixed time is scheduled
+    scheduled_sleep_timer: Option<ScheduledSleepTimer>,
+    sync_role: SyncRole,
+    ws_connections: Connections,
+    registered_media: HashMap<u64, MediaEntry>,
+    stations: Stations,
+    podcasts: Podcasts,
+    podcast_jobs: Sender<PodcastJob>,
+    podcast_refresh_interval: Duration,
+    last_podcast_refresh: Option<Instant>,
+    resume_policy: ResumePolicy,
+    resume_positions: ResumePositions,
+    loudness_cache: LoudnessCache,
+    store: Store,
+}
+
+/// Starts the player in an arbiter of its own. Must be called within a running actix system.
+pub fn start(config: PlayerConfig) -> Addr<Player> {
+    Player::start_in_arbiter(&Arbiter::new(), move |ctx| Player::new(config, ctx.address()))
+}
+
+impl Player {
+    /// `addr` is handed to the worker threads of the player, so they can report back to it
+    fn new(config: PlayerConfig, addr: Addr<Player>) -> Self {
+        let vlc_instance = vlc::Instance::new().expect("Failed to initialize vlc instance. This is a bug.");
+
+        let (_media_max_id, mut registered_media) = parse_media_dir(0, &config.media_dir, &config.parse_media_config).expect("Unable to read media dir.");
+        let store = config.store;
+        println!("Registered {} media ({} virtual)", registered_media.len(), registered_media.values().filter(|e| e.is_virtual()).count());
+
+        // gains read or measured before apply right away, other files are read in the background, once per file
+        let loudness_cache = LoudnessCache::load(&store);
+        let mut unread: HashSet<String> = HashSet::new();
+        let mut untagged: HashSet<String> = HashSet::new();
+        for entry in registered_media.values_mut() {
+            match loudness_cache.get(&entry.path) {
+                Some(Some(replay_gain)) => entry.replay_gain = Some(replay_gain),
+                Some(None) => { untagged.insert(entry.path.clone()); },
+                None => { unread.insert(entry.path.clone()); },
+            }
+        }
+        loudness::spawn_analyzer(addr.clone(), unread.into_iter().collect(), untagged.into_iter().collect(), config.analyze_loudness);
+
+        let mut zones: BTreeMap<u64, Zone> = config.zones
+            .into_iter()
+            .enumerate()
+            .map(|(id, zone_config)| (id as u64, Zone::new(id as u64, zone_config, &vlc_instance, &store)))
+            .collect();
+        println!("Zones: {}", zones.values().map(|zone| zone.name.as_str()).collect::<Vec<&str>>().join(", "));
+        let default_zone = zones.remove(&DEFAULT_ZONE).expect("No zones passed to the player. This is a bug.");
+        let sync_role = SyncRole::start(config.sync, &vlc_instance, &default_zone.audio_output, &registered_media, &store, addr.clone());
+
+        let mut player = Player {
+            vlc_instance,
+            zone: default_zone,
+            other_zones: zones,
+            settings: PlayerSettings::load(&store),
+            equalizer: Equalizer::load(&store),
+            timers: Timers::load(&store, unix_now()),
+            scheduled_sleep_timer: None,
+            sync_role,
+            ws_connections: Connections::default(),
+            registered_media,
+            stations: Stations::load(&store),
+            podcasts: Podcasts::load(&store),
+            podcast_jobs: podcasts::spawn_worker(addr),
+            podcast_refresh_interval: config.podcast_refresh_interval,
+            last_podcast_refresh: None,
+            resume_policy: config.resume_policy,
+            resume_positions: ResumePositions::load(&store),
+            loudness_cache,
+            store,
+        };
+
+        player.for_each_zone(|player| {
+            player.apply_equalizer();
+            player.apply_audio_output();
+            player.zone.volume = player.zone.volume.min(player.settings.max_volume as i32);
+            player.apply_volume();
+        });
+
+        player
+    }
+}
+
+impl Actor for Player {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.schedule_tick(ctx);
+        self.schedule_sleep_timer(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        println!("Player stopped");
+    }
+}
+
+impl Handler<PlayerMsg> for Player {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayerMsg, ctx: &mut Context<Self>) {
+        self.handle_msg(msg);
+        self.sync_followers();
+        self.schedule_sleep_timer(ctx);
+    }
+}
+
+impl Handler<ListEqualizerPresets> for Player {
+    type Result = MessageResult<ListEqualizerPresets>;
+
+    fn handle(&mut self, _msg: ListEqualizerPresets, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult((equalizer::presets(), equalizer::band_frequencies()))
+    }
+}
+
+impl Handler<ListAudioOutputs> for Player {
+    type Result = MessageResult<ListAudioOutputs>;
+
+    fn handle(&mut self, _msg: ListAudioOutputs, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(audio_output::list(&self.vlc_instance))
+    }
+}
+
+impl Handler<SubscribeZone> for Player {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: SubscribeZone, _ctx: &mut Context<Self>) -> Self::Result {
+        self.subscribe_zone(msg.ws, msg.zone_id)
+    }
+}
+
+impl Handler<GetPlayerState> for Player {
+    type Result = Result<OutgoingMsg, String>;
+
+    fn handle(&mut self, msg: GetPlayerState, _ctx: &mut Context<Self>) -> Self::Result {
+        if !self.select_zone(msg.zone_id) {
+            return Err(format!("Invalid zone_id: {}", msg.zone_id));
+        }
+        Ok(self.player_state(msg.zone_id))
+    }
+}
+
+impl Player {
+    fn handle_msg(&mut self, msg: PlayerMsg) {
+        // a pending pause or stop happens before playback changes any further
+        match msg {
+            PlayerMsg::Play(_) | PlayerMsg::Pause | PlayerMsg::Resume | PlayerMsg::Stop
+            | PlayerMsg::PlayStation(_) | PlayerMsg::PlayEpisode(_) | PlayerMsg::PlayQueue(_)
+            | PlayerMsg::Next | PlayerMsg::Previous => self.finish_fade(),
+            _ => {},
+        }
+
+        match msg {
+            PlayerMsg::Zone(zone_id, msg) => {
+                if self.select_zone(zone_id) {
+                    self.handle_msg(*msg);
+                } else {
+                    println!("Received message for invalid zone_id: {}", zone_id);
+                }
+            },
+            PlayerMsg::MoveQueue(zone_id) => self.move_queue(zone_id),
+            PlayerMsg::Play(media_id) => self.play(media_id),
+            PlayerMsg::Pause => self.pause(),
+            PlayerMsg::Resume => self.resume(),
+            PlayerMsg::Stop => self.stop(),
+            PlayerMsg::VolumeChange(volume) => self.change_volume(volume),
+            PlayerMsg::ToggleMute => {
+                self.zone.muted = !self.zone.muted;
+                self.apply_volume();
+                self.ws_connections.broadcast(OutgoingMsg::MuteChange{zone_id: self.zone.id, muted: self.zone.muted});
+            },
+            PlayerMsg::SetMaxVolume(max_volume) => {
+                self.settings.max_volume = max_volume.min(MAX_VOLUME_LIMIT);
+                self.settings.save(&self.store);
+                self.broadcast_settings();
+                self.for_each_zone(|player| {
+                    if player.zone.volume as u64 > player.settings.max_volume {
+                        player.change_volume(player.settings.max_volume);
+                    }
+                });
+            },
+            PlayerMsg::SetVolumeCurve(volume_curve) => {
+                self.settings.volume_curve = volume_curve;
+                self.settings.save(&self.store);
+                self.broadcast_settings();
+                self.for_each_zone(|player| player.apply_volume());
+            },
+            PlayerMsg::SetRate(rate) => {
+                if !rate.is_finite() {
+                    println!("Received invalid playback rate: {}", rate);
+                    return;
+                }
+                self.zone.rate = rate.clamp(MIN_RATE, MAX_RATE);
+                self.apply_rate();
+                let current_media = match &self.zone.playback_state {
+                    PlaybackState::Playing{current_media} | PlaybackState::Paused{current_media} => self.refresh_current_media(current_media),
+                    PlaybackState::Stopped => return,
+                };
+                self.zone.playback_state = match self.zone.playback_state {
+                    PlaybackState::Paused{..} => PlaybackState::Paused{current_media},
+                    _ => PlaybackState::Playing{current_media},
+                };
+                self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+            },
+            PlayerMsg::SetVolumeFade(fade) => {
+                self.settings.fade = fade.min(MAX_FADE);
+                self.settings.save(&self.store);
+                self.broadcast_settings();
+            },
+            PlayerMsg::Register(ws) => self.register(ws),
+            PlayerMsg::Unregister(ws) => {
+                self.ws_connections.unregister(&ws);
+            },
+            PlayerMsg::PlayStation(station_id) => self.play_station(station_id),
+            PlayerMsg::AddStation{name, url} => {
+                match self.stations.add(name, url) {
+                    Ok(station) => println!("Added station {}: {}", station.id, station.url),
+                    Err(e) => {
+                        println!("Failed to add station: {}", e);
+                        return;
+                    }
+                }
+                self.stations.save(&self.store);
+                self.ws_connections.broadcast(OutgoingMsg::StationsChange{stations: self.stations.list().clone()});
+            },
+            PlayerMsg::RemoveStation(station_id) => {
+                if self.stations.remove(station_id) {
+                    self.stations.save(&self.store);
+                    self.ws_connections.broadcast(OutgoingMsg::StationsChange{stations: self.stations.list().clone()});
+                } else {
+                    println!("Received station removal with invalid station_id: {}", station_id)
+                }
+            },
+            PlayerMsg::Subscribe(url) => {
+                let job = match self.podcasts.subscribe(url) {
+                    Ok(podcast) => PodcastJob::Refresh{podcast_id: podcast.id, url: podcast.url.clone()},
+                    Err(e) => {
+                        println!("Failed to subscribe to podcast: {}", e);
+                        return;
+                    }
+                };
+                self.podcasts.save(&self.store);
+                self.broadcast_podcasts();
+                self.send_podcast_job(job);
+            },
+            PlayerMsg::Unsubscribe(podcast_id) => {
+                match self.podcasts.unsubscribe(podcast_id) {
+                    Some(podcast) => {
+                        for path in podcast.episodes.iter().filter_map(|e| e.download.as_ref()) {
+                            if let Err(e) = std::fs::remove_file(path) {
+                                println!("Failed to remove downloaded episode {}: {}", path, e);
+                            }
+                        }
+                        // the episodes can't be played from the queues anymore
+                        let episode_ids: HashSet<u64> = podcast.episodes.iter().map(|e| e.id).collect();
+                        self.for_each_zone(|player| {
+                            let removed = player.zone.queue.retain(|entry| match entry {
+                                QueueEntry::Episode(episode_id) => !episode_ids.contains(episode_id),
+                                QueueEntry::Media(_) => true,
+                            });
+                            if removed {
+                                player.cancel_transition();
+                                player.broadcast_queue();
+                            }
+                        });
+                        self.podcasts.save(&self.store);
+                        self.broadcast_podcasts();
+                    },
+                    None => println!("Received unsubscribe with invalid podcast_id: {}", podcast_id),
+                }
+            },
+            PlayerMsg::RefreshPodcasts => self.refresh_podcasts(),
+            PlayerMsg::PlayEpisode(episode_id) => self.play_episode(episode_id),
+            PlayerMsg::DownloadEpisode(episode_id) => {
+                let podcast_id = match self.podcasts.podcast_of_episode(episode_id) {
+                    Some(podcast_id) => podcast_id,
+                    None => {
+                        println!("Received download request with invalid episode_id: {}", episode_id);
+                        return;
+                    }
+                };
+                let episode = self.podcasts.episode(episode_id).expect("Episode disappeared during lookup. This is a bug.");
+                if episode.download.is_some() {
+                    println!("Episode {} is already downloaded", episode_id);
+                    return;
+                }
+                let job = PodcastJob::Download{
+                    episode_id,
+                    url: episode.url.clone(),
+                    target: podcasts::download_target(&self.store, podcast_id, episode),
+                };
+                self.send_podcast_job(job);
+            },
+            PlayerMsg::DeleteDownload(episode_id) => {
+                let download = match self.podcasts.episode_mut(episode_id) {
+                    Some(episode) => episode.download.take(),
+                    None => {
+                        println!("Received download removal with invalid episode_id: {}", episode_id);
+                        return;
+                    }
+                };
+                if let Some(path) = download {
+                    // an episode that is currently playing from the file keeps playing, libvlc holds the file open
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        println!("Failed to remove downloaded episode {}: {}", path, e);
+                    }
+                    self.podcasts.save(&self.store);
+                    self.broadcast_podcasts();
+                }
+            },
+            PlayerMsg::MarkEpisodePlayed{episode_id, played} => {
+                match self.podcasts.episode_mut(episode_id) {
+                    Some(episode) => {
+                        episode.played = played;
+                        episode.position = 0;
+                        self.podcasts.save(&self.store);
+                        self.broadcast_podcasts();
+                    },
+                    None => println!("Received played state with invalid episode_id: {}", episode_id),
+                }
+            },
+            PlayerMsg::ClearResumePosition(media_id) => {
+                match self.registered_media.get(&media_id) {
+                    Some(entry) => {
+                        if self.resume_positions.remove(entry) {
+                            self.resume_positions.save(&self.store);
+                            self.broadcast_resume_positions();
+                        }
+                    },
+                    None => println!("Received resume position removal with invalid track_id: {}", media_id),
+                }
+            },
+            PlayerMsg::Enqueue(media_ids) => {
+                let (valid, invalid): (Vec<u64>, Vec<u64>) = media_ids
+                    .into_iter()
+                    .partition(|id| self.registered_media.contains_key(id));
+                if !invalid.is_empty() {
+                    println!("Received enqueue request with invalid track_ids: {:?}", invalid);
+                }
+                self.zone.queue.enqueue(valid.into_iter().map(QueueEntry::Media));
+                self.broadcast_queue();
+            },
+            PlayerMsg::EnqueueEpisode(episode_id) => {
+                if self.podcasts.episode(episode_id).is_none() {
+                    println!("Received enqueue request with invalid episode_id: {}", episode_id);
+                    return;
+                }
+                self.zone.queue.enqueue(Some(QueueEntry::Episode(episode_id)));
+                self.broadcast_queue();
+            },
+            PlayerMsg::RemoveFromQueue(index) => {
+                self.cancel_transition();
+                if self.zone.queue.remove(index) {
+                    self.broadcast_queue();
+                } else {
+                    println!("Received queue removal with invalid index: {}", index);
+                }
+            },
+            PlayerMsg::ClearQueue => {
+                self.cancel_transition();
+                self.zone.queue.clear();
+                self.broadcast_queue();
+            },
+            PlayerMsg::PlayQueue(index) => self.play_queue(index),
+            PlayerMsg::Next => {
+                match self.zone.queue.next() {
+                    Some((index, _)) => self.play_queue(index),
+                    None => println!("Received next message but there is no next queue entry"),
+                }
+            },
+            PlayerMsg::Previous => {
+                match self.zone.queue.previous() {
+                    Some((index, _)) => self.play_queue(index),
+                    None => println!("Received previous message but there is no previous queue entry"),
+                }
+            },
+            PlayerMsg::SetGapless(gapless) => {
+                self.for_each_zone(|player| player.cancel_transition());
+                self.settings.gapless = gapless;
+                self.settings.save(&self.store);
+                self.broadcast_settings();
+            },
+            PlayerMsg::SetCrossfade(crossfade) => {
+                self.for_each_zone(|player| player.cancel_transition());
+                self.settings.crossfade = crossfade.min(MAX_CROSSFADE);
+                self.settings.save(&self.store);
+                self.broadcast_settings();
+            },
+            PlayerMsg::SetReplayGain{mode, preamp, prevent_clipping} => {
+                if !preamp.is_finite() {
+                    println!("Received invalid ReplayGain preamp: {}", preamp);
+                    return;
+                }
+                self.settings.replay_gain = mode;
+                self.settings.preamp = preamp.clamp(-MAX_PREAMP, MAX_PREAMP);
+                self.settings.prevent_clipping = prevent_clipping;
+                self.settings.save(&self.store);
+                self.broadcast_settings();
+                self.for_each_zone(|player| player.gain_changed());
+            },
+            PlayerMsg::SetEqualizerEnabled(enabled) => {
+                self.equalizer.enabled = enabled;
+                self.equalizer_changed();
+            },
+            PlayerMsg::ApplyEqualizerPreset(preset) => {
+                match self.equalizer.apply_preset(&preset) {
+                    Ok(()) => self.equalizer_changed(),
+                    Err(e) => println!("Failed to apply equalizer preset: {}", e),
+                }
+            },
+            PlayerMsg::SetEqualizerBand{band, amp} => {
+                match self.equalizer.set_band(band, amp) {
+                    Ok(()) => self.equalizer_changed(),
+                    Err(e) => println!("Failed to set equalizer band: {}", e),
+                }
+            },
+            PlayerMsg::SetEqualizerPreamp(preamp) => {
+                match self.equalizer.set_preamp(preamp) {
+                    Ok(()) => self.equalizer_changed(),
+                    Err(e) => println!("Failed to set equalizer preamp: {}", e),
+                }
+            },
+            PlayerMsg::SetAudioOutput(audio_output) => {
+                if let Err(e) = audio_output.validate(&self.vlc_instance) {
+                    println!("Failed to select audio output: {}", e);
+                    self.ws_connections.broadcast(OutgoingMsg::Error);
+                    return;
+                }
+                self.zone.audio_output = audio_output;
+                self.apply_audio_output();
+                self.zone.audio_output.save(&self.store, &self.zone.name);
+                self.ws_connections.broadcast(OutgoingMsg::AudioOutputChange{zone_id: self.zone.id, audio_output: self.zone.audio_output.clone()});
+            },
+            PlayerMsg::SetSleepTimer{end, fade_out} => {
+                if let Err(e) = self.timers.set_sleep_timer(end, fade_out, self.zone.id, unix_now()) {
+                    println!("Failed to set sleep timer: {}", e);
+                    self.ws_connections.broadcast(OutgoingMsg::Error);
+                    return;
+                }
+                // the next queue entry must not start if the sleep timer stops playback after the current one
+                if self.sleep_after_current() {
+                    self.cancel_transition();
+                }
+                self.timers.save(&self.store);
+                self.broadcast_timers();
+            },
+            PlayerMsg::CancelSleepTimer => {
+                if self.timers.cancel_sleep_timer() {
+                    self.timers.save(&self.store);
+                    self.broadcast_timers();
+                } else {
+                    println!("Received sleep timer cancellation but there is no sleep timer");
+                }
+            },
+            PlayerMsg::AddAlarm(request) => {
+                if let AlarmTarget::Station{station_id} = &request.target {
+                    if self.stations.get(*station_id).is_none() {
+                        println!("Received alarm with invalid station_id: {}", station_id);
+                        return;
+                    }
+                }
+                match self.timers.add_alarm(request, self.zone.id, &self.registered_media, unix_now()) {
+                    Ok(alarm_id) => println!("Added alarm {}", alarm_id),
+                    Err(e) => {
+                        println!("Failed to add alarm: {}", e);
+                        return;
+                    }
+                }
+                self.timers.save(&self.store);
+                self.broadcast_timers();
+            },
+            PlayerMsg::RemoveAlarm(alarm_id) => {
+                if self.timers.remove_alarm(alarm_id) {
+                    self.timers.save(&self.store);
+                    self.broadcast_timers();
+                } else {
+                    println!("Received alarm removal with invalid alarm_id: {}", alarm_id);
+                }
+            },
+            PlayerMsg::PodcastFetched{podcast_id, result} => {
+                match result {
+                    Ok(feed) => {
+                        if self.podcasts.merge_feed(podcast_id, feed, unix_now()) {
+                            self.podcasts.save(&self.store);
+                            self.broadcast_podcasts();
+                        }
+                    },
+                    Err(e) => println!("Failed to refresh podcast {}: {}", podcast_id, e),
+                }
+            },
+            PlayerMsg::EpisodeDownloaded{episode_id, result} => {
+                match result {
+                    Ok(path) => {
+                        match self.podcasts.episode_mut(episode_id) {
+                            Some(episode) => episode.download = Some(path),
+                            None => {
+                                // unsubscribed while downloading
+                                let _ = std::fs::remove_file(&path);
+                                return;
+                            }
+                        }
+                        self.podcasts.save(&self.store);
+                        self.broadcast_podcasts();
+                    },
+                    Err(e) => {
+                        println!("Failed to download episode {}: {}", episode_id, e);
+                        self.ws_connections.broadcast(OutgoingMsg::Error);
+                    },
+                }
+            },
+            PlayerMsg::LoudnessMeasured{path, result} => {
+                let replay_gain = match result {
+                    Ok(replay_gain) => replay_gain,
+                    Err(e) => {
+                        println!("Failed to measure loudness of {}: {}", path, e);
+                        return;
+                    }
+                };
+                self.loudness_cache.insert(path.clone(), Some(replay_gain));
+                self.loudness_cache.save(&self.store);
+                self.apply_replay_gain(&path, replay_gain);
+            },
+            PlayerMsg::TagsRead(gains) => {
+                for (path, replay_gain) in gains {
+                    self.loudness_cache.insert(path.clone(), replay_gain);
+                    if let Some(replay_gain) = replay_gain {
+                        self.apply_replay_gain(&path, replay_gain);
+                    }
+                }
+                self.loudness_cache.save(&self.store);
+            },
+            PlayerMsg::GroupFollower(follower_id) => self.group_follower(follower_id, Some(self.zone.id)),
+            PlayerMsg::UngroupFollower(follower_id) => self.group_follower(follower_id, None),
+            PlayerMsg::SetFollowerLatency{follower_id, latency} => self.set_follower_latency(follower_id, latency),
+            PlayerMsg::FollowerConnected(follower) => self.follower_connected(follower),
+            PlayerMsg::FollowerDisconnected(follower_id) => self.follower_disconnected(follower_id),
+            PlayerMsg::LeaderConnected{follower_id, url_base, media_token} => self.leader_connected(follower_id, url_base, media_token),
+            PlayerMsg::LeaderDisconnected => self.leader_disconnected(),
+            PlayerMsg::ClockSample{offset, rtt} => self.clock_sample(offset, rtt),
+            PlayerMsg::LeaderState(state) => self.leader_state(state),
+        }
+    }
+
+    fn broadcast_queue(&self) {
+        self.ws_connections.broadcast(OutgoingMsg::QueueChange{zone_id: self.zone.id, queue: self.zone.queue.clone()});
+    }
+
+    fn broadcast_settings(&self) {
+        self.ws_connections.broadcast(OutgoingMsg::PlayerSettingsChange{settings: self.settings.clone()});
+    }
+
+    /// The playing or paused file, None for streams, episodes and when stopped
+    fn current_file(&self) -> Option<u64> {
+        match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} | PlaybackState::Paused{current_media} if current_media.source == MediaSource::File => Some(current_media.id),
+            _ => None,
+        }
+    }
+
+    /// The ReplayGain applied to the registered media in dB, according to the settings
+    fn media_gain(&self, media_id: u64) -> Option<f32> {
+        let replay_gain = self.registered_media.get(&media_id)?.replay_gain?;
+        replay_gain.applied_gain(self.settings.replay_gain, self.settings.preamp, self.settings.prevent_clipping)
+    }
+
+    /// The volume the media player is set to for the given media, the client volume with ReplayGain applied.
+    /// Pass None for streams and episodes.
+    fn output_volume(&self, media_id: Option<u64>) -> i32 {
+        if self.zone.muted {
+            return 0;
+        }
+        let gain_factor = media_id
+            .and_then(|media_id| self.media_gain(media_id))
+            .map(loudness::gain_factor)
+            .unwrap_or(1.0);
+        let volume = self.settings.volume_curve.apply(self.zone.volume as u64) * gain_factor * self.fade_factor();
+        (volume.round() as i32).clamp(0, MAX_OUTPUT_VOLUME)
+    }
+
+    /// Sets the volume of the active media player after the volume, mute state, ReplayGain or a fade changed
+    fn apply_volume(&self) {
+        use vlc::MediaPlayerAudioEx;
+
+        // crossfades pick up the new volume with their next step
+        if !self.transition_started() {
+            let _ = self.zone.mediaplayer.set_volume(self.output_volume(self.current_file()));
+        }
+    }
+
+    /// The playback rate for media of the given source, live streams can't play faster than they are received
+    fn source_rate(&self, source: MediaSource) -> f32 {
+        match source {
+            MediaSource::Station => 1.0,
+            MediaSource::File | MediaSource::Episode => self.zone.rate,
+        }
+    }
+
+    /// Sets the playback rate of both media players. libvlc preserves the pitch with its default time stretching filter.
+    fn apply_rate(&self) {
+        let rate = match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} | PlaybackState::Paused{current_media} => self.source_rate(current_media.source),
+            PlaybackState::Stopped => self.zone.rate,
+        };
+        if self.zone.mediaplayer.set_rate(rate).is_err() {
+            println!("Failed to set the playback rate to {}", rate);
+        }
+        // the standby media player only ever plays queue entries
+        let _ = self.zone.standby.set_rate(self.zone.rate);
+    }
+
+    /// Progress and gain of a registered media playing on the active media player
+    fn file_media(&self, media_id: u64) -> CurrentMedia {
+        let mut current_media = CurrentMedia::new(media_id, &self.registered_media[&media_id], &self.zone.mediaplayer);
+        current_media.gain = self.media_gain(media_id);
+        current_media
+    }
+
+    /// Applies changed ReplayGain settings or measurements to the current file
+    fn gain_changed(&mut self) {
+        let media_id = match self.current_file() {
+            Some(media_id) => media_id,
+            None => return,
+        };
+        self.apply_volume();
+        let current_media = self.file_media(media_id);
+        self.zone.playback_state = match self.zone.playback_state {
+            PlaybackState::Paused{..} => PlaybackState::Paused{current_media},
+            _ => PlaybackState::Playing{current_media},
+        };
+        self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+    }
+
+    /// Sets the gain of the entries of a file read or measured in the background, applying it in zones playing the file
+    fn apply_replay_gain(&mut self, path: &str, replay_gain: ReplayGain) {
+        for entry in self.registered_media.values_mut().filter(|e| e.path == path) {
+            entry.replay_gain = Some(replay_gain);
+        }
+        self.for_each_zone(|player| {
+            let playing_file = player.current_file()
+                .map(|media_id| player.registered_media[&media_id].path == path)
+                .unwrap_or(false);
+            if playing_file {
+                player.gain_changed();
+            }
+        });
+    }
+
+    /// Applies the equalizer to both media players of the zone, so transitions keep the same sound
+    fn apply_equalizer(&self) {
+        self.equalizer.apply(&self.zone.mediaplayer);
+        self.equalizer.apply(&self.zone.standby);
+    }
+
+    fn apply_audio_output(&self) {
+        self.zone.audio_output.apply(&self.zone.mediaplayer);
+        self.zone.audio_output.apply(&self.zone.standby);
+    }
+
+    fn equalizer_changed(&mut self) {
+        self.for_each_zone(|player| player.apply_equalizer());
+        self.equalizer.save(&self.store);
+        self.ws_connections.broadcast(OutgoingMsg::EqualizerChange{equalizer: self.equalizer.clone()});
+    }
+
+    fn broadcast_podcasts(&self) {
+        self.ws_connections.broadcast(OutgoingMsg::PodcastsChange{podcasts: self.podcasts.list().clone(), episodes: self.podcasts.download_names()});
+    }
+
+    fn send_podcast_job(&self, job: PodcastJob) {
+        if let Err(e) = self.podcast_jobs.send(job) {
+            println!("Failed to hand job to the podcast worker: {}", e);
+        }
+    }
+
+    fn refresh_podcasts(&mut self) {
+        self.last_podcast_refresh = Some(Instant::now());
+        for podcast in self.podcasts.list() {
+            self.send_podcast_job(PodcastJob::Refresh{podcast_id: podcast.id, url: podcast.url.clone()});
+        }
+    }
+
+    fn broadcast_resume_positions(&self) {
+        self.ws_connections.broadcast(OutgoingMsg::ResumePositionsChange{resume_positions: self.resume_positions.for_clients(&self.registered_media)});
+    }
+
+    /// Persists the position of the playing podcast episode or resumable file, so playback can resume there later
+    fn remember_position(&mut self) {
+        let current_media = match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} | PlaybackState::Paused{current_media} => current_media,
+            PlaybackState::Stopped => return,
+        };
+        match current_media.source {
+            MediaSource::Episode => {
+                if let (Some(time), Some(episode)) = (self.zone.mediaplayer.get_time(), self.podcasts.episode_mut(current_media.id)) {
+                    episode.position = time.max(0);
+                    self.podcasts.save(&self.store);
+                }
+            },
+            MediaSource::File => {
+                let entry = &self.registered_media[&current_media.id];
+                let refreshed = CurrentMedia::new(current_media.id, entry, &self.zone.mediaplayer);
+                if self.resume_policy.applies(entry, refreshed.length)
+                    && self.resume_positions.update(entry, refreshed.progress, refreshed.length, unix_now()) {
+                    self.resume_positions.save(&self.store);
+                    self.broadcast_resume_positions();
+                }
+            },
+            MediaSource::Station => {},
+        }
+        self.zone.last_position_save = Instant::now();
+    }
+
+    /// Ticks are scheduled independently of incoming messages, so a busy mailbox can't delay transitions
+    fn schedule_tick(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_later(self.next_tick_interval(), |player, ctx| {
+            player.tick();
+            player.schedule_tick(ctx);
+        });
+    }
+
+    /// Checks on timers, podcasts and the media players of all zones in between messages
+    fn tick(&mut self) {
+        self.tick_alarms();
+
+        let refresh_due = match self.last_podcast_refresh {
+            Some(last_refresh) => last_refresh.elapsed() >= self.podcast_refresh_interval,
+            None => true,
+        };
+        if refresh_due {
+            self.refresh_podcasts();
+        }
+
+        self.for_each_zone(|player| player.tick_zone());
+        self.sync_followers();
+        self.tick_follower();
+        self.flush_connections();
+    }
+
+    /// Sends what slow connections couldn't receive yet, and the complete state to those that fell too far behind
+    fn flush_connections(&mut self) {
+        for (ws, zone_id) in self.ws_connections.flush() {
+            self.send_player_state(&ws, zone_id.unwrap_or(DEFAULT_ZONE));
+            self.ws_connections.send(&ws, self.sync_msg());
+        }
+    }
+
+    /// Checks on the media player of the selected zone
+    fn tick_zone(&mut self) {
+        self.tick_fade();
+        self.tick_sleep_timer();
+        self.tick_volume_ramp();
+
+        if let PlaybackState::Playing{current_media} = &self.zone.playback_state {
+            match self.zone.mediaplayer.state() {
+                // the end of a file or the stop-time of virtual media has been reached,
+                // or a stream has been interrupted
+                vlc::State::Ended | vlc::State::Error => {
+                    let ended = self.zone.mediaplayer.state() == vlc::State::Ended;
+                    let source = current_media.source;
+                    if ended && self.transition_prepared() {
+                        self.complete_transition();
+                        return;
+                    }
+                    if ended {
+                        self.finish_current();
+                    }
+                    if source != MediaSource::Station && !self.sleep_after_current() {
+                        if let Some((index, _)) = self.zone.queue.next() {
+                            self.play_queue(index);
+                            return;
+                        }
+                    }
+                    self.zone.playback_state = PlaybackState::Stopped;
+                    self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+                    self.playback_ended();
+                },
+                _ => {
+                    if current_media.source != MediaSource::Station {
+                        self.tick_transition();
+                    }
+                    let current_media = match &self.zone.playback_state {
+                        PlaybackState::Playing{current_media} => current_media,
+                        _ => return,
+                    };
+                    if current_media.source != MediaSource::Station && self.zone.last_position_save.elapsed() >= POSITION_SAVE_INTERVAL {
+                        self.remember_position();
+                    } else if current_media.source == MediaSource::Station {
+                        // streams update their "now playing" metadata whenever the song changes
+                        let refreshed = CurrentMedia::stream(current_media.id, MediaSource::Station, &self.zone.mediaplayer);
+                        if refreshed.now_playing != current_media.now_playing {
+                            self.zone.playback_state = PlaybackState::Playing{current_media: refreshed};
+                            self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Bookkeeping for media that has been played to its end
+    fn finish_current(&mut self) {
+        let current_media = match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} | PlaybackState::Paused{current_media} => current_media,
+            PlaybackState::Stopped => return,
+        };
+        match current_media.source {
+            MediaSource::Episode => {
+                if let Some(episode) = self.podcasts.episode_mut(current_media.id) {
+                    episode.played = true;
+                    episode.position = 0;
+                }
+                self.podcasts.save(&self.store);
+                self.broadcast_podcasts();
+            },
+            MediaSource::File => {
+                // finished files start from the beginning next time
+                if self.resume_positions.remove(&self.registered_media[&current_media.id]) {
+                    self.resume_positions.save(&self.store);
+                    self.broadcast_resume_positions();
+                }
+            },
+            MediaSource::Station => {},
+        }
+    }
+
+    /// Queries the media player for the current progress of the given media
+    fn refresh_current_media(&self, current_media: &CurrentMedia) -> CurrentMedia {
+        match current_media.source {
+            MediaSource::File => self.file_media(current_media.id),
+            MediaSource::Station | MediaSource::Episode => CurrentMedia::stream(current_media.id, current_media.source, &self.zone.mediaplayer),
+        }
+    }
+
+    /// Plays a track outside of the queue
+    fn play(&mut self, media_id: u64) {
+        if self.zone.queue.position.take().is_some() {
+            self.broadcast_queue();
+        }
+        self.play_entry(media_id, None);
+    }
+
+    fn play_queue(&mut self, index: usize) {
+        self.play_queue_at(index, None);
+    }
+
+    /// Plays the queue entry, starting `resume_at` milliseconds into it or where it was left off if None
+    fn play_queue_at(&mut self, index: usize, resume_at: Option<i64>) {
+        match self.zone.queue.get(index) {
+            Some(entry) => {
+                self.zone.queue.position = Some(index);
+                self.broadcast_queue();
+                match entry {
+                    QueueEntry::Media(media_id) => self.play_entry(media_id, resume_at),
+                    QueueEntry::Episode(episode_id) => self.play_episode_at(episode_id, resume_at),
+                }
+            },
+            None => println!("Received queue request with invalid index: {}", index),
+        }
+    }
+
+    fn play_entry(&mut self, media_id: u64, resume_at: Option<i64>) {
+        use vlc::MediaPlayerAudioEx;
+
+        self.remember_position();
+        self.cancel_transition();
+        if let Some(entry) = self.registered_media.get(&media_id) {
+            println!("Received track on worker thread: k:'{}' V:'{}'", media_id, entry.display_name());
+            // continue where the file was left off
+            let resume_at = resume_at.or_else(|| self.resume_positions.get(entry)).unwrap_or(0);
+            // TODO: handle resiliently instead of expect
+            let md = create_media(&self.vlc_instance, entry, resume_at).expect("Failed to create vlc media from file path. This is a bug.");
+            self.zone.mediaplayer.set_media(&md);
+            let _ = self.zone.mediaplayer.set_volume(self.output_volume(Some(media_id)));
+            let _ = self.zone.mediaplayer.set_rate(self.source_rate(MediaSource::File));
+
+            // TODO: handle resiliently instead of expect
+            self.zone.mediaplayer.play().expect("Failed to play selected vlc media. This is a bug.");
+
+            self.zone.playback_state = PlaybackState::Playing{current_media: self.file_media(media_id)};
+            self.zone.last_position_save = Instant::now();
+
+            self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+        } else {
+            println!("Received track request with invalid track_id: {}", media_id)
+        }
+    }
+
+    fn play_station(&mut self, station_id: u64) {
+        use vlc::MediaPlayerAudioEx;
+
+        self.remember_position();
+        self.cancel_transition();
+        if self.zone.queue.position.take().is_some() {
+            self.broadcast_queue();
+        }
+        if let Some(station) = self.stations.get(station_id) {
+            println!("Received station on worker thread: k:'{}' V:'{}'", station_id, station.url);
+            let md = match vlc::Media::new_location(&self.vlc_instance, &station.url) {
+                Some(md) => md,
+                None => {
+                    println!("Failed to create vlc media from station url: {}", station.url);
+                    self.ws_connections.broadcast(OutgoingMsg::Error);
+                    return;
+                }
+            };
+            self.zone.mediaplayer.set_media(&md);
+            let _ = self.zone.mediaplayer.set_volume(self.output_volume(None));
+            let _ = self.zone.mediaplayer.set_rate(self.source_rate(MediaSource::Station));
+
+            match self.zone.mediaplayer.play() {
+                Ok(()) => {
+                    self.zone.playback_state = PlaybackState::Playing{current_media: CurrentMedia::stream(station_id, MediaSource::Station, &self.zone.mediaplayer)};
+                    self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+                },
+                Err(()) => {
+                    println!("failed to play station");
+                    self.ws_connections.broadcast(OutgoingMsg::Error)
+                }
+            }
+        } else {
+            println!("Received station request with invalid station_id: {}", station_id)
+        }
+    }
+
+    /// Plays an episode outside of the queue
+    fn play_episode(&mut self, episode_id: u64) {
+        if self.zone.queue.position.take().is_some() {
+            self.broadcast_queue();
+        }
+        self.play_episode_at(episode_id, None);
+    }
+
+    /// Plays the episode, starting `resume_at` milliseconds into it or where it was left off if None
+    fn play_episode_at(&mut self, episode_id: u64, resume_at: Option<i64>) {
+        use vlc::MediaPlayerAudioEx;
+
+        self.remember_position();
+        self.cancel_transition();
+        if let Some(episode) = self.podcasts.episode(episode_id) {
+            println!("Received episode on worker thread: k:'{}' V:'{}'", episode_id, episode.title);
+            let md = match create_episode_media(&self.vlc_instance, episode, resume_at.unwrap_or(episode.position)) {
+                Some(md) => md,
+                None => {
+                    println!("Failed to create vlc media for episode: {}", episode.url);
+                    self.ws_connections.broadcast(OutgoingMsg::Error);
+                    return;
+                }
+            };
+            self.zone.mediaplayer.set_media(&md);
+            let _ = self.zone.mediaplayer.set_volume(self.output_volume(None));
+            let _ = self.zone.mediaplayer.set_rate(self.source_rate(MediaSource::Episode));
+
+            match self.zone.mediaplayer.play() {
+                Ok(()) => {
+                    self.zone.playback_state = PlaybackState::Playing{current_media: CurrentMedia::stream(episode_id, MediaSource::Episode, &self.zone.mediaplayer)};
+                    self.zone.last_position_save = Instant::now();
+                    self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+                },
+                Err(()) => {
+                    println!("failed to play episode");
+                    self.ws_connections.broadcast(OutgoingMsg::Error)
+                }
+            }
+        } else {
+            println!("Received episode request with invalid episode_id: {}", episode_id)
+        }
+    }
+
+    fn pause(&mut self) {
+        if let PlaybackState::Playing{..} = self.zone.playback_state {
+            if self.fade_out(FadeEnd::Pause, self.settings.fade) {
+                return;
+            }
+        }
+        self.pause_now();
+    }
+
+    fn pause_now(&mut self) {
+        // a crossfade in progress is completed, the next entry is prepared again once playback resumes
+        if self.transition_started() {
+            self.complete_transition();
+        } else {
+            self.cancel_transition();
+        }
+        self.remember_position();
+        self.zone.mediaplayer.pause();
+        match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} => {
+                self.zone.playback_state = PlaybackState::Paused{current_media: self.refresh_current_media(current_media)};
+                self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+            }
+            PlaybackState::Paused {current_media: _} => {
+                println!("received pause message but is already paused");
+                // frontend state might be corrupted, send correct state to frontend?
+            }
+            PlaybackState::Stopped => {
+                println!("received pause message but is stopped");
+                // frontend state might be corrupted, send correct state to frontend?
+            }
+        }
+    }
+
+    // TODO: send more specific error message to client
+    fn resume(&mut self) {
+        match &self.zone.playback_state {
+            PlaybackState::Playing {current_media: _} => {
+                println!("Resume message received but already is already playing.");
+                // frontend state might be corrupted, send correct state to frontend?
+            }
+            PlaybackState::Paused {current_media} => {
+                if self.zone.mediaplayer.will_play() {
+                    let current_media = current_media.clone();
+                    self.fade_in();
+                    match self.zone.mediaplayer.play() {
+                        Ok(()) => {
+                            self.zone.playback_state = PlaybackState::Playing{current_media};
+                            self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+                        },
+                        Err(()) => {
+                            self.finish_fade();
+                            println!("failed to play media");
+                            self.ws_connections.broadcast(OutgoingMsg::Error)
+                        }
+                    }
+                } else {
+                    println!("player won't play media");
+                    self.ws_connections.broadcast(OutgoingMsg::Error)
+                }
+            },
+            PlaybackState::Stopped => {
+                println!("Pause message received but is stopped.")
+                // frontend state might be corrupted, send correct state to frontend?
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        if let PlaybackState::Playing{..} = self.zone.playback_state {
+            if self.fade_out(FadeEnd::Stop, self.settings.fade) {
+                return;
+            }
+        }
+        self.stop_now();
+    }
+
+    fn stop_now(&mut self) {
+        self.remember_position();
+        self.cancel_transition();
+        match self.zone.playback_state {
+            PlaybackState::Playing{current_media: _} => {
+                self.zone.playback_state = PlaybackState::Stopped;
+                self.zone.mediaplayer.stop();
+                self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+            },
+            PlaybackState::Paused{current_media: _} => {
+                self.zone.playback_state = PlaybackState::Stopped;
+                self.zone.mediaplayer.stop();
+            },
+            PlaybackState::Stopped => {
+                println!("Stop message received but is already stopped.")
+                // frontend state might be corrupted, send correct state to frontend?
+            }
+        }
+    }
+
+    fn change_volume(&mut self, volume: u64) {
+        let volume = volume.min(self.settings.max_volume);
+        // clients take over from the volume ramp of an alarm
+        self.zone.volume_ramp = None;
+        self.zone.volume = volume as i32;
+        // changing the volume ends a mute
+        if self.zone.muted {
+            self.zone.muted = false;
+            self.ws_connections.broadcast(OutgoingMsg::MuteChange{zone_id: self.zone.id, muted: false});
+        }
+        self.apply_volume();
+        self.ws_connections.broadcast(OutgoingMsg::VolumeChange{zone_id: self.zone.id, volume});
+    }
+
+    fn register(&mut self, ws: Addr<PlayerWs>) {
+        // connections receive updates of the default zone until they subscribe to another one
+        self.ws_connections.register(ws.clone());
+        self.send_player_state(&ws, DEFAULT_ZONE);
+        self.ws_connections.send(&ws, self.sync_msg());
+    }
+
+    /// The playback state of the selected zone, with up to date progress
+    fn current_playback_state(&self) -> PlaybackState {
+        match &self.zone.playback_state {
+            PlaybackState::Paused{current_media: _} => self.zone.playback_state.clone(),
+            PlaybackState::Stopped => self.zone.playback_state.clone(),
+            PlaybackState::Playing{current_media} => {
+                PlaybackState::new(self.refresh_current_media(current_media), &self.zone.mediaplayer)
+            }
+        }
+    }
+
+    /// Sends the complete state to a single connection, with the playback state, queue and volume of the given zone
+    fn send_player_state(&mut self, ws: &Addr<PlayerWs>, zone_id: u64) {
+        let state = self.player_state(zone_id);
+        self.ws_connections.send(ws, state);
+    }
+
+    /// The complete state, with the playback state, queue and volume of the given zone
+    fn player_state(&mut self, zone_id: u64) -> OutgoingMsg {
+        let zones = self.zone_states();
+        self.select_zone(zone_id);
+
+        OutgoingMsg::PlayerState{
+                zone_id: self.zone.id,
+                zones,
+                playback_state: self.current_playback_state(),
+                media: media_names(&self.registered_media),
+                episodes: self.podcasts.download_names(),
+                stations: self.stations.list().clone(),
+                podcasts: self.podcasts.list().clone(),
+                resume_positions: self.resume_positions.for_clients(&self.registered_media),
+                queue: self.zone.queue.clone(),
+                settings: self.settings.clone(),
+                equalizer: self.equalizer.clone(),
+                audio_output: self.zone.audio_output.clone(),
+                volume: self.zone.volume as u64,
+                muted: self.zone.muted,
+                timers: self.timers.for_clients(&self.registered_media),
+            }
+    }
+}
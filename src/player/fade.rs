@@ -0,0 +1,96 @@
+//! Short volume fades when pausing, resuming and stopping playback.
+//! A fade out delays the pause or stop until the volume has reached zero.
+
+use std::time::{Duration, Instant};
+
+use super::Player;
+
+/// Interval of volume updates during a fade
+const FADE_STEP: Duration = Duration::from_millis(20);
+
+/// What happens once a fade out has completed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FadeEnd {
+    Pause,
+    Stop,
+}
+
+pub struct Fade {
+    started: Instant,
+    duration: Duration,
+    /// None for fade ins
+    end: Option<FadeEnd>,
+}
+
+impl Fade {
+    fn new(duration: u64, end: Option<FadeEnd>) -> Self {
+        Fade {
+            started: Instant::now(),
+            duration: Duration::from_millis(duration),
+            end,
+        }
+    }
+
+    fn progress(&self) -> f32 {
+        (self.started.elapsed().as_millis() as f32 / self.duration.as_millis().max(1) as f32).min(1.0)
+    }
+}
+
+impl Player {
+    /// Factor the output volume is scaled by during a fade
+    pub(super) fn fade_factor(&self) -> f32 {
+        match &self.zone.fade {
+            Some(fade) if fade.end.is_some() => 1.0 - fade.progress(),
+            Some(fade) => fade.progress(),
+            None => 1.0,
+        }
+    }
+
+    /// How long the player loop may wait for the next tick without making a fade choppy
+    pub(super) fn fade_tick_interval(&self, default: Duration) -> Duration {
+        if self.zone.fade.is_some() { default.min(FADE_STEP) } else { default }
+    }
+
+    /// Starts fading out over `duration` milliseconds, the pause or stop happens once the fade has completed.
+    /// Returns false for a duration of 0, the caller has to pause or stop right away.
+    pub(super) fn fade_out(&mut self, end: FadeEnd, duration: u64) -> bool {
+        if duration == 0 {
+            return false;
+        }
+        self.zone.fade = Some(Fade::new(duration, Some(end)));
+        true
+    }
+
+    /// Starts fading in. Call before the media player starts playing, so it starts silent.
+    pub(super) fn fade_in(&mut self) {
+        self.zone.fade = if self.settings.fade > 0 { Some(Fade::new(self.settings.fade, None)) } else { None };
+        self.apply_volume();
+    }
+
+    /// Advances the fade. Called on every tick.
+    pub(super) fn tick_fade(&mut self) {
+        let completed = match &self.zone.fade {
+            Some(fade) => fade.progress() >= 1.0,
+            None => return,
+        };
+        if completed {
+            self.finish_fade();
+        } else {
+            self.apply_volume();
+        }
+    }
+
+    /// Completes the fade right away, pausing or stopping if the fade was a fade out
+    pub(super) fn finish_fade(&mut self) {
+        let fade = match self.zone.fade.take() {
+            Some(fade) => fade,
+            None => return,
+        };
+        match fade.end {
+            Some(FadeEnd::Pause) => self.pause_now(),
+            Some(FadeEnd::Stop) => self.stop_now(),
+            None => {},
+        }
+        self.apply_volume();
+    }
+}
@@ -0,0 +1,418 @@
+//! Playback synchronized with other instances.
+//! As leader, the player sends the state of every zone with grouped followers whenever it changes and once a second,
+//! so followers can correct their drift. As follower, it plays the state it receives on a media player of its own.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use actix::Addr;
+use vlc::MediaPlayerAudioEx;
+
+use super::Player;
+use crate::audio_output::AudioOutput;
+use crate::media_fs::MediaEntry;
+use crate::store::Store;
+use crate::sync::{self, SyncConfig, SyncMsg, SyncState, SyncMedia, SyncGroups, SyncInfo, FollowerInfo, FollowerConnection, ClockSync, MAX_LATENCY};
+use crate::websocket::{OutgoingMsg, PlaybackState, MediaSource};
+use crate::zones::DEFAULT_ZONE;
+use crate::vlc_helpers;
+
+/// How often the leader repeats the state of a zone, so followers can correct their drift
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Followers further off the leader's position than this seek to it, in milliseconds
+const MAX_DRIFT: i64 = 80;
+
+/// Seeks take a while to settle, followers wait this long before correcting again
+const CORRECTION_INTERVAL: Duration = Duration::from_secs(3);
+
+pub enum SyncRole {
+    Standalone,
+    Leader(Leader),
+    Follower(Follower),
+}
+
+pub struct Leader {
+    followers: BTreeMap<u64, FollowerConnection>,
+    groups: SyncGroups,
+    /// The media, playing state and rate last sent to the followers of each zone, and when
+    sent: HashMap<u64, (Option<SyncMedia>, bool, f32, Instant)>,
+}
+
+pub struct Follower {
+    /// Address of the leader's sync port
+    leader: String,
+    mediaplayer: vlc::MediaPlayer,
+    /// The leader's web server and the token it streams media to this follower with, None while disconnected
+    url_base: Option<(String, String)>,
+    clock: ClockSync,
+    state: Option<SyncState>,
+    /// The media loaded on the media player
+    media: Option<SyncMedia>,
+    last_correction: Instant,
+}
+
+impl SyncRole {
+    /// Starts accepting followers or connecting to the leader, depending on the configuration
+    pub(super) fn start(config: SyncConfig, vlc_instance: &vlc::Instance, audio_output: &AudioOutput, registered_media: &HashMap<u64, MediaEntry>, store: &Store, player: Addr<Player>) -> Self {
+        match config {
+            SyncConfig::Standalone => SyncRole::Standalone,
+            SyncConfig::Leader{listeners, http_port, tls, secret, media_paths, media_tokens} => {
+                *media_paths.write().expect("Media paths lock poisoned. This is a bug.") = registered_media
+                    .iter()
+                    .map(|(id, entry)| (*id, entry.path.clone()))
+                    .collect();
+                sync::spawn_leader(listeners, sync::LeaderConfig {
+                    http_port,
+                    tls,
+                    secret,
+                    media_tokens,
+                }, player);
+                SyncRole::Leader(Leader {
+                    followers: BTreeMap::new(),
+                    groups: SyncGroups::load(store),
+                    sent: HashMap::new(),
+                })
+            },
+            SyncConfig::Follower{leader, name, secret} => {
+                let mediaplayer = vlc::MediaPlayer::new(vlc_instance).expect("Failed to create vlc media player from vlc instance. This is a bug.");
+                audio_output.apply(&mediaplayer);
+                sync::spawn_follower(leader.clone(), name, secret, player);
+                SyncRole::Follower(Follower {
+                    leader,
+                    mediaplayer,
+                    url_base: None,
+                    clock: ClockSync::default(),
+                    state: None,
+                    media: None,
+                    last_correction: Instant::now(),
+                })
+            },
+        }
+    }
+}
+
+impl Leader {
+    fn settings(&self, follower: &FollowerConnection) -> sync::FollowerSettings {
+        self.groups.followers.get(&follower.name).cloned().unwrap_or_default()
+    }
+}
+
+impl Player {
+    fn sync_info(&self) -> SyncInfo {
+        match &self.sync_role {
+            SyncRole::Standalone => SyncInfo::Standalone,
+            SyncRole::Leader(leader) => SyncInfo::Leader {
+                followers: leader.followers
+                    .values()
+                    .map(|follower| {
+                        let settings = leader.settings(follower);
+                        FollowerInfo {
+                            id: follower.id,
+                            name: follower.name.clone(),
+                            address: follower.address.clone(),
+                            zone_id: settings.zone_id,
+                            latency: settings.latency,
+                        }
+                    })
+                    .collect(),
+            },
+            SyncRole::Follower(follower) => SyncInfo::Follower {
+                leader: follower.leader.clone(),
+                connected: follower.url_base.is_some(),
+                offset: follower.clock.offset(),
+                rtt: follower.clock.rtt(),
+            },
+        }
+    }
+
+    pub(super) fn sync_msg(&self) -> OutgoingMsg {
+        OutgoingMsg::SyncChange{sync: self.sync_info()}
+    }
+
+    fn broadcast_sync(&self) {
+        self.ws_connections.broadcast(self.sync_msg());
+    }
+
+    pub(super) fn follower_connected(&mut self, follower: FollowerConnection) {
+        let leader = match &mut self.sync_role {
+            SyncRole::Leader(leader) => leader,
+            _ => return,
+        };
+        leader.followers.insert(follower.id, follower);
+        // the new follower needs the state of its zone right away
+        leader.sent.clear();
+        self.sync_followers();
+        self.broadcast_sync();
+    }
+
+    pub(super) fn follower_disconnected(&mut self, follower_id: u64) {
+        if let SyncRole::Leader(leader) = &mut self.sync_role {
+            if let Some(follower) = leader.followers.remove(&follower_id) {
+                println!("Follower {} disconnected", follower.name);
+                self.broadcast_sync();
+            }
+        }
+    }
+
+    /// Groups the follower with the selected zone, or ungroups it for None. Ungrouped followers stop playing.
+    pub(super) fn group_follower(&mut self, follower_id: u64, zone_id: Option<u64>) {
+        let leader = match &mut self.sync_role {
+            SyncRole::Leader(leader) => leader,
+            _ => {
+                println!("Received follower grouping but this instance is not a leader");
+                return;
+            }
+        };
+        let follower = match leader.followers.get(&follower_id) {
+            Some(follower) => follower,
+            None => {
+                println!("Received follower grouping with invalid follower_id: {}", follower_id);
+                return;
+            }
+        };
+        leader.groups.followers.entry(follower.name.clone()).or_default().zone_id = zone_id;
+        leader.groups.save(&self.store);
+        if zone_id.is_none() {
+            let stop = SyncState {
+                media: None,
+                playing: false,
+                position: 0,
+                rate: 1.0,
+                at: sync::unix_millis(),
+                latency: 0,
+            };
+            let _ = follower.connection.send(&SyncMsg::State{state: stop});
+        }
+        leader.sent.clear();
+        self.sync_followers();
+        self.broadcast_sync();
+    }
+
+    pub(super) fn set_follower_latency(&mut self, follower_id: u64, latency: i64) {
+        let leader = match &mut self.sync_role {
+            SyncRole::Leader(leader) => leader,
+            _ => {
+                println!("Received follower latency but this instance is not a leader");
+                return;
+            }
+        };
+        let name = match leader.followers.get(&follower_id) {
+            Some(follower) => follower.name.clone(),
+            None => {
+                println!("Received follower latency with invalid follower_id: {}", follower_id);
+                return;
+            }
+        };
+        leader.groups.followers.entry(name).or_default().latency = latency.clamp(-MAX_LATENCY, MAX_LATENCY);
+        leader.groups.save(&self.store);
+        leader.sent.clear();
+        self.sync_followers();
+        self.broadcast_sync();
+    }
+
+    /// Media, playing state, media time and rate of the selected zone
+    fn zone_sync_state(&self) -> (Option<SyncMedia>, bool, i64, f32) {
+        let (current_media, playing) = match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} => (current_media, true),
+            PlaybackState::Paused{current_media} => (current_media, false),
+            PlaybackState::Stopped => return (None, false, 0, 1.0),
+        };
+        let media = match current_media.source {
+            MediaSource::File => self.registered_media.get(&current_media.id).map(|entry| SyncMedia {
+                url: sync::media_url(current_media.id),
+                start: entry.start,
+                end: entry.end,
+            }),
+            MediaSource::Station => self.stations.get(current_media.id).map(|station| SyncMedia {
+                url: station.url.clone(),
+                start: 0,
+                end: None,
+            }),
+            MediaSource::Episode => self.podcasts.episode(current_media.id).map(|episode| SyncMedia {
+                url: episode.url.clone(),
+                start: 0,
+                end: None,
+            }),
+        };
+        (media, playing, self.zone.mediaplayer.get_time().unwrap_or(0).max(0), self.zone.mediaplayer.get_rate())
+    }
+
+    /// Sends the state of every zone with grouped followers, if it changed or the heartbeat is due
+    pub(super) fn sync_followers(&mut self) {
+        let mut zone_ids: Vec<u64> = match &self.sync_role {
+            SyncRole::Leader(leader) => leader.followers
+                .values()
+                .filter_map(|follower| leader.settings(follower).zone_id)
+                .collect::<HashSet<u64>>()
+                .into_iter()
+                .collect(),
+            _ => return,
+        };
+        zone_ids.sort();
+        for zone_id in zone_ids {
+            if !self.select_zone(zone_id) {
+                continue;
+            }
+            let (media, playing, position, rate) = self.zone_sync_state();
+            let leader = match &mut self.sync_role {
+                SyncRole::Leader(leader) => leader,
+                _ => return,
+            };
+            let due = match leader.sent.get(&zone_id) {
+                Some((sent_media, sent_playing, sent_rate, sent)) => {
+                    sent_media != &media || *sent_playing != playing || *sent_rate != rate || sent.elapsed() >= HEARTBEAT_INTERVAL
+                },
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+            leader.sent.insert(zone_id, (media.clone(), playing, rate, Instant::now()));
+            let at = sync::unix_millis();
+            for follower in leader.followers.values() {
+                let settings = leader.settings(follower);
+                if settings.zone_id != Some(zone_id) {
+                    continue;
+                }
+                let state = SyncState {
+                    media: media.clone(),
+                    playing,
+                    position,
+                    rate,
+                    at,
+                    latency: settings.latency,
+                };
+                // a follower that went away is removed once its reading thread notices
+                if let Err(e) = follower.connection.send(&SyncMsg::State{state}) {
+                    println!("Failed to send state to follower {}: {}", follower.name, e);
+                }
+            }
+        }
+    }
+
+    pub(super) fn leader_connected(&mut self, follower_id: u64, url_base: String, media_token: String) {
+        if let SyncRole::Follower(follower) = &mut self.sync_role {
+            println!("Following leader {} as follower {}, streaming from {}", follower.leader, follower_id, url_base);
+            follower.url_base = Some((url_base, media_token));
+            self.broadcast_sync();
+        }
+    }
+
+    pub(super) fn leader_disconnected(&mut self) {
+        if let SyncRole::Follower(follower) = &mut self.sync_role {
+            follower.url_base = None;
+            follower.clock.reset();
+            follower.state = None;
+            if follower.media.take().is_some() {
+                follower.mediaplayer.stop();
+            }
+            self.broadcast_sync();
+        }
+    }
+
+    pub(super) fn clock_sample(&mut self, offset: i64, rtt: i64) {
+        let first = match &mut self.sync_role {
+            SyncRole::Follower(follower) => {
+                let first = follower.clock.offset().is_none();
+                follower.clock.add(offset, rtt);
+                first
+            },
+            _ => return,
+        };
+        // playback waits for the clock offset to be known
+        if first {
+            self.broadcast_sync();
+            self.apply_leader_state();
+        }
+    }
+
+    pub(super) fn leader_state(&mut self, state: SyncState) {
+        if let SyncRole::Follower(follower) = &mut self.sync_role {
+            follower.state = Some(state);
+            self.apply_leader_state();
+        }
+    }
+
+    /// Follows the volume of the default zone and corrects drift. Called on every tick.
+    pub(super) fn tick_follower(&mut self) {
+        if let SyncRole::Follower(_) = self.sync_role {
+            self.select_zone(DEFAULT_ZONE);
+            let volume = self.output_volume(None);
+            if let SyncRole::Follower(follower) = &self.sync_role {
+                let _ = follower.mediaplayer.set_volume(volume);
+            }
+            self.apply_leader_state();
+        }
+    }
+
+    /// Brings the follower's media player in line with the last state received from the leader
+    fn apply_leader_state(&mut self) {
+        let follower = match &mut self.sync_role {
+            SyncRole::Follower(follower) => follower,
+            _ => return,
+        };
+        let (state, offset, (url_base, media_token)) = match (&follower.state, follower.clock.offset(), &follower.url_base) {
+            (Some(state), Some(offset), Some(url_base)) => (state.clone(), offset, url_base.clone()),
+            _ => return,
+        };
+        let media = match &state.media {
+            Some(media) => media,
+            None => {
+                if follower.media.take().is_some() {
+                    follower.mediaplayer.stop();
+                }
+                return;
+            }
+        };
+        let target = state.target_position(offset);
+
+        if follower.media.as_ref() != Some(media) {
+            let url = if media.url.starts_with('/') { sync::authorized_url(&url_base, &media.url, &media_token) } else { media.url.clone() };
+            let md = match vlc::Media::new_location(&self.vlc_instance, &url) {
+                Some(md) => md,
+                None => {
+                    println!("Failed to create vlc media from leader url: {}", media.url);
+                    return;
+                }
+            };
+            if target > 0 {
+                unsafe { vlc_helpers::add_media_option(&md, &format!(":start-time={:.3}", target as f64 / 1000.0)) };
+            }
+            if let Some(end) = media.end {
+                unsafe { vlc_helpers::add_media_option(&md, &format!(":stop-time={:.3}", end as f64 / 1000.0)) };
+            }
+            follower.mediaplayer.set_media(&md);
+            let _ = follower.mediaplayer.set_rate(state.rate);
+            if state.playing && follower.mediaplayer.play().is_err() {
+                println!("Failed to play media of leader: {}", media.url);
+            }
+            follower.media = Some(media.clone());
+            follower.last_correction = Instant::now();
+            return;
+        }
+
+        // media the follower played to its end stays ended until the leader moves on
+        if state.playing != follower.mediaplayer.is_playing() && follower.mediaplayer.state() != vlc::State::Ended {
+            if state.playing {
+                let _ = follower.mediaplayer.play();
+            } else {
+                follower.mediaplayer.set_pause(true);
+            }
+            follower.last_correction = Instant::now();
+        }
+        if follower.mediaplayer.get_rate() != state.rate {
+            let _ = follower.mediaplayer.set_rate(state.rate);
+        }
+        if state.playing && follower.mediaplayer.state() == vlc::State::Playing && follower.last_correction.elapsed() >= CORRECTION_INTERVAL {
+            if let Some(time) = follower.mediaplayer.get_time() {
+                let drift = time - target;
+                if drift.abs() > MAX_DRIFT {
+                    println!("Follower is {} ms off the leader, seeking", drift);
+                    follower.mediaplayer.set_time(target);
+                    follower.last_correction = Instant::now();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Fires the sleep timer and alarms, and ramps up the volume of alarms.
+
+use std::time::{Duration, Instant};
+
+use actix::{AsyncContext, Context, SpawnHandle};
+
+use super::{Player, unix_now, unix_now_millis};
+use super::fade::FadeEnd;
+use crate::timers::{SleepTimer, SleepEnd, AlarmTarget, FiredAlarm};
+use crate::zones::DEFAULT_ZONE;
+use crate::websocket::{OutgoingMsg, PlaybackState};
+use crate::queue::QueueEntry;
+
+/// The actor timer of a sleep timer ending at a fixed time, along with what it was scheduled for
+pub struct ScheduledSleepTimer {
+    zone_id: u64,
+    time: i64,
+    fade_out: u64,
+    handle: SpawnHandle,
+}
+
+/// Raises the volume from zero after an alarm went off
+pub struct VolumeRamp {
+    started: Instant,
+    duration: Duration,
+    target: i32,
+}
+
+impl Player {
+    pub(super) fn broadcast_timers(&self) {
+        self.ws_connections.broadcast(OutgoingMsg::TimersChange{timers: self.timers.for_clients(&self.registered_media)});
+    }
+
+    /// Whether the sleep timer stops playback of the zone once the current media has ended
+    pub(super) fn sleep_after_current(&self) -> bool {
+        match self.timers.sleep_timer_of(self.zone.id).map(|sleep_timer| &sleep_timer.end) {
+            Some(SleepEnd::EndOfTrack) => true,
+            Some(SleepEnd::EndOfQueue) => self.zone.queue.next().is_none(),
+            _ => false,
+        }
+    }
+
+    /// Called once playback stopped because the current media ended
+    pub(super) fn playback_ended(&mut self) {
+        match self.timers.sleep_timer_of(self.zone.id).map(|sleep_timer| &sleep_timer.end) {
+            Some(SleepEnd::EndOfTrack) | Some(SleepEnd::EndOfQueue) => self.sleep_timer_fired(),
+            _ => {},
+        }
+    }
+
+    fn sleep_timer_fired(&mut self) {
+        println!("Sleep timer stops playback");
+        self.timers.cancel_sleep_timer();
+        self.timers.save(&self.store);
+        self.broadcast_timers();
+    }
+
+    /// Time left until the playing media ends, in milliseconds of wall clock time
+    fn remaining_wall_time(&self) -> Option<i64> {
+        let remaining = self.remaining_time()?;
+        Some((remaining as f64 / self.zone.mediaplayer.get_rate().max(0.1) as f64) as i64)
+    }
+
+    /// Fires due alarms in their zones. Called on every tick.
+    pub(super) fn tick_alarms(&mut self) {
+        let fired = self.timers.take_due_alarms(unix_now(), &self.registered_media);
+        if !fired.is_empty() {
+            self.timers.save(&self.store);
+            for alarm in fired {
+                self.fire_alarm(alarm);
+            }
+            self.broadcast_timers();
+        }
+    }
+
+    /// Keeps the actor timer of a sleep timer ending at a fixed time in line with the sleep timer.
+    /// Called after every message, as any of them may have set or cancelled the sleep timer.
+    pub(super) fn schedule_sleep_timer(&mut self, ctx: &mut Context<Self>) {
+        let wanted = match self.timers.sleep_timer() {
+            Some(SleepTimer{zone_id, end: SleepEnd::At{time}, fade_out}) => Some((*zone_id, *time, *fade_out)),
+            _ => None,
+        };
+        let scheduled = self.scheduled_sleep_timer.as_ref().map(|s| (s.zone_id, s.time, s.fade_out));
+        if wanted == scheduled {
+            return;
+        }
+        if let Some(scheduled) = self.scheduled_sleep_timer.take() {
+            ctx.cancel_future(scheduled.handle);
+        }
+        if let Some((zone_id, time, fade_out)) = wanted {
+            // the fade out ends when the timer runs out
+            let delay = (time.saturating_mul(1000).saturating_sub(unix_now_millis()).max(0) as u64).saturating_sub(fade_out);
+            let handle = ctx.run_later(Duration::from_millis(delay), move |player, ctx| {
+                player.scheduled_sleep_timer = None;
+                if player.select_zone(zone_id) {
+                    player.sleep_timer_due(time);
+                }
+                player.schedule_sleep_timer(ctx);
+            });
+            self.scheduled_sleep_timer = Some(ScheduledSleepTimer {
+                zone_id,
+                time,
+                fade_out,
+                handle,
+            });
+        }
+    }
+
+    /// Stops playback of the selected zone, fading out over what is left of the sleep timer ending at `time`
+    fn sleep_timer_due(&mut self, time: i64) {
+        let remaining = time.saturating_mul(1000).saturating_sub(unix_now_millis());
+        self.sleep_timer_fired();
+        match self.zone.playback_state {
+            PlaybackState::Playing{..} => {
+                if !self.fade_out(FadeEnd::Stop, remaining.max(0) as u64) {
+                    self.stop_now();
+                }
+            },
+            PlaybackState::Paused{..} => self.stop_now(),
+            PlaybackState::Stopped => {},
+        }
+    }
+
+    /// Starts the fade out of a sleep timer ending with the media, if it belongs to the selected zone. Called on every tick.
+    pub(super) fn tick_sleep_timer(&mut self) {
+        let (end, fade_out) = match self.timers.sleep_timer_of(self.zone.id) {
+            Some(sleep_timer) => (sleep_timer.end.clone(), sleep_timer.fade_out),
+            None => return,
+        };
+        match end {
+            // runs on a timer of the actor, see schedule_sleep_timer
+            SleepEnd::At{..} => {},
+            SleepEnd::EndOfTrack | SleepEnd::EndOfQueue => {
+                // without a fade out, playback stops once the media has ended
+                if fade_out == 0 || self.zone.fade.is_some() || !self.sleep_after_current() {
+                    return;
+                }
+                match self.remaining_wall_time() {
+                    Some(remaining) if remaining <= fade_out as i64 => {
+                        self.sleep_timer_fired();
+                        if !self.fade_out(FadeEnd::Stop, remaining.max(0) as u64) {
+                            self.stop_now();
+                        }
+                    },
+                    _ => {},
+                }
+            },
+        }
+    }
+
+    fn fire_alarm(&mut self, alarm: FiredAlarm) {
+        println!("Alarm {} went off", alarm.id);
+        // the zone may have been removed from the command line since the alarm was set
+        if !self.select_zone(alarm.zone_id) {
+            self.select_zone(DEFAULT_ZONE);
+        }
+        self.finish_fade();
+        if self.zone.muted {
+            self.zone.muted = false;
+            self.ws_connections.broadcast(OutgoingMsg::MuteChange{zone_id: self.zone.id, muted: false});
+        }
+        let volume = alarm.volume.min(self.settings.max_volume) as i32;
+        if alarm.ramp > 0 {
+            self.zone.volume = 0;
+            self.zone.volume_ramp = Some(VolumeRamp {
+                started: Instant::now(),
+                duration: Duration::from_secs(alarm.ramp),
+                target: volume,
+            });
+        } else {
+            self.zone.volume = volume;
+            self.zone.volume_ramp = None;
+        }
+        self.apply_volume();
+        self.ws_connections.broadcast(OutgoingMsg::VolumeChange{zone_id: self.zone.id, volume: self.zone.volume as u64});
+
+        match alarm.target {
+            AlarmTarget::Tracks{track_ids} => {
+                if track_ids.is_empty() {
+                    println!("None of the tracks of alarm {} are in the media directory anymore", alarm.id);
+                    return;
+                }
+                self.cancel_transition();
+                self.zone.queue.clear();
+                self.zone.queue.enqueue(track_ids.into_iter().map(QueueEntry::Media));
+                self.play_queue(0);
+            },
+            AlarmTarget::Station{station_id} => self.play_station(station_id),
+        }
+    }
+
+    /// Raises the volume of the selected zone after an alarm. Called on every tick.
+    pub(super) fn tick_volume_ramp(&mut self) {
+        let (progress, target) = match &self.zone.volume_ramp {
+            Some(ramp) => (
+                (ramp.started.elapsed().as_millis() as f32 / ramp.duration.as_millis().max(1) as f32).min(1.0),
+                ramp.target,
+            ),
+            None => return,
+        };
+        if progress >= 1.0 {
+            self.zone.volume_ramp = None;
+        }
+        let volume = (target as f32 * progress).round() as i32;
+        if volume != self.zone.volume {
+            self.zone.volume = volume;
+            self.apply_volume();
+            self.ws_connections.broadcast(OutgoingMsg::VolumeChange{zone_id: self.zone.id, volume: volume as u64});
+        }
+    }
+}
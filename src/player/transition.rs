@@ -0,0 +1,245 @@
+//! Gapless transitions and crossfades between consecutive queue entries.
+//! Shortly before the current entry ends, the next entry is opened paused on the standby media player,
+//! so it is already buffered when it has to start. Once started, the two media players swap roles.
+
+use std::time::{Duration, Instant};
+
+use vlc::MediaPlayerAudioEx;
+
+use super::{Player, create_media, create_episode_media};
+use crate::websocket::{OutgoingMsg, PlaybackState, CurrentMedia, MediaSource};
+use crate::queue::QueueEntry;
+use crate::vlc_helpers;
+
+/// How long before the start of a transition the next entry is opened, in milliseconds
+const PRELOAD_LEAD: i64 = 3_000;
+
+/// Compensates for the time the standby media player needs to output its first samples, in milliseconds
+const START_MARGIN: i64 = 30;
+
+/// Interval of volume updates during a crossfade
+const FADE_STEP: Duration = Duration::from_millis(50);
+
+/// Shortest wait in between ticks while waiting for a transition to start
+const MIN_TICK: Duration = Duration::from_millis(5);
+
+/// A transition to the next queue entry, prepared on the standby media player
+pub struct Transition {
+    queue_index: usize,
+    entry: QueueEntry,
+    /// Set once the standby media player has started playing
+    started: Option<Instant>,
+}
+
+impl Player {
+    fn transitions_enabled(&self) -> bool {
+        (self.settings.gapless || self.settings.crossfade > 0) && !self.sleep_after_current()
+    }
+
+    /// Time left until the end of the playing file entry or episode, in milliseconds
+    pub(super) fn remaining_time(&self) -> Option<i64> {
+        let current_media = match &self.zone.playback_state {
+            PlaybackState::Playing{current_media} => current_media,
+            _ => return None,
+        };
+        let entry_end = match current_media.source {
+            MediaSource::File => self.registered_media[&current_media.id].end,
+            MediaSource::Episode => None,
+            MediaSource::Station => return None,
+        };
+        let end = match entry_end {
+            Some(end) => end,
+            None => {
+                let file_length = unsafe { vlc_helpers::current_track_length(&self.zone.mediaplayer) };
+                if file_length <= 0 {
+                    return None;
+                }
+                file_length
+            }
+        };
+        let time = self.zone.mediaplayer.get_time()?;
+        Some(end - time)
+    }
+
+    /// Time left until the transition has to start, in milliseconds of wall clock time
+    fn until_transition_start(&self) -> Option<i64> {
+        // media time passes faster or slower than wall clock time unless playing at normal speed
+        let rate = self.zone.mediaplayer.get_rate().max(0.1) as f64;
+        self.remaining_time().map(|remaining| (remaining as f64 / rate) as i64 - self.settings.crossfade as i64 - START_MARGIN)
+    }
+
+    /// How long the player loop may wait for the next tick without starting a transition late
+    pub(super) fn tick_interval(&self, default: Duration) -> Duration {
+        match &self.zone.transition {
+            Some(Transition{started: Some(_), ..}) => FADE_STEP,
+            Some(Transition{started: None, ..}) => {
+                match self.until_transition_start() {
+                    Some(until_start) => default.min(Duration::from_millis(until_start.max(0) as u64)).max(MIN_TICK),
+                    None => default,
+                }
+            },
+            None => default,
+        }
+    }
+
+    /// Prepares, starts and advances transitions. Called on every tick while a file is playing.
+    pub(super) fn tick_transition(&mut self) {
+        if !self.transitions_enabled() {
+            return;
+        }
+        let until_start = match self.until_transition_start() {
+            Some(until_start) => until_start,
+            None => return,
+        };
+        let started = self.zone.transition.as_ref().map(|t| t.started);
+        match started {
+            None => {
+                if until_start <= PRELOAD_LEAD {
+                    self.prepare_transition();
+                }
+            },
+            Some(None) => {
+                if until_start <= 0 {
+                    self.start_transition();
+                }
+            },
+            Some(Some(started)) => {
+                let progress = started.elapsed().as_millis() as f64 / self.settings.crossfade.max(1) as f64;
+                if progress >= 1.0 {
+                    self.complete_transition();
+                } else {
+                    self.set_fade_volumes(progress);
+                }
+            },
+        }
+    }
+
+    /// Opens the next queue entry paused on the standby media player
+    fn prepare_transition(&mut self) {
+        let (queue_index, entry) = match self.zone.queue.next() {
+            Some(next) => next,
+            None => return,
+        };
+        let (md, source) = match entry {
+            QueueEntry::Media(media_id) => match self.registered_media.get(&media_id) {
+                Some(media) => (create_media(&self.vlc_instance, media, 0), MediaSource::File),
+                None => return,
+            },
+            // episodes continue where they were left off, as when played on their own
+            QueueEntry::Episode(episode_id) => match self.podcasts.episode(episode_id) {
+                Some(episode) => (create_episode_media(&self.vlc_instance, episode, episode.position), MediaSource::Episode),
+                None => return,
+            },
+        };
+        let md = match md {
+            Some(md) => md,
+            None => {
+                println!("Failed to create vlc media for the next queue entry: {:?}", entry);
+                return;
+            }
+        };
+        unsafe { vlc_helpers::add_media_option(&md, ":start-paused") };
+        self.zone.standby.set_media(&md);
+        let _ = self.zone.standby.set_rate(self.source_rate(source));
+
+        if self.zone.standby.play().is_err() {
+            println!("Failed to prepare the next queue entry: {:?}", entry);
+            return;
+        }
+        self.zone.transition = Some(Transition {
+            queue_index,
+            entry,
+            started: None,
+        });
+    }
+
+    /// The volume the media player is set to for the queue entry
+    fn entry_volume(&self, entry: QueueEntry) -> i32 {
+        match entry {
+            QueueEntry::Media(media_id) => self.output_volume(Some(media_id)),
+            QueueEntry::Episode(_) => self.output_volume(None),
+        }
+    }
+
+    /// The current media of the queue entry, once it plays on the active media player
+    fn entry_media(&self, entry: QueueEntry) -> CurrentMedia {
+        match entry {
+            QueueEntry::Media(media_id) => self.file_media(media_id),
+            QueueEntry::Episode(episode_id) => CurrentMedia::stream(episode_id, MediaSource::Episode, &self.zone.mediaplayer),
+        }
+    }
+
+    /// Starts playback on the standby media player. Without crossfade, the transition completes right away.
+    fn start_transition(&mut self) {
+        let crossfade = self.settings.crossfade;
+        let volume = match &self.zone.transition {
+            Some(transition) if crossfade == 0 => self.entry_volume(transition.entry),
+            _ => 0,
+        };
+        if let Some(transition) = &mut self.zone.transition {
+            let _ = self.zone.standby.set_volume(volume);
+            if self.zone.standby.play().is_err() {
+                println!("Failed to start the next queue entry");
+            }
+            transition.started = Some(Instant::now());
+        }
+        if crossfade == 0 {
+            self.complete_transition();
+        }
+    }
+
+    fn set_fade_volumes(&self, progress: f64) {
+        let next = match &self.zone.transition {
+            Some(transition) => transition.entry,
+            None => return,
+        };
+        let _ = self.zone.mediaplayer.set_volume((self.output_volume(self.current_file()) as f64 * (1.0 - progress)).round() as i32);
+        let _ = self.zone.standby.set_volume((self.entry_volume(next) as f64 * progress).round() as i32);
+    }
+
+    /// Stops the current entry and makes the standby media player the active one
+    pub(super) fn complete_transition(&mut self) {
+        let transition = match self.zone.transition.take() {
+            Some(transition) => transition,
+            None => return,
+        };
+        let volume = self.entry_volume(transition.entry);
+        if transition.started.is_none() {
+            let _ = self.zone.standby.set_volume(volume);
+            let _ = self.zone.standby.play();
+        }
+        self.finish_current();
+        self.zone.mediaplayer.stop();
+        std::mem::swap(&mut self.zone.mediaplayer, &mut self.zone.standby);
+        let _ = self.zone.mediaplayer.set_volume(volume);
+
+        self.zone.queue.position = Some(transition.queue_index);
+        self.zone.playback_state = PlaybackState::Playing{current_media: self.entry_media(transition.entry)};
+        self.zone.last_position_save = Instant::now();
+        self.ws_connections.broadcast(OutgoingMsg::PlaybackChange{zone_id: self.zone.id, playback_state: self.zone.playback_state.clone()});
+        self.ws_connections.broadcast(OutgoingMsg::QueueChange{zone_id: self.zone.id, queue: self.zone.queue.clone()});
+    }
+
+    /// Abandons a prepared transition, e.g. because the queue or the current media changed
+    pub(super) fn cancel_transition(&mut self) {
+        if let Some(transition) = self.zone.transition.take() {
+            self.zone.standby.stop();
+            if transition.started.is_some() {
+                let _ = self.zone.mediaplayer.set_volume(self.output_volume(self.current_file()));
+            }
+        }
+    }
+
+    /// Whether a transition has been prepared, so the next entry can start without a gap
+    pub(super) fn transition_prepared(&self) -> bool {
+        self.zone.transition.is_some()
+    }
+
+    /// Whether the standby media player is already audible
+    pub(super) fn transition_started(&self) -> bool {
+        match &self.zone.transition {
+            Some(transition) => transition.started.is_some(),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Switching between zones and moving the queue from one zone to another.
+//! The player keeps the selected zone in `zone` and all others in `other_zones`,
+//! so handling a message for a zone only takes selecting it first.
+
+use std::time::{Duration, Instant};
+
+use actix::Addr;
+
+use super::{Player, Zone, DEFAULT_VOLUME, TICK_INTERVAL};
+use crate::audio_output::AudioOutput;
+use crate::queue::Queue;
+use crate::store::Store;
+use crate::websocket::{PlaybackState, PlayerWs, MediaSource};
+use crate::zones::{ZoneConfig, ZoneState, DEFAULT_ZONE};
+
+impl Zone {
+    pub(super) fn new(id: u64, config: ZoneConfig, vlc_instance: &vlc::Instance, store: &Store) -> Self {
+        let audio_output = match config.audio_output {
+            Some(audio_output) => {
+                audio_output.save(store, &config.name);
+                audio_output
+            },
+            None => {
+                let audio_output = AudioOutput::load(store, &config.name);
+                // the persisted device may have been unplugged since
+                match audio_output.validate(vlc_instance) {
+                    Ok(()) => audio_output,
+                    Err(e) => {
+                        println!("Falling back to the default audio output in zone {}: {}", config.name, e);
+                        AudioOutput::default()
+                    }
+                }
+            }
+        };
+        Zone {
+            id,
+            name: config.name,
+            mediaplayer: vlc::MediaPlayer::new(vlc_instance).expect("Failed to create vlc media player from vlc instance. This is a bug."),
+            standby: vlc::MediaPlayer::new(vlc_instance).expect("Failed to create vlc media player from vlc instance. This is a bug."),
+            playback_state: PlaybackState::Stopped,
+            queue: Queue::default(),
+            audio_output,
+            volume: DEFAULT_VOLUME,
+            muted: false,
+            rate: 1.0,
+            transition: None,
+            fade: None,
+            volume_ramp: None,
+            last_position_save: Instant::now(),
+        }
+    }
+}
+
+impl Player {
+    /// Makes the zone the one messages are handled in. Returns false if there is no such zone.
+    pub(super) fn select_zone(&mut self, zone_id: u64) -> bool {
+        if zone_id == self.zone.id {
+            return true;
+        }
+        match self.other_zones.remove(&zone_id) {
+            Some(zone) => {
+                let previous = std::mem::replace(&mut self.zone, zone);
+                self.other_zones.insert(previous.id, previous);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn zone_ids(&self) -> Vec<u64> {
+        let mut zone_ids: Vec<u64> = self.other_zones.keys().cloned().collect();
+        zone_ids.push(self.zone.id);
+        zone_ids.sort();
+        zone_ids
+    }
+
+    /// Selects every zone in turn and calls `f` with it selected
+    pub(super) fn for_each_zone<F: FnMut(&mut Player)>(&mut self, mut f: F) {
+        for zone_id in self.zone_ids() {
+            self.select_zone(zone_id);
+            f(self);
+        }
+    }
+
+    /// How long the player loop may wait for the next tick without delaying fades and transitions in any zone
+    pub(super) fn next_tick_interval(&mut self) -> Duration {
+        let mut interval = TICK_INTERVAL;
+        self.for_each_zone(|player| interval = interval.min(player.tick_interval(player.fade_tick_interval(TICK_INTERVAL))));
+        interval
+    }
+
+    /// All zones along with their playback state, ordered by id
+    pub(super) fn zone_states(&mut self) -> Vec<ZoneState> {
+        let mut zone_states = Vec::new();
+        self.for_each_zone(|player| zone_states.push(ZoneState {
+            id: player.zone.id,
+            name: player.zone.name.clone(),
+            playback_state: player.current_playback_state(),
+            queue: player.zone.queue.clone(),
+            volume: player.zone.volume as u64,
+            muted: player.zone.muted,
+            audio_output: player.zone.audio_output.clone(),
+        }));
+        zone_states
+    }
+
+    /// Limits the zone updates the connection receives to a single zone, or all zones for None.
+    /// The connection is sent the state of the zone it subscribed to.
+    pub(super) fn subscribe_zone(&mut self, ws: Addr<PlayerWs>, zone_id: Option<u64>) -> Result<(), String> {
+        if let Some(zone_id) = zone_id {
+            if !self.zone_ids().contains(&zone_id) {
+                return Err(format!("Invalid zone_id: {}", zone_id));
+            }
+        }
+        self.ws_connections.subscribe(&ws, zone_id);
+        self.send_player_state(&ws, zone_id.unwrap_or(DEFAULT_ZONE));
+        Ok(())
+    }
+
+    /// Moves the queue of the selected zone to another zone, which takes over the playing queue entry where it is.
+    /// The queue of the other zone is replaced.
+    pub(super) fn move_queue(&mut self, to_zone_id: u64) {
+        if to_zone_id == self.zone.id || !self.zone_ids().contains(&to_zone_id) {
+            println!("Received queue move with invalid zone_id: {}", to_zone_id);
+            return;
+        }
+        self.finish_fade();
+        let playing_entry = match (&self.zone.playback_state, self.zone.queue.position) {
+            (PlaybackState::Playing{current_media}, Some(index)) if current_media.source != MediaSource::Station => {
+                Some((index, self.refresh_current_media(current_media).progress))
+            },
+            _ => None,
+        };
+        if playing_entry.is_some() {
+            self.stop_now();
+        }
+        self.cancel_transition();
+        let queue = std::mem::take(&mut self.zone.queue);
+        println!("Moving queue from zone {} to zone {}", self.zone.id, to_zone_id);
+        self.broadcast_queue();
+
+        self.select_zone(to_zone_id);
+        self.finish_fade();
+        self.cancel_transition();
+        self.zone.queue = queue;
+        match playing_entry {
+            Some((index, progress)) => self.play_queue_at(index, Some(progress)),
+            None => self.broadcast_queue(),
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! Podcast subscriptions, stored server side.
+//! Feeds are refreshed and episodes downloaded on a separate worker thread,
+//! which reports back to the player thread, the only owner of the podcast state.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use actix::Addr;
+use crossbeam_channel::{Sender};
+use serde::{Serialize, Deserialize};
+
+use crate::feed::{self, Feed};
+use crate::player::{Player, PlayerMsg};
+use crate::store::Store;
+
+/// Name of the file the subscriptions are persisted in
+const STORE_NAME: &str = "podcasts";
+
+/// Name of the directory inside the data directory episodes are downloaded to
+const DOWNLOAD_DIR: &str = "podcasts";
+
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+const READ_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Podcast {
+    pub id: u64,
+    pub url: String,
+    pub title: String,
+    /// Time of the last successful refresh as unix timestamp in seconds
+    pub last_refresh: Option<i64>,
+    /// Episodes, newest first
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Episode {
+    /// Unique across all podcasts
+    pub id: u64,
+    pub guid: String,
+    pub title: String,
+    /// Publication date as unix timestamp in seconds
+    pub published: Option<i64>,
+    pub url: String,
+    /// Path of the downloaded file, None if the episode is streamed
+    pub download: Option<String>,
+    pub played: bool,
+    /// Position playback will resume at, in milliseconds
+    pub position: i64,
+}
+
+impl Episode {
+    /// The location passed to libvlc, the downloaded file if available
+    pub fn location(&self) -> Location<'_> {
+        match &self.download {
+            Some(path) => Location::Path(path),
+            None => Location::Url(&self.url),
+        }
+    }
+}
+
+pub enum Location<'a> {
+    Path(&'a str),
+    Url(&'a str),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Podcasts {
+    next_podcast_id: u64,
+    next_episode_id: u64,
+    podcasts: Vec<Podcast>,
+}
+
+impl Podcasts {
+    pub fn load(store: &Store) -> Self {
+        store.load(STORE_NAME)
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+
+    pub fn list(&self) -> &Vec<Podcast> {
+        &self.podcasts
+    }
+
+    /// The id of the podcast the episode belongs to
+    pub fn podcast_of_episode(&self, episode_id: u64) -> Option<u64> {
+        self.podcasts.iter().find(|p| p.episodes.iter().any(|e| e.id == episode_id)).map(|p| p.id)
+    }
+
+    /// Maps the downloaded episodes to the names shown to clients alongside the registered media
+    pub fn download_names(&self) -> HashMap<u64, String> {
+        self.podcasts
+            .iter()
+            .flat_map(|p| p.episodes
+                .iter()
+                .filter(|e| e.download.is_some())
+                .map(move |e| (e.id, format!("{} - {}", p.title, e.title))))
+            .collect()
+    }
+
+    pub fn episode(&self, episode_id: u64) -> Option<&Episode> {
+        self.podcasts.iter().flat_map(|p| p.episodes.iter()).find(|e| e.id == episode_id)
+    }
+
+    pub fn episode_mut(&mut self, episode_id: u64) -> Option<&mut Episode> {
+        self.podcasts.iter_mut().flat_map(|p| p.episodes.iter_mut()).find(|e| e.id == episode_id)
+    }
+
+    /// Subscribes to a feed. The podcast has no episodes until its first refresh.
+    pub fn subscribe(&mut self, url: String) -> Result<&Podcast, String> {
+        let url = url.trim().to_string();
+        let lowercase_url = url.to_lowercase();
+        if !(lowercase_url.starts_with("http://") || lowercase_url.starts_with("https://")) {
+            return Err(format!("'{}' is not a http or https url", url));
+        }
+        if self.podcasts.iter().any(|p| p.url == url) {
+            return Err(format!("Already subscribed to '{}'", url));
+        }
+
+        let id = self.next_podcast_id;
+        self.next_podcast_id += 1;
+        self.podcasts.push(Podcast {
+            id,
+            title: url.clone(),
+            url,
+            last_refresh: None,
+            episodes: Vec::new(),
+        });
+        Ok(self.podcasts.last().expect("Podcast list is empty after push. This is a bug."))
+    }
+
+    /// Removes the podcast. Returns the removed podcast so its downloads can be cleaned up.
+    pub fn unsubscribe(&mut self, id: u64) -> Option<Podcast> {
+        let index = self.podcasts.iter().position(|p| p.id == id)?;
+        Some(self.podcasts.remove(index))
+    }
+
+    /// Merges a freshly fetched feed into the podcast.
+    /// Known episodes keep their played state, position and download, new episodes are added.
+    pub fn merge_feed(&mut self, podcast_id: u64, feed: Feed, now: i64) -> bool {
+        let next_episode_id = &mut self.next_episode_id;
+        let podcast = match self.podcasts.iter_mut().find(|p| p.id == podcast_id) {
+            Some(podcast) => podcast,
+            None => return false,
+        };
+        if let Some(title) = feed.title {
+            podcast.title = title;
+        }
+        for entry in feed.entries {
+            match podcast.episodes.iter_mut().find(|e| e.guid == entry.guid) {
+                Some(episode) => {
+                    episode.title = entry.title;
+                    episode.published = entry.published;
+                    episode.url = entry.url;
+                },
+                None => {
+                    podcast.episodes.push(Episode {
+                        id: *next_episode_id,
+                        guid: entry.guid,
+                        title: entry.title,
+                        published: entry.published,
+                        url: entry.url,
+                        download: None,
+                        played: false,
+                        position: 0,
+                    });
+                    *next_episode_id += 1;
+                }
+            }
+        }
+        podcast.episodes.sort_by_key(|episode| std::cmp::Reverse(episode.published));
+        podcast.last_refresh = Some(now);
+        true
+    }
+}
+
+/// Work handed to the podcast worker thread
+pub enum PodcastJob {
+    Refresh{podcast_id: u64, url: String},
+    Download{episode_id: u64, url: String, target: PathBuf},
+}
+
+/// Spawns the worker thread that fetches feeds and downloads episodes.
+/// Results are sent back to the player as PlayerMsg.
+pub fn spawn_worker(player: Addr<Player>) -> Sender<PodcastJob> {
+    let (sender, receiver) = crossbeam_channel::unbounded::<PodcastJob>();
+    thread::spawn(move || {
+        for job in receiver {
+            let msg = match job {
+                PodcastJob::Refresh{podcast_id, url} => {
+                    PlayerMsg::PodcastFetched{podcast_id, result: fetch_feed(&url)}
+                },
+                PodcastJob::Download{episode_id, url, target} => {
+                    PlayerMsg::EpisodeDownloaded{episode_id, result: download(&url, &target)}
+                },
+            };
+            if !player.connected() {
+                // the player is gone, nobody is interested in results anymore
+                break;
+            }
+            player.do_send(msg);
+        }
+    });
+    sender
+}
+
+fn get(url: &str) -> Result<ureq::Response, String> {
+    let response = ureq::get(url)
+        .timeout_connect(CONNECT_TIMEOUT_MS)
+        .timeout_read(READ_TIMEOUT_MS)
+        .call();
+    if let Some(e) = response.synthetic_error() {
+        return Err(format!("Request to {} failed: {}", url, e));
+    }
+    if !response.ok() {
+        return Err(format!("Request to {} failed with status {}", url, response.status()));
+    }
+    Ok(response)
+}
+
+fn fetch_feed(url: &str) -> Result<Feed, String> {
+    let body = get(url)?
+        .into_string()
+        .map_err(|e| format!("Failed to read feed {}: {}", url, e))?;
+    feed::parse(&body)
+}
+
+fn download(url: &str, target: &Path) -> Result<String, String> {
+    let response = get(url)?;
+    let write = || -> io::Result<()> {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // download to a temporary file, so an interrupted download is never mistaken for a complete one
+        let tmp_target = target.with_extension("part");
+        let mut file = fs::File::create(&tmp_target)?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp_target, target)
+    };
+    write().map_err(|e| format!("Failed to download {} to {}: {}", url, target.display(), e))?;
+    target
+        .to_str()
+        .map(|path| path.to_string())
+        .ok_or_else(|| format!("Download path {} is not valid unicode", target.display()))
+}
+
+/// The path an episode is downloaded to, keeping the file extension of the episode url if there is one
+pub fn download_target(store: &Store, podcast_id: u64, episode: &Episode) -> PathBuf {
+    let url_path = episode.url.split(['?', '#']).next().unwrap_or("");
+    let extension = Path::new(url_path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| extension.len() <= 5 && extension.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("mp3");
+    store.dir()
+        .join(DOWNLOAD_DIR)
+        .join(podcast_id.to_string())
+        .join(format!("{}.{}", episode.id, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    const EPISODE_BYTES: &[u8] = b"ID3 not really an mp3";
+
+    /// Serves `feed` at /feed.xml and the episode at /episodes/1.mp3 until the test ends, returning the base url
+    fn serve(feed: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test server");
+        let base = format!("http://{}", listener.local_addr().expect("Test server has no address"));
+        let feed = feed.replace("{base}", &base);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone test stream"));
+                let mut request_line = String::new();
+                let _ = reader.read_line(&mut request_line);
+                // skip the headers
+                let mut header = String::new();
+                while reader.read_line(&mut header).map(|n| n > 2).unwrap_or(false) {
+                    header.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or("");
+                let (status, body) = match path {
+                    "/feed.xml" => ("200 OK", feed.as_bytes()),
+                    "/episodes/1.mp3" => ("200 OK", EPISODE_BYTES),
+                    _ => ("404 Not Found", &b"not found"[..]),
+                };
+                let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                let _ = stream.write_all(body);
+            }
+        });
+        base
+    }
+
+    fn temp_store(name: &str) -> Store {
+        let dir = std::env::temp_dir().join(format!("fidelitas-test-{}-{}", std::process::id(), name));
+        Store::open(&dir).expect("Failed to open test store")
+    }
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Local podcast</title>
+    <item>
+      <title>Episode 1</title>
+      <guid>episode-1</guid>
+      <pubDate>Sat, 19 Oct 2019 07:00:00 +0000</pubDate>
+      <enclosure url="{base}/episodes/1.mp3" type="audio/mpeg" length="21"/>
+    </item>
+    <item>
+      <title>Episode 2</title>
+      <pubDate>Sun, 20 Oct 2019 07:00:00 +0000</pubDate>
+      <enclosure url="{base}/episodes/2.mp3" type="audio/mpeg" length="21"/>
+    </item>
+    <item>
+      <title>Show notes without audio</title>
+    </item>
+  </channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Atom podcast</title>
+  <entry>
+    <id>urn:episode:1</id>
+    <title>First</title>
+    <updated>2019-10-19T07:00:00Z</updated>
+    <link rel="alternate" href="{base}/episodes/1.html"/>
+    <link rel="enclosure" href="{base}/episodes/1.mp3" type="audio/mpeg"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn refreshes_rss_feed_from_server() {
+        let base = serve(RSS.to_string());
+        let feed = fetch_feed(&format!("{}/feed.xml", base)).expect("Failed to fetch feed");
+        assert_eq!(feed.title.as_deref(), Some("Local podcast"));
+        assert_eq!(feed.entries.len(), 2);
+        assert_eq!(feed.entries[0].guid, "episode-1");
+        assert_eq!(feed.entries[0].published, Some(1_571_468_400));
+        // without a guid the enclosure identifies the entry
+        assert_eq!(feed.entries[1].guid, format!("{}/episodes/2.mp3", base));
+
+        let mut podcasts = Podcasts::default();
+        let podcast_id = podcasts.subscribe(format!("{}/feed.xml", base)).expect("Failed to subscribe").id;
+        assert!(podcasts.merge_feed(podcast_id, feed.clone(), 100));
+        let podcast = &podcasts.list()[0];
+        assert_eq!(podcast.title, "Local podcast");
+        assert_eq!(podcast.last_refresh, Some(100));
+        // newest first
+        assert_eq!(podcast.episodes[0].title, "Episode 2");
+        let first_id = podcast.episodes[1].id;
+
+        // known episodes keep their id and state across refreshes
+        podcasts.episode_mut(first_id).expect("Episode missing").position = 5000;
+        assert!(podcasts.merge_feed(podcast_id, feed, 200));
+        assert_eq!(podcasts.list()[0].episodes.len(), 2);
+        assert_eq!(podcasts.episode(first_id).expect("Episode missing").position, 5000);
+    }
+
+    #[test]
+    fn refreshes_atom_feed_from_server() {
+        let base = serve(ATOM.to_string());
+        let feed = fetch_feed(&format!("{}/feed.xml", base)).expect("Failed to fetch feed");
+        assert_eq!(feed.title.as_deref(), Some("Atom podcast"));
+        assert_eq!(feed.entries.len(), 1);
+        assert_eq!(feed.entries[0].guid, "urn:episode:1");
+        assert_eq!(feed.entries[0].url, format!("{}/episodes/1.mp3", base));
+        assert_eq!(feed.entries[0].published, Some(1_571_468_400));
+    }
+
+    #[test]
+    fn reports_failed_requests() {
+        let base = serve(String::from("<html>not a feed</html>"));
+        assert!(fetch_feed(&format!("{}/missing.xml", base)).unwrap_err().contains("404"));
+        assert!(fetch_feed(&format!("{}/feed.xml", base)).is_err());
+    }
+
+    #[test]
+    fn downloads_episode_from_server() {
+        let base = serve(RSS.to_string());
+        let store = temp_store("downloads");
+        let mut podcasts = Podcasts::default();
+        let podcast_id = podcasts.subscribe(format!("{}/feed.xml", base)).expect("Failed to subscribe").id;
+        let feed = fetch_feed(&format!("{}/feed.xml", base)).expect("Failed to fetch feed");
+        podcasts.merge_feed(podcast_id, feed, 100);
+        let episode = podcasts.list()[0].episodes.iter().find(|e| e.guid == "episode-1").expect("Episode missing").clone();
+
+        let target = download_target(&store, podcast_id, &episode);
+        assert_eq!(target, store.dir().join("podcasts").join(podcast_id.to_string()).join(format!("{}.mp3", episode.id)));
+        let path = download(&episode.url, &target);
+        let contents = std::fs::read(&target);
+        let part_exists = target.with_extension("part").exists();
+        assert!(download(&format!("{}/episodes/404.mp3", base), &store.dir().join("404.mp3")).is_err());
+        std::fs::remove_dir_all(store.dir()).ok();
+
+        assert_eq!(path, Ok(target.to_str().expect("Test path is not unicode").to_string()));
+        assert_eq!(contents.expect("Download missing"), EPISODE_BYTES);
+        assert!(!part_exists);
+
+        podcasts.episode_mut(episode.id).expect("Episode missing").download = Some(String::from("/tmp/1.mp3"));
+        let names = podcasts.download_names();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[&episode.id], "Local podcast - Episode 1");
+    }
+}
@@ -0,0 +1,572 @@
+//! QR codes of urls, for opening the player on a phone.
+//! Encodes bytes with error correction level M, choosing the smallest version that fits,
+//! and renders the code for the terminal, as SVG and as PNG.
+
+use std::io::Write;
+
+/// Error correction codewords per block and number of blocks per version, for error correction level M
+const ECC_CODEWORDS_PER_BLOCK: [usize; 41] = [
+    0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26,
+    26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+];
+const ERROR_CORRECTION_BLOCKS: [usize; 41] = [
+    0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16,
+    17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+];
+/// The format bits of error correction level M
+const ECL_FORMAT_BITS: u32 = 0;
+
+/// Light modules around the code, which scanners need to find it
+const QUIET_ZONE: usize = 4;
+/// Pixels per module in PNG images
+const PNG_SCALE: usize = 8;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    is_function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes the bytes in byte mode, None if they are too long for any version
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        let version = (1..=40).find(|version| {
+            let count_bits = if *version < 10 { 8 } else { 16 };
+            data.len() < (1 << count_bits) && 4 + count_bits + data.len() * 8 <= data_codewords(*version) * 8
+        })?;
+        let count_bits = if version < 10 { 8 } else { 16 };
+
+        let mut bits = BitBuffer::default();
+        // byte mode
+        bits.push(0b0100, 4);
+        bits.push(data.len() as u32, count_bits);
+        for byte in data {
+            bits.push(u32::from(*byte), 8);
+        }
+        let capacity = data_codewords(version) * 8;
+        let terminator = (capacity - bits.len()).min(4);
+        bits.push(0, terminator);
+        let padding = (8 - bits.len() % 8) % 8;
+        bits.push(0, padding);
+        let mut codewords = bits.into_bytes();
+        for pad in [0xec, 0x11].iter().cycle() {
+            if codewords.len() >= data_codewords(version) {
+                break;
+            }
+            codewords.push(*pad);
+        }
+
+        let size = version * 4 + 17;
+        let mut qr = QrCode {
+            size,
+            modules: vec![false; size * size],
+            is_function: vec![false; size * size],
+        };
+        qr.draw_function_patterns(version);
+        qr.draw_codewords(&add_error_correction(&codewords, version));
+
+        let best_mask = (0..8).min_by_key(|mask| {
+            qr.apply_mask(*mask);
+            qr.draw_format_bits(*mask);
+            let penalty = qr.penalty();
+            // masking twice restores the modules
+            qr.apply_mask(*mask);
+            penalty
+        }).expect("There are always 8 masks. This is a bug.");
+        qr.apply_mask(best_mask);
+        qr.draw_format_bits(best_mask);
+        Some(qr)
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// Whether the module is dark, light for the quiet zone around the code
+    fn get_bordered(&self, x: isize, y: isize) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.size && (y as usize) < self.size && self.get(x as usize, y as usize)
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.is_function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        self.draw_finder_pattern(3, 3);
+        self.draw_finder_pattern(size - 4, 3);
+        self.draw_finder_pattern(3, size - 4);
+
+        let positions = alignment_pattern_positions(version);
+        let count = positions.len();
+        for i in 0..count {
+            for j in 0..count {
+                // the corners with finder patterns
+                if (i == 0 && (j == 0 || j == count - 1)) || (i == count - 1 && j == 0) {
+                    continue;
+                }
+                self.draw_alignment_pattern(positions[i], positions[j]);
+            }
+        }
+        // reserves the format bits, they are drawn once the mask is chosen
+        self.draw_format_bits(0);
+        self.draw_version(version);
+    }
+
+    fn draw_finder_pattern(&mut self, x: usize, y: usize) {
+        for dy in -4isize..=4 {
+            for dx in -4isize..=4 {
+                let (xx, yy) = (x as isize + dx, y as isize + dy);
+                if xx < 0 || yy < 0 || xx >= self.size as isize || yy >= self.size as isize {
+                    continue;
+                }
+                let distance = dx.abs().max(dy.abs());
+                self.set_function(xx as usize, yy as usize, distance != 2 && distance != 4);
+            }
+        }
+    }
+
+    fn draw_alignment_pattern(&mut self, x: usize, y: usize) {
+        for dy in -2isize..=2 {
+            for dx in -2isize..=2 {
+                self.set_function((x as isize + dx) as usize, (y as isize + dy) as usize, dx.abs().max(dy.abs()) != 1);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let data = ECL_FORMAT_BITS << 3 | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        let size = self.size;
+
+        // around the top left finder pattern
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        // split between the other finder patterns
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_version(&mut self, version: usize) {
+        if version < 7 {
+            return;
+        }
+        let mut remainder = version as u32;
+        for _ in 0..12 {
+            remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+        }
+        let bits = (version as u32) << 12 | remainder;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    /// Places the codewords in the zig zag pattern of two module wide columns, from the bottom right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut bit_index = 0;
+        let mut right = size as isize - 1;
+        while right >= 1 {
+            // the vertical timing pattern
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right as usize - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vertical } else { vertical };
+                    if !self.is_function[y * size + x] && bit_index < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[bit_index >> 3] >> (7 - (bit_index & 7))) & 1 != 0;
+                        bit_index += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        let size = self.size;
+        for y in 0..size {
+            for x in 0..size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.is_function[y * size + x] {
+                    self.modules[y * size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to scan, following the rules of the standard
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let rows: Vec<Vec<bool>> = (0..size).map(|y| (0..size).map(|x| self.get(x, y)).collect()).collect();
+        let columns: Vec<Vec<bool>> = (0..size).map(|x| (0..size).map(|y| self.get(x, y)).collect()).collect();
+        for line in rows.iter().chain(columns.iter()) {
+            // runs of five or more modules of the same color
+            let mut run = 1;
+            for i in 1..=size {
+                if i < size && line[i] == line[i - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        penalty += 3 + run - 5;
+                    }
+                    run = 1;
+                }
+            }
+            // patterns looking like finder patterns, with four light modules on either side
+            let at = |i: isize| i >= 0 && (i as usize) < size && line[i as usize];
+            for start in 0..size as isize - 6 {
+                let finder_like = [true, false, true, true, true, false, true]
+                    .iter()
+                    .enumerate()
+                    .all(|(offset, dark)| at(start + offset as isize) == *dark);
+                if finder_like && ((start - 4..start).all(|i| !at(i)) || (start + 7..start + 11).all(|i| !at(i))) {
+                    penalty += 40;
+                }
+            }
+        }
+        // blocks of two by two modules of the same color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.get(x, y);
+                if color == self.get(x + 1, y) && color == self.get(x, y + 1) && color == self.get(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        // imbalance of dark and light modules
+        let dark = self.modules.iter().filter(|dark| **dark).count() as isize;
+        let total = (size * size) as isize;
+        let k = ((dark * 20 - total * 10).abs() + total - 1) / total - 1;
+        penalty + k as usize * 10
+    }
+
+    /// Renders the code with half blocks, two rows of modules per line.
+    /// Light modules are drawn, so the code shows on the usual dark terminal background.
+    pub fn to_terminal(&self) -> String {
+        let border = QUIET_ZONE as isize;
+        let end = self.size as isize + border;
+        let mut output = String::new();
+        let mut y = -border;
+        while y < end {
+            for x in -border..end {
+                let top_light = !self.get_bordered(x, y);
+                let bottom_light = y + 1 < end && !self.get_bordered(x, y + 1);
+                output.push(match (top_light, bottom_light) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            output.push('\n');
+            y += 2;
+        }
+        output
+    }
+
+    pub fn to_svg(&self) -> String {
+        let dimension = self.size + QUIET_ZONE * 2;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.get(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+                }
+            }
+        }
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {0} {0}\" shape-rendering=\"crispEdges\">\
+            <rect width=\"{0}\" height=\"{0}\" fill=\"#ffffff\"/><path d=\"{1}\" fill=\"#000000\"/></svg>",
+            dimension, path
+        )
+    }
+
+    /// A grayscale PNG image with `PNG_SCALE` pixels per module
+    pub fn to_png(&self) -> Vec<u8> {
+        let dimension = (self.size + QUIET_ZONE * 2) * PNG_SCALE;
+        // every row starts with the filter type, none
+        let mut pixels = Vec::with_capacity((dimension + 1) * dimension);
+        for row in 0..dimension {
+            pixels.push(0);
+            let y = (row / PNG_SCALE) as isize - QUIET_ZONE as isize;
+            for column in 0..dimension {
+                let x = (column / PNG_SCALE) as isize - QUIET_ZONE as isize;
+                pixels.push(if self.get_bordered(x, y) { 0 } else { 255 });
+            }
+        }
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&pixels).expect("Writing to a vector can't fail. This is a bug.");
+        let compressed = encoder.finish().expect("Writing to a vector can't fail. This is a bug.");
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(dimension as u32).to_be_bytes());
+        header.extend_from_slice(&(dimension as u32).to_be_bytes());
+        // 8 bit grayscale, deflate, adaptive filtering, no interlacing
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        push_png_chunk(&mut png, b"IHDR", &header);
+        push_png_chunk(&mut png, b"IDAT", &compressed);
+        push_png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn push_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    png.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+#[derive(Default)]
+struct BitBuffer {
+    bits: Vec<bool>,
+}
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            self.bits.push((value >> i) & 1 != 0);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bits.chunks(8)
+            .map(|chunk| chunk.iter().fold(0u8, |byte, bit| byte << 1 | *bit as u8))
+            .collect()
+    }
+}
+
+/// Modules available for data and error correction, excluding function patterns and format and version information
+fn raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignment_count = version / 7 + 2;
+        modules -= (25 * alignment_count - 10) * alignment_count - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_data_modules(version) / 8 - ECC_CODEWORDS_PER_BLOCK[version] * ERROR_CORRECTION_BLOCKS[version]
+}
+
+fn alignment_pattern_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2 };
+    let size = version * 4 + 17;
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// Splits the data into blocks, appends their Reed-Solomon error correction codewords and interleaves them
+fn add_error_correction(data: &[u8], version: usize) -> Vec<u8> {
+    let block_count = ERROR_CORRECTION_BLOCKS[version];
+    let ecc_length = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = raw_data_modules(version) / 8;
+    let short_blocks = block_count - raw_codewords % block_count;
+    let short_block_length = raw_codewords / block_count;
+    let divisor = reed_solomon_divisor(ecc_length);
+
+    let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(block_count);
+    let mut offset = 0;
+    for i in 0..block_count {
+        let data_length = short_block_length - ecc_length + if i < short_blocks { 0 } else { 1 };
+        let mut block = data[offset..offset + data_length].to_vec();
+        offset += data_length;
+        let ecc = reed_solomon_remainder(&block, &divisor);
+        // a placeholder, so short and long blocks line up
+        if i < short_blocks {
+            block.push(0);
+        }
+        block.extend_from_slice(&ecc);
+        blocks.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_length - ecc_length || j >= short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root: u8 = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (value, coefficient) in result.iter_mut().zip(divisor.iter()) {
+            *value ^= gf_multiply(*coefficient, factor);
+        }
+    }
+    result
+}
+
+/// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u8 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1d);
+        z ^= ((y >> i) & 1) * x;
+    }
+    z
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The mask the format bits around the top left finder pattern specify, checking the error correction level
+    fn format_mask(qr: &QrCode) -> u32 {
+        let mut positions: Vec<(usize, usize)> = (0..6).map(|i| (8, i)).collect();
+        positions.extend_from_slice(&[(8, 7), (8, 8), (7, 8)]);
+        positions.extend((9..15).map(|i| (14 - i, 8)));
+        let bits = positions.iter().enumerate().fold(0, |bits, (i, (x, y))| bits | (qr.get(*x, *y) as u32) << i) ^ 0x5412;
+        assert_eq!(bits >> 13, ECL_FORMAT_BITS);
+        (bits >> 10) & 0b111
+    }
+
+    #[test]
+    fn computes_reed_solomon_error_correction() {
+        // "HELLO WORLD" in alphanumeric mode, version 1-M
+        let data = [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+        let ecc = reed_solomon_remainder(&data, &reed_solomon_divisor(10));
+        assert_eq!(ecc, vec![196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+        assert_eq!(add_error_correction(&data, 1), [&data[..], &ecc[..]].concat());
+    }
+
+    #[test]
+    fn codewords_are_multiples_of_the_generator() {
+        let data: Vec<u8> = (0..44).map(|i| (i * 37 % 256) as u8).collect();
+        let ecc = reed_solomon_remainder(&data, &reed_solomon_divisor(26));
+        let codeword: Vec<u8> = data.iter().chain(ecc.iter()).cloned().collect();
+        // the generator's roots are 2^0 to 2^25, so the codeword evaluates to zero at each of them
+        let mut root = 1;
+        for _ in 0..26 {
+            let value = codeword.iter().fold(0, |value, coefficient| gf_multiply(value, root) ^ coefficient);
+            assert_eq!(value, 0);
+            root = gf_multiply(root, 0x02);
+        }
+    }
+
+    #[test]
+    fn multiplies_in_the_galois_field() {
+        assert_eq!(gf_multiply(0x80, 0x02), 0x1d);
+        assert_eq!(gf_multiply(0x53, 0x01), 0x53);
+        assert_eq!(gf_multiply(0x53, 0x00), 0x00);
+        assert_eq!(gf_multiply(0x57, 0x83), gf_multiply(0x83, 0x57));
+    }
+
+    #[test]
+    fn matches_the_capacity_tables() {
+        assert_eq!(data_codewords(1), 16);
+        assert_eq!(data_codewords(2), 28);
+        assert_eq!(data_codewords(10), 216);
+        assert_eq!(data_codewords(40), 2334);
+        assert_eq!(alignment_pattern_positions(1), Vec::<usize>::new());
+        assert_eq!(alignment_pattern_positions(7), vec![6, 22, 38]);
+        assert_eq!(alignment_pattern_positions(32), vec![6, 34, 60, 86, 112, 138]);
+        assert_eq!(alignment_pattern_positions(40), vec![6, 30, 58, 86, 114, 142, 170]);
+    }
+
+    #[test]
+    fn picks_the_smallest_version() {
+        assert_eq!(QrCode::encode(&[b'a'; 14]).map(|qr| qr.size), Some(21));
+        assert_eq!(QrCode::encode(&[b'a'; 15]).map(|qr| qr.size), Some(25));
+        assert_eq!(QrCode::encode(&[b'a'; 2331]).map(|qr| qr.size), Some(177));
+        assert!(QrCode::encode(&[b'a'; 2332]).is_none());
+    }
+
+    #[test]
+    fn picks_the_mask_with_the_lowest_penalty() {
+        for url in &["http://192.168.1.20:8088/player", "https://[2001:db8::2]:8443/player?token=0123456789abcdef"] {
+            let mut qr = QrCode::encode(url.as_bytes()).expect("Failed to encode url");
+            let mask = format_mask(&qr);
+            let penalty = qr.penalty();
+            qr.apply_mask(mask);
+            for other in 0..8 {
+                qr.apply_mask(other);
+                qr.draw_format_bits(other);
+                assert_eq!(format_mask(&qr), other);
+                assert!(qr.penalty() >= penalty, "Mask {} has a lower penalty than the chosen mask {}", other, mask);
+                qr.apply_mask(other);
+            }
+        }
+    }
+}
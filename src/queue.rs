@@ -0,0 +1,113 @@
+//! The play queue: an ordered list of registered media and podcast episodes, and the entry currently playing
+
+use serde::Serialize;
+
+/// Registered media and podcast episodes have separate id spaces
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag="source", content="id")]
+pub enum QueueEntry {
+    Media(u64),
+    Episode(u64),
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Queue {
+    pub entries: Vec<QueueEntry>,
+    /// Index of the entry currently playing, None if the current media was not started from the queue
+    pub position: Option<usize>,
+}
+
+impl Queue {
+    pub fn enqueue<I: IntoIterator<Item = QueueEntry>>(&mut self, entries: I) {
+        self.entries.extend(entries);
+    }
+
+    /// Removes the entry at `index`. Returns false if there is no such entry.
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.entries.len() {
+            return false;
+        }
+        self.entries.remove(index);
+        self.position = match self.position {
+            Some(position) if position == index => None,
+            Some(position) if position > index => Some(position - 1),
+            position => position,
+        };
+        true
+    }
+
+    /// Removes the entries `keep` returns false for. Returns false if nothing was removed.
+    pub fn retain<F: Fn(&QueueEntry) -> bool>(&mut self, keep: F) -> bool {
+        let len = self.entries.len();
+        for index in (0..len).rev() {
+            if !keep(&self.entries[index]) {
+                self.remove(index);
+            }
+        }
+        self.entries.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.position = None;
+    }
+
+    pub fn get(&self, index: usize) -> Option<QueueEntry> {
+        self.entries.get(index).cloned()
+    }
+
+    /// The entry following the current one, if playback was started from the queue
+    pub fn next(&self) -> Option<(usize, QueueEntry)> {
+        let index = self.position? + 1;
+        self.get(index).map(|entry| (index, entry))
+    }
+
+    pub fn previous(&self) -> Option<(usize, QueueEntry)> {
+        let index = self.position?.checked_sub(1)?;
+        self.get(index).map(|entry| (index, entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(position: Option<usize>) -> Queue {
+        let mut queue = Queue::default();
+        queue.enqueue(vec![QueueEntry::Media(1), QueueEntry::Episode(1), QueueEntry::Media(2), QueueEntry::Episode(2)]);
+        queue.position = position;
+        queue
+    }
+
+    #[test]
+    fn navigates_mixed_entries() {
+        let queue = queue(Some(1));
+        assert_eq!(queue.next(), Some((2, QueueEntry::Media(2))));
+        assert_eq!(queue.previous(), Some((0, QueueEntry::Media(1))));
+        assert_eq!(Queue::default().next(), None);
+        assert_eq!(self::queue(None).next(), None);
+    }
+
+    #[test]
+    fn removing_entries_keeps_the_position() {
+        let mut queue = queue(Some(2));
+        assert!(queue.retain(|entry| match entry {
+            QueueEntry::Episode(_) => false,
+            QueueEntry::Media(_) => true,
+        }));
+        assert_eq!(queue.entries, vec![QueueEntry::Media(1), QueueEntry::Media(2)]);
+        assert_eq!(queue.position, Some(1));
+        assert!(!queue.retain(|_| true));
+
+        // removing the playing entry leaves the queue without a position
+        assert!(queue.remove(1));
+        assert_eq!(queue.position, None);
+        assert!(!queue.remove(5));
+    }
+
+    #[test]
+    fn serializes_the_source_of_entries() {
+        let json = serde_json::to_string(&queue(None).entries[..2]).expect("Failed to serialize queue");
+        assert_eq!(json, r#"[{"source":"Media","id":1},{"source":"Episode","id":1}]"#);
+    }
+}
@@ -0,0 +1,124 @@
+//! Limiting the rate of incoming websocket messages, per connection and across all connections.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Allows a steady rate of messages and bursts up to its capacity
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_second: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Bursts may be twice the rate per second
+    pub fn new(per_second: u32) -> Self {
+        let capacity = f64::from(per_second) * 2.0;
+        TokenBucket {
+            per_second: f64::from(per_second),
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one left
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn has_token_at(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+    }
+}
+
+/// Takes a token from both buckets if both have one left, otherwise takes none,
+/// so a message refused by one limit doesn't count against the other
+pub fn try_take_both(first: &mut TokenBucket, second: &mut TokenBucket) -> bool {
+    try_take_both_at(first, second, Instant::now())
+}
+
+fn try_take_both_at(first: &mut TokenBucket, second: &mut TokenBucket, now: Instant) -> bool {
+    first.has_token_at(now) && second.has_token_at(now) && first.try_take_at(now) && second.try_take_at(now)
+}
+
+/// The rate limits every connection is created with
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Messages per second of a single connection
+    pub per_connection: u32,
+    /// Shared by all connections, so many connections can't flood the player together
+    pub global: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimits {
+    pub fn new(per_connection: u32, global: u32) -> Self {
+        RateLimits {
+            per_connection,
+            global: Arc::new(Mutex::new(TokenBucket::new(global))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn allows_bursts_up_to_twice_the_rate() {
+        let mut bucket = TokenBucket::new(5);
+        let now = bucket.updated;
+        assert_eq!((0..20).filter(|_| bucket.try_take_at(now)).count(), 10);
+        assert!(!bucket.try_take_at(now));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let mut bucket = TokenBucket::new(5);
+        let start = bucket.updated;
+        while bucket.try_take_at(start) {}
+
+        // a token every 200ms
+        assert!(!bucket.try_take_at(start + Duration::from_millis(100)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(200)));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(200)));
+
+        // an idle bucket fills up to its capacity, not beyond
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..20).filter(|_| bucket.try_take_at(later)).count(), 10);
+    }
+
+    #[test]
+    fn takes_from_both_buckets_or_neither() {
+        let mut connection = TokenBucket::new(5);
+        let mut global = TokenBucket::new(1);
+        let now = connection.updated.max(global.updated);
+        assert_eq!((0..5).filter(|_| try_take_both_at(&mut connection, &mut global, now)).count(), 2);
+        // the refusals of the global bucket leave the connection's tokens alone
+        assert_eq!((0..20).filter(|_| connection.try_take_at(now)).count(), 8);
+        assert!(!try_take_both_at(&mut connection, &mut global, now));
+    }
+
+    #[test]
+    fn a_zero_rate_blocks_everything() {
+        let mut bucket = TokenBucket::new(0);
+        let now = bucket.updated;
+        assert!(!bucket.try_take_at(now + Duration::from_secs(10)));
+    }
+}
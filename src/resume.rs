@@ -0,0 +1,132 @@
+//! Resume positions for long media files such as audiobooks and lectures.
+//! Positions are keyed by file path and offset rather than media id,
+//! as media ids are reassigned whenever the media directory is scanned.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::media_fs::MediaEntry;
+use crate::store::Store;
+
+/// Name of the file the positions are persisted in
+const STORE_NAME: &str = "resume_positions";
+
+/// Positions closer than this to the start are not worth resuming at, in milliseconds
+const MIN_POSITION: i64 = 5_000;
+
+/// Positions closer than this to the end count as finished, in milliseconds
+const FINISHED_MARGIN: i64 = 10_000;
+
+/// Decides which media files remember their position
+#[derive(Clone, Debug)]
+pub struct ResumePolicy {
+    /// Files at least this long remember their position, in milliseconds. None disables the length rule.
+    min_length: Option<i64>,
+    /// Files inside these directories always remember their position
+    directories: Vec<PathBuf>,
+}
+
+impl ResumePolicy {
+    pub fn new(min_length: Option<i64>, directories: &[PathBuf]) -> Self {
+        ResumePolicy {
+            min_length,
+            directories: directories
+                .iter()
+                .map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.clone()))
+                .collect(),
+        }
+    }
+
+    /// `length` is the length of the entry in milliseconds, if known
+    pub fn applies(&self, entry: &MediaEntry, length: Option<i64>) -> bool {
+        let long_enough = match (self.min_length, length) {
+            (Some(min_length), Some(length)) => length >= min_length,
+            _ => false,
+        };
+        long_enough || self.in_directories(Path::new(&entry.path))
+    }
+
+    fn in_directories(&self, path: &Path) -> bool {
+        if self.directories.is_empty() {
+            return false;
+        }
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.directories.iter().any(|dir| path.starts_with(dir))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SavedPosition {
+    /// Position relative to the start of the entry, in milliseconds
+    position: i64,
+    /// Unix timestamp in seconds of the last update
+    updated: i64,
+}
+
+/// Resume position of a registered media, as reported to clients
+#[derive(Clone, Debug, Serialize)]
+pub struct ResumeInfo {
+    pub id: u64,
+    pub position: i64,
+    pub updated: i64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ResumePositions {
+    positions: HashMap<String, SavedPosition>,
+}
+
+impl ResumePositions {
+    pub fn load(store: &Store) -> Self {
+        store.load(STORE_NAME)
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+
+    /// The position to resume the entry at, relative to the start of the entry
+    pub fn get(&self, entry: &MediaEntry) -> Option<i64> {
+        self.positions.get(&entry.key()).map(|saved| saved.position)
+    }
+
+    /// Updates the position of the entry. Positions near the start are dropped,
+    /// positions near the end of the entry mark it as finished and remove the position.
+    /// Returns whether anything changed.
+    pub fn update(&mut self, entry: &MediaEntry, position: i64, length: Option<i64>, now: i64) -> bool {
+        let finished = match length {
+            Some(length) => length - position < FINISHED_MARGIN,
+            None => false,
+        };
+        if position < MIN_POSITION || finished {
+            return self.remove(entry);
+        }
+        self.positions.insert(entry.key(), SavedPosition {
+            position,
+            updated: now,
+        });
+        true
+    }
+
+    pub fn remove(&mut self, entry: &MediaEntry) -> bool {
+        self.positions.remove(&entry.key()).is_some()
+    }
+
+    /// The resume positions of the registered media, most recently listened to first
+    pub fn for_clients(&self, registered_media: &HashMap<u64, MediaEntry>) -> Vec<ResumeInfo> {
+        let mut infos: Vec<ResumeInfo> = registered_media
+            .iter()
+            .filter_map(|(id, entry)| {
+                self.positions.get(&entry.key()).map(|saved| ResumeInfo {
+                    id: *id,
+                    position: saved.position,
+                    updated: saved.updated,
+                })
+            })
+            .collect();
+        infos.sort_by_key(|info| std::cmp::Reverse(info.updated));
+        infos
+    }
+}
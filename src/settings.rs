@@ -0,0 +1,88 @@
+//! Player settings that can be changed by clients and are persisted across restarts
+
+use serde::{Serialize, Deserialize};
+
+use crate::loudness::ReplayGainMode;
+use crate::store::Store;
+
+/// Name of the file the settings are persisted in
+const STORE_NAME: &str = "player_settings";
+
+/// Longest crossfade accepted from clients, in milliseconds
+pub const MAX_CROSSFADE: u64 = 12_000;
+
+/// Largest preamp accepted from clients, in either direction, in dB
+pub const MAX_PREAMP: f32 = 15.0;
+
+/// Highest maximum volume accepted from clients, libvlc amplifies up to 200%
+pub const MAX_VOLUME_LIMIT: u64 = 200;
+
+/// Longest pause, resume and stop fade accepted from clients, in milliseconds
+pub const MAX_FADE: u64 = 5_000;
+
+/// Range the logarithmic volume curve spans below 100%, in dB
+const LOGARITHMIC_RANGE: f32 = 40.0;
+
+/// How the volume chosen by clients maps to the volume of the media player
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VolumeCurve {
+    Linear,
+    /// Equal steps of the volume are equal steps in loudness
+    Logarithmic,
+}
+
+impl VolumeCurve {
+    /// The media player volume in percent for the client volume
+    pub fn apply(self, volume: u64) -> f32 {
+        match self {
+            VolumeCurve::Linear => volume as f32,
+            VolumeCurve::Logarithmic if volume == 0 => 0.0,
+            VolumeCurve::Logarithmic => 100.0 * 10f32.powf((volume as f32 / 100.0 - 1.0) * LOGARITHMIC_RANGE / 20.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerSettings {
+    /// Start the next queue entry without a gap
+    pub gapless: bool,
+    /// Duration of the crossfade between queue entries in milliseconds, 0 disables crossfading
+    pub crossfade: u64,
+    /// Which ReplayGain values are applied, if any
+    pub replay_gain: ReplayGainMode,
+    /// Added to the ReplayGain of every file, in dB
+    pub preamp: f32,
+    /// Reduce the gain of files that would otherwise clip
+    pub prevent_clipping: bool,
+    /// Highest volume clients may set
+    pub max_volume: u64,
+    pub volume_curve: VolumeCurve,
+    /// Duration of the volume fade when pausing, resuming and stopping in milliseconds, 0 disables fading
+    pub fade: u64,
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        PlayerSettings {
+            gapless: true,
+            crossfade: 0,
+            replay_gain: ReplayGainMode::Off,
+            preamp: 0.0,
+            prevent_clipping: true,
+            max_volume: 100,
+            volume_curve: VolumeCurve::Linear,
+            fade: 200,
+        }
+    }
+}
+
+impl PlayerSettings {
+    pub fn load(store: &Store) -> Self {
+        store.load(STORE_NAME)
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+}
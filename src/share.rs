@@ -0,0 +1,55 @@
+//! Sharing the player url, e.g. with guests scanning a QR code on their phones.
+
+use serde::{Serialize, Deserialize};
+
+use crate::qr::QrCode;
+
+const PLAYER_PATH: &str = "/player";
+
+/// Query parameters of `/api/share`
+#[derive(Debug, Deserialize)]
+pub struct ShareQuery {
+    /// json, svg or png, defaults to json
+    pub format: Option<String>,
+    /// Whether the urls include the guest token
+    #[serde(default)]
+    pub guest: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Share {
+    /// The player urls, the preferred one first
+    pub urls: Vec<String>,
+    /// A QR code of the preferred url
+    pub qr_svg: String,
+}
+
+/// Guest tokens are put into urls unescaped, so they are limited to characters that need no escaping
+pub fn valid_token(token: &str) -> Result<(), String> {
+    if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("'{}' is not a valid token, only letters, digits, '-' and '_' are allowed", token));
+    }
+    Ok(())
+}
+
+/// The url of the player page below the advertised url, with the guest token if given
+pub fn player_url(advertised_url: &str, guest_token: Option<&str>) -> String {
+    match guest_token {
+        Some(token) => format!("{}{}?token={}", advertised_url, PLAYER_PATH, token),
+        None => format!("{}{}", advertised_url, PLAYER_PATH),
+    }
+}
+
+/// The QR code of the url, which always fits as urls are far shorter than the capacity of the largest code
+pub fn qr_code(url: &str) -> QrCode {
+    QrCode::encode(url.as_bytes()).expect("Player url is too long for a QR code. This is a bug.")
+}
+
+/// Prints the QR code of the preferred player url, for scanning it off the terminal
+pub fn print_qr_code(advertised_urls: &[String], guest_token: Option<&str>) {
+    if let Some(url) = advertised_urls.first() {
+        let url = player_url(url, guest_token);
+        println!("Scan to open {}", url);
+        print!("{}", qr_code(&url).to_terminal());
+    }
+}
@@ -0,0 +1,66 @@
+//! Internet radio stations and other http streams, stored server side
+
+use serde::{Serialize, Deserialize};
+
+use crate::store::Store;
+
+/// Name of the file the stations are persisted in
+const STORE_NAME: &str = "stations";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Station {
+    pub id: u64,
+    pub name: String,
+    /// http(s) url of the stream or playlist (m3u, pls, m3u8 for HLS), passed to libvlc as is
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Stations {
+    next_id: u64,
+    stations: Vec<Station>,
+}
+
+impl Stations {
+    pub fn load(store: &Store) -> Self {
+        store.load(STORE_NAME)
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+
+    pub fn list(&self) -> &Vec<Station> {
+        &self.stations
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Station> {
+        self.stations.iter().find(|s| s.id == id)
+    }
+
+    /// Adds a new station. Only http and https urls are accepted.
+    pub fn add(&mut self, name: String, url: String) -> Result<&Station, String> {
+        let url = url.trim().to_string();
+        let lowercase_url = url.to_lowercase();
+        if !(lowercase_url.starts_with("http://") || lowercase_url.starts_with("https://")) {
+            return Err(format!("'{}' is not a http or https url", url));
+        }
+        let name = if name.trim().is_empty() { url.clone() } else { name.trim().to_string() };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stations.push(Station {
+            id,
+            name,
+            url,
+        });
+        Ok(self.stations.last().expect("Station list is empty after push. This is a bug."))
+    }
+
+    /// Removes the station with the given id. Returns false if there is no such station.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let len = self.stations.len();
+        self.stations.retain(|s| s.id != id);
+        self.stations.len() != len
+    }
+}
@@ -0,0 +1,76 @@
+//! Persistent storage for server side state.
+//! Every kind of state is stored as a separate json file in the data directory.
+
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+#[derive(Clone, Debug)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// Opens the store in the given directory, creating the directory if it does not exist yet
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Store {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn file_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// Loads the value stored under `name`.
+    /// Falls back to the default value if nothing has been stored yet or the stored file can't be decoded.
+    pub fn load<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+        let path = self.file_path(name);
+        if !path.exists() {
+            return T::default();
+        }
+        match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(value) => value,
+                Err(e) => {
+                    eprintln!("Failed to decode {}, using defaults: {}", path.display(), e);
+                    T::default()
+                }
+            },
+            Err(e) => {
+                eprintln!("Failed to read {}, using defaults: {}", path.display(), e);
+                T::default()
+            }
+        }
+    }
+
+    /// Stores the value under `name`.
+    /// The file is written to a temporary location first, so an interrupted write can't corrupt previously stored state.
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> std::io::Result<()> {
+        let path = self.file_path(name);
+        let tmp_path = self.dir.join(format!(".{}.json.tmp", name));
+        let serialized = serde_json::to_string_pretty(value)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(serialized.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Stores the value and logs failures instead of returning them.
+    /// For state where losing an update is preferable to interrupting playback.
+    pub fn save_or_log<T: Serialize>(&self, name: &str, value: &T) {
+        if let Err(e) = self.save(name, value) {
+            eprintln!("Failed to store {}: {}", name, e);
+        }
+    }
+}
@@ -0,0 +1,642 @@
+//! Synchronized playback between instances in different rooms.
+//! A leader accepts followers on a TCP port. Followers estimate the offset of the leader's clock from round trips
+//! and play what a zone of the leader plays, at the position the leader dictates, shifted by their latency compensation.
+//! Followers prove they know the shared secret and receive a token of their own, which they stream files from the leader's web server with.
+//! Messages are newline delimited JSON.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix::Addr;
+use serde::{Serialize, Deserialize};
+
+use crate::auth;
+use crate::player::{Player, PlayerMsg};
+use crate::store::Store;
+
+/// Name of the file the groups and latencies of followers are persisted in
+const STORE_NAME: &str = "sync";
+
+/// Largest latency compensation accepted from clients, in milliseconds
+pub const MAX_LATENCY: i64 = 2000;
+
+/// How often followers measure the round trip to the leader
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pings sent right after connecting, so the clock offset is known quickly
+const INITIAL_PINGS: usize = 5;
+const INITIAL_PING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long followers wait before connecting again after losing the leader
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long sending to a follower may block the player thread
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a connection may take to introduce itself before it is closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest Hello accepted, in bytes including the newline
+const MAX_HELLO_LEN: u64 = 4096;
+
+/// Connections waiting for their handshake at once, further connections are closed right away
+const MAX_PENDING_HANDSHAKES: usize = 16;
+
+/// Number of recent round trips the clock offset is estimated from
+const CLOCK_SAMPLES: usize = 16;
+
+/// Paths of the registered media by id, published by the player thread so the web server can stream them to followers
+pub type MediaPaths = Arc<RwLock<HashMap<u64, String>>>;
+
+/// Media tokens of the connected followers, the web server only streams media to requests with one of them
+pub type MediaTokens = Arc<RwLock<HashSet<String>>>;
+
+/// The role of this instance, as chosen on the command line
+pub enum SyncConfig {
+    Standalone,
+    /// Accepts followers knowing `secret` on the listeners. `http_port` is the port of the web server followers stream files from, `tls` whether it serves HTTPS.
+    Leader{listeners: Vec<TcpListener>, http_port: u16, tls: bool, secret: String, media_paths: MediaPaths, media_tokens: MediaTokens},
+    /// Follows the leader at the address of its sync port, introducing itself with `name` and the leader's `secret`
+    Follower{leader: String, name: String, secret: String},
+}
+
+/// Path the web server streams the registered media at
+pub fn media_url(media_id: u64) -> String {
+    format!("/api/media/{}", media_id)
+}
+
+/// The url of media on the leader's web server at `url_base`, with the follower's media token
+pub fn authorized_url(url_base: &str, url: &str, media_token: &str) -> String {
+    format!("{}{}?media_token={}", url_base, url, media_token)
+}
+
+/// Milliseconds since the unix epoch, the time base of the protocol
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag="type")]
+pub enum SyncMsg {
+    /// Follower -> leader, the first message on a connection. Followers without the leader's secret are disconnected.
+    Hello{name: String, secret: String},
+    /// Follower -> leader, `t0` is the follower's time of sending
+    Ping{t0: i64},
+    /// Leader -> follower, answers Hello with the port of the leader's web server, whether it serves HTTPS
+    /// and the token media is streamed with, valid while the follower stays connected
+    Welcome{follower_id: u64, http_port: u16, #[serde(default)] tls: bool, media_token: String},
+    /// Leader -> follower, `t1` is the leader's time of answering
+    Pong{t0: i64, t1: i64},
+    /// Leader -> follower, what to play
+    State{state: SyncState},
+}
+
+/// Media a follower plays. Urls starting with '/' are relative to the leader's web server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncMedia {
+    pub url: String,
+    /// Bounds of virtual media within the file in milliseconds
+    pub start: i64,
+    pub end: Option<i64>,
+}
+
+/// Playback of a zone of the leader. `position` is the media time at leader time `at`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncState {
+    pub media: Option<SyncMedia>,
+    pub playing: bool,
+    /// In milliseconds, relative to the start of the file
+    pub position: i64,
+    pub rate: f32,
+    /// Leader time in milliseconds since the unix epoch
+    pub at: i64,
+    /// Latency compensation of the follower in milliseconds. The follower plays this far ahead of the leader.
+    pub latency: i64,
+}
+
+impl SyncState {
+    /// The media time the follower's media player should be at, `offset` being the leader's clock minus the follower's
+    pub fn target_position(&self, offset: i64) -> i64 {
+        self.target_position_at(unix_millis(), offset)
+    }
+
+    /// The media time the follower's media player should be at follower time `now`
+    fn target_position_at(&self, now: i64, offset: i64) -> i64 {
+        if !self.playing {
+            return self.position;
+        }
+        let elapsed = now + offset - self.at + self.latency;
+        self.position + (elapsed as f64 * self.rate as f64) as i64
+    }
+}
+
+/// The writing half of a connection, shared by the threads sending on it
+#[derive(Clone)]
+pub struct Connection {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream: Arc::new(Mutex::new(stream)),
+        }
+    }
+
+    pub fn send(&self, msg: &SyncMsg) -> std::io::Result<()> {
+        let mut line = serde_json::to_string(msg).expect("Failed to serialize sync message. This is a bug.");
+        line.push('\n');
+        let mut stream = self.stream.lock().expect("Sync connection lock poisoned. This is a bug.");
+        stream.write_all(line.as_bytes())
+    }
+
+    /// Ends the connection, which also ends the reading thread
+    pub fn close(&self) {
+        if let Ok(stream) = self.stream.lock() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// A follower connected to this leader
+pub struct FollowerConnection {
+    pub id: u64,
+    pub name: String,
+    pub address: String,
+    pub connection: Connection,
+}
+
+/// Settings of a follower, kept by the leader under the follower's name so they survive reconnects
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FollowerSettings {
+    /// The zone the follower is grouped with, None while ungrouped
+    pub zone_id: Option<u64>,
+    pub latency: i64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncGroups {
+    pub followers: HashMap<String, FollowerSettings>,
+}
+
+impl SyncGroups {
+    pub fn load(store: &Store) -> Self {
+        store.load(STORE_NAME)
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+}
+
+/// A follower as reported to clients of the leader
+#[derive(Clone, Debug, Serialize)]
+pub struct FollowerInfo {
+    pub id: u64,
+    pub name: String,
+    pub address: String,
+    pub zone_id: Option<u64>,
+    pub latency: i64,
+}
+
+/// The role of this instance as reported to clients
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag="role")]
+pub enum SyncInfo {
+    Standalone,
+    Leader{followers: Vec<FollowerInfo>},
+    /// `offset` is the leader's clock minus this instance's, `rtt` the round trip the estimate is based on, both in milliseconds
+    Follower{leader: String, connected: bool, offset: Option<i64>, rtt: Option<i64>},
+}
+
+/// Estimates the offset of the leader's clock.
+/// Uses the recent sample with the shortest round trip, as it is distorted the least by queuing along the way.
+#[derive(Default)]
+pub struct ClockSync {
+    /// (offset, round trip) in milliseconds
+    samples: VecDeque<(i64, i64)>,
+}
+
+impl ClockSync {
+    pub fn add(&mut self, offset: i64, rtt: i64) {
+        if self.samples.len() == CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((offset, rtt));
+    }
+
+    fn best(&self) -> Option<(i64, i64)> {
+        self.samples.iter().min_by_key(|(_, rtt)| *rtt).cloned()
+    }
+
+    pub fn offset(&self) -> Option<i64> {
+        self.best().map(|(offset, _)| offset)
+    }
+
+    pub fn rtt(&self) -> Option<i64> {
+        self.best().map(|(_, rtt)| rtt)
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// What the threads handling followers need to know
+#[derive(Clone)]
+pub struct LeaderConfig {
+    pub http_port: u16,
+    pub tls: bool,
+    pub secret: String,
+    pub media_tokens: MediaTokens,
+}
+
+/// Counts a connection as waiting for its handshake until dropped
+struct PendingHandshake(Arc<AtomicUsize>);
+
+impl PendingHandshake {
+    /// None if too many connections are waiting for their handshake already
+    fn start(pending: &Arc<AtomicUsize>) -> Option<Self> {
+        if pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_HANDSHAKES {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(PendingHandshake(pending.clone()))
+    }
+}
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts followers on the listeners. Every follower is handled on its own thread,
+/// which answers pings right away so the player thread can't distort the round trips.
+pub fn spawn_leader(listeners: Vec<TcpListener>, config: LeaderConfig, player: Addr<Player>) {
+    // followers are numbered across all listeners and the limit of pending handshakes applies to all of them
+    let next_id = Arc::new(AtomicU64::new(0));
+    let pending = Arc::new(AtomicUsize::new(0));
+    for listener in listeners {
+        let next_id = next_id.clone();
+        let pending = pending.clone();
+        let config = config.clone();
+        let player = player.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("Failed to accept follower: {}", e);
+                        continue;
+                    }
+                };
+                let pending_handshake = match PendingHandshake::start(&pending) {
+                    Some(pending_handshake) => pending_handshake,
+                    None => {
+                        println!("Closing connection from {:?}, too many followers are connecting at once", stream.peer_addr());
+                        continue;
+                    }
+                };
+                let id = next_id.fetch_add(1, Ordering::SeqCst);
+                let config = config.clone();
+                let player = player.clone();
+                thread::spawn(move || handle_follower(id, stream, pending_handshake, config, player));
+            }
+        });
+    }
+}
+
+/// A follower which proved it knows the secret and was sent its media token
+struct Handshake {
+    name: String,
+    media_token: String,
+    connection: Connection,
+    reader: BufReader<TcpStream>,
+}
+
+/// Reads the Hello of a new connection and welcomes it, if it knows the secret.
+/// The media token is valid from before the follower learns it, it needs to be removed once the follower disconnects.
+fn handshake(id: u64, stream: TcpStream, config: &LeaderConfig) -> Result<Handshake, String> {
+    let _ = stream.set_nodelay(true);
+    // a stalled follower must not block the player thread
+    stream.set_write_timeout(Some(WRITE_TIMEOUT)).map_err(|e| e.to_string())?;
+    // nor may a connection that doesn't introduce itself occupy its thread
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).map_err(|e| e.to_string())?;
+    let connection = Connection::new(stream.try_clone().map_err(|e| e.to_string())?);
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    (&mut reader).take(MAX_HELLO_LEN).read_line(&mut line).map_err(|e| e.to_string())?;
+    if !line.ends_with('\n') {
+        return Err(String::from("Did not introduce itself"));
+    }
+    let name = match serde_json::from_str::<SyncMsg>(&line) {
+        Ok(SyncMsg::Hello{name, secret}) => {
+            if !auth::constant_time_eq(secret.as_bytes(), config.secret.as_bytes()) {
+                return Err(format!("Follower {} sent a wrong secret", name));
+            }
+            name
+        },
+        _ => return Err(String::from("Did not introduce itself")),
+    };
+    reader.get_ref().set_read_timeout(None).map_err(|e| e.to_string())?;
+
+    let media_token = auth::random_token();
+    config.media_tokens.write().expect("Media tokens lock poisoned. This is a bug.").insert(media_token.clone());
+    let welcome = SyncMsg::Welcome{follower_id: id, http_port: config.http_port, tls: config.tls, media_token: media_token.clone()};
+    if let Err(e) = connection.send(&welcome) {
+        config.media_tokens.write().expect("Media tokens lock poisoned. This is a bug.").remove(&media_token);
+        return Err(e.to_string());
+    }
+    Ok(Handshake {
+        name,
+        media_token,
+        connection,
+        reader,
+    })
+}
+
+fn handle_follower(id: u64, stream: TcpStream, pending_handshake: PendingHandshake, config: LeaderConfig, player: Addr<Player>) {
+    let address = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| String::from("unknown"));
+    let handshake = handshake(id, stream, &config);
+    drop(pending_handshake);
+    let Handshake{name, media_token, connection, reader} = match handshake {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            println!("Rejected follower at {}: {}", address, e);
+            return;
+        }
+    };
+
+    println!("Follower {} connected from {}", name, address);
+    serve_follower(FollowerConnection {
+        id,
+        name,
+        address,
+        connection,
+    }, reader.lines(), &player);
+    config.media_tokens.write().expect("Media tokens lock poisoned. This is a bug.").remove(&media_token);
+}
+
+/// Answers the pings of a follower which introduced itself, until it disconnects
+fn serve_follower(follower: FollowerConnection, lines: Lines<BufReader<TcpStream>>, player: &Addr<Player>) {
+    let id = follower.id;
+    let connection = follower.connection.clone();
+    if !player.connected() {
+        return;
+    }
+    player.do_send(PlayerMsg::FollowerConnected(follower));
+
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        match serde_json::from_str::<SyncMsg>(&line) {
+            Ok(SyncMsg::Ping{t0}) => {
+                if connection.send(&SyncMsg::Pong{t0, t1: unix_millis()}).is_err() {
+                    break;
+                }
+            },
+            Ok(msg) => println!("Received unexpected message from follower {}: {:?}", id, msg),
+            Err(e) => println!("Failed to decode message from follower {}: {}", id, e),
+        }
+    }
+    player.do_send(PlayerMsg::FollowerDisconnected(id));
+}
+
+/// Connects to the leader at `address` and keeps reconnecting whenever the connection is lost
+pub fn spawn_follower(address: String, name: String, secret: String, player: Addr<Player>) {
+    thread::spawn(move || {
+        loop {
+            match TcpStream::connect(&address) {
+                Ok(stream) => {
+                    println!("Connected to leader {}", address);
+                    follow(stream, &name, &secret, &player);
+                    println!("Lost connection to leader {}", address);
+                    if !player.connected() {
+                        // the player is gone
+                        break;
+                    }
+                    player.do_send(PlayerMsg::LeaderDisconnected);
+                },
+                Err(e) => println!("Failed to connect to leader {}: {}", address, e),
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+    });
+}
+
+fn follow(stream: TcpStream, name: &str, secret: &str, player: &Addr<Player>) {
+    let _ = stream.set_nodelay(true);
+    let leader_ip = match stream.peer_addr() {
+        Ok(addr) => addr.ip(),
+        Err(_) => return,
+    };
+    let connection = match stream.try_clone() {
+        Ok(writer) => Connection::new(writer),
+        Err(_) => return,
+    };
+    if connection.send(&SyncMsg::Hello{name: name.to_string(), secret: secret.to_string()}).is_err() {
+        return;
+    }
+
+    let pinger = connection.clone();
+    thread::spawn(move || {
+        let mut pings = 0;
+        loop {
+            if pinger.send(&SyncMsg::Ping{t0: unix_millis()}).is_err() {
+                break;
+            }
+            pings += 1;
+            thread::sleep(if pings < INITIAL_PINGS { INITIAL_PING_INTERVAL } else { PING_INTERVAL });
+        }
+    });
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let msg = match serde_json::from_str::<SyncMsg>(&line) {
+            Ok(msg) => match leader_msg(msg, leader_ip, unix_millis()) {
+                Some(msg) => msg,
+                None => continue,
+            },
+            Err(e) => {
+                println!("Failed to decode message from leader: {}", e);
+                continue;
+            },
+        };
+        if !player.connected() {
+            break;
+        }
+        player.do_send(msg);
+    }
+    // stops the pinger
+    connection.close();
+}
+
+/// The player message for a message from the leader at `leader_ip`, received at follower time `now`
+fn leader_msg(msg: SyncMsg, leader_ip: IpAddr, now: i64) -> Option<PlayerMsg> {
+    match msg {
+        SyncMsg::Welcome{follower_id, http_port, tls, media_token} => Some(PlayerMsg::LeaderConnected{
+            follower_id,
+            url_base: format!("{}://{}", if tls { "https" } else { "http" }, SocketAddr::new(leader_ip, http_port)),
+            media_token,
+        }),
+        // assumes the way to the leader takes as long as the way back
+        SyncMsg::Pong{t0, t1} => Some(PlayerMsg::ClockSample{offset: t1 - (t0 + now) / 2, rtt: now - t0}),
+        SyncMsg::State{state} => Some(PlayerMsg::LeaderState(state)),
+        msg => {
+            println!("Received unexpected message from leader: {:?}", msg);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(playing: bool) -> SyncState {
+        SyncState {
+            media: None,
+            playing,
+            position: 10_000,
+            rate: 1.0,
+            at: 1_000_000,
+            latency: 0,
+        }
+    }
+
+    fn leader_config() -> LeaderConfig {
+        LeaderConfig {
+            http_port: 8080,
+            tls: false,
+            secret: String::from("secret"),
+            media_tokens: MediaTokens::default(),
+        }
+    }
+
+    /// Runs the leader's handshake for a connection sending `hello`, returning the follower's name and the leader's answer
+    fn run_handshake(hello: &[u8], config: &LeaderConfig) -> (Result<String, String>, Option<SyncMsg>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test listener");
+        let address = listener.local_addr().expect("Failed to get test listener address");
+        let leader_config = config.clone();
+        let leader = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Failed to accept test connection");
+            handshake(7, stream, &leader_config).map(|handshake| handshake.name)
+        });
+
+        let mut stream = TcpStream::connect(address).expect("Failed to connect to test listener");
+        // rejected connections may be closed before all of it is sent
+        let _ = stream.write_all(hello);
+        let name = leader.join().expect("Leader thread panicked");
+        let mut line = String::new();
+        // the leader closes rejected connections without an answer
+        stream.set_read_timeout(Some(Duration::from_secs(1))).expect("Failed to set read timeout");
+        let _ = BufReader::new(stream).read_line(&mut line);
+        (name, serde_json::from_str(&line).ok())
+    }
+
+    #[test]
+    fn estimates_clock_offset_from_fastest_round_trip() {
+        let mut clock = ClockSync::default();
+        assert_eq!((clock.offset(), clock.rtt()), (None, None));
+        clock.add(120, 40);
+        clock.add(100, 8);
+        clock.add(150, 90);
+        assert_eq!((clock.offset(), clock.rtt()), (Some(100), Some(8)));
+
+        // the fast sample ages out
+        for _ in 0..CLOCK_SAMPLES - 1 {
+            clock.add(130, 30);
+        }
+        assert_eq!((clock.offset(), clock.rtt()), (Some(130), Some(30)));
+        clock.reset();
+        assert_eq!(clock.offset(), None);
+    }
+
+    #[test]
+    fn computes_clock_sample_from_pong() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        // sent at 1000, answered at leader time 5030, received at 1100, so the leader is 3980 ms ahead
+        match leader_msg(SyncMsg::Pong{t0: 1000, t1: 5030}, ip, 1100) {
+            Some(PlayerMsg::ClockSample{offset, rtt}) => assert_eq!((offset, rtt), (3980, 100)),
+            _ => panic!("Unexpected message"),
+        }
+        assert!(leader_msg(SyncMsg::Ping{t0: 0}, ip, 0).is_none());
+    }
+
+    #[test]
+    fn computes_target_position() {
+        // the follower's clock is 500 ms behind the leader's
+        let offset = 500;
+        assert_eq!(state(true).target_position_at(1_000_500, offset), 11_000);
+        assert_eq!(state(false).target_position_at(1_000_500, offset), 10_000);
+        let fast = SyncState { rate: 1.5, ..state(true) };
+        assert_eq!(fast.target_position_at(1_000_500, offset), 11_500);
+        // followers play ahead by their latency compensation
+        let delayed = SyncState { latency: 200, ..state(true) };
+        assert_eq!(delayed.target_position_at(999_500, offset), 10_200);
+    }
+
+    #[test]
+    fn completes_handshake_over_loopback() {
+        let config = leader_config();
+        let hello = serde_json::to_string(&SyncMsg::Hello{name: String::from("kitchen"), secret: String::from("secret")})
+            .expect("Failed to serialize hello") + "\n";
+        let (name, welcome) = run_handshake(hello.as_bytes(), &config);
+        assert_eq!(name, Ok(String::from("kitchen")));
+
+        let welcome = welcome.expect("Missing welcome");
+        match leader_msg(welcome, IpAddr::from([127, 0, 0, 1]), 0) {
+            Some(PlayerMsg::LeaderConnected{follower_id, url_base, media_token}) => {
+                assert_eq!(follower_id, 7);
+                assert_eq!(url_base, "http://127.0.0.1:8080");
+                assert!(config.media_tokens.read().expect("Media tokens lock poisoned").contains(&media_token));
+                assert_eq!(authorized_url(&url_base, &media_url(3), &media_token), format!("http://127.0.0.1:8080/api/media/3?media_token={}", media_token));
+            },
+            _ => panic!("Unexpected message"),
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_secrets_and_oversized_hellos() {
+        let config = leader_config();
+        let wrong = serde_json::to_string(&SyncMsg::Hello{name: String::from("kitchen"), secret: String::from("guess")})
+            .expect("Failed to serialize hello") + "\n";
+        let (name, welcome) = run_handshake(wrong.as_bytes(), &config);
+        assert!(name.is_err());
+        assert!(welcome.is_none());
+
+        let oversized = format!("{{\"type\":\"Hello\",\"name\":\"{}\",\"secret\":\"secret\"}}\n", "a".repeat(MAX_HELLO_LEN as usize));
+        let (name, welcome) = run_handshake(oversized.as_bytes(), &config);
+        assert!(name.is_err());
+        assert!(welcome.is_none());
+        assert!(config.media_tokens.read().expect("Media tokens lock poisoned").is_empty());
+    }
+
+    #[test]
+    fn limits_pending_handshakes() {
+        let pending = Arc::new(AtomicUsize::new(0));
+        let handshakes: Vec<PendingHandshake> = (0..MAX_PENDING_HANDSHAKES).filter_map(|_| PendingHandshake::start(&pending)).collect();
+        assert_eq!(handshakes.len(), MAX_PENDING_HANDSHAKES);
+        assert!(PendingHandshake::start(&pending).is_none());
+        drop(handshakes);
+        assert_eq!(pending.load(Ordering::SeqCst), 0);
+        assert!(PendingHandshake::start(&pending).is_some());
+    }
+}
@@ -0,0 +1,499 @@
+//! The sleep timer and scheduled alarms.
+//! Alarms fire at a local time on chosen weekdays and start a list of tracks or a station.
+//! Tracks are persisted by key rather than media id, as media ids are reassigned whenever the media directory is scanned.
+//! The sleep timer and every alarm belong to the zone they were set in.
+
+use std::collections::HashMap;
+
+use chrono::{Datelike, Local, LocalResult, NaiveDate, TimeZone};
+use serde::{Serialize, Deserialize};
+
+use crate::media_fs::MediaEntry;
+use crate::store::Store;
+use crate::MAX_MINUTES;
+
+/// Name of the file the timers are persisted in
+const STORE_NAME: &str = "timers";
+
+/// Longest volume ramp of an alarm accepted from clients, in seconds
+pub const MAX_RAMP: u64 = 30 * 60;
+
+/// Longest sleep timer fade out accepted from clients, in milliseconds
+pub const MAX_SLEEP_FADE: u64 = 5 * 60 * 1000;
+
+/// When the sleep timer stops playback, as requested by clients
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag="kind")]
+pub enum SleepRequest {
+    Minutes{minutes: u64},
+    EndOfTrack,
+    EndOfQueue,
+}
+
+/// When the sleep timer stops playback
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag="kind")]
+pub enum SleepEnd {
+    /// Unix timestamp in seconds
+    At{time: i64},
+    EndOfTrack,
+    EndOfQueue,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SleepTimer {
+    /// Zone whose playback the timer stops
+    #[serde(default)]
+    pub zone_id: u64,
+    pub end: SleepEnd,
+    /// Duration of the fade out before playback stops in milliseconds, 0 stops without fading
+    pub fade_out: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    fn matches(self, weekday: chrono::Weekday) -> bool {
+        let number = match self {
+            Weekday::Mon => 0,
+            Weekday::Tue => 1,
+            Weekday::Wed => 2,
+            Weekday::Thu => 3,
+            Weekday::Fri => 4,
+            Weekday::Sat => 5,
+            Weekday::Sun => 6,
+        };
+        weekday.num_days_from_monday() == number
+    }
+}
+
+/// What an alarm plays, as sent by and to clients
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag="type")]
+pub enum AlarmTarget {
+    Tracks{track_ids: Vec<u64>},
+    Station{station_id: u64},
+}
+
+/// What an alarm plays, as persisted
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag="type")]
+enum SavedTarget {
+    /// Keys of the media entries
+    Tracks{tracks: Vec<String>},
+    Station{station_id: u64},
+}
+
+/// An alarm as requested by clients
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlarmRequest {
+    pub hour: u32,
+    pub minute: u32,
+    /// An alarm without weekdays fires once
+    pub weekdays: Vec<Weekday>,
+    pub target: AlarmTarget,
+    /// Volume the alarm ramps up to
+    pub volume: u64,
+    /// Duration of the volume ramp in seconds, 0 starts at full volume
+    pub ramp: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Alarm {
+    id: u64,
+    /// Alarms persisted before there were zones belong to the default zone, which has id 0
+    #[serde(default)]
+    zone_id: u64,
+    hour: u32,
+    minute: u32,
+    weekdays: Vec<Weekday>,
+    target: SavedTarget,
+    volume: u64,
+    ramp: u64,
+    /// Unix timestamp in seconds the alarm fires next, computed when loading
+    #[serde(skip)]
+    next: Option<i64>,
+}
+
+/// An alarm that went off, with its tracks resolved to the current media ids
+pub struct FiredAlarm {
+    pub id: u64,
+    pub zone_id: u64,
+    pub target: AlarmTarget,
+    pub volume: u64,
+    pub ramp: u64,
+}
+
+/// An alarm as reported to clients
+#[derive(Clone, Debug, Serialize)]
+pub struct AlarmInfo {
+    pub id: u64,
+    pub zone_id: u64,
+    pub hour: u32,
+    pub minute: u32,
+    pub weekdays: Vec<Weekday>,
+    pub target: AlarmTarget,
+    pub volume: u64,
+    pub ramp: u64,
+    /// Unix timestamp in seconds the alarm fires next
+    pub next: Option<i64>,
+}
+
+/// The sleep timer and alarms as reported to clients
+#[derive(Clone, Debug, Serialize)]
+pub struct TimersInfo {
+    pub sleep_timer: Option<SleepTimer>,
+    pub alarms: Vec<AlarmInfo>,
+}
+
+impl Alarm {
+    /// The first time after `now` the alarm fires, as unix timestamp in seconds
+    fn next_after(&self, now: i64) -> Option<i64> {
+        self.next_after_in(&Local, now)
+    }
+
+    /// The first time after `now` the alarm fires in the time zone.
+    /// If the time is skipped by a daylight saving time change, the alarm fires an hour later that day.
+    fn next_after_in<Tz: TimeZone>(&self, tz: &Tz, now: i64) -> Option<i64> {
+        let mut date: NaiveDate = tz.timestamp_opt(now, 0).earliest()?.naive_local().date();
+        // a week from today covers every weekday, the extra day covers today's time having passed
+        for _ in 0..8 {
+            if self.weekdays.is_empty() || self.weekdays.iter().any(|weekday| weekday.matches(date.weekday())) {
+                let time = date
+                    .and_hms_opt(self.hour, self.minute, 0)
+                    .and_then(|naive| match tz.from_local_datetime(&naive) {
+                        LocalResult::None => tz.from_local_datetime(&(naive + chrono::Duration::hours(1))).earliest(),
+                        time => time.earliest(),
+                    })
+                    .map(|time| time.timestamp());
+                match time {
+                    Some(time) if time > now => return Some(time),
+                    _ => {},
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+fn track_ids(keys: &[String], registered_media: &HashMap<u64, MediaEntry>) -> Vec<u64> {
+    let ids: HashMap<String, u64> = registered_media.iter().map(|(id, entry)| (entry.key(), *id)).collect();
+    keys.iter().filter_map(|key| ids.get(key).cloned()).collect()
+}
+
+fn resolve_target(target: &SavedTarget, registered_media: &HashMap<u64, MediaEntry>) -> AlarmTarget {
+    match target {
+        SavedTarget::Tracks{tracks} => AlarmTarget::Tracks{track_ids: track_ids(tracks, registered_media)},
+        SavedTarget::Station{station_id} => AlarmTarget::Station{station_id: *station_id},
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Timers {
+    next_alarm_id: u64,
+    sleep_timer: Option<SleepTimer>,
+    alarms: Vec<Alarm>,
+}
+
+impl Timers {
+    /// Loads the timers. Alarms missed while the server was not running are skipped.
+    pub fn load(store: &Store, now: i64) -> Self {
+        let mut timers: Timers = store.load(STORE_NAME);
+        for alarm in &mut timers.alarms {
+            alarm.next = alarm.next_after(now);
+        }
+        timers
+    }
+
+    pub fn save(&self, store: &Store) {
+        store.save_or_log(STORE_NAME, self)
+    }
+
+    /// The sleep timer if it belongs to the zone
+    pub fn sleep_timer_of(&self, zone_id: u64) -> Option<&SleepTimer> {
+        self.sleep_timer.as_ref().filter(|sleep_timer| sleep_timer.zone_id == zone_id)
+    }
+
+    /// The sleep timer of whichever zone it was set in
+    pub fn sleep_timer(&self) -> Option<&SleepTimer> {
+        self.sleep_timer.as_ref()
+    }
+
+    /// Sets the sleep timer of the zone, replacing the sleep timer of any zone
+    pub fn set_sleep_timer(&mut self, request: SleepRequest, fade_out: u64, zone_id: u64, now: i64) -> Result<(), String> {
+        let end = match request {
+            SleepRequest::Minutes{minutes} if minutes > MAX_MINUTES => {
+                return Err(format!("{} minutes is more than {} minutes", minutes, MAX_MINUTES));
+            },
+            SleepRequest::Minutes{minutes} => {
+                let time = (minutes as i64).checked_mul(60)
+                    .and_then(|seconds| now.checked_add(seconds))
+                    .ok_or_else(|| format!("Invalid sleep timer of {} minutes", minutes))?;
+                SleepEnd::At{time}
+            },
+            SleepRequest::EndOfTrack => SleepEnd::EndOfTrack,
+            SleepRequest::EndOfQueue => SleepEnd::EndOfQueue,
+        };
+        self.sleep_timer = Some(SleepTimer {
+            zone_id,
+            end,
+            fade_out: fade_out.min(MAX_SLEEP_FADE),
+        });
+        Ok(())
+    }
+
+    /// Returns whether there was a sleep timer
+    pub fn cancel_sleep_timer(&mut self) -> bool {
+        self.sleep_timer.take().is_some()
+    }
+
+    pub fn add_alarm(&mut self, request: AlarmRequest, zone_id: u64, registered_media: &HashMap<u64, MediaEntry>, now: i64) -> Result<u64, String> {
+        if request.hour > 23 || request.minute > 59 {
+            return Err(format!("Invalid time {:02}:{:02}", request.hour, request.minute));
+        }
+        let target = match request.target {
+            AlarmTarget::Tracks{track_ids} => {
+                if track_ids.is_empty() {
+                    return Err(String::from("An alarm needs at least one track"));
+                }
+                let tracks = track_ids
+                    .iter()
+                    .map(|id| registered_media.get(id).map(|entry| entry.key()).ok_or_else(|| format!("Invalid track_id {}", id)))
+                    .collect::<Result<Vec<String>, String>>()?;
+                SavedTarget::Tracks{tracks}
+            },
+            AlarmTarget::Station{station_id} => SavedTarget::Station{station_id},
+        };
+        let id = self.next_alarm_id;
+        self.next_alarm_id += 1;
+        let mut alarm = Alarm {
+            id,
+            zone_id,
+            hour: request.hour,
+            minute: request.minute,
+            weekdays: request.weekdays,
+            target,
+            volume: request.volume,
+            ramp: request.ramp.min(MAX_RAMP),
+            next: None,
+        };
+        alarm.next = alarm.next_after(now);
+        self.alarms.push(alarm);
+        Ok(id)
+    }
+
+    pub fn remove_alarm(&mut self, id: u64) -> bool {
+        let len = self.alarms.len();
+        self.alarms.retain(|alarm| alarm.id != id);
+        self.alarms.len() != len
+    }
+
+    /// Takes the alarms due at `now`. Recurring alarms are scheduled again, alarms without weekdays are removed.
+    pub fn take_due_alarms(&mut self, now: i64, registered_media: &HashMap<u64, MediaEntry>) -> Vec<FiredAlarm> {
+        let mut fired = Vec::new();
+        for alarm in &mut self.alarms {
+            match alarm.next {
+                Some(next) if next <= now => {
+                    fired.push(FiredAlarm {
+                        id: alarm.id,
+                        zone_id: alarm.zone_id,
+                        target: resolve_target(&alarm.target, registered_media),
+                        volume: alarm.volume,
+                        ramp: alarm.ramp,
+                    });
+                    alarm.next = if alarm.weekdays.is_empty() { None } else { alarm.next_after(now) };
+                },
+                _ => {},
+            }
+        }
+        self.alarms.retain(|alarm| alarm.next.is_some());
+        fired
+    }
+
+    pub fn for_clients(&self, registered_media: &HashMap<u64, MediaEntry>) -> TimersInfo {
+        TimersInfo {
+            sleep_timer: self.sleep_timer.clone(),
+            alarms: self.alarms
+                .iter()
+                .map(|alarm| AlarmInfo {
+                    id: alarm.id,
+                    zone_id: alarm.zone_id,
+                    hour: alarm.hour,
+                    minute: alarm.minute,
+                    weekdays: alarm.weekdays.clone(),
+                    target: resolve_target(&alarm.target, registered_media),
+                    volume: alarm.volume,
+                    ramp: alarm.ramp,
+                    next: alarm.next,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, NaiveDateTime};
+
+    /// Central European time in 2019, switching to summer time on Sunday March 31st at 02:00.
+    /// Only the spring change is modelled.
+    #[derive(Clone, Debug)]
+    struct Cet2019;
+
+    /// 2019-03-31T01:00:00Z
+    const SUMMER_TIME_START: i64 = 1_553_994_000;
+
+    impl TimeZone for Cet2019 {
+        type Offset = FixedOffset;
+
+        fn from_offset(_offset: &FixedOffset) -> Self {
+            Cet2019
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms(0, 0, 0))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let winter = local.timestamp() - 3600 < SUMMER_TIME_START;
+            let summer = local.timestamp() - 7200 >= SUMMER_TIME_START;
+            match (winter, summer) {
+                (true, false) => LocalResult::Single(FixedOffset::east(3600)),
+                (false, true) => LocalResult::Single(FixedOffset::east(7200)),
+                (true, true) => LocalResult::Ambiguous(FixedOffset::east(3600), FixedOffset::east(7200)),
+                (false, false) => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms(0, 0, 0))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            FixedOffset::east(if utc.timestamp() < SUMMER_TIME_START { 3600 } else { 7200 })
+        }
+    }
+
+    fn alarm(hour: u32, minute: u32, weekdays: Vec<Weekday>) -> Alarm {
+        Alarm {
+            id: 0,
+            zone_id: 0,
+            hour,
+            minute,
+            weekdays,
+            target: SavedTarget::Station{station_id: 0},
+            volume: 50,
+            ramp: 0,
+            next: None,
+        }
+    }
+
+    fn timestamp(local: &str) -> i64 {
+        let naive = NaiveDateTime::parse_from_str(local, "%Y-%m-%d %H:%M").expect("Invalid test time");
+        Cet2019.from_local_datetime(&naive).earliest().expect("Invalid test time").timestamp()
+    }
+
+    #[test]
+    fn alarms_fire_on_their_next_weekday() {
+        // Friday March 29th
+        let now = timestamp("2019-03-29 08:00");
+        let daily = alarm(7, 30, Vec::new());
+        assert_eq!(daily.next_after_in(&Cet2019, now), Some(timestamp("2019-03-30 07:30")));
+        assert_eq!(daily.next_after_in(&Cet2019, now - 3600), Some(timestamp("2019-03-29 07:30")));
+        let weekdays = alarm(7, 30, vec![Weekday::Mon, Weekday::Tue]);
+        assert_eq!(weekdays.next_after_in(&Cet2019, now), Some(timestamp("2019-04-01 07:30")));
+        // today's time has passed, the next one is a week from now
+        let fridays = alarm(7, 30, vec![Weekday::Fri]);
+        assert_eq!(fridays.next_after_in(&Cet2019, now), Some(timestamp("2019-04-05 07:30")));
+    }
+
+    #[test]
+    fn alarms_skipped_by_daylight_saving_time_fire_later_that_day() {
+        let now = timestamp("2019-03-30 12:00");
+        let early = alarm(2, 30, Vec::new());
+        assert_eq!(early.next_after_in(&Cet2019, now), Some(timestamp("2019-03-31 03:30")));
+        // unaffected times keep their local time across the change
+        let morning = alarm(7, 0, Vec::new());
+        assert_eq!(morning.next_after_in(&Cet2019, now), Some(timestamp("2019-03-31 07:00")));
+        assert_eq!(timestamp("2019-03-31 07:00") - timestamp("2019-03-30 07:00"), 23 * 3600);
+    }
+
+    #[test]
+    fn sets_sleep_timer_within_limits() {
+        let mut timers = Timers::default();
+        timers.set_sleep_timer(SleepRequest::Minutes{minutes: 30}, 10 * MAX_SLEEP_FADE, 2, 1000).expect("Failed to set sleep timer");
+        let sleep_timer = timers.sleep_timer().expect("Missing sleep timer");
+        assert_eq!(sleep_timer.end, SleepEnd::At{time: 1000 + 30 * 60});
+        assert_eq!(sleep_timer.fade_out, MAX_SLEEP_FADE);
+        assert!(timers.sleep_timer_of(2).is_some());
+        assert!(timers.sleep_timer_of(0).is_none());
+
+        // rejected timers keep the previous one
+        assert!(timers.set_sleep_timer(SleepRequest::Minutes{minutes: MAX_MINUTES + 1}, 0, 0, 1000).is_err());
+        assert!(timers.set_sleep_timer(SleepRequest::Minutes{minutes: u64::MAX}, 0, 0, 1000).is_err());
+        assert!(timers.set_sleep_timer(SleepRequest::Minutes{minutes: 1}, 0, 0, i64::MAX).is_err());
+        assert!(timers.sleep_timer_of(2).is_some());
+
+        timers.set_sleep_timer(SleepRequest::EndOfQueue, 0, 0, 1000).expect("Failed to set sleep timer");
+        assert_eq!(timers.sleep_timer_of(0).map(|sleep_timer| &sleep_timer.end), Some(&SleepEnd::EndOfQueue));
+        assert!(timers.cancel_sleep_timer());
+        assert!(!timers.cancel_sleep_timer());
+    }
+
+    #[test]
+    fn takes_due_alarms() {
+        let mut registered_media = HashMap::new();
+        registered_media.insert(4, MediaEntry::file(String::from("/music/a.flac")));
+        registered_media.insert(5, MediaEntry::file(String::from("/music/b.flac")));
+        let request = |weekdays: Vec<Weekday>, target: AlarmTarget| AlarmRequest {
+            hour: 7,
+            minute: 0,
+            weekdays,
+            target,
+            volume: 40,
+            ramp: 60,
+        };
+        let all_days = vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun];
+
+        let mut timers = Timers::default();
+        let now = 1_554_000_000;
+        let once = timers.add_alarm(request(Vec::new(), AlarmTarget::Tracks{track_ids: vec![5, 4]}), 1, &registered_media, now)
+            .expect("Failed to add alarm");
+        let daily = timers.add_alarm(request(all_days, AlarmTarget::Station{station_id: 3}), 0, &registered_media, now)
+            .expect("Failed to add alarm");
+        assert!(timers.add_alarm(request(Vec::new(), AlarmTarget::Tracks{track_ids: vec![6]}), 0, &registered_media, now).is_err());
+        let next = timers.alarms[0].next.expect("Alarm is not scheduled");
+        assert_eq!(timers.alarms[1].next, Some(next));
+
+        assert!(timers.take_due_alarms(next - 1, &registered_media).is_empty());
+
+        // media ids change when the media directory is scanned again
+        let mut rescanned = HashMap::new();
+        rescanned.insert(14, registered_media[&4].clone());
+        rescanned.insert(15, registered_media[&5].clone());
+        let fired = timers.take_due_alarms(next, &rescanned);
+        assert_eq!(fired.iter().map(|alarm| alarm.id).collect::<Vec<u64>>(), vec![once, daily]);
+        assert_eq!((fired[0].zone_id, fired[0].volume, fired[0].ramp), (1, 40, 60));
+        match &fired[0].target {
+            AlarmTarget::Tracks{track_ids} => assert_eq!(track_ids, &vec![15, 14]),
+            target => panic!("Unexpected target {:?}", target),
+        }
+
+        // the one-off alarm is gone, the daily one fires again within a day
+        assert_eq!(timers.alarms.len(), 1);
+        let again = timers.alarms[0].next.expect("Alarm is not scheduled");
+        assert!(again > next && again <= next + 25 * 3600);
+        assert!(timers.take_due_alarms(next, &rescanned).is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+//! Serving the player over HTTPS and WSS, with a certificate from files or a generated self-signed one for use in the LAN.
+
+use std::path::{Path, PathBuf};
+use std::net::IpAddr;
+
+use crate::bind;
+
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+
+/// Self-signed certificates are valid for this long
+#[cfg(feature = "tls")]
+const SELF_SIGNED_VALIDITY_DAYS: u32 = 825;
+/// Self-signed certificates are replaced when they expire within this many days
+#[cfg(feature = "tls")]
+const SELF_SIGNED_RENEW_DAYS: u32 = 30;
+
+/// The certificate and key the server uses
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, optionally followed by intermediate certificates
+    pub cert: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    /// Checks that the certificate and key can be loaded and belong together
+    pub fn new(cert: PathBuf, key: PathBuf) -> Result<Self, String> {
+        let config = TlsConfig { cert, key };
+        config.check()?;
+        Ok(config)
+    }
+
+    #[cfg(feature = "tls")]
+    fn check(&self) -> Result<(), String> {
+        self.acceptor().map(|_| ())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn check(&self) -> Result<(), String> {
+        Err(format!("Can't serve HTTPS with {} and {}, this build has no TLS support. It was built without the 'tls' feature.", self.cert.display(), self.key.display()))
+    }
+
+    /// A TLS acceptor for one listener of the http server
+    #[cfg(feature = "tls")]
+    pub fn acceptor(&self) -> Result<SslAcceptorBuilder, String> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+            .map_err(|e| format!("Failed to set up TLS: {}", e))?;
+        builder.set_private_key_file(&self.key, SslFiletype::PEM)
+            .map_err(|e| format!("Failed to load TLS key {}: {}", self.key.display(), e))?;
+        builder.set_certificate_chain_file(&self.cert)
+            .map_err(|e| format!("Failed to load TLS certificate {}: {}", self.cert.display(), e))?;
+        builder.check_private_key()
+            .map_err(|e| format!("TLS key {} does not belong to certificate {}: {}", self.key.display(), self.cert.display(), e))?;
+        Ok(builder)
+    }
+}
+
+/// A self-signed certificate in `dir` for the host name and addresses, generated unless a previous one still covers them.
+/// Browsers warn about it once, which is acceptable in a LAN without a domain.
+pub fn self_signed(dir: &Path, host_name: &str, addresses: &[IpAddr]) -> Result<TlsConfig, String> {
+    let cert = dir.join("self-signed-cert.pem");
+    let key = dir.join("self-signed-key.pem");
+    if !self_signed_is_current(&cert, addresses) {
+        println!("Generating a self-signed TLS certificate in {}", dir.display());
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create TLS directory {}: {}", dir.display(), e))?;
+        generate_self_signed(&cert, &key, host_name, addresses)?;
+    }
+    TlsConfig::new(cert, key)
+}
+
+/// Whether the certificate exists, doesn't expire soon and is valid for all addresses
+#[cfg(feature = "tls")]
+fn self_signed_is_current(cert: &Path, addresses: &[IpAddr]) -> bool {
+    use openssl::asn1::Asn1Time;
+    use openssl::x509::X509;
+
+    let cert = match std::fs::read(cert).ok().and_then(|pem| X509::from_pem(&pem).ok()) {
+        Some(cert) => cert,
+        None => return false,
+    };
+    let renew_at = match Asn1Time::days_from_now(SELF_SIGNED_RENEW_DAYS) {
+        Ok(renew_at) => renew_at,
+        Err(_) => return false,
+    };
+    if cert.not_after() < renew_at {
+        return false;
+    }
+    let covered: Vec<Vec<u8>> = cert.subject_alt_names()
+        .map(|names| names.iter().filter_map(|name| name.ipaddress().map(|ip| ip.to_vec())).collect())
+        .unwrap_or_else(Vec::new);
+    addresses.iter().all(|address| {
+        let octets = match address {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        covered.contains(&octets)
+    })
+}
+
+#[cfg(not(feature = "tls"))]
+fn self_signed_is_current(_cert: &Path, _addresses: &[IpAddr]) -> bool {
+    false
+}
+
+#[cfg(feature = "tls")]
+fn generate_self_signed(cert_path: &Path, key_path: &Path, host_name: &str, addresses: &[IpAddr]) -> Result<(), String> {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName};
+
+    let generate = || -> Result<(Vec<u8>, Vec<u8>), openssl::error::ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, host_name)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&Asn1Time::days_from_now(SELF_SIGNED_VALIDITY_DAYS)?)?;
+        builder.append_extension(BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(KeyUsage::new().critical().digital_signature().key_encipherment().build()?)?;
+        builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+        let mut alt_names = SubjectAlternativeName::new();
+        alt_names.dns("localhost");
+        alt_names.dns(host_name);
+        alt_names.dns(&format!("{}.local", host_name));
+        for address in addresses {
+            alt_names.ip(&address.to_string());
+        }
+        let alt_names = alt_names.build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(alt_names)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+
+        Ok((builder.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+    };
+    let (cert, key) = generate().map_err(|e| format!("Failed to generate a self-signed TLS certificate: {}", e))?;
+    write_private(key_path, &key)?;
+    std::fs::write(cert_path, cert)
+        .map_err(|e| format!("Failed to write TLS certificate {}: {}", cert_path.display(), e))
+}
+
+#[cfg(not(feature = "tls"))]
+fn generate_self_signed(_cert_path: &Path, _key_path: &Path, _host_name: &str, _addresses: &[IpAddr]) -> Result<(), String> {
+    Err(String::from("This build has no TLS support, it was built without the 'tls' feature"))
+}
+
+/// Writes the key readable only by the owner
+#[cfg(feature = "tls")]
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), String> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(|e| format!("Failed to write TLS key {}: {}", path.display(), e))
+}
+
+/// The url to redirect a plain http request to, on the https port of the same host. None if the host is invalid.
+pub fn https_redirect_url(host: &str, https_port: u16, path_and_query: &str) -> Option<String> {
+    // strip the port of the http server
+    let (host, _) = bind::split_host_port(host)?;
+    if https_port == 443 {
+        Some(format!("https://{}{}", host, path_and_query))
+    } else {
+        Some(format!("https://{}:{}{}", host, https_port, path_and_query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        assert_eq!(https_redirect_url("player.local:8080", 8443, "/player?token=a"), Some(String::from("https://player.local:8443/player?token=a")));
+        assert_eq!(https_redirect_url("192.168.1.20", 8443, "/"), Some(String::from("https://192.168.1.20:8443/")));
+        // the default port is left out
+        assert_eq!(https_redirect_url("192.168.1.20:80", 443, "/player"), Some(String::from("https://192.168.1.20/player")));
+    }
+
+    #[test]
+    fn keeps_the_brackets_of_ipv6_hosts() {
+        assert_eq!(https_redirect_url("[2001:db8::2]:8080", 8443, "/"), Some(String::from("https://[2001:db8::2]:8443/")));
+        assert_eq!(https_redirect_url("[::1]", 8443, "/"), Some(String::from("https://[::1]:8443/")));
+        assert_eq!(https_redirect_url("[fe80::1]:80", 443, "/"), Some(String::from("https://[fe80::1]/")));
+    }
+
+    #[test]
+    fn rejects_invalid_hosts() {
+        for host in &["", "evil.com/", "a b", "host:", "host:80:81", "host:port", "2001:db8::2", "[::1", "[]:80", "[::1]x", "[::1]:80]", "ho]st", "[::1]:8a"] {
+            assert_eq!(https_redirect_url(host, 8443, "/"), None, "Accepted host {}", host);
+        }
+    }
+}
@@ -12,3 +12,153 @@ use vlc;
 pub unsafe fn current_track_length (mediaplayer: &vlc::MediaPlayer) -> vlc::sys::libvlc_time_t {
     vlc::sys::libvlc_media_player_get_length((&mediaplayer).raw())
 }
+
+/// Adds an input option such as `:start-time=12.5` to the media before it is played.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+/// How to avoid undefined behaviour:
+/// Do not call while the media is being played by a media player.
+pub unsafe fn add_media_option (media: &vlc::Media, option: &str) {
+    let c_option = std::ffi::CString::new(option).expect("Media option contains a nul byte. This is a bug.");
+    vlc::sys::libvlc_media_add_option(media.raw(), c_option.as_ptr());
+}
+
+/// Names of the equalizer presets built into libvlc, in index order.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+pub fn equalizer_preset_names() -> Vec<String> {
+    unsafe {
+        (0..vlc::sys::libvlc_audio_equalizer_get_preset_count())
+            .filter_map(|index| {
+                let name = vlc::sys::libvlc_audio_equalizer_get_preset_name(index);
+                if name.is_null() {
+                    None
+                } else {
+                    Some(std::ffi::CStr::from_ptr(name).to_string_lossy().into_owned())
+                }
+            })
+            .collect()
+    }
+}
+
+/// Center frequencies of the equalizer bands in Hz.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+pub fn equalizer_band_frequencies() -> Vec<f32> {
+    unsafe {
+        (0..vlc::sys::libvlc_audio_equalizer_get_band_count())
+            .map(|index| vlc::sys::libvlc_audio_equalizer_get_band_frequency(index))
+            .collect()
+    }
+}
+
+/// Preamp and band amplification of a built in equalizer preset, in dB.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+pub fn equalizer_preset(index: u32) -> Option<(f32, Vec<f32>)> {
+    unsafe {
+        let equalizer = vlc::sys::libvlc_audio_equalizer_new_from_preset(index);
+        if equalizer.is_null() {
+            return None;
+        }
+        let preamp = vlc::sys::libvlc_audio_equalizer_get_preamp(equalizer);
+        let bands = (0..vlc::sys::libvlc_audio_equalizer_get_band_count())
+            .map(|band| vlc::sys::libvlc_audio_equalizer_get_amp_at_index(equalizer, band))
+            .collect();
+        vlc::sys::libvlc_audio_equalizer_release(equalizer);
+        Some((preamp, bands))
+    }
+}
+
+/// Sets the equalizer of the media player to the given preamp and band amplification in dB, None disables the equalizer.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+/// How to avoid undefined behaviour:
+/// libvlc copies the settings, so the media player may be used from the same thread as usual. Do not share it across threads.
+pub unsafe fn set_equalizer (mediaplayer: &vlc::MediaPlayer, settings: Option<(f32, &[f32])>) -> Result<(), ()> {
+    let (preamp, bands) = match settings {
+        Some(settings) => settings,
+        None => {
+            let result = vlc::sys::libvlc_media_player_set_equalizer(mediaplayer.raw(), std::ptr::null_mut());
+            return if result == 0 { Ok(()) } else { Err(()) };
+        }
+    };
+    let equalizer = vlc::sys::libvlc_audio_equalizer_new();
+    if equalizer.is_null() {
+        return Err(());
+    }
+    vlc::sys::libvlc_audio_equalizer_set_preamp(equalizer, preamp);
+    for (band, amp) in bands.iter().enumerate() {
+        vlc::sys::libvlc_audio_equalizer_set_amp_at_index(equalizer, *amp, band as u32);
+    }
+    let result = vlc::sys::libvlc_media_player_set_equalizer(mediaplayer.raw(), equalizer);
+    vlc::sys::libvlc_audio_equalizer_release(equalizer);
+    if result == 0 { Ok(()) } else { Err(()) }
+}
+
+/// Converts a string owned by libvlc, which may be null
+unsafe fn from_c_string(ptr: *const std::os::raw::c_char) -> String {
+    if ptr.is_null() {
+        String::new()
+    } else {
+        std::ffi::CStr::from_ptr(ptr).to_string_lossy().into_owned()
+    }
+}
+
+/// Name and description of the audio output modules available to libvlc.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+pub fn audio_outputs(instance: &vlc::Instance) -> Vec<(String, String)> {
+    let mut outputs = Vec::new();
+    unsafe {
+        let list = vlc::sys::libvlc_audio_output_list_get(instance.raw());
+        let mut item = list;
+        while !item.is_null() {
+            outputs.push((from_c_string((*item).psz_name), from_c_string((*item).psz_description)));
+            item = (*item).p_next;
+        }
+        if !list.is_null() {
+            vlc::sys::libvlc_audio_output_list_release(list);
+        }
+    }
+    outputs
+}
+
+/// Id and description of the devices of an audio output module.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+pub fn audio_output_devices(instance: &vlc::Instance, output: &str) -> Vec<(String, String)> {
+    let c_output = match std::ffi::CString::new(output) {
+        Ok(c_output) => c_output,
+        Err(_) => return Vec::new(),
+    };
+    let mut devices = Vec::new();
+    unsafe {
+        let list = vlc::sys::libvlc_audio_output_device_list_get(instance.raw(), c_output.as_ptr());
+        let mut item = list;
+        while !item.is_null() {
+            devices.push((from_c_string((*item).psz_device), from_c_string((*item).psz_description)));
+            item = (*item).p_next;
+        }
+        if !list.is_null() {
+            vlc::sys::libvlc_audio_output_device_list_release(list);
+        }
+    }
+    devices
+}
+
+/// Selects the audio output module of the media player. Takes effect with the next media played.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+/// How to avoid undefined behaviour:
+/// Do not share the media player across threads.
+pub unsafe fn set_audio_output (mediaplayer: &vlc::MediaPlayer, output: &str) -> Result<(), ()> {
+    let c_output = std::ffi::CString::new(output).map_err(|_| ())?;
+    if vlc::sys::libvlc_audio_output_set(mediaplayer.raw(), c_output.as_ptr()) == 0 { Ok(()) } else { Err(()) }
+}
+
+/// Selects the device of the audio output module. If the media player is currently using that module, the device changes right away.
+/// Necessary because the rust bindings to libvlc do not yet offer a corresponding abstraction.
+/// How to avoid undefined behaviour:
+/// Do not share the media player across threads.
+pub unsafe fn set_audio_output_device (mediaplayer: &vlc::MediaPlayer, output: &str, device: &str) -> Result<(), ()> {
+    let c_output = std::ffi::CString::new(output).map_err(|_| ())?;
+    let c_device = std::ffi::CString::new(device).map_err(|_| ())?;
+    // the device for the next time the module is started
+    vlc::sys::libvlc_audio_output_device_set(mediaplayer.raw(), c_output.as_ptr(), c_device.as_ptr());
+    // the device of the running module, if any
+    vlc::sys::libvlc_audio_output_device_set(mediaplayer.raw(), std::ptr::null(), c_device.as_ptr());
+    Ok(())
+}
@@ -2,22 +2,147 @@
 /// over the websocket connection with the frontend
 /// As well as an actix actor implementation to send an receive these messages
 use std::collections::{HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use serde::{Serialize, Deserialize};
-use actix::{StreamHandler, Actor};
+use actix::{StreamHandler, Actor, Addr};
 use actix_web_actors::ws;
 
 use crate::vlc_helpers;
-use crate::{PlayerMsg};
+use crate::media_fs::MediaEntry;
+use crate::stations::Station;
+use crate::podcasts::Podcast;
+use crate::resume::ResumeInfo;
+use crate::queue::Queue;
+use crate::settings::{PlayerSettings, VolumeCurve};
+use crate::loudness::ReplayGainMode;
+use crate::equalizer::Equalizer;
+use crate::audio_output::{AudioOutput, OutputInfo};
+use crate::timers::{SleepRequest, AlarmRequest, AlarmTarget, Weekday, TimersInfo};
+use crate::zones::{ZoneState, DEFAULT_ZONE};
+use crate::sync::SyncInfo;
+use crate::player::{Player, PlayerMsg, ListEqualizerPresets, ListAudioOutputs, SubscribeZone};
+use crate::auth::Role;
+use crate::rate_limit::{self, RateLimits, TokenBucket};
+
+/// Larger messages close the connection. Enqueueing thousands of tracks at once still fits.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Connections which keep sending after being rate limited are closed after dropping this many messages in a row
+const MAX_DROPPED_MESSAGES: u32 = 100;
+/// How often the server pings every connection
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Connections the server heard nothing from for this long, not even a pong, are considered dead and closed
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag="type")]
 pub enum IncomingMsg {
     VolumeChange {volume: u64},
+    ToggleMute,
+    SetMaxVolume {max_volume: u64},
+    SetVolumeCurve {curve: VolumeCurve},
+    SetVolumeFade {fade: u64},
+    SetRate {rate: f32},
     Play {track_id: u64},
     Pause,
     Stop,
     Resume,
+    PlayStation {station_id: u64},
+    AddStation {name: String, url: String},
+    RemoveStation {station_id: u64},
+    Subscribe {url: String},
+    Unsubscribe {podcast_id: u64},
+    RefreshPodcasts,
+    PlayEpisode {episode_id: u64},
+    DownloadEpisode {episode_id: u64},
+    DeleteDownload {episode_id: u64},
+    MarkEpisodePlayed {episode_id: u64, played: bool},
+    ClearResumePosition {track_id: u64},
+    Enqueue {track_ids: Vec<u64>},
+    EnqueueEpisode {episode_id: u64},
+    RemoveFromQueue {index: usize},
+    ClearQueue,
+    PlayQueue {index: usize},
+    Next,
+    Previous,
+    SetGapless {gapless: bool},
+    SetCrossfade {crossfade: u64},
+    SetReplayGain {mode: ReplayGainMode, preamp: f32, prevent_clipping: bool},
+    ListEqualizerPresets,
+    SetEqualizerEnabled {enabled: bool},
+    ApplyEqualizerPreset {preset: String},
+    SetEqualizerBand {band: usize, amp: f32},
+    SetEqualizerPreamp {preamp: f32},
+    ListAudioOutputs,
+    SetAudioOutput {output: Option<String>, device: Option<String>},
+    SetSleepTimer {end: SleepRequest, fade_out: u64},
+    CancelSleepTimer,
+    AddAlarm {hour: u32, minute: u32, weekdays: Vec<Weekday>, target: AlarmTarget, volume: u64, ramp: u64},
+    RemoveAlarm {alarm_id: u64},
+    /// None subscribes to all zones
+    SubscribeZone {zone_id: Option<u64>},
+    MoveQueue {to_zone_id: u64},
+    GroupFollower {follower_id: u64},
+    UngroupFollower {follower_id: u64},
+    SetFollowerLatency {follower_id: u64, latency: i64},
+}
+
+impl IncomingMsg {
+    /// The role a connection needs to send the message
+    pub fn required_role(&self) -> Role {
+        match self {
+            IncomingMsg::ListEqualizerPresets
+            | IncomingMsg::ListAudioOutputs
+            | IncomingMsg::SubscribeZone{..} => Role::Listener,
+
+            IncomingMsg::VolumeChange{..}
+            | IncomingMsg::ToggleMute
+            | IncomingMsg::SetRate{..}
+            | IncomingMsg::Play{..}
+            | IncomingMsg::Pause
+            | IncomingMsg::Stop
+            | IncomingMsg::Resume
+            | IncomingMsg::PlayStation{..}
+            | IncomingMsg::RefreshPodcasts
+            | IncomingMsg::PlayEpisode{..}
+            | IncomingMsg::MarkEpisodePlayed{..}
+            | IncomingMsg::ClearResumePosition{..}
+            | IncomingMsg::Enqueue{..}
+            | IncomingMsg::EnqueueEpisode{..}
+            | IncomingMsg::RemoveFromQueue{..}
+            | IncomingMsg::ClearQueue
+            | IncomingMsg::PlayQueue{..}
+            | IncomingMsg::Next
+            | IncomingMsg::Previous
+            | IncomingMsg::SetSleepTimer{..}
+            | IncomingMsg::CancelSleepTimer
+            | IncomingMsg::MoveQueue{..} => Role::Controller,
+
+            IncomingMsg::SetMaxVolume{..}
+            | IncomingMsg::SetVolumeCurve{..}
+            | IncomingMsg::SetVolumeFade{..}
+            | IncomingMsg::AddStation{..}
+            | IncomingMsg::RemoveStation{..}
+            | IncomingMsg::Subscribe{..}
+            | IncomingMsg::Unsubscribe{..}
+            | IncomingMsg::DownloadEpisode{..}
+            | IncomingMsg::DeleteDownload{..}
+            | IncomingMsg::SetGapless{..}
+            | IncomingMsg::SetCrossfade{..}
+            | IncomingMsg::SetReplayGain{..}
+            | IncomingMsg::SetEqualizerEnabled{..}
+            | IncomingMsg::ApplyEqualizerPreset{..}
+            | IncomingMsg::SetEqualizerBand{..}
+            | IncomingMsg::SetEqualizerPreamp{..}
+            | IncomingMsg::SetAudioOutput{..}
+            | IncomingMsg::AddAlarm{..}
+            | IncomingMsg::RemoveAlarm{..}
+            | IncomingMsg::GroupFollower{..}
+            | IncomingMsg::UngroupFollower{..}
+            | IncomingMsg::SetFollowerLatency{..} => Role::Admin,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -28,44 +153,189 @@ pub enum OutgoingMsg {
     // Resume,
     // Stop,
     FsChange,
-    PlaybackChange{playback_state : PlaybackState},
-    PlayerState{playback_state: PlaybackState, media: HashMap<u64, String>}, //change type of media to MediaMetadata
+    PlaybackChange{zone_id: u64, playback_state : PlaybackState},
+    /// Playback state, queue, audio output and volume are those of the zone `zone_id`
+    PlayerState{zone_id: u64, zones: Vec<ZoneState>, playback_state: PlaybackState, media: HashMap<u64, String>, episodes: HashMap<u64, String>, stations: Vec<Station>, podcasts: Vec<Podcast>, resume_positions: Vec<ResumeInfo>, queue: Queue, settings: PlayerSettings, equalizer: Equalizer, audio_output: AudioOutput, volume: u64, muted: bool, timers: TimersInfo}, //change type of media to MediaMetadata
     RegisterSuccess,
     Error,
-    VolumeChange{volume: u64}
+    VolumeChange{zone_id: u64, volume: u64},
+    MuteChange{zone_id: u64, muted: bool},
+    StationsChange{stations: Vec<Station>},
+    /// `episodes` names the downloaded episodes, which are listed alongside the registered media
+    PodcastsChange{podcasts: Vec<Podcast>, episodes: HashMap<u64, String>},
+    ResumePositionsChange{resume_positions: Vec<ResumeInfo>},
+    QueueChange{zone_id: u64, queue: Queue},
+    PlayerSettingsChange{settings: PlayerSettings},
+    EqualizerPresets{presets: Vec<String>, frequencies: Vec<f32>},
+    EqualizerChange{equalizer: Equalizer},
+    AudioOutputs{outputs: Vec<OutputInfo>},
+    AudioOutputChange{zone_id: u64, audio_output: AudioOutput},
+    TimersChange{timers: TimersInfo},
+    SyncChange{sync: SyncInfo},
+    /// Sent when the connection is established, so clients can hide controls the role doesn't allow
+    Session{role: Role},
+    /// The connection's role doesn't allow the message it sent
+    PermissionDenied{message_type: String, required_role: Role},
+    /// Messages of the connection are dropped until it slows down
+    RateLimited,
+}
+
+impl OutgoingMsg {
+    /// The zone the message is about, None for state shared by all zones
+    pub fn zone_id(&self) -> Option<u64> {
+        match self {
+            OutgoingMsg::PlaybackChange{zone_id, ..}
+            | OutgoingMsg::VolumeChange{zone_id, ..}
+            | OutgoingMsg::MuteChange{zone_id, ..}
+            | OutgoingMsg::QueueChange{zone_id, ..}
+            | OutgoingMsg::AudioOutputChange{zone_id, ..} => Some(*zone_id),
+            _ => None,
+        }
+    }
+
+    /// Whether the message reports a piece of state, which makes earlier messages about the same state obsolete.
+    /// Replies, errors and notifications are not state.
+    fn is_state(&self) -> bool {
+        matches!(self,
+            OutgoingMsg::PlaybackChange{..}
+            | OutgoingMsg::PlayerState{..}
+            | OutgoingMsg::VolumeChange{..}
+            | OutgoingMsg::MuteChange{..}
+            | OutgoingMsg::StationsChange{..}
+            | OutgoingMsg::PodcastsChange{..}
+            | OutgoingMsg::ResumePositionsChange{..}
+            | OutgoingMsg::QueueChange{..}
+            | OutgoingMsg::PlayerSettingsChange{..}
+            | OutgoingMsg::EqualizerChange{..}
+            | OutgoingMsg::AudioOutputChange{..}
+            | OutgoingMsg::TimersChange{..}
+            | OutgoingMsg::SyncChange{..})
+    }
+
+    /// Whether the message makes an older, not yet sent message obsolete, so only the latest state is sent to slow connections
+    pub fn supersedes(&self, older: &OutgoingMsg) -> bool {
+        if !self.is_state() || !older.is_state() {
+            return false;
+        }
+        match (self, older) {
+            // the complete state contains everything but synchronized playback
+            (OutgoingMsg::PlayerState{..}, OutgoingMsg::SyncChange{..}) => false,
+            (OutgoingMsg::PlayerState{..}, _) => true,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(older) && self.zone_id() == older.zone_id(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl OutgoingMsg {
+    /// The complete state of a zone without any media, for tests
+    pub(crate) fn empty_player_state(zone_id: u64) -> Self {
+        OutgoingMsg::PlayerState {
+            zone_id,
+            zones: Vec::new(),
+            playback_state: PlaybackState::Stopped,
+            media: HashMap::new(),
+            episodes: HashMap::new(),
+            stations: Vec::new(),
+            podcasts: Vec::new(),
+            resume_positions: Vec::new(),
+            queue: Queue::default(),
+            settings: PlayerSettings::default(),
+            // the default equalizer asks libvlc for its bands
+            equalizer: Equalizer {
+                enabled: false,
+                preset: None,
+                preamp: 0.0,
+                bands: Vec::new(),
+            },
+            audio_output: AudioOutput::default(),
+            volume: 100,
+            muted: false,
+            timers: TimersInfo {
+                sleep_timer: None,
+                alarms: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Where the playing media comes from. Media and stations have separate id spaces.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum MediaSource {
+    File,
+    Station,
+    Episode,
 }
 
-#[derive(Clone, Copy, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct CurrentMedia {
     pub id: u64,
-    pub length: i64,
-    pub progress: i64
+    pub source: MediaSource,
+    /// None for streams of unknown length, such as internet radio
+    pub length: Option<i64>,
+    pub progress: i64,
+    /// The "now playing" information transmitted by the stream (ICY metadata), if any
+    pub now_playing: Option<String>,
+    /// The ReplayGain applied to the media in dB, None if no gain is applied
+    pub gain: Option<f32>,
+    /// Playback speed, 1.0 is normal speed. Progress advances by `rate` milliseconds per millisecond while playing.
+    pub rate: f32,
 }
 
 impl CurrentMedia {
-    pub fn new (media_id : u64, mediaplayer: &vlc::MediaPlayer) -> Self {
-        let media_length = unsafe { vlc_helpers::current_track_length(mediaplayer)};
-        if let Some(media_progress) =  mediaplayer.get_time() {
-            println!("track progress: {}", media_progress);
+    /// Length and progress are relative to the media entry, so cue sheet tracks and chapters report their own bounds
+    pub fn new (media_id : u64, entry: &MediaEntry, mediaplayer: &vlc::MediaPlayer) -> Self {
+        let file_length = unsafe { vlc_helpers::current_track_length(mediaplayer)};
+        let media_length = match entry.end {
+            Some(end) => end - entry.start,
+            None => (file_length - entry.start).max(0),
+        };
+        if let Some(media_time) =  mediaplayer.get_time() {
+            println!("track progress: {}", media_time);
             CurrentMedia {
                 id: media_id,
-                length: media_length,
-                progress: media_progress
+                source: MediaSource::File,
+                length: Some(media_length),
+                progress: (media_time - entry.start).max(0).min(media_length),
+                now_playing: None,
+                gain: None,
+                rate: mediaplayer.get_rate(),
             }
         }
         else {
             println!("Failed to get media time progress. Defaulting to 0");
             CurrentMedia {
                 id: media_id,
-                length: media_length,
+                source: MediaSource::File,
+                length: Some(media_length),
                 progress: 0,
+                now_playing: None,
+                gain: None,
+                rate: mediaplayer.get_rate(),
             }
         }
-        
+
+    }
+
+    /// Streams and podcast episodes report their length as soon as libvlc knows it, live streams have no length
+    pub fn stream (id: u64, source: MediaSource, mediaplayer: &vlc::MediaPlayer) -> Self {
+        let stream_length = unsafe { vlc_helpers::current_track_length(mediaplayer)};
+        let now_playing = mediaplayer
+            .get_media()
+            .and_then(|md| md.get_meta(vlc::Meta::NowPlaying));
+        CurrentMedia {
+            id,
+            source,
+            length: if stream_length > 0 { Some(stream_length) } else { None },
+            progress: mediaplayer.get_time().unwrap_or(0).max(0),
+            now_playing,
+            gain: None,
+            rate: mediaplayer.get_rate(),
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 #[serde(tag="playback-type")]
 pub enum PlaybackState {
     Playing{ current_media: CurrentMedia},
@@ -74,14 +344,14 @@ pub enum PlaybackState {
 }
 
 impl PlaybackState {
-    pub fn new (media_id: u64, mediaplayer: &vlc::MediaPlayer) -> Self {
+    /// Derives the playback state from the media player, with up to date progress for the current media
+    pub fn new (current_media: CurrentMedia, mediaplayer: &vlc::MediaPlayer) -> Self {
         match mediaplayer.state() {
-            vlc::State::Playing => {
-                
-                PlaybackState::Playing { current_media: CurrentMedia::new(media_id, mediaplayer)}
+            vlc::State::Playing | vlc::State::Opening | vlc::State::Buffering => {
+                PlaybackState::Playing { current_media}
             },
             vlc::State::Paused => {
-                PlaybackState::Paused { current_media: CurrentMedia::new(media_id, mediaplayer)}
+                PlaybackState::Paused { current_media}
             },
             vlc::State::Stopped => { 
                 PlaybackState::Stopped
@@ -107,11 +377,113 @@ type BasicError = &'static str;
 
 
 pub struct PlayerWs {
-    pub sender: crossbeam_channel::Sender<PlayerMsg>,
+    pub player: Addr<Player>,
+    /// The zone the connection subscribed to, messages without a zone_id are addressed to it.
+    /// Connections start out subscribed to the default zone, None subscribes to all zones.
+    pub zone: Option<u64>,
+    pub role: Role,
+    limiter: TokenBucket,
+    global_limiter: Arc<Mutex<TokenBucket>>,
+    /// Messages dropped in a row because of the rate limits
+    dropped: u32,
+    /// When the client last sent anything, including pongs
+    last_heartbeat: Instant,
+}
+
+impl PlayerWs {
+    pub fn new(player: Addr<Player>, role: Role, limits: &RateLimits) -> Self {
+        PlayerWs {
+            player,
+            zone: Some(DEFAULT_ZONE),
+            role,
+            limiter: TokenBucket::new(limits.per_connection),
+            global_limiter: limits.global.clone(),
+            dropped: 0,
+            last_heartbeat: Instant::now(),
+        }
+    }
+
+    fn within_rate_limits(&mut self) -> bool {
+        let mut global_limiter = self.global_limiter.lock().expect("Rate limit lock is poisoned. This is a bug.");
+        rate_limit::try_take_both(&mut self.limiter, &mut global_limiter)
+    }
+
+    /// Asks the player for the equalizer presets and answers the client once they arrive
+    fn list_equalizer_presets(&self, ctx: &mut <Self as Actor>::Context) {
+        //bring traits into scope for access to ctx.spawn() and into_actor()
+        use actix::{AsyncContext, ActorFuture, WrapFuture};
+
+        ctx.spawn(self.player.send(ListEqualizerPresets)
+            .into_actor(self)
+            .map(|(presets, frequencies), _, ctx| {
+                ctx.text(serde_json::json!(OutgoingMsg::EqualizerPresets{presets, frequencies}).to_string());
+            })
+            .map_err(|e, _, _| println!("Failed to list equalizer presets: {}", e)));
+    }
+
+    /// Asks the player for the audio outputs and answers the client once they arrive
+    fn list_audio_outputs(&self, ctx: &mut <Self as Actor>::Context) {
+        //bring traits into scope for access to ctx.spawn() and into_actor()
+        use actix::{AsyncContext, ActorFuture, WrapFuture};
+
+        ctx.spawn(self.player.send(ListAudioOutputs)
+            .into_actor(self)
+            .map(|outputs, _, ctx| {
+                ctx.text(serde_json::json!(OutgoingMsg::AudioOutputs{outputs}).to_string());
+            })
+            .map_err(|e, _, _| println!("Failed to list audio outputs: {}", e)));
+    }
+
+    /// Subscribes the connection to the zone once the player confirmed it exists, otherwise the previous zone is kept.
+    /// Later messages are handled after the subscription, so they are addressed to the new zone.
+    fn subscribe_zone(&self, zone_id: Option<u64>, ctx: &mut <Self as Actor>::Context) {
+        //bring traits into scope for access to ctx.wait(), ctx.address() and into_actor()
+        use actix::{AsyncContext, ActorFuture, WrapFuture};
+
+        ctx.wait(self.player.send(SubscribeZone{ws: ctx.address(), zone_id})
+            .into_actor(self)
+            .map(move |result, act, ctx| match result {
+                Ok(()) => act.zone = zone_id,
+                Err(e) => {
+                    println!("Failed to subscribe to zone: {}", e);
+                    ctx.text(serde_json::json!(OutgoingMsg::Error).to_string());
+                },
+            })
+            .map_err(|e, _, _| println!("Failed to subscribe to zone: {}", e)));
+    }
+
+    /// Pings the client and closes the connection once it stopped responding
+    fn heartbeat(&self, ctx: &mut <Self as Actor>::Context) {
+        //bring traits into scope for access to ctx.run_interval() and ctx.stop()
+        use actix::AsyncContext;
+        use actix::ActorContext;
+
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if act.last_heartbeat.elapsed() > CLIENT_TIMEOUT {
+                println!("Closing a connection which didn't respond for {} seconds", CLIENT_TIMEOUT.as_secs());
+                ctx.stop();
+                return;
+            }
+            ctx.ping("");
+        });
+    }
 }
 
 impl Actor for PlayerWs {
     type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.heartbeat(ctx);
+    }
+
+    /// Every way a connection ends stops the actor, so this is the one place it's unregistered
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        // bring trait into scope for access to ctx.address()
+        use actix::AsyncContext;
+
+        self.player.do_send(PlayerMsg::Unregister(ctx.address()));
+        println!("Ws unregistered");
+    }
 }
 
 impl actix::Handler<OutgoingMsg> for PlayerWs {
@@ -127,40 +499,123 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for PlayerWs {
         // bring trait into scope for access to ctx.address()
         use actix::AsyncContext;
 
-        let addr = ctx.address();
-        match self.sender.send(PlayerMsg::Register(addr)) {
-            Ok(()) => {
-                println!("Ws registered");
-            },
-            Err(e) => {
-                println!("Ws registration failed: {}", e);
-            },
-        }
+        ctx.text(serde_json::json!(OutgoingMsg::Session{role: self.role}).to_string());
+        self.player.do_send(PlayerMsg::Register(ctx.address()));
+        println!("Ws registered");
     }
     fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
 
+        // bring trait into scope for access to ctx.stop()
+        use actix::ActorContext;
+
+        self.last_heartbeat = Instant::now();
         match msg {
             ws::Message::Ping(msg) => ctx.pong(&msg),
+            ws::Message::Pong(_) => (),
+            ws::Message::Close(reason) => {
+                // answer the close frame, the client closes the tcp connection afterwards
+                println!("Ws closed by client: {:?}", reason);
+                ctx.close(reason);
+                ctx.stop();
+            },
             ws::Message::Text(text) => {
+                if !self.within_rate_limits() {
+                    self.dropped += 1;
+                    if self.dropped == 1 {
+                        ctx.text(serde_json::json!(OutgoingMsg::RateLimited).to_string());
+                    }
+                    if self.dropped >= MAX_DROPPED_MESSAGES {
+                        println!("Closing a connection which keeps exceeding the rate limit");
+                        ctx.close(Some(ws::CloseReason {
+                            code: ws::CloseCode::Policy,
+                            description: Some(String::from("Rate limit exceeded")),
+                        }));
+                        ctx.stop();
+                    }
+                    return;
+                }
+                self.dropped = 0;
                 println!("received:{}", &text);
-                let deserialized: serde_json::Result<IncomingMsg> = serde_json::from_str(&text);
+                // any message may name the zone it is addressed to
+                let deserialized: serde_json::Result<(IncomingMsg, Option<u64>)> = serde_json::from_str::<serde_json::Value>(&text)
+                    .and_then(|value| {
+                        let zone_id = value.get("zone_id").and_then(|zone_id| zone_id.as_u64());
+                        serde_json::from_value(value).map(|msg| (msg, zone_id))
+                    });
                 match deserialized {
-                    Ok(msg) => {
-                        let send_result = match msg {
-                            IncomingMsg::VolumeChange{volume} => self.sender.send(PlayerMsg::VolumeChange(volume)),
-                            IncomingMsg::Play{track_id} => self.sender.send(PlayerMsg::Play(track_id)),
-                            IncomingMsg::Pause => self.sender.send(PlayerMsg::Pause),
-                            IncomingMsg::Stop => self.sender.send(PlayerMsg::Stop),
-                            IncomingMsg::Resume => self.sender.send(PlayerMsg::Resume),
+                    Ok((msg, _)) if msg.required_role() > self.role => {
+                        let message_type = serde_json::from_str::<serde_json::Value>(&text).ok()
+                            .and_then(|value| value.get("type").and_then(|message_type| message_type.as_str()).map(|message_type| message_type.to_string()))
+                            .unwrap_or_default();
+                        println!("Denied {} to a connection with role {:?}", message_type, self.role);
+                        ctx.text(serde_json::json!(OutgoingMsg::PermissionDenied{message_type, required_role: msg.required_role()}).to_string());
+                    }
+                    Ok((msg, zone_id)) => {
+                        let zone_id = zone_id.or(self.zone).unwrap_or(DEFAULT_ZONE);
+                        let player_msg = match msg {
+                            IncomingMsg::VolumeChange{volume} => PlayerMsg::VolumeChange(volume),
+                            IncomingMsg::ToggleMute => PlayerMsg::ToggleMute,
+                            IncomingMsg::SetMaxVolume{max_volume} => PlayerMsg::SetMaxVolume(max_volume),
+                            IncomingMsg::SetVolumeCurve{curve} => PlayerMsg::SetVolumeCurve(curve),
+                            IncomingMsg::SetVolumeFade{fade} => PlayerMsg::SetVolumeFade(fade),
+                            IncomingMsg::SetRate{rate} => PlayerMsg::SetRate(rate),
+                            IncomingMsg::Play{track_id} => PlayerMsg::Play(track_id),
+                            IncomingMsg::Pause => PlayerMsg::Pause,
+                            IncomingMsg::Stop => PlayerMsg::Stop,
+                            IncomingMsg::Resume => PlayerMsg::Resume,
+                            IncomingMsg::PlayStation{station_id} => PlayerMsg::PlayStation(station_id),
+                            IncomingMsg::AddStation{name, url} => PlayerMsg::AddStation{name, url},
+                            IncomingMsg::RemoveStation{station_id} => PlayerMsg::RemoveStation(station_id),
+                            IncomingMsg::Subscribe{url} => PlayerMsg::Subscribe(url),
+                            IncomingMsg::Unsubscribe{podcast_id} => PlayerMsg::Unsubscribe(podcast_id),
+                            IncomingMsg::RefreshPodcasts => PlayerMsg::RefreshPodcasts,
+                            IncomingMsg::PlayEpisode{episode_id} => PlayerMsg::PlayEpisode(episode_id),
+                            IncomingMsg::DownloadEpisode{episode_id} => PlayerMsg::DownloadEpisode(episode_id),
+                            IncomingMsg::DeleteDownload{episode_id} => PlayerMsg::DeleteDownload(episode_id),
+                            IncomingMsg::MarkEpisodePlayed{episode_id, played} => PlayerMsg::MarkEpisodePlayed{episode_id, played},
+                            IncomingMsg::ClearResumePosition{track_id} => PlayerMsg::ClearResumePosition(track_id),
+                            IncomingMsg::Enqueue{track_ids} => PlayerMsg::Enqueue(track_ids),
+                            IncomingMsg::EnqueueEpisode{episode_id} => PlayerMsg::EnqueueEpisode(episode_id),
+                            IncomingMsg::RemoveFromQueue{index} => PlayerMsg::RemoveFromQueue(index),
+                            IncomingMsg::ClearQueue => PlayerMsg::ClearQueue,
+                            IncomingMsg::PlayQueue{index} => PlayerMsg::PlayQueue(index),
+                            IncomingMsg::Next => PlayerMsg::Next,
+                            IncomingMsg::Previous => PlayerMsg::Previous,
+                            IncomingMsg::SetGapless{gapless} => PlayerMsg::SetGapless(gapless),
+                            IncomingMsg::SetCrossfade{crossfade} => PlayerMsg::SetCrossfade(crossfade),
+                            IncomingMsg::SetReplayGain{mode, preamp, prevent_clipping} => PlayerMsg::SetReplayGain{mode, preamp, prevent_clipping},
+                            IncomingMsg::ListEqualizerPresets => {
+                                // answered to this connection only
+                                self.list_equalizer_presets(ctx);
+                                return;
+                            },
+                            IncomingMsg::SetEqualizerEnabled{enabled} => PlayerMsg::SetEqualizerEnabled(enabled),
+                            IncomingMsg::ApplyEqualizerPreset{preset} => PlayerMsg::ApplyEqualizerPreset(preset),
+                            IncomingMsg::SetEqualizerBand{band, amp} => PlayerMsg::SetEqualizerBand{band, amp},
+                            IncomingMsg::SetEqualizerPreamp{preamp} => PlayerMsg::SetEqualizerPreamp(preamp),
+                            IncomingMsg::ListAudioOutputs => {
+                                // answered to this connection only
+                                self.list_audio_outputs(ctx);
+                                return;
+                            },
+                            IncomingMsg::SetAudioOutput{output, device} => PlayerMsg::SetAudioOutput(AudioOutput{output, device}),
+                            IncomingMsg::SetSleepTimer{end, fade_out} => PlayerMsg::SetSleepTimer{end, fade_out},
+                            IncomingMsg::CancelSleepTimer => PlayerMsg::CancelSleepTimer,
+                            IncomingMsg::AddAlarm{hour, minute, weekdays, target, volume, ramp} => {
+                                PlayerMsg::AddAlarm(AlarmRequest{hour, minute, weekdays, target, volume, ramp})
+                            },
+                            IncomingMsg::RemoveAlarm{alarm_id} => PlayerMsg::RemoveAlarm(alarm_id),
+                            IncomingMsg::SubscribeZone{zone_id} => {
+                                // subscriptions concern the connection rather than a zone
+                                self.subscribe_zone(zone_id, ctx);
+                                return;
+                            },
+                            IncomingMsg::MoveQueue{to_zone_id} => PlayerMsg::MoveQueue(to_zone_id),
+                            IncomingMsg::GroupFollower{follower_id} => PlayerMsg::GroupFollower(follower_id),
+                            IncomingMsg::UngroupFollower{follower_id} => PlayerMsg::UngroupFollower(follower_id),
+                            IncomingMsg::SetFollowerLatency{follower_id, latency} => PlayerMsg::SetFollowerLatency{follower_id, latency},
                         };
-                        match send_result {
-                            Ok(()) => {
-                                // everything good
-                            }
-                            Err(_) => {
-                                // TODO: Retry? Notify client?
-                            }
-                        }
+                        self.player.do_send(PlayerMsg::Zone(zone_id, Box::new(player_msg)));
                     }
                     Err(_) => {
                         println!("Failed to deserialize message: '{}'", &text);
@@ -168,27 +623,85 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for PlayerWs {
                     },
                 }
             }
-            _ => (),
+            ws::Message::Binary(_) => println!("Ignoring binary message, only text messages are supported"),
+            ws::Message::Nop => (),
         }
     }
+    fn error(&mut self, err: ws::ProtocolError, ctx: &mut Self::Context) -> actix::Running {
+        match err {
+            ws::ProtocolError::Overflow => {
+                println!("Closing a connection which sent a message larger than {} bytes", MAX_MESSAGE_SIZE);
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Size,
+                    description: Some(String::from("Message too large")),
+                }));
+            },
+            err => println!("Websocket protocol error: {}", err),
+        }
+        actix::Running::Stop
+    }
     fn finished(&mut self, ctx: &mut Self::Context) {
-        //bring traits into scope for access to ctx.address() and ctx.stop()
-        use actix::AsyncContext;
+        //bring trait into scope for access to ctx.stop()
         use actix::ActorContext;
 
-        let addr = ctx.address();
-        match self.sender.send(PlayerMsg::Unregister(addr)) {
-            Ok(()) => {
-                println!("Ws unregistered");
-            },
-            Err(e) => {
-                println!("Ws unregistration failed: {}", e);
-            },
-        }
         ctx.stop();
     }
 }
 
 impl actix::Message for OutgoingMsg {
     type Result = Result<(), BasicError>;
-}
\ No newline at end of file
+}
+
+/// Sent by the player to connections which fell too far behind
+pub struct Disconnect;
+
+impl actix::Message for Disconnect {
+    type Result = ();
+}
+
+impl actix::Handler<Disconnect> for PlayerWs {
+    type Result = ();
+    fn handle(&mut self, _msg: Disconnect, ctx: &mut Self::Context) {
+        // bring trait into scope for access to ctx.stop()
+        use actix::ActorContext;
+
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Again,
+            description: Some(String::from("Too far behind, reconnect to receive the current state")),
+        }));
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_state_supersedes_older_state_of_the_same_zone() {
+        let volume = |zone_id, volume| OutgoingMsg::VolumeChange{zone_id, volume};
+        assert!(volume(0, 50).supersedes(&volume(0, 40)));
+        assert!(!volume(1, 50).supersedes(&volume(0, 40)));
+        assert!(!volume(0, 50).supersedes(&OutgoingMsg::MuteChange{zone_id: 0, muted: true}));
+        assert!(OutgoingMsg::StationsChange{stations: Vec::new()}.supersedes(&OutgoingMsg::StationsChange{stations: Vec::new()}));
+    }
+
+    #[test]
+    fn replies_and_notifications_are_never_superseded() {
+        assert!(!OutgoingMsg::Error.supersedes(&OutgoingMsg::Error));
+        assert!(!OutgoingMsg::RateLimited.supersedes(&OutgoingMsg::RateLimited));
+        assert!(!OutgoingMsg::empty_player_state(0).supersedes(&OutgoingMsg::Session{role: Role::Admin}));
+        assert!(!OutgoingMsg::empty_player_state(0).supersedes(&OutgoingMsg::AudioOutputs{outputs: Vec::new()}));
+        assert!(!OutgoingMsg::Error.supersedes(&OutgoingMsg::VolumeChange{zone_id: 0, volume: 40}));
+    }
+
+    #[test]
+    fn complete_state_supersedes_everything_but_synchronized_playback() {
+        assert!(OutgoingMsg::empty_player_state(0).supersedes(&OutgoingMsg::VolumeChange{zone_id: 1, volume: 40}));
+        assert!(OutgoingMsg::empty_player_state(0).supersedes(&OutgoingMsg::QueueChange{zone_id: 0, queue: Queue::default()}));
+        assert!(OutgoingMsg::empty_player_state(1).supersedes(&OutgoingMsg::empty_player_state(0)));
+        assert!(!OutgoingMsg::empty_player_state(0).supersedes(&OutgoingMsg::SyncChange{sync: SyncInfo::Standalone}));
+        // partial state doesn't replace the complete state
+        assert!(!OutgoingMsg::VolumeChange{zone_id: 0, volume: 40}.supersedes(&OutgoingMsg::empty_player_state(0)));
+    }
+}
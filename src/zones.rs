@@ -0,0 +1,97 @@
+//! Playback zones, e.g. a kitchen and an office. Every zone has its own media player, queue, volume and audio output.
+//! The library, stations, podcasts and player settings are shared by all zones.
+
+use serde::Serialize;
+
+use crate::audio_output::AudioOutput;
+use crate::queue::Queue;
+use crate::websocket::PlaybackState;
+
+/// The zone clients address when they don't name one
+pub const DEFAULT_ZONE: u64 = 0;
+
+/// Name of the only zone if no zones are defined on the command line
+pub const DEFAULT_ZONE_NAME: &str = "default";
+
+/// A zone as defined on the command line
+#[derive(Clone, Debug)]
+pub struct ZoneConfig {
+    pub name: String,
+    /// Output selected on the command line, replaces the persisted selection of the zone
+    pub audio_output: Option<AudioOutput>,
+}
+
+/// A zone along with its playback state, as reported to clients
+#[derive(Clone, Debug, Serialize)]
+pub struct ZoneState {
+    pub id: u64,
+    pub name: String,
+    pub playback_state: PlaybackState,
+    pub queue: Queue,
+    pub volume: u64,
+    pub muted: bool,
+    pub audio_output: AudioOutput,
+}
+
+/// Parses a zone definition of the form NAME or NAME=OUTPUT[:DEVICE], e.g. kitchen=alsa:hw:1,0
+pub fn parse_zone(spec: &str) -> Result<ZoneConfig, String> {
+    let mut parts = spec.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    // the name is part of the file the zone's audio output is persisted in
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("'{}' is not a valid zone name, use letters, digits, '-' and '_'", name));
+    }
+    let audio_output = match parts.next() {
+        Some(output) => {
+            let mut output_parts = output.splitn(2, ':');
+            let output = output_parts.next().unwrap_or("");
+            if output.is_empty() {
+                return Err(format!("Missing audio output of zone '{}'", name));
+            }
+            Some(AudioOutput {
+                output: Some(output.to_string()),
+                device: output_parts.next().map(|device| device.to_string()),
+            })
+        },
+        None => None,
+    };
+    Ok(ZoneConfig {
+        name: name.to_string(),
+        audio_output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_name_output_and_device() {
+        let zone = parse_zone("kitchen=alsa:hw:1,0").expect("Failed to parse zone");
+        assert_eq!(zone.name, "kitchen");
+        assert_eq!(zone.audio_output, Some(AudioOutput {
+            output: Some(String::from("alsa")),
+            device: Some(String::from("hw:1,0")),
+        }));
+
+        let zone = parse_zone("living_room-2=pulse").expect("Failed to parse zone");
+        assert_eq!(zone.name, "living_room-2");
+        assert_eq!(zone.audio_output, Some(AudioOutput {
+            output: Some(String::from("pulse")),
+            device: None,
+        }));
+
+        let zone = parse_zone("bath").expect("Failed to parse zone");
+        assert_eq!(zone.audio_output, None);
+    }
+
+    #[test]
+    fn rejects_invalid_zones() {
+        assert!(parse_zone("").is_err());
+        assert!(parse_zone("=alsa").is_err());
+        assert!(parse_zone("kitchen=").is_err());
+        assert!(parse_zone("kitchen=:hw:1,0").is_err());
+        assert!(parse_zone("../kitchen=alsa").is_err());
+        assert!(parse_zone("küche").is_err());
+    }
+}